@@ -1,66 +1,317 @@
 use std::collections::HashSet;
+use std::fs;
 use std::sync::{Arc, Mutex};
 
-use crate::coprocessor::{Coprocessor0, CAUSE};
+use crate::config::{Config, TimeMode, DEFAULT_RNG_SEED, DEFAULT_RTC_EPOCH};
+use crate::coprocessor::{Coprocessor0, CAUSE, PERF_LEVEL, TIMER_LEVEL};
 use crate::cpu::Size;
 use crate::devices::device::Device;
 use crate::dram::Dram;
 use crate::exception::Exception;
 use crate::rom::Rom;
-use crate::devices::{uart::Uart, virtio::Virtio};
+use crate::devices::{disk::Disk, dma::{Dma, CHANNELS, CHANNEL_SIZE, DMA_IRQ}, framebuffer::{Framebuffer, FRAMEBUFFER_IRQ}, gpio::{Gpio, GPIO_IRQ}, i2c::{Eeprom, I2c, Lm75, EEPROM_ADDRESS, I2C_IRQ, LM75_ADDRESS}, irq::Irq, netdev, rtc::{Rtc, RTC_IRQ}, sd::{SdHost, SD_IRQ}, serial, spi::{Spi, SpiFlash, FLASH_CS, FLASH_SIZE, SPI_IRQ}, syscon::Syscon, uart::Uart, watchdog::Watchdog};
+use crate::devices::virtio::{blk::{Blk, DEFAULT_CAPACITY}, console::Console, net::Net, p9::P9, rng::Rng, Virtio, VIRTIO_IRQ};
 
 
 pub const DRAM_BASE: u32 = 0x00000000;
 pub const DRAM_SIZE: u32 = 0x80000000;
 pub const DRAM_END: u32 = DRAM_BASE + DRAM_SIZE - 1;
+/// Small system devices share the page at 0xffffb000.
+pub const RTC_BASE: u32 = 0xffffb000;
+pub const RTC_SIZE: u32 = 0x20;
+pub const RTC_END: u32 = RTC_BASE + RTC_SIZE - 1;
+pub const SYSCON_BASE: u32 = 0xffffb100;
+pub const SYSCON_SIZE: u32 = 0x8;
+pub const SYSCON_END: u32 = SYSCON_BASE + SYSCON_SIZE - 1;
+pub const WATCHDOG_BASE: u32 = 0xffffb200;
+pub const WATCHDOG_SIZE: u32 = 0x14;
+pub const WATCHDOG_END: u32 = WATCHDOG_BASE + WATCHDOG_SIZE - 1;
+pub const FRAMEBUFFER_BASE: u32 = 0xffffb300;
+pub const FRAMEBUFFER_SIZE: u32 = 0x24;
+pub const FRAMEBUFFER_END: u32 = FRAMEBUFFER_BASE + FRAMEBUFFER_SIZE - 1;
+pub const DMA_BASE: u32 = 0xffffb400;
+pub const DMA_SIZE: u32 = CHANNELS as u32 * CHANNEL_SIZE;
+pub const DMA_END: u32 = DMA_BASE + DMA_SIZE - 1;
+pub const SD_BASE: u32 = 0xffffb500;
+pub const SD_SIZE: u32 = 0x34;
+pub const SD_END: u32 = SD_BASE + SD_SIZE - 1;
+pub const GPIO_BASE: u32 = 0xffffb600;
+pub const GPIO_SIZE: u32 = 0x18;
+pub const GPIO_END: u32 = GPIO_BASE + GPIO_SIZE - 1;
+pub const I2C_BASE: u32 = 0xffffb700;
+pub const I2C_SIZE: u32 = 0x14;
+pub const I2C_END: u32 = I2C_BASE + I2C_SIZE - 1;
+pub const SPI_BASE: u32 = 0xffffb800;
+pub const SPI_SIZE: u32 = 0x14;
+pub const SPI_END: u32 = SPI_BASE + SPI_SIZE - 1;
 pub const COPROCESSOR_BASE: u32 = 0xffffc000;
-pub const COPROCESSOR_SIZE: u32 = 0x80;
+pub const COPROCESSOR_SIZE: u32 = 0x800;
 pub const COPROCESSOR_END: u32 = COPROCESSOR_BASE + COPROCESSOR_SIZE - 1;
 
 pub const UART_BASE: u32 = 0xffffd000;
 pub const UART_SIZE: u32 = 0x100;
-pub const UART_END: u32 = UART_BASE + UART_SIZE - 1;
+/// UARTs sit in `UART_SIZE` slots of the UART region. The last slot holds the UART directory the
+/// guest reads to find them: the number of UARTs, then the base address and interrupt level of
+/// each, one word apiece.
+pub const UART_REGION_SIZE: u32 = 0x1000;
+pub const UART_END: u32 = UART_BASE + UART_REGION_SIZE - 1;
+pub const UART_DIRECTORY: u32 = UART_END + 1 - UART_SIZE;
 
 pub const VIRTIO_BASE: u32 = 0xffffe000;
 pub const VIRTIO_SIZE: u32 = 0x1000;
 pub const VIRTIO_END: u32 = VIRTIO_BASE + VIRTIO_SIZE - 1;
+/// Each virtio device gets its own transport registers in a slot of the virtio region. Slots are
+/// handed out in the order block device, console, network, entropy, 9P share, skipping devices
+/// that are not configured.
+pub const VIRTIO_SLOT_SIZE: u32 = 0x200;
 pub const ROM_BASE: u32 = 0xfffff000;
 pub const ROM_SIZE: u32 = 0x1000;
 pub const ROM_END: u32 = ROM_BASE - 1 + ROM_SIZE;
+
+// Sources of the shared interrupt lines. A UART may sit on any free level, so sources are unique
+// across the virtio, DMA, SD, framebuffer, RTC and UART ranges; the GPIO, I2C and SPI
+// controllers share the default UART line below the UARTs.
+/// Virtio transports, one each in slot order.
+const VIRTIO_SOURCE: u8 = 0;
+/// DMA channels, one each.
+const DMA_SOURCE: u8 = 8;
+const SD_SOURCE: u8 = 12;
+const FRAMEBUFFER_SOURCE: u8 = 14;
+const RTC_SOURCE: u8 = 15;
+/// UARTs, one each in configuration order.
+const UART_SOURCE: u8 = 16;
+const GPIO_SOURCE: u8 = 0;
+const I2C_SOURCE: u8 = 1;
+const SPI_SOURCE: u8 = 2;
+
 pub struct Bus {
     pub dram: Dram,
     pub coprocessor: Coprocessor0,
-    uart: Uart,
-    pub virtio: Virtio,
+    pub rtc: Rtc,
+    pub syscon: Syscon,
+    pub watchdog: Watchdog,
+    pub framebuffer: Framebuffer,
+    /// Taken out while it moves data, so a channel pointed at the DMA registers gets a bus error.
+    dma: Option<Dma>,
+    sd: SdHost,
+    pub gpio: Gpio,
+    /// Comes with an EEPROM and a temperature sensor attached.
+    pub i2c: I2c,
+    /// Comes with a flash chip attached.
+    pub spi: Spi,
+    /// Base address, interrupt level and device of each UART, in directory order.
+    uarts: Vec<(u32, u8, Uart)>,
+    /// Virtio transports by slot; slot 0 is the block device.
+    pub virtio: Vec<Virtio>,
     rom: Rom,
     pub atomic: HashSet<u32>
 }
 
 impl Bus {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
+        let coprocessor = Coprocessor0::new(config.time_mode);
+        let cause = coprocessor.raw(CAUSE);
+        let lines: Vec<Irq> = (0..8).map(|level| Irq::new(cause.clone(), level)).collect();
+        let virtio_irq = lines[VIRTIO_IRQ as usize].clone();
+        let disk = match &config.disk {
+            Some(disk) => Disk::open(&disk.path, disk.read_only, disk.overlay.clone())
+                .unwrap_or_else(|e| panic!("cannot open disk image {}: {}", disk.path, e)),
+            None => Disk::blank(DEFAULT_CAPACITY),
+        };
+        let mut virtio = vec![Virtio::new(Box::new(Blk::new(disk)), config.virtio_version, virtio_irq.share(VIRTIO_SOURCE))];
+        if !config.console_ports.is_empty() {
+            let console = Console::new(&config.console_ports)
+                .unwrap_or_else(|e| panic!("cannot open console ports: {}", e));
+            let irq = virtio_irq.share(VIRTIO_SOURCE + virtio.len() as u8);
+            virtio.push(Virtio::new(Box::new(console), config.virtio_version, irq));
+        }
+        if let Some(net) = &config.net {
+            let backend = netdev::open(&net.link, net.pcap.as_deref())
+                .unwrap_or_else(|e| panic!("cannot open network link: {}", e));
+            let irq = virtio_irq.share(VIRTIO_SOURCE + virtio.len() as u8);
+            virtio.push(Virtio::new(Box::new(Net::new(net.mac, backend)), config.virtio_version, irq));
+        }
+        if config.rng {
+            let seed = match config.time_mode {
+                TimeMode::Deterministic => Some(config.rng_seed.unwrap_or(DEFAULT_RNG_SEED)),
+                TimeMode::RealTime => config.rng_seed,
+            };
+            let rng = Rng::new(seed).unwrap_or_else(|e| panic!("cannot open host entropy: {}", e));
+            let irq = virtio_irq.share(VIRTIO_SOURCE + virtio.len() as u8);
+            virtio.push(Virtio::new(Box::new(rng), config.virtio_version, irq));
+        }
+        if let Some(share) = &config.share {
+            let p9 = P9::new(&share.path, &share.tag, share.read_only)
+                .unwrap_or_else(|e| panic!("cannot share {}: {}", share.path, e));
+            let irq = virtio_irq.share(VIRTIO_SOURCE + virtio.len() as u8);
+            virtio.push(Virtio::new(Box::new(p9), config.virtio_version, irq));
+        }
+        let mut uarts: Vec<(u32, u8, Uart)> = Vec::new();
+        for (i, uart) in config.uarts.iter().enumerate() {
+            if !(UART_BASE..UART_DIRECTORY).contains(&uart.base) || !uart.base.is_multiple_of(UART_SIZE) {
+                panic!("UART base {:#x} is not a slot of the UART region", uart.base);
+            }
+            if uarts.iter().any(|(base, ..)| *base == uart.base) {
+                panic!("two UARTs at {:#x}", uart.base);
+            }
+            // levels 0 and 1 are software interrupts, CP0 drives the timer and perf counter lines
+            if !(2..8).contains(&uart.irq) || uart.irq == TIMER_LEVEL || uart.irq == PERF_LEVEL {
+                panic!("UART interrupt level {} is not available", uart.irq);
+            }
+            let backend = serial::open(&uart.link).unwrap_or_else(|e| panic!("cannot open serial backend: {}", e));
+            let irq = lines[uart.irq as usize].share(UART_SOURCE + i as u8);
+            uarts.push((uart.base, uart.irq, Uart::new(backend, irq)));
+        }
+        let epoch = match config.time_mode {
+            TimeMode::Deterministic => Some(config.rtc_epoch.unwrap_or(DEFAULT_RTC_EPOCH)),
+            TimeMode::RealTime => config.rtc_epoch,
+        };
+        let rtc = Rtc::new(config.time_mode, epoch, lines[RTC_IRQ as usize].share(RTC_SOURCE));
+        let framebuffer = Framebuffer::new(config.fb_dump.clone(), lines[FRAMEBUFFER_IRQ as usize].share(FRAMEBUFFER_SOURCE));
+        let dma = Dma::new((0..CHANNELS).map(|i| lines[DMA_IRQ as usize].share(DMA_SOURCE + i as u8)).collect());
+        let card = config.sd.as_ref().map(|sd| {
+            Disk::open(&sd.path, sd.read_only, sd.overlay.clone()).unwrap_or_else(|e| panic!("cannot open SD card image {}: {}", sd.path, e))
+        });
+        let sd = SdHost::new(card, lines[SD_IRQ as usize].share(SD_SOURCE));
+        let gpio = Gpio::new(lines[GPIO_IRQ as usize].share(GPIO_SOURCE));
+        let read_image = |path: &str| fs::read(path).unwrap_or_else(|e| panic!("cannot read image {}: {}", path, e));
+        let eeprom = Eeprom::default();
+        if let Some(path) = &config.eeprom_image {
+            eeprom.load(&read_image(path));
+        }
+        let mut i2c = I2c::new(lines[I2C_IRQ as usize].share(I2C_SOURCE));
+        i2c.attach(EEPROM_ADDRESS, Box::new(eeprom));
+        i2c.attach(LM75_ADDRESS, Box::new(Lm75::default()));
+        let flash = SpiFlash::default();
+        if let Some(path) = &config.flash_image {
+            let image = read_image(path);
+            if image.len() > FLASH_SIZE {
+                panic!("SPI flash image {} is larger than {} bytes", path, FLASH_SIZE);
+            }
+            flash.load(0, &image);
+        }
+        let mut spi = Spi::new(lines[SPI_IRQ as usize].share(SPI_SOURCE));
+        spi.attach(FLASH_CS, Box::new(flash));
         Self {
             rom: Rom::new(),
-            coprocessor: Coprocessor0::new(),
-            uart: Uart::new(),
-            virtio: Virtio::new(),
+            coprocessor,
+            rtc,
+            syscon: Syscon::default(),
+            watchdog: Watchdog::new(config.watchdog_action, config.watchdog_timeout),
+            framebuffer,
+            dma: Some(dma),
+            sd,
+            gpio,
+            i2c,
+            spi,
+            uarts,
+            virtio,
             dram: Dram::new(),
             atomic: HashSet::new()
         }
     }
     pub fn get_raw_cause(&self) -> Arc<Mutex<u32>> {
-        self.coprocessor.registers[CAUSE as usize].clone()
+        self.coprocessor.raw(CAUSE)
     }
     pub fn load_rom(&mut self, file: &str) {
         self.rom.load_binary(file);
     }
+    /// Advances every timed device by `cycles` and lets devices serve pending requests.
+    pub fn advance(&mut self, cycles: u64) {
+        self.coprocessor.advance(cycles);
+        self.rtc.advance(cycles);
+        self.watchdog.advance(cycles);
+        self.framebuffer.advance(cycles, &mut self.dram);
+        self.sd.advance(cycles, &mut self.dram);
+        self.gpio.advance(cycles);
+        self.i2c.advance(cycles);
+        self.spi.advance(cycles);
+        if self.dma_busy() {
+            if let Some(mut dma) = self.dma.take() {
+                dma.advance(cycles, self);
+                self.dma = Some(dma);
+            }
+        }
+        for (_, _, uart) in self.uarts.iter_mut() {
+            uart.advance(cycles);
+        }
+        for transport in self.virtio.iter_mut() {
+            if let Err(e) = transport.process(&mut self.dram) {
+                eprintln!("virtio: request failed: {:?}", e);
+                transport.needs_reset();
+            }
+        }
+    }
+    /// Returns true while a DMA channel is transferring and owns the bus.
+    pub fn dma_busy(&self) -> bool {
+        self.dma.as_ref().is_some_and(Dma::busy)
+    }
+    /// The UART whose slot holds `addr`.
+    fn uart_at(&mut self, addr: u32) -> Option<&mut Uart> {
+        let base = addr & !(UART_SIZE - 1);
+        self.uarts.iter_mut().find(|(b, ..)| *b == base).map(|(_, _, uart)| uart)
+    }
+    /// Reads a word of the UART directory. Words past the last UART read as zero.
+    fn read_uart_directory(&self, offset: u32, size: Size) -> Result<u32, Exception> {
+        if size != Size::Word || !offset.is_multiple_of(4) {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        if offset == 0 {
+            return Ok(self.uarts.len() as u32);
+        }
+        let entry = (offset - 4) / 8;
+        Ok(match self.uarts.get(entry as usize) {
+            Some((base, _, _)) if (offset - 4).is_multiple_of(8) => *base,
+            Some((_, irq, _)) => *irq as u32,
+            None => 0,
+        })
+    }
+    /// Cycles until the next timed device event, if any device schedules one.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        let uarts = self.uarts.iter().filter_map(|(_, _, uart)| uart.cycles_until_event());
+        let devices = [
+            self.coprocessor.cycles_until_event(),
+            self.rtc.cycles_until_event(),
+            self.watchdog.cycles_until_event(),
+            self.framebuffer.cycles_until_event(),
+            self.dma.as_ref().and_then(Dma::cycles_until_event),
+            self.sd.cycles_until_event(),
+            self.gpio.cycles_until_event(),
+            self.i2c.cycles_until_event(),
+            self.spi.cycles_until_event(),
+        ];
+        devices.into_iter().flatten().chain(uarts).min()
+    }
 }
 impl Device for Bus {
     fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
         match addr {
             DRAM_BASE..=DRAM_END => self.dram.read(addr - DRAM_BASE, size),
+            RTC_BASE..=RTC_END => self.rtc.read(addr - RTC_BASE, size),
+            SYSCON_BASE..=SYSCON_END => self.syscon.read(addr - SYSCON_BASE, size),
+            WATCHDOG_BASE..=WATCHDOG_END => self.watchdog.read(addr - WATCHDOG_BASE, size),
+            FRAMEBUFFER_BASE..=FRAMEBUFFER_END => self.framebuffer.read(addr - FRAMEBUFFER_BASE, size),
+            DMA_BASE..=DMA_END => match self.dma.as_mut() {
+                Some(dma) => dma.read(addr - DMA_BASE, size),
+                None => Err(Exception::LoadIllegalAddress),
+            },
+            SD_BASE..=SD_END => self.sd.read(addr - SD_BASE, size),
+            GPIO_BASE..=GPIO_END => self.gpio.read(addr - GPIO_BASE, size),
+            I2C_BASE..=I2C_END => self.i2c.read(addr - I2C_BASE, size),
+            SPI_BASE..=SPI_END => self.spi.read(addr - SPI_BASE, size),
             COPROCESSOR_BASE..=COPROCESSOR_END => self.coprocessor.read(addr - COPROCESSOR_BASE, size),
-            UART_BASE..=UART_END => self.uart.read(addr - UART_BASE, size),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio.read(addr - VIRTIO_BASE, size),
+            UART_DIRECTORY..=UART_END => self.read_uart_directory(addr - UART_DIRECTORY, size),
+            UART_BASE..=UART_END => match self.uart_at(addr) {
+                Some(uart) => uart.read(addr % UART_SIZE, size),
+                None => Err(Exception::LoadIllegalAddress),
+            },
+            VIRTIO_BASE..=VIRTIO_END => {
+                let offset = addr - VIRTIO_BASE;
+                match self.virtio.get_mut((offset / VIRTIO_SLOT_SIZE) as usize) {
+                    Some(transport) => transport.read(offset % VIRTIO_SLOT_SIZE, size),
+                    None => Err(Exception::LoadIllegalAddress),
+                }
+            }
             ROM_BASE..=ROM_END => self.rom.read(addr - ROM_BASE, size),
             _ => Err(Exception::LoadIllegalAddress)
         }
@@ -69,9 +320,30 @@ impl Device for Bus {
         self.atomic.remove(&addr);
         match addr {
             DRAM_BASE..=DRAM_END => self.dram.write(addr - DRAM_BASE, data, size),
+            RTC_BASE..=RTC_END => self.rtc.write(addr - RTC_BASE, data, size),
+            SYSCON_BASE..=SYSCON_END => self.syscon.write(addr - SYSCON_BASE, data, size),
+            WATCHDOG_BASE..=WATCHDOG_END => self.watchdog.write(addr - WATCHDOG_BASE, data, size),
+            FRAMEBUFFER_BASE..=FRAMEBUFFER_END => self.framebuffer.write(addr - FRAMEBUFFER_BASE, data, size),
+            DMA_BASE..=DMA_END => match self.dma.as_mut() {
+                Some(dma) => dma.write(addr - DMA_BASE, data, size),
+                None => Err(Exception::StoreIllegalAddress),
+            },
+            SD_BASE..=SD_END => self.sd.write(addr - SD_BASE, data, size),
+            GPIO_BASE..=GPIO_END => self.gpio.write(addr - GPIO_BASE, data, size),
+            I2C_BASE..=I2C_END => self.i2c.write(addr - I2C_BASE, data, size),
+            SPI_BASE..=SPI_END => self.spi.write(addr - SPI_BASE, data, size),
             COPROCESSOR_BASE..=COPROCESSOR_END => self.coprocessor.write(addr - COPROCESSOR_BASE, data, size),
-            UART_BASE..=UART_END => self.uart.write(addr - UART_BASE, data, size),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio.write(addr - VIRTIO_BASE, data, size),
+            UART_BASE..=UART_END => match self.uart_at(addr) {
+                Some(uart) => uart.write(addr % UART_SIZE, data, size),
+                None => Err(Exception::StoreIllegalAddress),
+            },
+            VIRTIO_BASE..=VIRTIO_END => {
+                let offset = addr - VIRTIO_BASE;
+                match self.virtio.get_mut((offset / VIRTIO_SLOT_SIZE) as usize) {
+                    Some(transport) => transport.write(offset % VIRTIO_SLOT_SIZE, data, size),
+                    None => Err(Exception::StoreIllegalAddress),
+                }
+            }
             ROM_BASE..=ROM_END => self.rom.write(addr - ROM_BASE, data, size),
             _ => Err(Exception::LoadIllegalAddress)
         }
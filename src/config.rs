@@ -0,0 +1,279 @@
+use std::env;
+
+use crate::bus::UART_BASE;
+
+use crate::devices::chardev::Backend;
+use crate::devices::disk::Overlay;
+use crate::devices::framebuffer::{DumpConfig, ImageFormat};
+use crate::devices::netdev::Link;
+use crate::devices::serial::SerialLink;
+use crate::devices::uart::UART_IRQ;
+use crate::devices::watchdog::Action;
+use crate::devices::virtio::console::{PortConfig, MAX_PORTS};
+
+/// How guest time relates to host time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeMode {
+    /// COUNT follows the host clock and an idle guest puts the host thread to sleep.
+    RealTime,
+    /// COUNT follows executed cycles and an idle guest skips ahead to the next event.
+    Deterministic,
+}
+
+/// A disk image for the virtio block device or the SD card.
+pub struct DiskConfig {
+    pub path: String,
+    pub read_only: bool,
+    pub overlay: Overlay,
+}
+
+/// The virtio network device.
+pub struct NetConfig {
+    pub link: Link,
+    /// Records every frame to this pcap file.
+    pub pcap: Option<String>,
+    pub mac: [u8; 6],
+}
+
+/// A UART and where it is connected on the host.
+#[derive(Debug, Clone)]
+pub struct UartConfig {
+    /// Physical address, a 0x100-aligned slot of the UART region.
+    pub base: u32,
+    /// Interrupt level.
+    pub irq: u8,
+    pub link: SerialLink,
+}
+
+/// A host directory exported through virtio-9p.
+pub struct ShareConfig {
+    pub path: String,
+    /// Mount tag the guest passes to mount.
+    pub tag: String,
+    pub read_only: bool,
+}
+
+/// Machine configuration, filled from the command line.
+pub struct Config {
+    pub kernel: String,
+    pub time_mode: TimeMode,
+    pub disk: Option<DiskConfig>,
+    /// The card in the SD host's slot, the slot is empty without one.
+    pub sd: Option<DiskConfig>,
+    /// UARTs in the order the guest's UART directory lists them.
+    pub uarts: Vec<UartConfig>,
+    /// virtio-mmio transport version: 2, or 1 for the legacy interface.
+    pub virtio_version: u32,
+    /// Ports of the virtio console, port 0 first. No console without ports.
+    pub console_ports: Vec<PortConfig>,
+    pub net: Option<NetConfig>,
+    /// Adds the virtio entropy device.
+    pub rng: bool,
+    /// Seed of the entropy device. Deterministic runs default to a fixed one, others use host
+    /// entropy unless a seed is given.
+    pub rng_seed: Option<u64>,
+    pub share: Option<ShareConfig>,
+    /// Seconds since the Unix epoch the RTC starts at. Deterministic runs default to a fixed
+    /// one, others use host time unless an epoch is given.
+    pub rtc_epoch: Option<u64>,
+    /// What an expired watchdog does.
+    pub watchdog_action: Action,
+    /// Runs the watchdog from power-on with this timeout in milliseconds.
+    pub watchdog_timeout: Option<u32>,
+    /// Framebuffer dumps, none if not given.
+    pub fb_dump: Option<DumpConfig>,
+    /// Initial contents of the I2C EEPROM, erased without one.
+    pub eeprom_image: Option<String>,
+    /// Initial contents of the SPI flash, erased without one.
+    pub flash_image: Option<String>,
+    /// Services UHI semihosting calls made with `sdbbp 1`.
+    pub semihosting: bool,
+    /// Runs the kernel as a SPIM program, servicing `syscall` in the emulator.
+    pub spim: bool,
+    /// Runs the kernel as a static Linux executable, servicing its system calls in the emulator.
+    pub linux: bool,
+    /// The environment of a Linux executable, as NAME=value.
+    pub env: Vec<String>,
+    /// The guest's command line after the program name, from after `--`.
+    pub args: Vec<String>,
+    /// Reports every interrupt and exception the CPU takes on stderr.
+    pub trace: bool,
+    /// Prints the run statistics on stderr at exit.
+    pub stats: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            kernel: String::from("os/main.o"),
+            time_mode: TimeMode::RealTime,
+            disk: None,
+            sd: None,
+            uarts: vec![UartConfig { base: UART_BASE, irq: UART_IRQ, link: SerialLink::Stdio }],
+            virtio_version: 2,
+            console_ports: Vec::new(),
+            net: None,
+            rng: false,
+            rng_seed: None,
+            share: None,
+            rtc_epoch: None,
+            watchdog_action: Action::Reset,
+            watchdog_timeout: None,
+            fb_dump: None,
+            eeprom_image: None,
+            flash_image: None,
+            semihosting: false,
+            spim: false,
+            linux: false,
+            env: Vec::new(),
+            args: Vec::new(),
+            trace: false,
+            stats: false,
+        }
+    }
+}
+
+/// Seed of the entropy device in deterministic runs without `--rng-seed`.
+pub const DEFAULT_RNG_SEED: u64 = 0x6d69_7073;
+/// Start of the RTC in deterministic runs without `--rtc-epoch`: 2000-01-01 00:00:00 UTC.
+pub const DEFAULT_RTC_EPOCH: u64 = 946_684_800;
+
+impl Config {
+    /// Usage: `mips-emu [--deterministic] [--virtio-legacy] [--disk image [--disk-ro] [--overlay discard|delta]]
+    /// [--sd image [--sd-ro]] [--serial serial] [--uart base,irq,serial]...
+    /// [--console-port name=file:[input,]output|pipe:path|unix:path|tcp:port]...
+    /// [--net none|user|listen:path|connect:path [--net-pcap file] [--net-mac xx:xx:xx:xx:xx:xx]]
+    /// [--rng [--rng-seed n]] [--9p dir [--9p-ro] [--9p-tag tag]] [--rtc-epoch seconds]
+    /// [--watchdog reset|nmi|halt] [--watchdog-timeout ms]
+    /// [--fb-dump prefix [--fb-format png|ppm] [--fb-dump-every frames]] [--eeprom image] [--spi-flash image]
+    /// [--semihosting] [--spim] [--linux [--env NAME=value]...] [--trace] [--stats] [kernel] [-- args...]`
+    ///
+    /// where serial is `stdio|pty|file:[input,]output|pipe:path|unix:path|tcp:port`. `--serial`
+    /// connects the first UART, `--uart` adds another one. Arguments after `--` are the guest's.
+    pub fn from_args() -> Self {
+        let mut config = Self::default();
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--deterministic" => config.time_mode = TimeMode::Deterministic,
+                "--realtime" => config.time_mode = TimeMode::RealTime,
+                "--virtio-legacy" => config.virtio_version = 1,
+                "--disk" => {
+                    let path = args.next().expect("--disk needs an image path");
+                    config.disk = Some(DiskConfig { path, read_only: false, overlay: Overlay::None });
+                }
+                "--disk-ro" => config.disk.as_mut().expect("--disk-ro needs --disk first").read_only = true,
+                "--sd" => {
+                    let path = args.next().expect("--sd needs an image path");
+                    config.sd = Some(DiskConfig { path, read_only: false, overlay: Overlay::None });
+                }
+                "--sd-ro" => config.sd.as_mut().expect("--sd-ro needs --sd first").read_only = true,
+                "--overlay" => {
+                    let mode = args.next().expect("--overlay needs discard or a delta path");
+                    let overlay = if mode == "discard" { Overlay::Discard } else { Overlay::Save(mode) };
+                    config.disk.as_mut().expect("--overlay needs --disk first").overlay = overlay;
+                }
+                "--serial" => {
+                    let spec = args.next().expect("--serial needs a backend");
+                    config.uarts[0].link = SerialLink::parse(&spec).unwrap_or_else(|| panic!("unknown serial backend {}", spec));
+                }
+                "--uart" => {
+                    let uart = args.next().expect("--uart needs base,irq,backend");
+                    let mut fields = uart.splitn(3, ',');
+                    let (Some(base), Some(irq), Some(spec)) = (fields.next(), fields.next(), fields.next()) else {
+                        panic!("--uart needs base,irq,backend");
+                    };
+                    let base = u32::from_str_radix(base.trim_start_matches("0x"), 16).expect("bad UART base address");
+                    let irq = irq.parse().expect("bad UART interrupt level");
+                    let link = SerialLink::parse(spec).unwrap_or_else(|| panic!("unknown serial backend {}", spec));
+                    config.uarts.push(UartConfig { base, irq, link });
+                }
+                "--console-port" => {
+                    let port = args.next().expect("--console-port needs name=backend");
+                    let (name, spec) = port.split_once('=').expect("--console-port needs name=backend");
+                    let backend = Backend::parse(spec).unwrap_or_else(|| panic!("unknown console backend {}", spec));
+                    config.console_ports.push(PortConfig { name: name.to_string(), backend });
+                }
+                "--net" => {
+                    let spec = args.next().expect("--net needs a link");
+                    let link = Link::parse(&spec).unwrap_or_else(|| panic!("unknown network link {}", spec));
+                    // QEMU's default MAC address
+                    config.net = Some(NetConfig { link, pcap: None, mac: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56] });
+                }
+                "--net-pcap" => {
+                    let path = args.next().expect("--net-pcap needs a file");
+                    config.net.as_mut().expect("--net-pcap needs --net first").pcap = Some(path);
+                }
+                "--net-mac" => {
+                    let mac = args.next().expect("--net-mac needs an address");
+                    let bytes: Vec<u8> = mac
+                        .split(':')
+                        .map(|byte| u8::from_str_radix(byte, 16).expect("bad MAC address"))
+                        .collect();
+                    config.net.as_mut().expect("--net-mac needs --net first").mac =
+                        bytes.try_into().expect("a MAC address has 6 bytes");
+                }
+                "--rng" => config.rng = true,
+                "--rng-seed" => {
+                    let seed = args.next().expect("--rng-seed needs a number");
+                    config.rng_seed = Some(seed.parse().expect("--rng-seed needs a number"));
+                }
+                "--9p" => {
+                    let path = args.next().expect("--9p needs a directory");
+                    config.share = Some(ShareConfig { path, tag: String::from("host"), read_only: false });
+                }
+                "--9p-ro" => config.share.as_mut().expect("--9p-ro needs --9p first").read_only = true,
+                "--9p-tag" => {
+                    let tag = args.next().expect("--9p-tag needs a tag");
+                    config.share.as_mut().expect("--9p-tag needs --9p first").tag = tag;
+                }
+                "--rtc-epoch" => {
+                    let epoch = args.next().expect("--rtc-epoch needs seconds since 1970");
+                    config.rtc_epoch = Some(epoch.parse().expect("--rtc-epoch needs seconds since 1970"));
+                }
+                "--watchdog" => {
+                    let action = args.next().expect("--watchdog needs reset, nmi or halt");
+                    config.watchdog_action = Action::parse(&action).unwrap_or_else(|| panic!("unknown watchdog action {}", action));
+                }
+                "--watchdog-timeout" => {
+                    let timeout = args.next().expect("--watchdog-timeout needs milliseconds");
+                    config.watchdog_timeout = Some(timeout.parse().expect("--watchdog-timeout needs milliseconds"));
+                }
+                "--fb-dump" => {
+                    let prefix = args.next().expect("--fb-dump needs a file prefix");
+                    config.fb_dump = Some(DumpConfig { prefix, format: ImageFormat::Png, every: None });
+                }
+                "--fb-format" => {
+                    let format = match args.next().as_deref() {
+                        Some("png") => ImageFormat::Png,
+                        Some("ppm") => ImageFormat::Ppm,
+                        _ => panic!("--fb-format needs png or ppm"),
+                    };
+                    config.fb_dump.as_mut().expect("--fb-format needs --fb-dump first").format = format;
+                }
+                "--fb-dump-every" => {
+                    let every = args.next().expect("--fb-dump-every needs a frame count");
+                    config.fb_dump.as_mut().expect("--fb-dump-every needs --fb-dump first").every =
+                        Some(every.parse().expect("--fb-dump-every needs a frame count"));
+                }
+                "--eeprom" => config.eeprom_image = Some(args.next().expect("--eeprom needs an image path")),
+                "--spi-flash" => config.flash_image = Some(args.next().expect("--spi-flash needs an image path")),
+                "--semihosting" => config.semihosting = true,
+                "--spim" => config.spim = true,
+                "--linux" => config.linux = true,
+                "--env" => config.env.push(args.next().expect("--env needs NAME=value")),
+                "--trace" => config.trace = true,
+                "--stats" => config.stats = true,
+                "--" => config.args = args.by_ref().collect(),
+                _ => config.kernel = arg,
+            }
+        }
+        assert!(!(config.spim && config.linux), "--spim and --linux cannot be combined");
+        assert!(config.console_ports.len() <= MAX_PORTS, "at most {} --console-port", MAX_PORTS);
+        if config.spim || config.linux {
+            // the terminal is the program's console
+            config.uarts.retain(|uart| !matches!(uart.link, SerialLink::Stdio));
+        }
+        config
+    }
+}
@@ -1,127 +1,391 @@
-
 use std::{sync::{Mutex, Arc}, thread, time::Duration};
 
 use crate::{cpu::Size, exception::Exception, devices::device::Device, utils::{set_byte_of_word, set_halfword_of_word, get_byte_from_word, get_halfword_from_word}};
 use crate::memory::{PRESENT, READ, VALID, WRITE};
+use crate::config::TimeMode;
+
+/// Registers are indexed by `number << 3 | select`, the same encoding `mfc0`/`mtc0` use.
+pub const fn cp0(number: u8, select: u8) -> u8 {
+    number << 3 | select
+}
+pub const REGISTERS_COUNT: usize = 256;
+/// The MMIO window keeps its layout from before selects: word n is register n select 0, except
+/// that word 15 is still EBase. The whole register file follows from this offset, a word per index.
+pub const SELECT_WINDOW: u32 = 0x400;
+/// Words of the window laid out by register number alone.
+const LEGACY_REGISTERS: u32 = 32;
+
+pub const RANDOM: u8 = cp0(1, 0);
+pub const PTBASE: u8 = cp0(4, 0);
+pub const CONTEXT: u8 = cp0(4, 1);
+pub const WIRED: u8 = cp0(6, 0);
+pub const BADVADDR: u8 = cp0(8, 0);
+pub const COUNT: u8 = cp0(9, 0);
+pub const COMPARE: u8 = cp0(11, 0);
+pub const SR: u8 = cp0(12, 0);
+pub const SRSCTL: u8 = cp0(12, 2);
+pub const SRSMAP: u8 = cp0(12, 3);
+pub const CAUSE: u8 = cp0(13, 0);
+pub const EPC: u8 = cp0(14, 0);
+pub const PRID: u8 = cp0(15, 0);
+pub const EBASE: u8 = cp0(15, 1);
+pub const CONFIG0: u8 = cp0(16, 0);
+pub const CONFIG1: u8 = cp0(16, 1);
+pub const CONFIG2: u8 = cp0(16, 2);
+pub const CONFIG3: u8 = cp0(16, 3);
+/// WatchLo/WatchHi pairs live at selects 0 to WATCH_PAIRS - 1.
+pub const WATCHLO: u8 = cp0(18, 0);
+pub const WATCHHI: u8 = cp0(19, 0);
+/// PerfCtl/PerfCnt pairs live at selects 2n and 2n + 1.
+pub const PERFCTL: u8 = cp0(25, 0);
+pub const PERFCNT: u8 = cp0(25, 1);
+pub const ERROREPC: u8 = cp0(30, 0);
+
+// SR fields
+pub const SR_IE: u32 = 1 << 0;
+pub const SR_EXL: u32 = 1 << 1;
+pub const SR_ERL: u32 = 1 << 2;
+pub const SR_UM: u32 = 1 << 4;
+pub const SR_NMI: u32 = 1 << 19;
+pub const SR_BEV: u32 = 1 << 22;
+// Cause fields
+pub const CAUSE_WP: u32 = 1 << 22;
+pub const CAUSE_PCI: u32 = 1 << 26;
+// WatchLo enables, also the WatchHi status bits
+pub const WATCH_W: u32 = 1 << 0;
+pub const WATCH_R: u32 = 1 << 1;
+pub const WATCH_I: u32 = 1 << 2;
+
+pub const WATCH_PAIRS: u8 = 4;
+
+/// Number of GPR sets, the normal one included.
+pub const SHADOW_SETS: usize = 4;
+
+// PerfCtl fields
+const PERF_EXL: u32 = 1 << 0;
+const PERF_K: u32 = 1 << 1;
+const PERF_U: u32 = 1 << 3;
+const PERF_IE: u32 = 1 << 4;
+// PerfCtl events, in PerfCtl[11:5]
+pub const PERF_CYCLES: u32 = 0;
+pub const PERF_INSTRUCTIONS: u32 = 1;
+pub const PERF_LOADS: u32 = 2;
+pub const PERF_STORES: u32 = 3;
+pub const PERF_BRANCHES: u32 = 4;
+pub const PERF_EXCEPTIONS: u32 = 5;
+pub const PERF_PAGE_WALKS: u32 = 6;
+
+pub const PERF_PAIRS: u8 = 2;
+/// Interrupt level raised while an enabled counter has bit 31 set.
+pub const PERF_LEVEL: u8 = 4;
+
+/// Number of entries `Random` cycles through, as if there was a TLB of this size.
+pub const TLB_ENTRIES: u32 = 16;
+/// Company 0x01 (MIPS Technologies), processor 0x93 (24K), revision 0.
+const PRID_VALUE: u32 = 0x00019300;
+/// M (Config1 present), little endian, MIPS32 release 2, standard TLB, kseg0 uncached. The
+/// page table at PTBASE is walked in hardware, Random and Wired behave as for the TLB.
+const CONFIG0_VALUE: u32 = 1 << 31 | 1 << 10 | 1 << 7 | 2;
+/// M, TLB_ENTRIES entries, 64 sets/16-byte lines/4-way I and D caches, performance counters,
+/// watch registers.
+const CONFIG1_VALUE: u32 =
+    1 << 31 | (TLB_ENTRIES - 1) << 25 | 3 << 19 | 3 << 16 | 3 << 10 | 3 << 7 | 1 << 4 | 1 << 3;
+/// M, no secondary or tertiary cache.
+const CONFIG2_VALUE: u32 = 1 << 31;
+const CONFIG3_VALUE: u32 = 0;
+
+/// Implemented registers: index, reset value, bits writable by `mtc0`.
+const LAYOUT: [(u8, u32, u32); 31] = [
+    (RANDOM, TLB_ENTRIES - 1, 0),
+    (PTBASE, PRESENT | VALID | READ | WRITE, 0xffffffff),
+    (CONTEXT, 0, 0xff800000),
+    (WIRED, 0, TLB_ENTRIES - 1),
+    (BADVADDR, 0, 0),
+    (COUNT, 0, 0xffffffff),
+    (COMPARE, 10, 0xffffffff),
+    (SR, 0x0000ff01, 0xff78ff1f),
+    // HSS is fixed, ESS and PSS are writable, CSS changes on exception entry and eret
+    (SRSCTL, (SHADOW_SETS as u32 - 1) << 26, 0x0000f3c0),
+    (SRSMAP, 0, 0xffffffff),
+    (CAUSE, 0, 0x00c00300),
+    (EPC, 0, 0xffffffff),
+    (PRID, PRID_VALUE, 0),
+    (EBASE, 0x80000000, 0x3ffff000),
+    (CONFIG0, CONFIG0_VALUE, 0x7),
+    (CONFIG1, CONFIG1_VALUE, 0),
+    (CONFIG2, CONFIG2_VALUE, 0),
+    (CONFIG3, CONFIG3_VALUE, 0),
+    (WATCHLO, 0, 0xffffffff),
+    (WATCHLO + 1, 0, 0xffffffff),
+    (WATCHLO + 2, 0, 0xffffffff),
+    (WATCHLO + 3, 0, 0xffffffff),
+    // M marks another pair at the next select, status bits [2:0] are write-one-to-clear
+    (WATCHHI, 1 << 31, 0x40ff0ff8),
+    (WATCHHI + 1, 1 << 31, 0x40ff0ff8),
+    (WATCHHI + 2, 1 << 31, 0x40ff0ff8),
+    (WATCHHI + 3, 0, 0x40ff0ff8),
+    (PERFCTL, 1 << 31, 0xfff),
+    (PERFCNT, 0, 0xffffffff),
+    (PERFCTL + 2, 0, 0xfff),
+    (PERFCNT + 2, 0, 0xffffffff),
+    (ERROREPC, 0, 0xffffffff),
+];
 
-pub const PTBASE: u8 = 4;
-pub const COUNT: u8 = 9;
-pub const COMPARE: u8 = 11;
-pub const SR: u8 = 12;
-pub const CAUSE: u8 = 13;
-pub const EPC: u8 = 14;
-pub const EBASE: u8 = 15;
 const TIMER_INTERVAL_MS: u64 = 10;
+/// Cycles per COUNT increment in deterministic mode.
+pub const CYCLES_PER_COUNT: u64 = 10000;
+/// The virtual clock rate: one COUNT increment per timer interval.
+pub const CYCLES_PER_SECOND: u64 = CYCLES_PER_COUNT * 1000 / TIMER_INTERVAL_MS;
 pub const TIMER_LEVEL: u8 = 5;
+
+/// A register slot. Bits outside `write_mask` are read-only to the guest.
+struct Register {
+    value: Arc<Mutex<u32>>,
+    write_mask: u32,
+}
+
 pub struct Coprocessor0 {
-    pub timer: thread::JoinHandle<()>,
-    pub registers: [Arc<Mutex<u32>>; 32],
+    pub timer: Option<thread::JoinHandle<()>>,
+    registers: [Option<Register>; REGISTERS_COUNT],
+    time_mode: TimeMode,
+    /// Cycles elapsed since the last COUNT increment, deterministic mode only.
+    residue: u64,
+}
+
+/// Increments COUNT and raises the timer interrupt when it reaches COMPARE.
+fn count_tick(count: &Mutex<u32>, compare: &Mutex<u32>, cause: &Mutex<u32>) {
+    let mut count_ptr = count.lock().unwrap();
+    *count_ptr = count_ptr.wrapping_add(1);
+    let compare_ptr = compare.lock().unwrap();
+    if *count_ptr == *compare_ptr {
+        *count_ptr = 0;
+        raise_timer(cause);
+    }
+}
+
+fn raise_timer(cause: &Mutex<u32>) {
+    let mut ptr = cause.lock().unwrap();
+    *ptr = (*ptr | (1 << (TIMER_LEVEL + 8))) & 0xffffff83;
 }
 
 impl Coprocessor0 {
-    pub fn new() -> Self {
-        let registers = [
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(PRESENT | VALID | READ | WRITE)), // PTBASE
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)), // COUNT
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(10)), // COMPARE
-            Arc::new(Mutex::new(0x0000ff01)), // SR
-            Arc::new(Mutex::new(0)), // CAUSE
-            Arc::new(Mutex::new(0)), // EPC
-            Arc::new(Mutex::new(0x80000000)), // EBASE
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0)),
-            Arc::new(Mutex::new(0))];
-        let count = registers[COUNT as usize].clone();
-        let compare = registers[COMPARE as usize].clone();
-        let cause = registers[CAUSE as usize].clone();
-        Coprocessor0 { registers, timer: thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_millis(TIMER_INTERVAL_MS));
-                let mut count_ptr = count.lock().unwrap();
-                *count_ptr += 1;
-                let compare_ptr = compare.lock().unwrap();
-                if *count_ptr == *compare_ptr {
-                    *count_ptr = 0;
-                    let mut ptr = cause.lock().unwrap();
-                    *ptr = (*ptr | (1 << (TIMER_LEVEL + 8))) & 0xffffff83;
+    pub fn new(time_mode: TimeMode) -> Self {
+        let mut registers: [Option<Register>; REGISTERS_COUNT] = std::array::from_fn(|_| None);
+        for (index, value, write_mask) in LAYOUT {
+            registers[index as usize] = Some(Register { value: Arc::new(Mutex::new(value)), write_mask });
+        }
+        let mut cp0 = Coprocessor0 { registers, timer: None, time_mode, residue: 0 };
+        if time_mode == TimeMode::RealTime {
+            let count = cp0.raw(COUNT);
+            let compare = cp0.raw(COMPARE);
+            let cause = cp0.raw(CAUSE);
+            cp0.timer = Some(thread::spawn(move || {
+                loop {
+                    thread::sleep(Duration::from_millis(TIMER_INTERVAL_MS));
+                    count_tick(&count, &compare, &cause);
                 }
+            }));
+        }
+        cp0
+    }
+    /// Shared handle to an implemented register, for devices that update it from other threads.
+    pub fn raw(&self, index: u8) -> Arc<Mutex<u32>> {
+        match &self.registers[index as usize] {
+            Some(reg) => reg.value.clone(),
+            None => panic!("CP0 register {}.{} is not implemented", index >> 3, index & 7),
+        }
+    }
+    /// Reads a register. Unimplemented registers read as zero.
+    pub fn get(&self, index: u8) -> u32 {
+        match &self.registers[index as usize] {
+            Some(reg) => *reg.value.lock().unwrap(),
+            None => 0,
+        }
+    }
+    /// Writes a register, ignoring its write mask. Used by the CPU itself, not by `mtc0`.
+    pub fn set(&self, index: u8, value: u32) {
+        if let Some(reg) = &self.registers[index as usize] {
+            *reg.value.lock().unwrap() = value;
+        }
+    }
+    /// Records the address that failed translation in BadVAddr and Context.
+    pub fn set_bad_vaddr(&self, vaddr: u32) {
+        self.set(BADVADDR, vaddr);
+        let context = self.get(CONTEXT);
+        self.set(CONTEXT, context & 0xff800000 | (vaddr >> 13) << 4);
+    }
+    /// Guest write: only bits in the write mask change, then side effects are applied.
+    fn write_masked(&mut self, index: u8, value: u32) {
+        let Some(reg) = &self.registers[index as usize] else {
+            return;
+        };
+        {
+            let mut ptr = reg.value.lock().unwrap();
+            *ptr = *ptr & !reg.write_mask | value & reg.write_mask;
+        }
+        match index {
+            // writing Compare acknowledges the timer interrupt
+            COMPARE => {
+                let cause = self.get(CAUSE);
+                self.set(CAUSE, cause & !(1 << (TIMER_LEVEL + 8)));
+            }
+            WIRED => self.set(RANDOM, TLB_ENTRIES - 1),
+            _ if (WATCHHI..WATCHHI + WATCH_PAIRS).contains(&index) => {
+                let watchhi = self.get(index);
+                self.set(index, watchhi & !(value & (WATCH_I | WATCH_R | WATCH_W)));
+            }
+            _ if (PERFCTL..PERFCTL + 2 * PERF_PAIRS).contains(&index) => self.update_perf_interrupt(),
+            _ => {}
+        }
+    }
+    /// Adds `n` occurrences of `event` to every counter selecting it in the current mode.
+    pub fn count_event(&self, event: u32, n: u64) {
+        let sr = self.get(SR);
+        let mode = if sr & (SR_EXL | SR_ERL) != 0 {
+            PERF_EXL
+        } else if sr & SR_UM != 0 {
+            PERF_U
+        } else {
+            PERF_K
+        };
+        let mut overflowed = false;
+        for pair in 0..PERF_PAIRS {
+            let ctl = self.get(PERFCTL + 2 * pair);
+            if ctl >> 5 & 0x7f != event || ctl & mode == 0 {
+                continue;
+            }
+            let cnt = self.get(PERFCNT + 2 * pair);
+            let new = cnt.wrapping_add(n as u32);
+            self.set(PERFCNT + 2 * pair, new);
+            overflowed |= ctl & PERF_IE != 0 && new & (1 << 31) != 0;
+        }
+        if overflowed {
+            self.update_perf_interrupt();
+        }
+    }
+    /// Asserts the performance counter interrupt while an enabled counter has bit 31 set.
+    fn update_perf_interrupt(&self) {
+        let asserted = (0..PERF_PAIRS).any(|pair| {
+            self.get(PERFCTL + 2 * pair) & PERF_IE != 0 && self.get(PERFCNT + 2 * pair) & (1 << 31) != 0
+        });
+        let cause = self.get(CAUSE) & !(CAUSE_PCI | 1 << (PERF_LEVEL + 8));
+        if asserted {
+            self.set(CAUSE, cause | CAUSE_PCI | 1 << (PERF_LEVEL + 8));
+        } else {
+            self.set(CAUSE, cause);
+        }
+    }
+    /// Checks `vaddr` against every watch pair enabled for `kind` (one of WATCH_I, WATCH_R and
+    /// WATCH_W) and records a hit in the WatchHi status bits. Matching ignores ASIDs since there
+    /// are none.
+    pub fn watch_hit(&self, vaddr: u32, kind: u32) -> bool {
+        let mut hit = false;
+        for pair in 0..WATCH_PAIRS {
+            let watchlo = self.get(WATCHLO + pair);
+            if watchlo & kind == 0 {
+                continue;
             }
-        }) }
+            let watchhi = self.get(WATCHHI + pair);
+            // WatchHi.Mask [11:3] excludes address bits from the comparison, [2:0] always are
+            let ignored = watchhi & 0xff8 | 0x7;
+            if (watchlo ^ vaddr) & !ignored == 0 {
+                self.set(WATCHHI + pair, watchhi | kind);
+                hit = true;
+            }
+        }
+        hit
+    }
+    /// Advances Random and, in deterministic mode, the timer by `cycles`. In real-time mode the
+    /// timer thread owns COUNT instead.
+    pub fn advance(&mut self, cycles: u64) {
+        // Random counts down from TLB_ENTRIES - 1 to Wired, then wraps
+        let wired = self.get(WIRED) as u64;
+        let span = TLB_ENTRIES as u64 - wired;
+        let random = (self.get(RANDOM) as u64).max(wired) - wired;
+        self.set(RANDOM, (wired + (random + span - cycles % span) % span) as u32);
+
+        if self.time_mode != TimeMode::Deterministic {
+            return;
+        }
+        self.residue += cycles;
+        let counts = self.residue / CYCLES_PER_COUNT;
+        self.residue %= CYCLES_PER_COUNT;
+        if counts == 0 {
+            return;
+        }
+        // jump straight to the last match instead of ticking one count at a time
+        let count = self.get(COUNT);
+        let compare = self.get(COMPARE);
+        let until_match = match compare.wrapping_sub(count) {
+            0 => 1 << 32,
+            n => n as u64,
+        };
+        if counts < until_match {
+            self.set(COUNT, count.wrapping_add(counts as u32));
+            return;
+        }
+        let period = if compare == 0 { 1 << 32 } else { compare as u64 };
+        raise_timer(&self.raw(CAUSE));
+        self.set(COUNT, ((counts - until_match) % period) as u32);
+    }
+    /// Cycles until COUNT next reaches COMPARE, deterministic mode only.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        if self.time_mode != TimeMode::Deterministic {
+            return None;
+        }
+        let counts = match self.get(COMPARE).wrapping_sub(self.get(COUNT)) {
+            0 => 1 << 32,
+            n => n as u64,
+        };
+        Some(counts * CYCLES_PER_COUNT - self.residue)
+    }
+}
+/// The register at `addr` in the MMIO window, if any.
+fn mmio_index(addr: u32) -> Option<u8> {
+    match addr >> 2 {
+        15 => Some(EBASE),
+        number if number < LEGACY_REGISTERS => Some(cp0(number as u8, 0)),
+        _ if addr >= SELECT_WINDOW => Some(((addr - SELECT_WINDOW) >> 2) as u8),
+        _ => None,
     }
 }
+
 impl Device for Coprocessor0 {
     fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
-        let base = (addr >> 2) as usize;
+        let index = mmio_index(addr);
         let offset = (addr & 0x3) as u8;
+        let val = index.map_or(0, |index| self.get(index));
         match size {
-            Size::Byte => {
-                let val = self.registers[base].lock().unwrap();
-                Ok(get_byte_from_word(*val, offset) as u32)
-            },
+            Size::Byte => Ok(get_byte_from_word(val, offset) as u32),
             Size::Halfword => match offset {
-                0 | 2 => {
-                    let val = self.registers[base].lock().unwrap();
-                    Ok(get_halfword_from_word(*val, offset) as u32)
-                },
+                0 | 2 => Ok(get_halfword_from_word(val, offset) as u32),
                 _ => Err(Exception::LoadIllegalAddress)
             }
             Size::Word => match offset {
-                0 => {
-                    let val = self.registers[base].lock().unwrap();
-                    Ok(*val)
-                },
+                0 => Ok(val),
                 _ => Err(Exception::LoadIllegalAddress)
             }
         }
     }
 
     fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
-        let base = (addr >> 2) as usize;
+        let Some(index) = mmio_index(addr) else {
+            return Ok(());
+        };
         let offset = (addr & 0x3) as u8;
-        match size {
-            Size::Byte => {
-                let mut val = self.registers[base].lock().unwrap();
-                *val = set_byte_of_word(*val, offset, data as u8);
-                Ok(())
-            },
+        let val = self.get(index);
+        let val = match size {
+            Size::Byte => set_byte_of_word(val, offset, data as u8),
             Size::Halfword => match offset {
-                0 | 2 => {
-                    let mut val = self.registers[base].lock().unwrap();
-                    *val = set_halfword_of_word(*val, offset, data as u16);
-                    Ok(())
-                },
-                _ => Err(Exception::LoadIllegalAddress)
+                0 | 2 => set_halfword_of_word(val, offset, data as u16),
+                _ => return Err(Exception::StoreIllegalAddress)
             }
             Size::Word => match offset {
-                0 => {
-                    let mut val = self.registers[base].lock().unwrap();
-                    *val = data;
-                    Ok(())
-                },
-                _ => Err(Exception::LoadIllegalAddress)
+                0 => data,
+                _ => return Err(Exception::StoreIllegalAddress)
             }
-        }
+        };
+        self.write_masked(index, val);
+        Ok(())
     }
-}
\ No newline at end of file
+}
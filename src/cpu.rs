@@ -1,17 +1,27 @@
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::{
     bus::{Bus, COPROCESSOR_BASE},
+    config::{Config, TimeMode},
+    stats::Stats,
     exception::Exception,
-    devices::device::Device,
+    devices::{device::Device, syscon::Power, watchdog::{Action, WATCHDOG_EXIT_STATUS}},
+    linux::{self, Linux},
     memory,
+    semihosting::{self, Semihosting, UHI_CODE},
+    spim::{self, Spim},
     utils::sgn_ext_imm_16,
-    coprocessor::{SR, EPC, CAUSE, EBASE}
+    coprocessor::{cp0, SR, SRSCTL, SRSMAP, SHADOW_SETS, EPC, CAUSE, EBASE, SELECT_WINDOW, ERROREPC, SR_IE, SR_EXL, SR_ERL, SR_UM, SR_BEV, SR_NMI, CAUSE_WP, WATCH_I, WATCH_R, WATCH_W,
+        PERF_CYCLES, PERF_INSTRUCTIONS, PERF_LOADS, PERF_STORES, PERF_BRANCHES, PERF_EXCEPTIONS, CYCLES_PER_SECOND}
 };
 
 pub const REGISTERS_COUNT: usize = 32;
 pub const REBOOT_VECTOR: u32 = 0x0;
 pub const PGSIZE: u32 = 0x1000;
+/// Host time slept per tick while waiting for an interrupt in real-time mode.
+const IDLE_SLICE_MS: u64 = 1;
 
 
 // register names
@@ -89,10 +99,10 @@ impl Instruction {
         let shamt: u8 = ((inst >> 6) & 0x1f) as u8;
         let funct: u8 = (inst & 0x3f) as u8;
         let imm16: u16 = (inst & 0xffff) as u16;
-        let imm26: u32 = (inst & 0x07ffffff) as u32;
+        let imm26: u32 = (inst & 0x03ffffff) as u32;
         match opcode {
-            0x0 | 0x10 => Self::R { opcode, rs, rt, rd, shamt, funct },
-            0x4 | 0x5 | 0x8 | 0x9 | 0xa | 0xb | 0xc | 0xd | 0xf | 0x23 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2b | 0x30 | 0x38 => Self::I { opcode, rs, rt, imm: imm16 },
+            0x0 | 0x10 | 0x1c | 0x1f => Self::R { opcode, rs, rt, rd, shamt, funct },
+            0x1 | 0x4..=0xf | 0x20..=0x26 | 0x28..=0x2b | 0x2e | 0x30 | 0x33 | 0x38 => Self::I { opcode, rs, rt, imm: imm16 },
             0x2 | 0x3 => Self::J { opcode, imm: imm26 },
             _ => Self::Undefined { opcode, rest: imm26 }
         }
@@ -117,29 +127,114 @@ impl Instruction {
 }
 
 pub struct Cpu {
+    /// The current register set, SRSCtl.CSS.
     pub registers: [u32; REGISTERS_COUNT],
+    /// Every register set; the entry for the current set is stale while it is in `registers`.
+    pub shadow: [[u32; REGISTERS_COUNT]; SHADOW_SETS],
     pub pc: u32,
     pub hi: u32,
     pub lo: u32,
-    pub bus: Bus
+    pub bus: Bus,
+    pub time_mode: TimeMode,
+    /// Set by `wait`, cleared once an unmasked interrupt is pending.
+    pub waiting: bool,
+    /// Services `sdbbp 1` calls when semihosting is on.
+    pub semihosting: Option<Semihosting>,
+    /// Services `syscall` like SPIM and MARS in SPIM mode.
+    pub spim: Option<Spim>,
+    /// Stands in for the kernel of a Linux executable in Linux mode.
+    pub linux: Option<Linux>,
+    /// Reports interrupts and exceptions on stderr.
+    pub trace: bool,
+    /// Runs the instruction after a taken branch before the target, as in Linux mode.
+    pub branch_delay: bool,
+    /// The target of a taken branch whose delay slot runs next.
+    pub delayed_branch: Option<u32>,
+    pub stats: Stats
 }
 impl Cpu {
-    pub fn new(kernel_file: &str) -> Self {
-        let mut bus = Bus::new();
+    pub fn new(config: &Config) -> Self {
+        let mut cpu = Cpu::bare(config);
+        if config.linux {
+            linux::start(&mut cpu, config);
+            return cpu;
+        }
+        let allocator = memory::load_kernel(&mut cpu.bus.dram, &config.kernel);
+        if config.spim {
+            spim::start(&mut cpu, allocator);
+        }
+        cpu
+    }
+    /// Creates a CPU with the meta page table set up but no kernel loaded.
+    pub fn bare(config: &Config) -> Self {
+        let mut bus = Bus::new(config);
         memory::create_meta_page_table(&mut bus.dram);
-        memory::load_kernel(&mut bus.dram, kernel_file);
-        Cpu { registers: [0; REGISTERS_COUNT], pc: REBOOT_VECTOR, bus, hi: 0, lo: 0 }
+        Cpu {
+            registers: [0; REGISTERS_COUNT],
+            shadow: [[0; REGISTERS_COUNT]; SHADOW_SETS],
+            pc: REBOOT_VECTOR,
+            bus,
+            hi: 0,
+            lo: 0,
+            time_mode: config.time_mode,
+            waiting: false,
+            semihosting: config.semihosting.then(|| {
+                Semihosting::new(std::iter::once(config.kernel.clone()).chain(config.args.iter().cloned()).collect())
+            }),
+            spim: None,
+            linux: None,
+            trace: config.trace,
+            branch_delay: config.linux,
+            delayed_branch: None,
+            stats: Stats::default()
+        }
     }
     pub fn load_coprocessor0(&mut self, reg_code: u8) -> Result<u32, Exception> {
-        self.bus.read(COPROCESSOR_BASE + ((reg_code as u32) << 2), Size::Word)
+        Ok(self.bus.coprocessor.get(reg_code))
     }
+    /// Writes a CP0 register bypassing its write mask; `mtc0` goes through the bus instead.
     pub fn write_coprocessor0(&mut self, reg_code: u8, data: u32) -> Result<(), Exception> {
-        self.bus.write(COPROCESSOR_BASE + ((reg_code as u32) << 2), data, Size::Word)
+        self.bus.coprocessor.set(reg_code, data);
+        Ok(())
+    }
+    /// Raises a Watch exception if `vaddr` hits an enabled watchpoint. While EXL or ERL is set the
+    /// exception is deferred through Cause.WP instead.
+    fn check_watch(&mut self, vaddr: u32, kind: u32) -> Result<(), Exception> {
+        if !self.bus.coprocessor.watch_hit(vaddr, kind) {
+            return Ok(());
+        }
+        if self.load_coprocessor0(SR)? & (SR_EXL | SR_ERL) != 0 {
+            let cause = self.load_coprocessor0(CAUSE)?;
+            return self.write_coprocessor0(CAUSE, cause | CAUSE_WP);
+        }
+        Err(Exception::Watch)
+    }
+    /// Translates the virtual address of a load or store and checks its permissions.
+    fn data_address(&mut self, vaddr: u32, store: bool) -> Result<u32, Exception> {
+        self.check_watch(vaddr, if store { WATCH_W } else { WATCH_R })?;
+        let paddr = memory::walkpgdir(self, vaddr)?;
+        let user = self.load_coprocessor0(SR)? & SR_UM != 0;
+        if store {
+            if user && !paddr.user || !paddr.write {
+                return Err(Exception::StoreIllegalAddress);
+            }
+        } else if user && !paddr.user || !paddr.read {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        self.bus.coprocessor.count_event(if store { PERF_STORES } else { PERF_LOADS }, 1);
+        if self.time_mode == TimeMode::Deterministic && self.bus.dma_busy() {
+            // the DMA engine holds the bus this cycle, the access goes through on the next one.
+            // Instruction fetches do not contend, as if they hit a cache.
+            self.stats.dma_stalls += 1;
+            self.advance(1);
+        }
+        Ok(paddr.paddr)
     }
     fn execute(&mut self) -> Result<u32, Exception> {
         // Fetch
+        self.check_watch(self.pc, WATCH_I)?;
         let ppc = memory::walkpgdir(self, self.pc)?;
-        if !ppc.user && ((self.load_coprocessor0(SR)?) >> 4 & 1 != 0) || !ppc.read {
+        if !ppc.user && (self.load_coprocessor0(SR)? & SR_UM != 0) || !ppc.read {
             return Err(Exception::LoadIllegalAddress);
         }
         let res = self.bus.read(ppc.paddr, Size::Word)?;
@@ -160,15 +255,60 @@ impl Cpu {
                         }
                         0x03 => {
                             // sra
-                            self.registers[rd as usize] = self.registers[rt as usize] >> shamt;
+                            self.registers[rd as usize] = (self.registers[rt as usize] as i32 >> shamt) as u32;
+                        }
+                        0x04 => {
+                            // sllv
+                            self.registers[rd as usize] = self.registers[rt as usize] << (self.registers[rs as usize] & 0x1f);
+                        }
+                        0x06 => {
+                            // srlv
+                            self.registers[rd as usize] = self.registers[rt as usize] >> (self.registers[rs as usize] & 0x1f);
+                        }
+                        0x07 => {
+                            // srav
+                            self.registers[rd as usize] = (self.registers[rt as usize] as i32 >> (self.registers[rs as usize] & 0x1f)) as u32;
                         }
                         0x08 => {
                             // jr
-                            self.pc = self.registers[rs as usize]
+                            self.bus.coprocessor.count_event(PERF_BRANCHES, 1);
+                            return Ok(self.branch(self.registers[rs as usize]));
+                        }
+                        0x09 => {
+                            // jalr
+                            self.bus.coprocessor.count_event(PERF_BRANCHES, 1);
+                            let target = self.registers[rs as usize];
+                            self.registers[rd as usize] = self.link();
+                            return Ok(self.branch(target));
+                        }
+                        0x0a => {
+                            // movz
+                            if self.registers[rt as usize] == 0 {
+                                self.registers[rd as usize] = self.registers[rs as usize];
+                            }
+                        }
+                        0x0b => {
+                            // movn
+                            if self.registers[rt as usize] != 0 {
+                                self.registers[rd as usize] = self.registers[rs as usize];
+                            }
                         }
                         0x0c => {
-                            // syscall
-                            return Err(Exception::Syscall);
+                            // syscall, serviced by the emulator in SPIM and Linux mode
+                            if self.linux.is_some() {
+                                linux::call(self);
+                            } else if self.spim.is_some() {
+                                spim::call(self)?;
+                            } else {
+                                return Err(Exception::Syscall);
+                            }
+                        }
+                        0x0d => {
+                            // break
+                            return Err(Exception::Break);
+                        }
+                        0x0f => {
+                            // sync, memory accesses are never reordered
                         }
                         0x10 => {
                             // mfhi
@@ -199,14 +339,19 @@ impl Cpu {
                             self.lo = (val & 0xffffffff) as u32
                         }
                         0x1a => {
-                            // div
-                            self.lo = ((self.registers[rs as usize] as i32) / (self.registers[rt as usize] as i32)) as u32;
-                            self.hi = ((self.registers[rs as usize] as i32) % (self.registers[rt as usize] as i32)) as u32;
+                            // div, which leaves hi and lo alone on a zero divisor
+                            let (dividend, divisor) = (self.registers[rs as usize] as i32, self.registers[rt as usize] as i32);
+                            if divisor != 0 {
+                                self.lo = dividend.wrapping_div(divisor) as u32;
+                                self.hi = dividend.wrapping_rem(divisor) as u32;
+                            }
                         }
                         0x1b => {
                             // divu
-                            self.lo = (self.registers[rs as usize]) / (self.registers[rt as usize]);
-                            self.hi = (self.registers[rs as usize]) % (self.registers[rt as usize]);
+                            if let Some(quotient) = self.registers[rs as usize].checked_div(self.registers[rt as usize]) {
+                                self.lo = quotient;
+                                self.hi = self.registers[rs as usize] % self.registers[rt as usize];
+                            }
                         }
                         0x20 => {
                             // add
@@ -214,7 +359,7 @@ impl Cpu {
                         }
                         0x21 => {
                             // addu
-                            self.registers[rd as usize] = self.registers[rs as usize] + self.registers[rt as usize];
+                            self.registers[rd as usize] = self.registers[rs as usize].wrapping_add(self.registers[rt as usize]);
                         }
                         0x22 => {
                             // sub
@@ -222,7 +367,7 @@ impl Cpu {
                         }
                         0x23 => {
                             // subu
-                            self.registers[rd as usize] = self.registers[rs as usize] - self.registers[rt as usize];
+                            self.registers[rd as usize] = self.registers[rs as usize].wrapping_sub(self.registers[rt as usize]);
                         }
                         0x24 => {
                             // and
@@ -256,41 +401,120 @@ impl Cpu {
                                 self.registers[rd as usize] = 0;
                             }
                         }
+                        0x34 => {
+                            // teq
+                            if self.registers[rs as usize] == self.registers[rt as usize] {
+                                return Err(Exception::Trap);
+                            }
+                        }
                         _ => {
                             return Err(Exception::InstructionBusError);
                         }
                     }
                 } else if opcode == 0x10 {
+                    // the low 3 bits of funct select a register within rd
+                    let index = cp0(rd, funct & 0x7);
+                    let reg_addr = COPROCESSOR_BASE + SELECT_WINDOW + ((index as u32) << 2);
                     if rs == 0 {
                         // mfc0
-                        self.registers[rt as usize] = self.load_coprocessor0(rd)?;
+                        self.registers[rt as usize] = self.bus.read(reg_addr, Size::Word)?;
                     } else if rs == 4 {
                         // mtc0
-                        self.write_coprocessor0(rd, self.registers[rt as usize])?;
-                    } else if rs == 0x10 {
+                        self.bus.write(reg_addr, self.registers[rt as usize], Size::Word)?;
+                    } else if rs == 0x10 && funct == 0x18 {
                         // eret
                         let sr = self.load_coprocessor0(SR)?;
-                        self.write_coprocessor0(SR, sr & 0xfffffffd)?; // clear exception
-                        return Ok(self.load_coprocessor0(EPC)?)
+                        if sr & SR_ERL != 0 {
+                            self.write_coprocessor0(SR, sr & !SR_ERL)?;
+                            return self.load_coprocessor0(ERROREPC);
+                        }
+                        self.write_coprocessor0(SR, sr & !SR_EXL)?; // clear exception
+                        let pss = self.load_coprocessor0(SRSCTL)? >> 6 & 0xf;
+                        self.switch_register_set(pss);
+                        return self.load_coprocessor0(EPC)
+                    } else if rs == 0x0a {
+                        // rdpgpr
+                        let pss = (self.load_coprocessor0(SRSCTL)? >> 6 & 0xf) as usize;
+                        self.registers[rd as usize] = self.register_of_set(pss, rt);
+                    } else if rs == 0x0e {
+                        // wrpgpr
+                        let pss = (self.load_coprocessor0(SRSCTL)? >> 6 & 0xf) as usize;
+                        self.set_register_of_set(pss, rd, self.registers[rt as usize]);
+                    } else if rs & 0x10 != 0 && funct == 0x20 {
+                        // wait
+                        self.waiting = true;
+                        self.stats.waits += 1;
                     } else {
                         return Err(Exception::InstructionBusError)
                     }
+                } else if opcode == 0x1c && funct == 0x02 {
+                    // mul
+                    self.registers[rd as usize] = (self.registers[rs as usize] as i32).wrapping_mul(self.registers[rt as usize] as i32) as u32;
+                } else if opcode == 0x1c && funct == 0x20 {
+                    // clz
+                    self.registers[rd as usize] = self.registers[rs as usize].leading_zeros();
+                } else if opcode == 0x1c && funct == 0x21 {
+                    // clo
+                    self.registers[rd as usize] = self.registers[rs as usize].leading_ones();
+                } else if opcode == 0x1c && funct == 0x3f {
+                    // sdbbp, a reserved instruction unless it is a semihosting call
+                    let code = (rs as u32) << 15 | (rt as u32) << 10 | (rd as u32) << 5 | shamt as u32;
+                    if code != UHI_CODE || self.semihosting.is_none() {
+                        return Err(Exception::InstructionBusError);
+                    }
+                    semihosting::call(self)?;
+                } else if let (0x1f, 0x3b, 29, Some(linux)) = (opcode, funct, rd, &self.linux) {
+                    // rdhwr $29, the thread pointer Linux emulates for user programs
+                    self.registers[rt as usize] = linux.tls;
                 } else {
                     return Err(Exception::InstructionBusError)
                 }
             },
             Instruction::I { opcode, rs, rt, imm } => {
+                let target = ((self.pc as i32) + 4 + (sgn_ext_imm_16(imm) << 2)) as u32;
                 match opcode {
+                    0x1 => {
+                        // REGIMM: bltz, bgez, bltzal and bgezal, which link even when not taken
+                        self.bus.coprocessor.count_event(PERF_BRANCHES, 1);
+                        let negative = (self.registers[rs as usize] as i32) < 0;
+                        let taken = match rt {
+                            0x00 | 0x10 => negative,
+                            0x01 | 0x11 => !negative,
+                            _ => return Err(Exception::InstructionBusError),
+                        };
+                        if rt & 0x10 != 0 {
+                            self.registers[31] = self.link();
+                        }
+                        if taken {
+                            return Ok(self.branch(target));
+                        }
+                    }
                     0x4 => {
                         // beq
+                        self.bus.coprocessor.count_event(PERF_BRANCHES, 1);
                         if self.registers[rs as usize] == self.registers[rt as usize] {
-                            return Ok(((self.pc as i32) + 4 + (sgn_ext_imm_16(imm) << 2)) as u32);
+                            return Ok(self.branch(target));
                         }
                     }
                     0x5 => {
                         // bne
+                        self.bus.coprocessor.count_event(PERF_BRANCHES, 1);
                         if self.registers[rs as usize] != self.registers[rt as usize] {
-                            return Ok(((self.pc as i32) + 4 + (sgn_ext_imm_16(imm) << 2)) as u32);
+                            return Ok(self.branch(target));
+                        }
+                    }
+                    0x6 => {
+                        // blez
+                        self.bus.coprocessor.count_event(PERF_BRANCHES, 1);
+                        if self.registers[rs as usize] as i32 <= 0 {
+                            return Ok(self.branch(target));
+                        }
+                    }
+                    0x7 => {
+                        // bgtz
+                        self.bus.coprocessor.count_event(PERF_BRANCHES, 1);
+                        if self.registers[rs as usize] as i32 > 0 {
+                            return Ok(self.branch(target));
                         }
                     }
                     0x8 => {
@@ -299,7 +523,7 @@ impl Cpu {
                     }
                     0x9 => {
                         // addiu
-                        self.registers[rt as usize] = self.registers[rs as usize] + (imm as u32);
+                        self.registers[rt as usize] = self.registers[rs as usize].wrapping_add(sgn_ext_imm_16(imm) as u32);
                     }
                     0xa => {
                         // slti
@@ -310,8 +534,8 @@ impl Cpu {
                         }
                     }
                     0xb => {
-                        // sltiu
-                        if self.registers[rs as usize] < imm as u32 {
+                        // sltiu, an unsigned compare with the sign-extended immediate
+                        if self.registers[rs as usize] < sgn_ext_imm_16(imm) as u32 {
                             self.registers[rt as usize] = 1;
                         } else {
                             self.registers[rt as usize] = 0;
@@ -333,82 +557,97 @@ impl Cpu {
                         // lui
                         self.registers[rt as usize] = (imm as u32) << 16;
                     }
+                    0x20 => {
+                        // lb
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, false)?;
+                        self.registers[rt as usize] = self.bus.read(paddr, Size::Byte)? as u8 as i8 as u32;
+                    }
+                    0x21 => {
+                        // lh
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, false)?;
+                        self.registers[rt as usize] = self.bus.read(paddr, Size::Halfword)? as u16 as i16 as u32;
+                    }
+                    0x22 => {
+                        // lwl, the bytes from the word's start up to the address into the high end of rt
+                        let vaddr = (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32;
+                        let paddr = self.data_address(vaddr, false)?;
+                        let word = self.bus.read(paddr & !0x3, Size::Word)?;
+                        let shift = (3 - (vaddr & 0x3)) * 8;
+                        self.registers[rt as usize] = self.registers[rt as usize] & !(u32::MAX << shift) | word << shift;
+                    }
                     0x23 => {
                         // lw
-                        let paddr = memory::walkpgdir(self, (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32)?;
-                        let user = self.load_coprocessor0(SR)? >> 4 & 1 != 0;
-                        if user && !paddr.user || !paddr.read  {
-                            return Err(Exception::LoadIllegalAddress);
-                        }
-                        self.registers[rt as usize] = self.bus.read(paddr.paddr, Size::Word)?;
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, false)?;
+                        self.registers[rt as usize] = self.bus.read(paddr, Size::Word)?;
                     }
                     0x24 => {
                         // lbu
-                        let paddr = memory::walkpgdir(self, (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32)?;
-                        let user = self.load_coprocessor0(SR)? >> 4 & 1 != 0;
-                        if user && !paddr.user || !paddr.read  {
-                            return Err(Exception::LoadIllegalAddress);
-                        }
-                        self.registers[rt as usize] = self.bus.read(paddr.paddr, Size::Byte)?;
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, false)?;
+                        self.registers[rt as usize] = self.bus.read(paddr, Size::Byte)?;
                     }
                     0x25 => {
                         // lhu
-                        let paddr = memory::walkpgdir(self, (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32)?;
-                        let user = self.load_coprocessor0(SR)? >> 4 & 1 != 0;
-                        if user && !paddr.user || !paddr.read  {
-                            return Err(Exception::LoadIllegalAddress);
-                        }
-                        self.registers[rt as usize] = self.bus.read(paddr.paddr, Size::Halfword)?;
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, false)?;
+                        self.registers[rt as usize] = self.bus.read(paddr, Size::Halfword)?;
+                    }
+                    0x26 => {
+                        // lwr, the bytes from the address up to the word's end into the low end of rt
+                        let vaddr = (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32;
+                        let paddr = self.data_address(vaddr, false)?;
+                        let word = self.bus.read(paddr & !0x3, Size::Word)?;
+                        let shift = (vaddr & 0x3) * 8;
+                        self.registers[rt as usize] = self.registers[rt as usize] & !(u32::MAX >> shift) | word >> shift;
                     }
                     0x28 => {
                         // sb
-                        let paddr = memory::walkpgdir(self, (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32)?;
-                        let user = self.load_coprocessor0(SR)? >> 4 & 1 != 0;
-                        if user && !paddr.user || !paddr.write  {
-                            return Err(Exception::StoreIllegalAddress);
-                        }
-                        self.bus.write(paddr.paddr, self.registers[rt as usize] & 0xff, Size::Byte)?;
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, true)?;
+                        self.bus.write(paddr, self.registers[rt as usize] & 0xff, Size::Byte)?;
                         memory::set_page_dirty(self, (sgn_ext_imm_16(imm) as u32) & 0xfffff000)?;
                     }
                     0x29 => {
                         // sh
-                        let paddr = memory::walkpgdir(self, (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32)?;
-                        let user = self.load_coprocessor0(SR)? >> 4 & 1 != 0;
-                        if user && !paddr.user || !paddr.write  {
-                            return Err(Exception::StoreIllegalAddress);
-                        }
-                        self.bus.write(paddr.paddr, self.registers[rt as usize] & 0xffff, Size::Halfword)?;
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, true)?;
+                        self.bus.write(paddr, self.registers[rt as usize] & 0xffff, Size::Halfword)?;
+                        memory::set_page_dirty(self, (sgn_ext_imm_16(imm) as u32) & 0xfffff000)?;
+                    }
+                    0x2a => {
+                        // swl, the high end of rt into the word's start up to the address
+                        let vaddr = (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32;
+                        let paddr = self.data_address(vaddr, true)? & !0x3;
+                        let shift = (3 - (vaddr & 0x3)) * 8;
+                        let word = self.bus.read(paddr, Size::Word)? & !(u32::MAX >> shift) | self.registers[rt as usize] >> shift;
+                        self.bus.write(paddr, word, Size::Word)?;
                         memory::set_page_dirty(self, (sgn_ext_imm_16(imm) as u32) & 0xfffff000)?;
                     }
                     0x2b => {
                         // sw
-                        let paddr = memory::walkpgdir(self, (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32)?;
-                        let user = self.load_coprocessor0(SR)? >> 4 & 1 != 0;
-                        if user && !paddr.user || !paddr.write  {
-                            return Err(Exception::StoreIllegalAddress);
-                        }
-                        self.bus.write(paddr.paddr, self.registers[rt as usize], Size::Word)?;
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, true)?;
+                        self.bus.write(paddr, self.registers[rt as usize], Size::Word)?;
+                        memory::set_page_dirty(self, (sgn_ext_imm_16(imm) as u32) & 0xfffff000)?;
+                    }
+                    0x2e => {
+                        // swr, the low end of rt into the address up to the word's end
+                        let vaddr = (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32;
+                        let paddr = self.data_address(vaddr, true)? & !0x3;
+                        let shift = (vaddr & 0x3) * 8;
+                        let word = self.bus.read(paddr, Size::Word)? & !(u32::MAX << shift) | self.registers[rt as usize] << shift;
+                        self.bus.write(paddr, word, Size::Word)?;
                         memory::set_page_dirty(self, (sgn_ext_imm_16(imm) as u32) & 0xfffff000)?;
                     }
                     0x30 => {
                         // ll
-                        let paddr = memory::walkpgdir(self, (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32)?;
-                        let user = self.load_coprocessor0(SR)? >> 4 & 1 != 0;
-                        if user && !paddr.user || !paddr.read  {
-                            return Err(Exception::LoadIllegalAddress);
-                        }
-                        self.registers[rt as usize] = self.bus.read(paddr.paddr, Size::Word)?;
-                        self.bus.atomic.insert(paddr.paddr);
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, false)?;
+                        self.registers[rt as usize] = self.bus.read(paddr, Size::Word)?;
+                        self.bus.atomic.insert(paddr);
+                    }
+                    0x33 => {
+                        // pref, there is no cache to prefetch into
                     }
                     0x38 => {
                         // sc
-                        let paddr = memory::walkpgdir(self, (self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32)?;
-                        let user = self.load_coprocessor0(SR)? >> 4 & 1 != 0;
-                        if user && !paddr.user || !paddr.write  {
-                            return Err(Exception::StoreIllegalAddress);
-                        }
-                        if self.bus.atomic.contains(&paddr.paddr) {
-                            self.bus.write(paddr.paddr, self.registers[rt as usize], Size::Word)?;
+                        let paddr = self.data_address((self.registers[rs as usize] as i32 + sgn_ext_imm_16(imm)) as u32, true)?;
+                        if self.bus.atomic.contains(&paddr) {
+                            self.bus.write(paddr, self.registers[rt as usize], Size::Word)?;
                             self.registers[rt as usize] = 1;
                         } else {
                             self.registers[rt as usize] = 0;
@@ -420,13 +659,14 @@ impl Cpu {
                 }
             },
             Instruction::J { opcode, imm } => {
+                self.bus.coprocessor.count_event(PERF_BRANCHES, 1);
                 match opcode {
                     0x2 => {
-                        return Ok((((self.pc as i32) + 4) as u32) & 0xf0000000 | (imm << 2));
+                        return Ok(self.branch((((self.pc as i32) + 4) as u32) & 0xf0000000 | (imm << 2)));
                     }
-                    0x4 => {
-                        self.registers[31] = self.pc + 8;
-                        return Ok((((self.pc as i32) + 4) as u32) & 0xf0000000 | (imm << 2));
+                    0x3 => {
+                        self.registers[31] = self.link();
+                        return Ok(self.branch((((self.pc as i32) + 4) as u32) & 0xf0000000 | (imm << 2)));
                     }
                     _ => {
                         return Err(Exception::InstructionBusError)
@@ -439,66 +679,241 @@ impl Cpu {
         }
         Ok(self.pc + 4)
     }
+    /// Returns where a taken branch to `target` continues: the target itself, or with branch
+    /// delay slots the next instruction, after which `tick_except` moves on to the target.
+    fn branch(&mut self, target: u32) -> u32 {
+        if !self.branch_delay {
+            return target;
+        }
+        self.delayed_branch = Some(target);
+        self.pc + 4
+    }
+    /// Returns the address a linking branch or jump saves, past its delay slot if there is one.
+    fn link(&self) -> u32 {
+        if self.branch_delay {
+            self.pc + 8
+        } else {
+            self.pc + 4
+        }
+    }
     fn tick_except(&mut self) -> Result<(), Exception> {
         // check if interrupted
         let cause = self.load_coprocessor0(CAUSE)?;
         let pending_interrupts = cause >> 8 & 0xff;
-        let exception_code = cause >> 2 & 0x1f;
         let status = self.load_coprocessor0(SR)?;
-        let interrupt_enabled = (status & 1 != 0) && (status & 2 == 0);
+        let interrupt_enabled = (status & SR_IE != 0) && (status & (SR_EXL | SR_ERL) == 0);
         let interrupt_mask = status >> 8 & 0xff;
-        if interrupt_enabled && (pending_interrupts & interrupt_mask != 0) && exception_code == 0 {
-            println!("dealing interrupt");
+        if self.waiting {
+            if pending_interrupts & interrupt_mask == 0 {
+                self.idle();
+                return Ok(());
+            }
+            self.waiting = false;
+        }
+        self.advance(1);
+        if interrupt_enabled && (pending_interrupts & interrupt_mask != 0) {
+            if self.trace {
+                eprintln!("dealing interrupt at pc {:#x}", self.pc);
+            }
             // interrupt occurred, transfer to OS
-            self.write_coprocessor0(EPC, self.pc)?;  // if interrupt, pc goes back
-            self.pc = self.load_coprocessor0(EBASE)?;
-        } else if exception_code != 0 {
-            println!("dealing exception");
-            // exception occurred, transfer to OS
-            self.write_coprocessor0(EPC, self.pc + 4)?;
-            self.pc = self.load_coprocessor0(EBASE)?;
+            self.except(Exception::Interrupt, self.pc)?;  // if interrupt, pc goes back
+        } else if cause & CAUSE_WP != 0 && status & (SR_EXL | SR_ERL) == 0 {
+            // watchpoint hit while EXL or ERL was set, deliver it now
+            self.except(Exception::Watch, self.pc)?;
         } else {
+            let delayed_branch = self.delayed_branch.take();
             match self.execute() {
                 Ok(pc_dst) => {
-                    self.pc = pc_dst;
+                    self.pc = delayed_branch.unwrap_or(pc_dst);
+                    self.stats.instructions += 1;
+                    self.bus.coprocessor.count_event(PERF_INSTRUCTIONS, 1);
                 }
                 Err(exception) => {
-                    println!("single error asserted");
-                    let sr = self.load_coprocessor0(SR)?;
-                    self.write_coprocessor0(SR, sr | 0x2)?;
-                    let cause = self.load_coprocessor0(CAUSE)?;
-                    self.write_coprocessor0(CAUSE, cause & 0xffffff83 | ((exception as u32) << 2))?;
+                    if self.trace {
+                        eprintln!("dealing exception {:?} at pc {:#x}", exception, self.pc);
+                    }
+                    // exception occurred, transfer to OS. A watched access has not happened yet,
+                    // so it is retried after eret.
+                    let epc = match exception {
+                        Exception::Watch => self.pc,
+                        _ => self.pc + 4,
+                    };
+                    self.except(exception, epc)?;
                 }
             };
         }
         Ok(())
     }
+    /// Enters the exception handler at EBASE. An exception raised while one is already being
+    /// handled (EXL or ERL set) cannot be recovered and is passed back up as a double error.
+    fn except(&mut self, exception: Exception, epc: u32) -> Result<(), Exception> {
+        let sr = self.load_coprocessor0(SR)?;
+        if self.linux.is_some() {
+            // there is no kernel to take it, the process dies of the signal
+            linux::kill(self, exception);
+            return Ok(());
+        }
+        if sr & (SR_EXL | SR_ERL) != 0 {
+            return Err(exception);
+        }
+        self.bus.coprocessor.count_event(PERF_EXCEPTIONS, 1);
+        self.write_coprocessor0(SR, sr | SR_EXL)?;
+        let cause = self.load_coprocessor0(CAUSE)?;
+        // interrupts take the set SRSMap assigns to their level, everything else SRSCtl.ESS
+        let srsctl = self.load_coprocessor0(SRSCTL)?;
+        let set = match exception {
+            Exception::Interrupt => {
+                let pending = cause >> 8 & sr >> 8 & 0xff;
+                let level = 31 - pending.leading_zeros();
+                self.load_coprocessor0(SRSMAP)? >> (4 * level) & 0xf
+            }
+            _ => srsctl >> 12 & 0xf,
+        };
+        self.write_coprocessor0(SRSCTL, srsctl & !0x3c0 | (srsctl & 0xf) << 6)?;
+        self.switch_register_set(set);
+        self.write_coprocessor0(CAUSE, cause & 0xffffff83 | ((exception as u32) << 2))?;
+        self.write_coprocessor0(EPC, epc)?;
+        self.pc = self.load_coprocessor0(EBASE)? & 0xfffff000;
+        Ok(())
+    }
+    /// Makes `set` the current register set. Sets beyond SRSCtl.HSS fall back to set 0.
+    fn switch_register_set(&mut self, set: u32) {
+        let srsctl = self.bus.coprocessor.get(SRSCTL);
+        let css = (srsctl & 0xf) as usize;
+        let set = if set as usize >= SHADOW_SETS { 0 } else { set as usize };
+        if set != css {
+            self.shadow[css] = self.registers;
+            self.registers = self.shadow[set];
+        }
+        self.bus.coprocessor.set(SRSCTL, srsctl & !0xf | set as u32);
+    }
+    fn register_of_set(&self, set: usize, reg: u8) -> u32 {
+        if set == (self.bus.coprocessor.get(SRSCTL) & 0xf) as usize {
+            self.registers[reg as usize]
+        } else {
+            self.shadow[set][reg as usize]
+        }
+    }
+    fn set_register_of_set(&mut self, set: usize, reg: u8, value: u32) {
+        if set == (self.bus.coprocessor.get(SRSCTL) & 0xf) as usize {
+            self.registers[reg as usize] = value;
+        } else {
+            self.shadow[set][reg as usize] = value;
+        }
+    }
+    /// Soft reset: the interrupted pc is kept in ErrorEPC and the reset vector runs with ERL set.
+    pub fn reset(&mut self) {
+        self.bus.coprocessor.set(ERROREPC, self.pc);
+        let sr = self.bus.coprocessor.get(SR);
+        self.bus.coprocessor.set(SR, sr | SR_ERL | SR_BEV);
+        self.switch_register_set(0);
+        let srsctl = self.bus.coprocessor.get(SRSCTL);
+        self.bus.coprocessor.set(SRSCTL, srsctl & !0xf3c0);
+        self.waiting = false;
+        self.pc = REBOOT_VECTOR;
+    }
+    /// Non-maskable interrupt: the reset vector runs like after a soft reset, with Status.NMI set.
+    pub fn nmi(&mut self) {
+        self.reset();
+        let sr = self.bus.coprocessor.get(SR);
+        self.bus.coprocessor.set(SR, sr | SR_NMI);
+    }
+    /// Advances virtual time and every timed device by `cycles`.
+    fn advance(&mut self, cycles: u64) {
+        self.stats.cycles += cycles;
+        self.bus.coprocessor.count_event(PERF_CYCLES, cycles);
+        self.bus.advance(cycles);
+    }
+    /// Lets time pass while `wait` has stalled the pipeline.
+    fn idle(&mut self) {
+        match self.time_mode {
+            TimeMode::Deterministic => {
+                // nothing can happen before the next device event, skip straight to it
+                let cycles = self.bus.cycles_until_event().unwrap_or(1);
+                self.stats.idle_cycles += cycles;
+                self.advance(cycles);
+            }
+            TimeMode::RealTime => {
+                let start = Instant::now();
+                thread::sleep(Duration::from_millis(IDLE_SLICE_MS));
+                self.stats.idle_time += start.elapsed();
+                // devices keep running on the virtual clock while the core sleeps
+                self.advance(IDLE_SLICE_MS * CYCLES_PER_SECOND / 1000);
+            }
+        }
+    }
     pub fn print_status(&self) {
-        println!("Registers");
+        eprintln!("Registers");
         for i in 0..32 {
-            println!("$r{}: {:#x}", i, self.registers[i]);
+            eprintln!("$r{}: {:#x}", i, self.registers[i]);
         }
-        println!("\nPC: {:#x}", self.pc);
+        eprintln!("\nPC: {:#x}", self.pc);
     }
     fn tick(&mut self) {
-        if let Err(_) = self.tick_except() {
-            println!("double error asserted");
-            self.pc = REBOOT_VECTOR;
+        if self.tick_except().is_err() {
+            if self.trace {
+                eprintln!("double error asserted");
+            }
+            self.reset();
         }
     }
     pub fn interrupt(cause: Arc<Mutex<u32>>, level: u8) {
         let mut ptr = cause.lock().unwrap();
         *ptr = (*ptr | (1 << (level + 8))) & 0xffffff83;
     }
-    pub fn run(&mut self, stop_signal: Arc<Mutex<bool>>) {
-        while !*(stop_signal.lock().unwrap()) {
-            self.tick();
+    /// Runs until the guest powers the machine off or halts it, and returns its exit status.
+    pub fn run(&mut self) -> u32 {
+        loop {
+            if let Some(status) = self.step() {
+                return status;
+            }
+        }
+    }
+    /// Runs at most `cycles` ticks. Returns the exit status if the guest stopped the machine.
+    pub fn debug(&mut self, cycles: usize) -> Option<u32> {
+        for _ in 0..cycles {
+            if let Some(status) = self.step() {
+                return Some(status);
+            }
         }
+        None
     }
-    pub fn debug(&mut self, cycles: usize) {
-        for i in 0..cycles {
-            self.tick();
-            // self.print_status();
+    /// Runs one tick and carries out what an expired watchdog or the guest through the power
+    /// controller, semihosting, SPIM syscalls or Linux system calls asks for. Returns the exit status once the machine
+    /// is stopped.
+    fn step(&mut self) -> Option<u32> {
+        self.tick();
+        if let Some(status) = self.semihosting.as_mut().and_then(Semihosting::take_exit) {
+            return Some(status);
+        }
+        if let Some(status) = self.spim.as_mut().and_then(Spim::take_exit) {
+            return Some(status);
+        }
+        if let Some(status) = self.linux.as_mut().and_then(Linux::take_exit) {
+            return Some(status);
+        }
+        match self.bus.watchdog.take_expiry() {
+            Some(Action::Reset) => self.reset(),
+            Some(Action::Nmi) => self.nmi(),
+            Some(Action::Halt) => {
+                eprintln!("{}", self.bus.watchdog.report());
+                eprintln!("halted at pc {:#x}", self.pc);
+                self.print_status();
+                return Some(WATCHDOG_EXIT_STATUS);
+            }
+            None => {}
+        }
+        match self.bus.syscon.take_request()? {
+            Power::Off(status) => Some(status),
+            Power::Reboot => {
+                self.reset();
+                None
+            }
+            Power::Halt(status) => {
+                eprintln!("halted at pc {:#x}", self.pc);
+                self.print_status();
+                Some(status)
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,174 @@
+//! Host-side byte streams for guest character devices.
+//!
+//! Host I/O happens on helper threads so the emulator never blocks: received bytes are queued
+//! for the device to pick up, and output is handed to a writer thread through a channel.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Where a character device is connected on the host.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    /// Output is written to a file. Input, if given, is read from a second file.
+    File { input: Option<String>, output: String },
+    /// A pair of named pipes `PATH.in` (input) and `PATH.out` (output), or the single pipe
+    /// `PATH` for both directions when the pair does not exist.
+    Pipe(String),
+    /// A Unix socket the emulator listens on. Output is held back until a client connects.
+    Unix(String),
+    /// Like `Unix`, but a TCP port on localhost.
+    Tcp(u16),
+}
+
+impl Backend {
+    /// Parses `file:OUTPUT`, `file:INPUT,OUTPUT`, `pipe:PATH`, `unix:PATH` or `tcp:PORT`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (kind, path) = spec.split_once(':')?;
+        let path = path.to_string();
+        match kind {
+            "file" => Some(match path.split_once(',') {
+                Some((input, output)) => Backend::File { input: Some(input.to_string()), output: output.to_string() },
+                None => Backend::File { input: None, output: path },
+            }),
+            "pipe" => Some(Backend::Pipe(path)),
+            "unix" => Some(Backend::Unix(path)),
+            "tcp" => path.parse().ok().map(Backend::Tcp),
+            _ => None,
+        }
+    }
+}
+
+/// A connected host byte stream.
+pub struct CharDev {
+    input: Arc<Mutex<VecDeque<u8>>>,
+    output: Sender<Vec<u8>>,
+}
+
+impl CharDev {
+    pub fn open(backend: &Backend) -> io::Result<Self> {
+        let input = Arc::new(Mutex::new(VecDeque::new()));
+        let (output, rx) = mpsc::channel::<Vec<u8>>();
+        match backend {
+            Backend::File { input: source, output: path } => {
+                if let Some(source) = source {
+                    let file = File::open(source)?;
+                    let queue = input.clone();
+                    thread::spawn(move || receive(file, queue));
+                }
+                let mut file = File::create(path)?;
+                thread::spawn(move || {
+                    for data in rx {
+                        if file.write_all(&data).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            Backend::Pipe(path) => {
+                let (in_path, out_path) = if Path::new(&format!("{}.in", path)).exists() {
+                    (format!("{}.in", path), format!("{}.out", path))
+                } else {
+                    (path.clone(), path.clone())
+                };
+                // opening a pipe blocks until the other end is opened too
+                let queue = input.clone();
+                thread::spawn(move || match File::open(&in_path) {
+                    Ok(file) => receive(file, queue),
+                    Err(e) => eprintln!("chardev: cannot open {}: {}", in_path, e),
+                });
+                thread::spawn(move || match OpenOptions::new().write(true).open(&out_path) {
+                    Ok(mut file) => {
+                        for data in rx {
+                            if file.write_all(&data).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("chardev: cannot open {}: {}", out_path, e),
+                });
+            }
+            Backend::Unix(path) => {
+                remove_stale_socket(path);
+                let listener = UnixListener::bind(path)?;
+                let queue = input.clone();
+                thread::spawn(move || {
+                    if let Ok((stream, _)) = listener.accept() {
+                        serve(stream.try_clone(), stream, queue, rx);
+                    }
+                });
+            }
+            Backend::Tcp(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", *port))?;
+                let queue = input.clone();
+                thread::spawn(move || {
+                    if let Ok((stream, _)) = listener.accept() {
+                        serve(stream.try_clone(), stream, queue, rx);
+                    }
+                });
+            }
+        }
+        Ok(Self { input, output })
+    }
+
+    /// Returns true if received bytes are waiting.
+    pub fn pending(&self) -> bool {
+        !self.input.lock().unwrap().is_empty()
+    }
+
+    /// Takes up to `max` received bytes.
+    pub fn read(&self, max: usize) -> Vec<u8> {
+        let mut input = self.input.lock().unwrap();
+        let len = max.min(input.len());
+        input.drain(..len).collect()
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        // the writer thread is gone once the host side closed, the output is dropped then
+        let _ = self.output.send(data.to_vec());
+    }
+}
+
+/// Runs a connected stream: `reader` feeds `queue` on its own thread, and output from `rx` is
+/// written to `stream` until either side is closed.
+fn serve<S: Read + Write + Send + 'static>(
+    reader: io::Result<S>,
+    mut stream: S,
+    queue: Arc<Mutex<VecDeque<u8>>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+) {
+    if let Ok(reader) = reader {
+        thread::spawn(move || receive(reader, queue));
+    }
+    for data in rx {
+        if stream.write_all(&data).is_err() {
+            break;
+        }
+    }
+}
+
+/// Queues everything read from `source` until it is closed.
+pub fn receive(mut source: impl Read, queue: Arc<Mutex<VecDeque<u8>>>) {
+    let mut buf = [0; 256];
+    loop {
+        match source.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => queue.lock().unwrap().extend(&buf[..n]),
+        }
+    }
+}
+
+/// Removes a socket left at `path` by an earlier run, which would make bind fail. Anything
+/// else at `path` is kept, so binding reports it instead of deleting it.
+pub fn remove_stale_socket(path: &str) {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        let _ = std::fs::remove_file(path);
+    }
+}
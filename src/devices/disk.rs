@@ -0,0 +1,196 @@
+//! Disk images shared by the block devices.
+//!
+//! A disk is backed by an image file, or blank with only the written sectors kept in memory. With
+//! an overlay, writes go to an in-memory copy of each touched sector instead of the image, and are
+//! either thrown away or saved to a delta file when the disk is dropped.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// What happens to guest writes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Overlay {
+    /// Writes go straight to the image.
+    None,
+    /// Writes are kept in memory and lost at exit.
+    Discard,
+    /// Writes are kept in memory and saved to this delta file at exit. An existing delta file is
+    /// applied on open, so runs can continue from each other.
+    Save(String),
+}
+
+enum Backing {
+    File(File),
+    /// Reads as zeroes, written sectors live in `dirty`.
+    Blank,
+}
+
+pub struct Disk {
+    backing: Backing,
+    sectors: u64,
+    read_only: bool,
+    overlay: Overlay,
+    /// Sectors written since open when an overlay is active, or to a blank disk.
+    dirty: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Disk {
+    /// Creates a writable disk holding `data`, padded to whole sectors.
+    #[cfg(test)]
+    pub fn memory(data: Vec<u8>) -> Self {
+        let mut disk = Self::blank((data.len() as u64).div_ceil(SECTOR_SIZE));
+        disk.write(0, &data).unwrap();
+        disk
+    }
+
+    /// Creates a writable disk of `sectors` zeroed sectors, without allocating them up front.
+    pub fn blank(sectors: u64) -> Self {
+        Self { backing: Backing::Blank, sectors, read_only: false, overlay: Overlay::None, dirty: BTreeMap::new() }
+    }
+
+    /// Opens an image file. The capacity is the file size rounded up to whole sectors. The image
+    /// itself is only opened for writing when it is neither read-only nor overlaid.
+    pub fn open(path: &str, read_only: bool, overlay: Overlay) -> io::Result<Self> {
+        let writable = !read_only && overlay == Overlay::None;
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let sectors = file.metadata()?.len().div_ceil(SECTOR_SIZE);
+        let mut disk = Self { backing: Backing::File(file), sectors, read_only, overlay, dirty: BTreeMap::new() };
+        if let Overlay::Save(delta) = &disk.overlay {
+            if let Ok(bytes) = fs::read(delta) {
+                disk.load_delta(&bytes)?;
+            }
+        }
+        Ok(disk)
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Reads `buf.len()` bytes starting at byte `offset`.
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.check_range(offset, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let (sector, start) = (pos / SECTOR_SIZE, (pos % SECTOR_SIZE) as usize);
+            let len = (SECTOR_SIZE as usize - start).min(buf.len() - done);
+            let data = self.read_sector(sector)?;
+            buf[done..done + len].copy_from_slice(&data[start..start + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` starting at byte `offset`.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "disk is read-only"));
+        }
+        self.check_range(offset, data.len())?;
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let (sector, start) = (pos / SECTOR_SIZE, (pos % SECTOR_SIZE) as usize);
+            let len = (SECTOR_SIZE as usize - start).min(data.len() - done);
+            let mut sector_data = self.read_sector(sector)?;
+            sector_data[start..start + len].copy_from_slice(&data[done..done + len]);
+            self.write_sector(sector, sector_data)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.backing {
+            Backing::File(file) if self.overlay == Overlay::None => file.sync_data(),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> io::Result<()> {
+        if offset.checked_add(len as u64).is_none_or(|end| end > self.sectors * SECTOR_SIZE) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "access beyond the end of the disk"));
+        }
+        Ok(())
+    }
+
+    fn read_sector(&mut self, sector: u64) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.dirty.get(&sector) {
+            return Ok(data.clone());
+        }
+        let mut data = vec![0; SECTOR_SIZE as usize];
+        match &mut self.backing {
+            Backing::File(file) => {
+                // the last sector may be partial, the rest of it reads as zeroes
+                file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+                let mut filled = 0;
+                while filled < data.len() {
+                    match file.read(&mut data[filled..])? {
+                        0 => break,
+                        n => filled += n,
+                    }
+                }
+            }
+            Backing::Blank => {}
+        }
+        Ok(data)
+    }
+
+    fn write_sector(&mut self, sector: u64, data: Vec<u8>) -> io::Result<()> {
+        if self.overlay != Overlay::None || matches!(self.backing, Backing::Blank) {
+            self.dirty.insert(sector, data);
+            return Ok(());
+        }
+        match &mut self.backing {
+            Backing::File(file) => {
+                file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+                file.write_all(&data)?;
+            }
+            Backing::Blank => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// The delta file is a list of records: a little-endian u64 sector number followed by the
+    /// sector's contents.
+    fn load_delta(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let record = 8 + SECTOR_SIZE as usize;
+        if !bytes.len().is_multiple_of(record) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated delta file"));
+        }
+        for chunk in bytes.chunks(record) {
+            let sector = u64::from_le_bytes(chunk[..8].try_into().unwrap());
+            if sector < self.sectors {
+                self.dirty.insert(sector, chunk[8..].to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    fn save_delta(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        for (sector, data) in &self.dirty {
+            file.write_all(&sector.to_le_bytes())?;
+            file.write_all(data)?;
+        }
+        file.sync_all()
+    }
+}
+
+impl Drop for Disk {
+    fn drop(&mut self) {
+        if let Overlay::Save(path) = &self.overlay {
+            if let Err(e) = self.save_delta(path) {
+                eprintln!("disk: cannot save delta to {}: {}", path, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! The dma module implements a DMA engine with a few channels. A channel copies between any two
+//! bus addresses, memory or device registers, and follows a chain of scatter-gather descriptors
+//! in guest memory. The engine shares the bus with the CPU and moves one unit per bus cycle.
+
+use crate::cpu::Size;
+use crate::exception::Exception;
+
+use super::device::Device;
+use super::irq::Irq;
+
+/// The interrupt level of the DMA engine, shared with the virtio transports.
+pub const DMA_IRQ: u8 = 3;
+pub const CHANNELS: usize = 4;
+/// Each channel has its registers in a slot of this size.
+pub const CHANNEL_SIZE: u32 = 0x20;
+
+/// Next source address.
+const SRC: u32 = 0x00;
+/// Next destination address.
+const DST: u32 = 0x04;
+/// Bytes left in the current segment.
+const LEN: u32 = 0x08;
+/// Physical address of the descriptor loaded once the segment is done, 0 ends the chain.
+const NEXT: u32 = 0x0c;
+/// One of the `CONTROL_` bits each.
+const CONTROL: u32 = 0x10;
+/// `STATUS_DONE` or `STATUS_ERROR`, writing 1 clears a bit.
+const STATUS: u32 = 0x14;
+/// Bytes moved since the channel was started.
+const TRANSFERRED: u32 = 0x18;
+
+/// Writing 1 starts the channel, it reads 1 until the transfer ends. Writing 0 aborts it.
+pub const CONTROL_START: u32 = 1;
+/// Interrupt when the channel stops with a status bit set.
+pub const CONTROL_IRQ: u32 = 1 << 1;
+/// The source address stays put, for reading a device FIFO.
+pub const CONTROL_SRC_FIXED: u32 = 1 << 2;
+/// The destination address stays put, for writing a device FIFO.
+pub const CONTROL_DST_FIXED: u32 = 1 << 3;
+/// Moves single bytes even where words would do, for byte-wide device registers.
+pub const CONTROL_BYTE: u32 = 1 << 4;
+
+pub const STATUS_DONE: u32 = 1;
+/// A bus error or a misaligned descriptor stopped the channel.
+pub const STATUS_ERROR: u32 = 1 << 1;
+
+/// A descriptor is four words: source, destination, length and the next descriptor.
+pub const DESCRIPTOR_SIZE: u32 = 16;
+
+struct Channel {
+    src: u32,
+    dst: u32,
+    len: u32,
+    next: u32,
+    control: u32,
+    status: u32,
+    transferred: u32,
+    irq: Irq,
+}
+
+impl Channel {
+    fn busy(&self) -> bool {
+        self.control & CONTROL_START != 0
+    }
+
+    fn update_irq(&self) {
+        if self.status != 0 && self.control & CONTROL_IRQ != 0 {
+            self.irq.raise();
+        } else {
+            self.irq.lower();
+        }
+    }
+
+    fn stop(&mut self, status: u32) {
+        self.control &= !CONTROL_START;
+        self.status |= status;
+        self.update_irq();
+    }
+
+    /// Stops with `STATUS_DONE` once the segment is done and no descriptor follows.
+    fn check_done(&mut self) {
+        if self.len == 0 && self.next == 0 {
+            self.stop(STATUS_DONE);
+        }
+    }
+
+    fn load_descriptor(&mut self, mem: &mut dyn Device) -> Result<(), Exception> {
+        if !self.next.is_multiple_of(4) {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        let mut words = [0; 4];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = mem.read(self.next.wrapping_add(4 * i as u32), Size::Word)?;
+        }
+        [self.src, self.dst, self.len, self.next] = words;
+        Ok(())
+    }
+
+    fn copy(&mut self, mem: &mut dyn Device, word: bool) -> Result<(), Exception> {
+        let size = || if word { Size::Word } else { Size::Byte };
+        let data = mem.read(self.src, size())?;
+        mem.write(self.dst, data, size())
+    }
+
+    /// Takes one turn on the bus and returns the cycles it used.
+    fn step(&mut self, mem: &mut dyn Device) -> u64 {
+        if self.len == 0 {
+            match self.load_descriptor(mem) {
+                Ok(()) => self.check_done(),
+                Err(_) => self.stop(STATUS_ERROR),
+            }
+            return (DESCRIPTOR_SIZE / 4) as u64;
+        }
+        let word = self.control & CONTROL_BYTE == 0 && self.len >= 4 && self.src.is_multiple_of(4) && self.dst.is_multiple_of(4);
+        let width = if word { 4 } else { 1 };
+        if self.copy(mem, word).is_err() {
+            self.stop(STATUS_ERROR);
+            return 1;
+        }
+        if self.control & CONTROL_SRC_FIXED == 0 {
+            self.src = self.src.wrapping_add(width);
+        }
+        if self.control & CONTROL_DST_FIXED == 0 {
+            self.dst = self.dst.wrapping_add(width);
+        }
+        self.len -= width;
+        self.transferred = self.transferred.wrapping_add(width);
+        self.check_done();
+        1
+    }
+}
+
+pub struct Dma {
+    channels: Vec<Channel>,
+    /// The channel that gets the bus next, so busy channels take turns.
+    turn: usize,
+}
+
+impl Dma {
+    /// A DMA engine whose channel `i` interrupts through `irqs[i]`.
+    pub fn new(irqs: Vec<Irq>) -> Self {
+        let channels = irqs
+            .into_iter()
+            .map(|irq| Channel { src: 0, dst: 0, len: 0, next: 0, control: 0, status: 0, transferred: 0, irq })
+            .collect();
+        Self { channels, turn: 0 }
+    }
+
+    /// Returns true while any channel is transferring.
+    pub fn busy(&self) -> bool {
+        self.channels.iter().any(Channel::busy)
+    }
+
+    /// Gives the busy channels `cycles` bus cycles in turn. `mem` is the bus without the DMA
+    /// engine.
+    pub fn advance(&mut self, cycles: u64, mem: &mut dyn Device) {
+        let mut left = cycles;
+        while left > 0 {
+            let count = self.channels.len();
+            let Some(i) = (0..count).map(|k| (self.turn + k) % count).find(|i| self.channels[*i].busy()) else {
+                break;
+            };
+            self.turn = (i + 1) % count;
+            // a descriptor load that runs over the budget finishes anyway
+            left = left.saturating_sub(self.channels[i].step(mem));
+        }
+    }
+
+    /// Cycles until a busy channel can stop at the earliest.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        self.channels.iter().filter(|channel| channel.busy()).map(|channel| channel.len.div_ceil(4).max(1) as u64).min()
+    }
+}
+
+impl Device for Dma {
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        if size != Size::Word {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        let channel = self.channels.get((addr / CHANNEL_SIZE) as usize).ok_or(Exception::LoadIllegalAddress)?;
+        match addr % CHANNEL_SIZE {
+            SRC => Ok(channel.src),
+            DST => Ok(channel.dst),
+            LEN => Ok(channel.len),
+            NEXT => Ok(channel.next),
+            CONTROL => Ok(channel.control),
+            STATUS => Ok(channel.status),
+            TRANSFERRED => Ok(channel.transferred),
+            _ => Err(Exception::LoadIllegalAddress),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
+        if size != Size::Word {
+            return Err(Exception::StoreIllegalAddress);
+        }
+        let channel = self.channels.get_mut((addr / CHANNEL_SIZE) as usize).ok_or(Exception::StoreIllegalAddress)?;
+        match addr % CHANNEL_SIZE {
+            SRC => channel.src = data,
+            DST => channel.dst = data,
+            LEN => channel.len = data,
+            NEXT => channel.next = data,
+            CONTROL => {
+                let start = data & CONTROL_START != 0 && !channel.busy();
+                channel.control = data & (CONTROL_START | CONTROL_IRQ | CONTROL_SRC_FIXED | CONTROL_DST_FIXED | CONTROL_BYTE);
+                if start {
+                    channel.transferred = 0;
+                    channel.check_done();
+                }
+            }
+            STATUS => channel.status &= !data,
+            TRANSFERRED => {}
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+        channel.update_irq();
+        Ok(())
+    }
+}
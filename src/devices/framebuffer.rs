@@ -0,0 +1,303 @@
+//! The framebuffer module implements a linear framebuffer in DRAM. The guest points it at a
+//! buffer and picks the resolution and pixel format; the emulator has no window, frames are
+//! dumped to PPM or PNG files instead.
+
+use std::fs;
+use std::io;
+
+use crate::coprocessor::CYCLES_PER_SECOND;
+use crate::cpu::Size;
+use crate::exception::Exception;
+
+use super::device::Device;
+use super::irq::Irq;
+
+/// The interrupt level of the vsync interrupt.
+pub const FRAMEBUFFER_IRQ: u8 = 7;
+/// Frames per second of emulated time.
+pub const REFRESH_RATE: u64 = 60;
+
+const WIDTH: u32 = 0x00;
+const HEIGHT: u32 = 0x04;
+/// One of the `FORMAT_` values.
+const FORMAT: u32 = 0x08;
+/// Bytes per line, 0 for tightly packed lines.
+const STRIDE: u32 = 0x0c;
+/// Physical address of the first pixel.
+const ADDR: u32 = 0x10;
+/// Bit 0 turns the display on, bit 1 enables the vsync interrupt.
+const CONTROL: u32 = 0x14;
+/// Bit 0 is set at every vsync, writing 1 clears it.
+const STATUS: u32 = 0x18;
+/// Number of frames shown since the display was turned on.
+const FRAME: u32 = 0x1c;
+/// Any write dumps the current frame.
+const DUMP: u32 = 0x20;
+
+/// 32-bit words 0x00RRGGBB.
+pub const FORMAT_XRGB8888: u32 = 0;
+/// 16-bit halfwords, 5 bits red, 6 green, 5 blue.
+pub const FORMAT_RGB565: u32 = 1;
+
+const CONTROL_ENABLE: u32 = 1;
+const CONTROL_VSYNC_IRQ: u32 = 1 << 1;
+
+/// Largest supported resolution in either direction.
+const MAX_SIZE: u32 = 4096;
+
+/// Image file format of dumps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+}
+
+/// Where and how often frames are dumped.
+#[derive(Debug, Clone)]
+pub struct DumpConfig {
+    /// Dumps go to `{prefix}{frame:06}.ppm` or `.png`.
+    pub prefix: String,
+    pub format: ImageFormat,
+    /// Dumps every this many frames, besides the dumps the guest asks for.
+    pub every: Option<u32>,
+}
+
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    format: u32,
+    stride: u32,
+    addr: u32,
+    control: u32,
+    vsync: bool,
+    frame: u32,
+    /// Cycles until the next vsync.
+    remaining: u64,
+    /// A dump the guest asked for, made once DRAM is at hand.
+    dump_requested: bool,
+    dump: Option<DumpConfig>,
+    irq: Irq,
+}
+
+impl Framebuffer {
+    pub fn new(dump: Option<DumpConfig>, irq: Irq) -> Self {
+        Self {
+            width: 640,
+            height: 480,
+            format: FORMAT_XRGB8888,
+            stride: 0,
+            addr: 0,
+            control: 0,
+            vsync: false,
+            frame: 0,
+            remaining: 0,
+            dump_requested: false,
+            dump,
+            irq,
+        }
+    }
+
+    fn frame_cycles() -> u64 {
+        CYCLES_PER_SECOND / REFRESH_RATE
+    }
+
+    fn bytes_per_pixel(&self) -> u32 {
+        if self.format == FORMAT_RGB565 {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// Counts frames while the display is on, and makes the dumps that are due.
+    pub fn advance(&mut self, cycles: u64, mem: &mut dyn Device) {
+        if self.control & CONTROL_ENABLE != 0 {
+            let mut left = cycles;
+            while left >= self.remaining {
+                left -= self.remaining;
+                self.remaining = Self::frame_cycles();
+                self.frame = self.frame.wrapping_add(1);
+                self.vsync = true;
+                let every = self.dump.as_ref().and_then(|dump| dump.every);
+                if every.is_some_and(|every| every > 0 && self.frame.is_multiple_of(every)) {
+                    self.dump_requested = true;
+                }
+            }
+            self.remaining -= left;
+        }
+        if std::mem::take(&mut self.dump_requested) {
+            if let Err(e) = self.dump_frame(mem) {
+                eprintln!("framebuffer: cannot dump frame {}: {}", self.frame, e);
+            }
+        }
+        if self.vsync && self.control & CONTROL_VSYNC_IRQ != 0 {
+            self.irq.raise();
+        } else {
+            self.irq.lower();
+        }
+    }
+
+    /// Cycles until the next vsync, if the display is on.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        (self.control & CONTROL_ENABLE != 0).then_some(self.remaining.max(1))
+    }
+
+    /// Reads the current frame as 8-bit RGB triples, line by line.
+    pub fn snapshot(&self, mem: &mut dyn Device) -> Result<(u32, u32, Vec<u8>), Exception> {
+        let bpp = self.bytes_per_pixel();
+        let stride = if self.stride == 0 { self.width * bpp } else { self.stride };
+        let mut rgb = Vec::with_capacity((self.width * self.height * 3) as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let addr = self.addr.wrapping_add(y.wrapping_mul(stride)).wrapping_add(x * bpp);
+                let pixel = if bpp == 2 {
+                    let p = mem.read(addr, Size::Halfword)?;
+                    // widen to 8 bits, repeating the top bits so white stays white
+                    let (r, g, b) = (p >> 11 & 0x1f, p >> 5 & 0x3f, p & 0x1f);
+                    [(r << 3 | r >> 2) as u8, (g << 2 | g >> 4) as u8, (b << 3 | b >> 2) as u8]
+                } else {
+                    let p = mem.read(addr, Size::Word)?;
+                    [(p >> 16) as u8, (p >> 8) as u8, p as u8]
+                };
+                rgb.extend_from_slice(&pixel);
+            }
+        }
+        Ok((self.width, self.height, rgb))
+    }
+
+    fn dump_frame(&self, mem: &mut dyn Device) -> io::Result<()> {
+        let Some(dump) = &self.dump else {
+            return Ok(());
+        };
+        let (width, height, rgb) = self
+            .snapshot(mem)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("frame not in memory: {:?}", e)))?;
+        let (data, ext) = match dump.format {
+            ImageFormat::Ppm => (ppm(width, height, &rgb), "ppm"),
+            ImageFormat::Png => (png(width, height, &rgb), "png"),
+        };
+        fs::write(format!("{}{:06}.{}", dump.prefix, self.frame, ext), data)
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        if size != Size::Word {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        match addr {
+            WIDTH => Ok(self.width),
+            HEIGHT => Ok(self.height),
+            FORMAT => Ok(self.format),
+            STRIDE => Ok(self.stride),
+            ADDR => Ok(self.addr),
+            CONTROL => Ok(self.control),
+            STATUS => Ok(self.vsync as u32),
+            FRAME => Ok(self.frame),
+            DUMP => Ok(0),
+            _ => Err(Exception::LoadIllegalAddress),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
+        if size != Size::Word {
+            return Err(Exception::StoreIllegalAddress);
+        }
+        match addr {
+            WIDTH => self.width = data.min(MAX_SIZE),
+            HEIGHT => self.height = data.min(MAX_SIZE),
+            FORMAT if data == FORMAT_XRGB8888 || data == FORMAT_RGB565 => self.format = data,
+            // unsupported formats are ignored, the guest reads back what it got
+            FORMAT => {}
+            STRIDE => self.stride = data,
+            ADDR => self.addr = data,
+            CONTROL => {
+                if data & CONTROL_ENABLE != 0 && self.control & CONTROL_ENABLE == 0 {
+                    self.frame = 0;
+                    self.remaining = Self::frame_cycles();
+                }
+                self.control = data & (CONTROL_ENABLE | CONTROL_VSYNC_IRQ);
+            }
+            STATUS if data & 1 != 0 => self.vsync = false,
+            STATUS | FRAME => {}
+            DUMP => self.dump_requested = true,
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+        if !(self.vsync && self.control & CONTROL_VSYNC_IRQ != 0) {
+            self.irq.lower();
+        }
+        Ok(())
+    }
+}
+
+/// Encodes 8-bit RGB triples as a binary PPM (P6).
+pub fn ppm(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    data.extend_from_slice(rgb);
+    data
+}
+
+/// Encodes 8-bit RGB triples as a PNG. The image data is stored without compression, which every
+/// decoder reads and needs no compressor.
+/// https://www.w3.org/TR/png/
+pub fn png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    // every line starts with filter type 0, none
+    let line = (width * 3) as usize;
+    let mut raw = Vec::with_capacity((line + 1) * height as usize);
+    for y in 0..height as usize {
+        raw.push(0);
+        raw.extend_from_slice(&rgb[y * line..(y + 1) * line]);
+    }
+
+    // zlib stream (RFC 1950) of stored deflate blocks (RFC 1951 3.2.4)
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // bit depth 8, color type 2 (truecolor), deflate, adaptive filtering, no interlace
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut data = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    for (kind, body) in [(b"IHDR", &ihdr), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+        data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        let start = data.len();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        let crc = crc32(&data[start..]);
+        data.extend_from_slice(&crc.to_be_bytes());
+    }
+    data
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+/// CRC-32 as PNG chunks use it, bit by bit since frames are dumped rarely.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
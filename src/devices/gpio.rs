@@ -0,0 +1,174 @@
+//! The gpio module implements a block of 32 general purpose pins with edge interrupts. The host
+//! side of the pins is a `Pins` handle, through which test code drives inputs, now or at a
+//! later virtual time, and reads back every change of the pin levels.
+
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::Size;
+use crate::exception::Exception;
+
+use super::device::Device;
+use super::irq::Irq;
+
+/// The interrupt level of the GPIO block, shared with the UART.
+pub const GPIO_IRQ: u8 = 2;
+
+/// Pin levels, read-only.
+const IN: u32 = 0x00;
+/// Levels of the output pins.
+const OUT: u32 = 0x04;
+/// 1 makes a pin an output.
+const DIR: u32 = 0x08;
+/// Pins interrupting on a rising edge.
+const RISE: u32 = 0x0c;
+/// Pins interrupting on a falling edge.
+const FALL: u32 = 0x10;
+/// Pins that saw an enabled edge, writing 1 clears a bit.
+const IRQ_STATUS: u32 = 0x14;
+
+#[derive(Default)]
+struct External {
+    /// Pins the host drives, and their levels.
+    driven: u32,
+    levels: u32,
+    /// Future changes as (cycle, pin, level), applied in order.
+    scheduled: Vec<(u64, u32, Option<bool>)>,
+    /// Pin levels after every change, with the cycle it happened at.
+    history: Vec<(u64, u32)>,
+}
+
+impl External {
+    fn set(&mut self, pin: u32, level: Option<bool>) {
+        let bit = 1 << pin;
+        match level {
+            Some(level) => {
+                self.driven |= bit;
+                self.levels = if level { self.levels | bit } else { self.levels & !bit };
+            }
+            None => self.driven &= !bit,
+        }
+    }
+}
+
+/// The host side of the pins. Clones share the pins.
+#[derive(Clone, Default)]
+pub struct Pins(Arc<Mutex<External>>);
+
+#[cfg(test)]
+impl Pins {
+    /// Drives input `pin` high or low.
+    pub fn drive(&self, pin: u32, level: bool) {
+        self.0.lock().unwrap().set(pin, Some(level));
+    }
+    /// Stops driving `pin`, an undriven input reads low.
+    pub fn release(&self, pin: u32) {
+        self.0.lock().unwrap().set(pin, None);
+    }
+    /// Drives `pin` at virtual time `cycle`, or releases it with `None`.
+    pub fn schedule(&self, cycle: u64, pin: u32, level: Option<bool>) {
+        let mut external = self.0.lock().unwrap();
+        let at = external.scheduled.partition_point(|(c, ..)| *c <= cycle);
+        external.scheduled.insert(at, (cycle, pin, level));
+    }
+    /// Pin levels after every change so far, as (cycle, levels).
+    pub fn history(&self) -> Vec<(u64, u32)> {
+        self.0.lock().unwrap().history.clone()
+    }
+    /// The current level of `pin`.
+    pub fn level(&self, pin: u32) -> bool {
+        self.0.lock().unwrap().history.last().is_some_and(|(_, levels)| levels & 1 << pin != 0)
+    }
+}
+
+pub struct Gpio {
+    out: u32,
+    dir: u32,
+    rise: u32,
+    fall: u32,
+    irq_status: u32,
+    levels: u32,
+    /// Virtual time, to timestamp the history and apply scheduled changes.
+    cycles: u64,
+    pins: Pins,
+    irq: Irq,
+}
+
+impl Gpio {
+    pub fn new(irq: Irq) -> Self {
+        let pins = Pins::default();
+        pins.0.lock().unwrap().history.push((0, 0));
+        Self { out: 0, dir: 0, rise: 0, fall: 0, irq_status: 0, levels: 0, cycles: 0, pins, irq }
+    }
+
+    /// A handle to the host side of the pins.
+    #[cfg(test)]
+    pub fn pins(&self) -> Pins {
+        self.pins.clone()
+    }
+
+    /// Works out the pin levels, outputs win over the host, and latches enabled edges.
+    fn update(&mut self) {
+        let mut external = self.pins.0.lock().unwrap();
+        while external.scheduled.first().is_some_and(|(cycle, ..)| *cycle <= self.cycles) {
+            let (_, pin, level) = external.scheduled.remove(0);
+            external.set(pin, level);
+        }
+        let levels = self.out & self.dir | external.levels & external.driven & !self.dir;
+        if levels != self.levels {
+            self.irq_status |= levels & !self.levels & self.rise | !levels & self.levels & self.fall;
+            self.levels = levels;
+            external.history.push((self.cycles, levels));
+        }
+        if self.irq_status != 0 {
+            self.irq.raise();
+        } else {
+            self.irq.lower();
+        }
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        self.update();
+    }
+
+    /// Cycles until the next scheduled pin change, if any.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        let external = self.pins.0.lock().unwrap();
+        external.scheduled.first().map(|(cycle, ..)| cycle.saturating_sub(self.cycles).max(1))
+    }
+}
+
+impl Device for Gpio {
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        if size != Size::Word {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        self.update();
+        match addr {
+            IN => Ok(self.levels),
+            OUT => Ok(self.out),
+            DIR => Ok(self.dir),
+            RISE => Ok(self.rise),
+            FALL => Ok(self.fall),
+            IRQ_STATUS => Ok(self.irq_status),
+            _ => Err(Exception::LoadIllegalAddress),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
+        if size != Size::Word {
+            return Err(Exception::StoreIllegalAddress);
+        }
+        match addr {
+            OUT => self.out = data,
+            DIR => self.dir = data,
+            RISE => self.rise = data,
+            FALL => self.fall = data,
+            IRQ_STATUS => self.irq_status &= !data,
+            IN => {}
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+        self.update();
+        Ok(())
+    }
+}
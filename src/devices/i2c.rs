@@ -0,0 +1,324 @@
+//! The i2c module implements an I2C master with the registers of the OpenCores I2C controller,
+//! spaced one word apart, and the slaves on its bus. Slaves are host-side models; test code
+//! attaches its own by implementing `I2cSlave`.
+//! https://opencores.org/projects/i2c
+
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::Size;
+use crate::exception::Exception;
+
+use super::device::Device;
+use super::irq::Irq;
+
+/// The interrupt level of the I2C master, shared with the UART.
+pub const I2C_IRQ: u8 = 2;
+/// Bus addresses of the slaves every machine has.
+pub const EEPROM_ADDRESS: u8 = 0x50;
+pub const LM75_ADDRESS: u8 = 0x48;
+
+/// Clock prescaler, SCL runs at the 1 MHz virtual clock / (5 * (PRER + 1)).
+const PRER_LO: u32 = 0x00;
+const PRER_HI: u32 = 0x04;
+/// `CTR_` bits.
+const CTR: u32 = 0x08;
+/// Writes go to TXR, reads come from RXR.
+const TXR_RXR: u32 = 0x0c;
+/// Writes go to CR (`CR_` bits), reads come from SR (`SR_` bits).
+const CR_SR: u32 = 0x10;
+
+pub const CTR_EN: u32 = 0x80;
+pub const CTR_IEN: u32 = 0x40;
+
+pub const CR_STA: u32 = 0x80;
+pub const CR_STO: u32 = 0x40;
+pub const CR_RD: u32 = 0x20;
+pub const CR_WR: u32 = 0x10;
+/// Sent after a read: 0 acknowledges, 1 does not.
+pub const CR_ACK: u32 = 0x08;
+pub const CR_IACK: u32 = 0x01;
+
+/// The slave did not acknowledge the last byte.
+pub const SR_RXACK: u32 = 0x80;
+/// Between a start and a stop condition.
+pub const SR_BUSY: u32 = 0x40;
+/// Transfer in progress.
+pub const SR_TIP: u32 = 0x02;
+/// A transfer finished.
+pub const SR_IF: u32 = 0x01;
+
+/// A device on the I2C bus.
+pub trait I2cSlave: Send {
+    /// A start condition addressed this slave; `read` is the direction bit.
+    fn start(&mut self, read: bool);
+    /// A byte from the master. Returns false to not acknowledge it.
+    fn write(&mut self, byte: u8) -> bool;
+    /// The next byte for the master.
+    fn read(&mut self) -> u8;
+    fn stop(&mut self);
+}
+
+pub struct I2c {
+    prescale: u16,
+    ctr: u32,
+    txr: u8,
+    rxr: u8,
+    sr: u32,
+    /// The command in progress and the cycles it still takes.
+    command: Option<(u32, u64)>,
+    /// The next written byte is an address, after a start condition.
+    addressing: bool,
+    /// Index of the slave of the current transaction.
+    selected: Option<usize>,
+    slaves: Vec<(u8, Box<dyn I2cSlave>)>,
+    irq: Irq,
+}
+
+impl I2c {
+    pub fn new(irq: Irq) -> Self {
+        Self { prescale: 0xffff, ctr: 0, txr: 0, rxr: 0, sr: 0, command: None, addressing: false, selected: None, slaves: Vec::new(), irq }
+    }
+
+    /// Connects `slave` at bus address `address`, replacing the slave there.
+    pub fn attach(&mut self, address: u8, slave: Box<dyn I2cSlave>) {
+        self.slaves.retain(|(a, _)| *a != address);
+        self.slaves.push((address, slave));
+    }
+
+    fn update_irq(&self) {
+        if self.sr & SR_IF != 0 && self.ctr & CTR_IEN != 0 {
+            self.irq.raise();
+        } else {
+            self.irq.lower();
+        }
+    }
+
+    /// Cycles a command takes: a bit time per start or stop condition, nine per byte.
+    fn command_cycles(&self, cr: u32) -> u64 {
+        let bit = 5 * (self.prescale as u64 + 1);
+        let bits = (cr & CR_STA != 0) as u64 + (cr & CR_STO != 0) as u64 + 9 * (cr & (CR_RD | CR_WR) != 0) as u64;
+        bit * bits
+    }
+
+    /// Carries out a command once its time on the bus is over.
+    fn execute(&mut self, cr: u32) {
+        if cr & CR_STA != 0 {
+            self.sr |= SR_BUSY;
+            self.addressing = true;
+        }
+        if cr & CR_WR != 0 {
+            let ack = if std::mem::take(&mut self.addressing) {
+                self.selected = self.slaves.iter().position(|(address, _)| *address == self.txr >> 1);
+                if let Some(i) = self.selected {
+                    self.slaves[i].1.start(self.txr & 1 != 0);
+                }
+                self.selected.is_some()
+            } else {
+                self.selected.is_some_and(|i| self.slaves[i].1.write(self.txr))
+            };
+            self.sr = if ack { self.sr & !SR_RXACK } else { self.sr | SR_RXACK };
+        } else if cr & CR_RD != 0 {
+            self.rxr = self.selected.map_or(0xff, |i| self.slaves[i].1.read());
+        }
+        if cr & CR_STO != 0 {
+            if let Some(i) = self.selected.take() {
+                self.slaves[i].1.stop();
+            }
+            self.sr &= !SR_BUSY;
+        }
+        self.sr = self.sr & !SR_TIP | SR_IF;
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        if let Some((cr, left)) = self.command {
+            if left <= cycles {
+                self.command = None;
+                self.execute(cr);
+            } else {
+                self.command = Some((cr, left - cycles));
+            }
+        }
+        self.update_irq();
+    }
+
+    /// Cycles until the command in progress finishes, if any.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        self.command.map(|(_, left)| left.max(1))
+    }
+}
+
+impl Device for I2c {
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        if size == Size::Halfword {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        match addr {
+            PRER_LO => Ok(self.prescale as u32 & 0xff),
+            PRER_HI => Ok(self.prescale as u32 >> 8),
+            CTR => Ok(self.ctr),
+            TXR_RXR => Ok(self.rxr as u32),
+            CR_SR => Ok(self.sr),
+            _ => Err(Exception::LoadIllegalAddress),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
+        if size == Size::Halfword {
+            return Err(Exception::StoreIllegalAddress);
+        }
+        let data = data & 0xff;
+        match addr {
+            PRER_LO => self.prescale = self.prescale & 0xff00 | data as u16,
+            PRER_HI => self.prescale = self.prescale & 0xff | (data as u16) << 8,
+            CTR => self.ctr = data & (CTR_EN | CTR_IEN),
+            TXR_RXR => self.txr = data as u8,
+            CR_SR => {
+                if data & CR_IACK != 0 {
+                    self.sr &= !SR_IF;
+                }
+                let cr = data & (CR_STA | CR_STO | CR_RD | CR_WR | CR_ACK);
+                if cr & !CR_ACK != 0 && self.ctr & CTR_EN != 0 && self.command.is_none() {
+                    self.sr |= SR_TIP;
+                    self.command = Some((cr, self.command_cycles(cr)));
+                }
+            }
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+        self.update_irq();
+        Ok(())
+    }
+}
+
+struct EepromState {
+    data: Vec<u8>,
+    pointer: u8,
+    /// The next written byte sets the pointer.
+    addressing: bool,
+}
+
+/// A 24C02 EEPROM: 256 bytes written in pages of 8. Clones share the contents.
+#[derive(Clone)]
+pub struct Eeprom(Arc<Mutex<EepromState>>);
+
+impl Default for Eeprom {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(EepromState { data: vec![0xff; 256], pointer: 0, addressing: false })))
+    }
+}
+
+impl Eeprom {
+    #[cfg(test)]
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().data.clone()
+    }
+    /// Replaces the contents from the start with `data`.
+    pub fn load(&self, data: &[u8]) {
+        let mut eeprom = self.0.lock().unwrap();
+        let len = data.len().min(eeprom.data.len());
+        eeprom.data[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+impl I2cSlave for Eeprom {
+    fn start(&mut self, read: bool) {
+        self.0.lock().unwrap().addressing = !read;
+    }
+    fn write(&mut self, byte: u8) -> bool {
+        let mut eeprom = self.0.lock().unwrap();
+        if std::mem::take(&mut eeprom.addressing) {
+            eeprom.pointer = byte;
+        } else {
+            let pointer = eeprom.pointer;
+            eeprom.data[pointer as usize] = byte;
+            // writes wrap around within the page
+            eeprom.pointer = pointer & !7 | pointer.wrapping_add(1) & 7;
+        }
+        true
+    }
+    fn read(&mut self) -> u8 {
+        let mut eeprom = self.0.lock().unwrap();
+        let byte = eeprom.data[eeprom.pointer as usize];
+        eeprom.pointer = eeprom.pointer.wrapping_add(1);
+        byte
+    }
+    fn stop(&mut self) {}
+}
+
+struct Lm75State {
+    /// Temperature in millidegrees Celsius.
+    temperature: i32,
+    pointer: u8,
+    addressing: bool,
+    /// Byte of a two-byte register the next access hits.
+    index: usize,
+    config: u8,
+    thyst: u16,
+    tos: u16,
+}
+
+/// An LM75 temperature sensor. The temperature is set from the host; clones share it.
+#[derive(Clone)]
+pub struct Lm75(Arc<Mutex<Lm75State>>);
+
+impl Default for Lm75 {
+    fn default() -> Self {
+        let state = Lm75State { temperature: 25_000, pointer: 0, addressing: false, index: 0, config: 0, thyst: 75 << 8, tos: 80 << 8 };
+        Self(Arc::new(Mutex::new(state)))
+    }
+}
+
+#[cfg(test)]
+impl Lm75 {
+    pub fn set_temperature(&self, millidegrees: i32) {
+        self.0.lock().unwrap().temperature = millidegrees;
+    }
+}
+
+impl Lm75State {
+    /// The register the pointer selects, with 9-bit temperatures in the top bits.
+    fn register(&self) -> u16 {
+        match self.pointer {
+            0 => ((self.temperature / 500) as i16 as u16) << 7,
+            1 => (self.config as u16) << 8,
+            2 => self.thyst,
+            _ => self.tos,
+        }
+    }
+}
+
+impl I2cSlave for Lm75 {
+    fn start(&mut self, read: bool) {
+        let mut lm75 = self.0.lock().unwrap();
+        lm75.addressing = !read;
+        lm75.index = 0;
+    }
+    fn write(&mut self, byte: u8) -> bool {
+        let mut lm75 = self.0.lock().unwrap();
+        if std::mem::take(&mut lm75.addressing) {
+            lm75.pointer = byte & 3;
+            return true;
+        }
+        let shift = if lm75.index.is_multiple_of(2) { 8 } else { 0 };
+        let merge = |reg: u16| reg & !(0xff << shift) | (byte as u16) << shift;
+        match lm75.pointer {
+            1 => lm75.config = byte,
+            2 => lm75.thyst = merge(lm75.thyst) & 0xff80,
+            3 => lm75.tos = merge(lm75.tos) & 0xff80,
+            // the temperature is read-only
+            _ => return false,
+        }
+        lm75.index += 1;
+        true
+    }
+    fn read(&mut self) -> u8 {
+        let mut lm75 = self.0.lock().unwrap();
+        let register = lm75.register();
+        let byte = if lm75.index.is_multiple_of(2) { register >> 8 } else { register & 0xff };
+        // the one-byte configuration register repeats its byte
+        if lm75.pointer != 1 {
+            lm75.index += 1;
+        }
+        byte as u8
+    }
+    fn stop(&mut self) {}
+}
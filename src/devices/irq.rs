@@ -0,0 +1,33 @@
+use std::sync::{Arc, Mutex};
+
+/// An interrupt line wired to one of the CP0 Cause.IP bits. Several sources can share a line;
+/// the Cause bit stays set while any of them is asserted.
+#[derive(Clone)]
+pub struct Irq {
+    cause: Arc<Mutex<u32>>,
+    level: u8,
+    /// Asserted sources of the line, one bit each.
+    asserted: Arc<Mutex<u32>>,
+    source: u8,
+}
+
+impl Irq {
+    pub fn new(cause: Arc<Mutex<u32>>, level: u8) -> Self {
+        Self { cause, level, asserted: Arc::new(Mutex::new(0)), source: 0 }
+    }
+    /// Returns another source on the same line.
+    pub fn share(&self, source: u8) -> Self {
+        Self { source, ..self.clone() }
+    }
+    pub fn raise(&self) {
+        *self.asserted.lock().unwrap() |= 1 << self.source;
+        *self.cause.lock().unwrap() |= 1 << (self.level + 8);
+    }
+    pub fn lower(&self) {
+        let mut asserted = self.asserted.lock().unwrap();
+        *asserted &= !(1 << self.source);
+        if *asserted == 0 {
+            *self.cause.lock().unwrap() &= !(1 << (self.level + 8));
+        }
+    }
+}
@@ -1,3 +1,17 @@
 pub mod uart;
 pub mod virtio;
-pub mod device;
\ No newline at end of file
+pub mod device;
+pub mod irq;
+pub mod disk;
+pub mod chardev;
+pub mod netdev;
+pub mod serial;
+pub mod rtc;
+pub mod syscon;
+pub mod watchdog;
+pub mod framebuffer;
+pub mod dma;
+pub mod sd;
+pub mod gpio;
+pub mod i2c;
+pub mod spi;
@@ -0,0 +1,293 @@
+//! Host-side links for the network device. Everything runs in user space and offline: frames go
+//! to another emulator over a Unix socket, to a built-in echo stack, or nowhere, and can be
+//! recorded to a pcap file on the way.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::devices::chardev::remove_stale_socket;
+
+/// Where guest frames go.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Link {
+    /// Frames are dropped and nothing is received.
+    None,
+    /// The built-in stack answers ARP, ICMP echo and UDP echo for `USER_IP`.
+    User,
+    /// Listens on a Unix socket for a second emulator.
+    Listen(String),
+    /// Connects to a Unix socket another emulator listens on.
+    Connect(String),
+}
+
+impl Link {
+    /// Parses `none`, `user`, `listen:PATH` or `connect:PATH`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        match spec.split_once(':') {
+            Some(("listen", path)) => Some(Link::Listen(path.to_string())),
+            Some(("connect", path)) => Some(Link::Connect(path.to_string())),
+            None if spec == "none" => Some(Link::None),
+            None if spec == "user" => Some(Link::User),
+            _ => None,
+        }
+    }
+}
+
+/// A source and sink of Ethernet frames.
+pub trait NetBackend: Send {
+    fn send(&mut self, frame: &[u8]);
+    /// Takes the next received frame, if any.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Opens `link`, recording every frame to `pcap` if given.
+pub fn open(link: &Link, pcap: Option<&str>) -> io::Result<Box<dyn NetBackend>> {
+    let backend: Box<dyn NetBackend> = match link {
+        Link::None => Box::new(Null),
+        Link::User => Box::new(UserStack::default()),
+        Link::Listen(path) => Box::new(Socket::listen(path)?),
+        Link::Connect(path) => Box::new(Socket::connect(path)?),
+    };
+    match pcap {
+        Some(path) => Ok(Box::new(Pcap::create(path, backend)?)),
+        None => Ok(backend),
+    }
+}
+
+struct Null;
+
+impl NetBackend for Null {
+    fn send(&mut self, _frame: &[u8]) {}
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Records frames in both directions in the classic libpcap format.
+/// https://wiki.wireshark.org/Development/LibpcapFileFormat
+struct Pcap {
+    file: File,
+    inner: Box<dyn NetBackend>,
+}
+
+impl Pcap {
+    fn create(path: &str, inner: Box<dyn NetBackend>) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes()); // magic
+        header.extend_from_slice(&2u16.to_le_bytes()); // version_major
+        header.extend_from_slice(&4u16.to_le_bytes()); // version_minor
+        header.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+        header.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+        header.extend_from_slice(&65535u32.to_le_bytes()); // snaplen
+        header.extend_from_slice(&1u32.to_le_bytes()); // network: Ethernet
+        file.write_all(&header)?;
+        Ok(Self { file, inner })
+    }
+
+    fn record(&mut self, frame: &[u8]) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + frame.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes()); // incl_len
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes()); // orig_len
+        record.extend_from_slice(frame);
+        if let Err(e) = self.file.write_all(&record) {
+            eprintln!("net: cannot write pcap record: {}", e);
+        }
+    }
+}
+
+impl NetBackend for Pcap {
+    fn send(&mut self, frame: &[u8]) {
+        self.record(frame);
+        self.inner.send(frame);
+    }
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let frame = self.inner.recv()?;
+        self.record(&frame);
+        Some(frame)
+    }
+}
+
+/// A stream to another emulator. Each frame is sent as a big-endian u32 length followed by the
+/// frame, like QEMU's stream sockets.
+struct Socket {
+    received: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    output: Sender<Vec<u8>>,
+}
+
+impl Socket {
+    fn listen(path: &str) -> io::Result<Self> {
+        remove_stale_socket(path);
+        let listener = UnixListener::bind(path)?;
+        Ok(Self::spawn(move || listener.accept().map(|(stream, _)| stream)))
+    }
+
+    fn connect(path: &str) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Self::spawn(move || Ok(stream)))
+    }
+
+    /// Runs the stream on helper threads once `connect` returns it. Frames sent before that are
+    /// held back.
+    fn spawn(connect: impl FnOnce() -> io::Result<UnixStream> + Send + 'static) -> Self {
+        let received = Arc::new(Mutex::new(VecDeque::new()));
+        let (output, rx) = mpsc::channel::<Vec<u8>>();
+        let queue = received.clone();
+        thread::spawn(move || {
+            let Ok(mut stream) = connect() else {
+                return;
+            };
+            if let Ok(mut reader) = stream.try_clone() {
+                thread::spawn(move || loop {
+                    let mut len = [0; 4];
+                    if reader.read_exact(&mut len).is_err() {
+                        break;
+                    }
+                    let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+                    if reader.read_exact(&mut frame).is_err() {
+                        break;
+                    }
+                    queue.lock().unwrap().push_back(frame);
+                });
+            }
+            for frame in rx {
+                let len = (frame.len() as u32).to_be_bytes();
+                if stream.write_all(&len).and_then(|_| stream.write_all(&frame)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { received, output }
+    }
+}
+
+impl NetBackend for Socket {
+    fn send(&mut self, frame: &[u8]) {
+        // the writer thread is gone once the peer hung up, the frame is dropped then
+        let _ = self.output.send(frame.to_vec());
+    }
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.received.lock().unwrap().pop_front()
+    }
+}
+
+/// Address the built-in stack answers on, QEMU's user network host address.
+pub const USER_IP: [u8; 4] = [10, 0, 2, 2];
+pub const USER_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const IP_PROTO_ICMP: u8 = 1;
+const IP_PROTO_UDP: u8 = 17;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// A minimal stack for testing: it answers ARP requests for `USER_IP`, and echoes ICMP echo
+/// requests and UDP datagrams sent to it.
+#[derive(Default)]
+struct UserStack {
+    replies: VecDeque<Vec<u8>>,
+}
+
+impl UserStack {
+    fn arp(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        // Ethernet/IPv4 request: htype 1, ptype 0x0800, hlen 6, plen 4, oper 1
+        let arp = frame.get(14..42)?;
+        if arp[0..8] != [0, 1, 8, 0, 6, 4, 0, 1] || arp[24..28] != USER_IP {
+            return None;
+        }
+        let mut reply = ethernet(&arp[8..14], ETHERTYPE_ARP);
+        reply.extend_from_slice(&[0, 1, 8, 0, 6, 4, 0, 2]);
+        reply.extend_from_slice(&USER_MAC);
+        reply.extend_from_slice(&USER_IP);
+        reply.extend_from_slice(&arp[8..18]); // sender hardware and protocol address
+        Some(reply)
+    }
+
+    fn ipv4(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        let ip = frame.get(14..)?;
+        let header_len = ((*ip.first()? & 0xf) * 4) as usize;
+        let total_len = u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]) as usize;
+        if ip[0] >> 4 != 4 || header_len < 20 || total_len < header_len || ip.len() < total_len || ip[16..20] != USER_IP {
+            return None;
+        }
+        let payload = &ip[header_len..total_len];
+        let reply_payload = match ip[9] {
+            IP_PROTO_ICMP if payload.len() >= 8 && payload[0] == ICMP_ECHO_REQUEST => {
+                let mut icmp = payload.to_vec();
+                icmp[0] = ICMP_ECHO_REPLY;
+                icmp[2..4].fill(0);
+                let sum = checksum(&icmp);
+                icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+                icmp
+            }
+            IP_PROTO_UDP if payload.len() >= 8 => {
+                let mut udp = payload.to_vec();
+                udp[0..2].copy_from_slice(&payload[2..4]);
+                udp[2..4].copy_from_slice(&payload[0..2]);
+                // a zero checksum means none was computed
+                udp[6..8].fill(0);
+                udp
+            }
+            _ => return None,
+        };
+
+        let mut reply = ethernet(&frame[6..12], ETHERTYPE_IPV4);
+        let mut header = [0; 20];
+        header[0] = 0x45;
+        header[2..4].copy_from_slice(&((20 + reply_payload.len()) as u16).to_be_bytes());
+        header[8] = 64; // TTL
+        header[9] = ip[9];
+        header[12..16].copy_from_slice(&USER_IP);
+        header[16..20].copy_from_slice(&ip[12..16]);
+        let sum = checksum(&header);
+        header[10..12].copy_from_slice(&sum.to_be_bytes());
+        reply.extend_from_slice(&header);
+        reply.extend_from_slice(&reply_payload);
+        Some(reply)
+    }
+}
+
+impl NetBackend for UserStack {
+    fn send(&mut self, frame: &[u8]) {
+        if frame.len() < 14 {
+            return;
+        }
+        let reply = match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP => self.arp(frame),
+            ETHERTYPE_IPV4 => self.ipv4(frame),
+            _ => None,
+        };
+        self.replies.extend(reply);
+    }
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.replies.pop_front()
+    }
+}
+
+/// Starts a frame from `USER_MAC` to `dst`.
+fn ethernet(dst: &[u8], ethertype: u16) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(64);
+    frame.extend_from_slice(dst);
+    frame.extend_from_slice(&USER_MAC);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame
+}
+
+/// The Internet checksum (RFC 1071).
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2).map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32).sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
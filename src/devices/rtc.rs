@@ -0,0 +1,139 @@
+//! The rtc module implements the Goldfish real-time clock: a nanosecond counter since the Unix
+//! epoch with one alarm.
+//! https://android.googlesource.com/platform/external/qemu/+/master/docs/GOLDFISH-VIRTUAL-HARDWARE.TXT
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::TimeMode;
+use crate::coprocessor::CYCLES_PER_SECOND;
+use crate::cpu::Size;
+use crate::exception::Exception;
+
+use super::device::Device;
+use super::irq::Irq;
+
+/// The interrupt level of the RTC.
+pub const RTC_IRQ: u8 = 6;
+
+/// Low word of the time. Reading it latches the high word.
+const TIME_LOW: u32 = 0x00;
+const TIME_HIGH: u32 = 0x04;
+/// Writing the low word arms the alarm with the previously written high word.
+const ALARM_LOW: u32 = 0x08;
+const ALARM_HIGH: u32 = 0x0c;
+const IRQ_ENABLED: u32 = 0x10;
+const CLEAR_ALARM: u32 = 0x14;
+/// Reads 1 while the alarm is armed.
+const ALARM_STATUS: u32 = 0x18;
+const CLEAR_INTERRUPT: u32 = 0x1c;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+pub struct Rtc {
+    time_mode: TimeMode,
+    /// Virtual time since the machine started, in deterministic mode.
+    cycles: u64,
+    /// Guest time minus the underlying clock, changed by setting the time.
+    offset: i64,
+    time_high: u32,
+    alarm_high: u32,
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    irq_pending: bool,
+    irq: Irq,
+}
+
+impl Rtc {
+    /// Starts at host time, or at `epoch` seconds if given. Deterministic machines always need
+    /// an epoch since their clock does not follow the host.
+    pub fn new(time_mode: TimeMode, epoch: Option<u64>, irq: Irq) -> Self {
+        let mut rtc =
+            Self { time_mode, cycles: 0, offset: 0, time_high: 0, alarm_high: 0, alarm: None, irq_enabled: false, irq_pending: false, irq };
+        if let Some(epoch) = epoch {
+            rtc.set_time(epoch * NANOS_PER_SECOND);
+        }
+        rtc
+    }
+
+    /// The underlying clock: host time, or virtual time counted from zero.
+    fn clock(&self) -> u64 {
+        match self.time_mode {
+            TimeMode::RealTime => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64,
+            TimeMode::Deterministic => (self.cycles as u128 * NANOS_PER_SECOND as u128 / CYCLES_PER_SECOND as u128) as u64,
+        }
+    }
+
+    /// Nanoseconds since the Unix epoch as the guest sees them.
+    pub fn now(&self) -> u64 {
+        self.clock().wrapping_add(self.offset as u64)
+    }
+
+    fn set_time(&mut self, time: u64) {
+        self.offset = time.wrapping_sub(self.clock()) as i64;
+    }
+
+    /// Fires the alarm once its time has come.
+    fn check_alarm(&mut self) {
+        if self.alarm.is_some_and(|alarm| self.now() >= alarm) {
+            self.alarm = None;
+            self.irq_pending = true;
+        }
+        if self.irq_pending && self.irq_enabled {
+            self.irq.raise();
+        } else {
+            self.irq.lower();
+        }
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        self.check_alarm();
+    }
+
+    /// Cycles until the alarm goes off, if armed.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        let nanos = self.alarm?.saturating_sub(self.now());
+        Some((nanos as u128 * CYCLES_PER_SECOND as u128).div_ceil(NANOS_PER_SECOND as u128).max(1) as u64)
+    }
+}
+
+impl Device for Rtc {
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        if size != Size::Word {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        match addr {
+            TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                Ok(now as u32)
+            }
+            TIME_HIGH => Ok(self.time_high),
+            ALARM_LOW => Ok(self.alarm.unwrap_or(0) as u32),
+            ALARM_HIGH => Ok((self.alarm.unwrap_or(0) >> 32) as u32),
+            IRQ_ENABLED => Ok(self.irq_enabled as u32),
+            ALARM_STATUS => Ok(self.alarm.is_some() as u32),
+            CLEAR_ALARM | CLEAR_INTERRUPT => Ok(0),
+            _ => Err(Exception::LoadIllegalAddress),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
+        if size != Size::Word {
+            return Err(Exception::StoreIllegalAddress);
+        }
+        match addr {
+            TIME_LOW => self.set_time((self.time_high as u64) << 32 | data as u64),
+            TIME_HIGH => self.time_high = data,
+            ALARM_LOW => self.alarm = Some((self.alarm_high as u64) << 32 | data as u64),
+            ALARM_HIGH => self.alarm_high = data,
+            IRQ_ENABLED => self.irq_enabled = data & 1 != 0,
+            CLEAR_ALARM => self.alarm = None,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            ALARM_STATUS => {}
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+        self.check_alarm();
+        Ok(())
+    }
+}
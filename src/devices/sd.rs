@@ -0,0 +1,464 @@
+//! The sd module implements an SD host controller with one high-capacity SD card slot. The guest
+//! sends SD commands through the controller and moves block data by PIO through the DATA
+//! register, or lets the controller copy it to and from DRAM.
+//! https://www.sdcard.org/downloads/pls/ (Physical Layer Simplified Specification)
+
+use crate::cpu::Size;
+use crate::exception::Exception;
+
+use super::device::Device;
+use super::disk::{Disk, SECTOR_SIZE};
+use super::irq::Irq;
+
+/// The interrupt level of the SD host, shared with the virtio transports.
+pub const SD_IRQ: u8 = 3;
+/// Cycles the card takes to read or program a block, about 5 MB/s.
+pub const BLOCK_CYCLES: u64 = 100;
+
+/// Argument of the next command.
+const ARG: u32 = 0x00;
+/// Writing a command index sends the command.
+const CMD: u32 = 0x04;
+/// Response words, RESP0 holds bits 31:0. Long responses fill all four.
+const RESP0: u32 = 0x08;
+const RESP3: u32 = 0x14;
+/// `STATUS_` bits, writing 1 clears a bit.
+const STATUS: u32 = 0x18;
+/// `STATUS_` bits that interrupt.
+const IRQ_ENABLE: u32 = 0x1c;
+/// PIO data port, one word of the block buffer per access.
+const DATA: u32 = 0x20;
+/// Physical address the next block goes to or comes from with `CONTROL_DMA`.
+const DMA_ADDR: u32 = 0x24;
+/// Blocks of a CMD18 or CMD25 transfer, which stops by itself after them.
+const BLOCK_COUNT: u32 = 0x28;
+const CONTROL: u32 = 0x2c;
+/// `PRESENT_` bits, read-only.
+const PRESENT: u32 = 0x30;
+
+pub const STATUS_CMD_DONE: u32 = 1;
+pub const STATUS_DATA_DONE: u32 = 1 << 1;
+/// A block waits in the buffer for PIO reads.
+pub const STATUS_BUF_READ_READY: u32 = 1 << 2;
+/// The buffer waits for a block of PIO writes.
+pub const STATUS_BUF_WRITE_READY: u32 = 1 << 3;
+/// The card did not answer: no card, an illegal command in this state, or a bad argument.
+pub const STATUS_CMD_ERROR: u32 = 1 << 4;
+/// A block could not be read or written.
+pub const STATUS_DATA_ERROR: u32 = 1 << 5;
+
+/// Data moves between the card and DRAM at `DMA_ADDR` instead of through DATA.
+pub const CONTROL_DMA: u32 = 1;
+
+pub const PRESENT_CARD: u32 = 1;
+pub const PRESENT_WRITE_PROTECT: u32 = 1 << 1;
+/// A data transfer is under way.
+pub const PRESENT_DATA_BUSY: u32 = 1 << 2;
+
+/// Card status bits of R1 responses.
+pub const R1_OUT_OF_RANGE: u32 = 1 << 31;
+pub const R1_BLOCK_LEN_ERROR: u32 = 1 << 29;
+pub const R1_WP_VIOLATION: u32 = 1 << 26;
+pub const R1_READY_FOR_DATA: u32 = 1 << 8;
+pub const R1_APP_CMD: u32 = 1 << 5;
+
+/// OCR bits: powered up, high capacity, 2.7-3.6 V.
+pub const OCR_READY: u32 = 1 << 31;
+pub const OCR_CCS: u32 = 1 << 30;
+const OCR_VOLTAGES: u32 = 0x00ff8000;
+
+/// The relative card address CMD3 hands out.
+pub const RCA: u32 = 0x0001;
+const BLOCK_SIZE: usize = SECTOR_SIZE as usize;
+
+/// Card states, numbered as in CURRENT_STATE of the card status.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle = 0,
+    Ready = 1,
+    Ident = 2,
+    Stby = 3,
+    Tran = 4,
+    Data = 5,
+    Rcv = 6,
+}
+
+/// A multi-block read or write in progress.
+struct Transfer {
+    write: bool,
+    block: u64,
+    /// Blocks left, including the one in the buffer.
+    blocks: u32,
+    /// Cycles until the card is done with the current block.
+    busy: u64,
+}
+
+pub struct SdHost {
+    card: Option<Disk>,
+    state: State,
+    /// The previous command was CMD55, this one is an application command.
+    app: bool,
+    arg: u32,
+    resp: [u32; 4],
+    status: u32,
+    irq_enable: u32,
+    dma_addr: u32,
+    block_count: u32,
+    control: u32,
+    transfer: Option<Transfer>,
+    buffer: Vec<u8>,
+    /// Next byte of `buffer` the DATA port reads or writes.
+    pos: usize,
+    irq: Irq,
+}
+
+impl SdHost {
+    /// A controller with `card` inserted, or an empty slot.
+    pub fn new(card: Option<Disk>, irq: Irq) -> Self {
+        Self {
+            card,
+            state: State::Idle,
+            app: false,
+            arg: 0,
+            resp: [0; 4],
+            status: 0,
+            irq_enable: 0,
+            dma_addr: 0,
+            block_count: 0,
+            control: 0,
+            transfer: None,
+            buffer: Vec::new(),
+            pos: 0,
+            irq,
+        }
+    }
+
+    fn update_irq(&self) {
+        if self.status & self.irq_enable != 0 {
+            self.irq.raise();
+        } else {
+            self.irq.lower();
+        }
+    }
+
+    fn sectors(&self) -> u64 {
+        self.card.as_ref().map_or(0, Disk::sectors)
+    }
+
+    fn read_only(&self) -> bool {
+        self.card.as_ref().is_some_and(Disk::read_only)
+    }
+
+    /// Card status as R1 reports it.
+    fn card_status(&self) -> u32 {
+        let ready = if self.transfer.is_none() { R1_READY_FOR_DATA } else { 0 };
+        (self.state as u32) << 9 | ready | if self.app { R1_APP_CMD } else { 0 }
+    }
+
+    /// CID register: manufacturer, product name "MIPSD", revision 1.0, serial 1, made 2024-01.
+    fn cid() -> u128 {
+        let mut cid = 0x5au128 << 120 | (u16::from_be_bytes(*b"EM") as u128) << 104;
+        for (i, byte) in b"MIPSD".iter().enumerate() {
+            cid |= (*byte as u128) << (96 - 8 * i);
+        }
+        cid | 0x10 << 56 | 1 << 24 | (24 << 4 | 1) << 8
+    }
+
+    /// CSD register, version 2.0. C_SIZE counts 512 KiB units, an image smaller than that
+    /// still reports one.
+    fn csd(&self) -> u128 {
+        let c_size = (self.sectors() / 1024).max(1) - 1;
+        let mut csd = 1u128 << 126 | 0x0e << 112 | 0x32 << 96 | 0x5b5 << 84 | 9 << 80;
+        csd |= (c_size as u128 & 0x3fffff) << 48 | 0x7f << 39 | 9 << 22;
+        if self.read_only() {
+            // TMP_WRITE_PROTECT
+            csd |= 1 << 12;
+        }
+        csd
+    }
+
+    /// Fills the response words from a 128-bit register, with its CRC7 and end bit in bits 7:0.
+    fn long_response(&mut self, register: u128) {
+        let bytes = register.to_be_bytes();
+        let register = register | (crc7(&bytes[..15]) << 1 | 1) as u128;
+        for (i, word) in self.resp.iter_mut().enumerate() {
+            *word = (register >> (32 * i)) as u32;
+        }
+    }
+
+    fn short_response(&mut self, response: u32) {
+        self.resp = [response, 0, 0, 0];
+    }
+
+    /// Sends a command to the card. Returns false if the card gives no response.
+    fn command(&mut self, index: u32) -> bool {
+        let app = std::mem::take(&mut self.app);
+        if self.card.is_none() {
+            return false;
+        }
+        let arg = self.arg;
+        let rca = arg >> 16;
+        match (app, index, self.state) {
+            // GO_IDLE_STATE
+            (_, 0, _) => {
+                self.transfer = None;
+                self.state = State::Idle;
+                self.short_response(0);
+            }
+            // ALL_SEND_CID
+            (_, 2, State::Ready) => {
+                self.long_response(Self::cid());
+                self.state = State::Ident;
+            }
+            // SEND_RELATIVE_ADDR, R6
+            (_, 3, State::Ident | State::Stby) => {
+                self.state = State::Stby;
+                self.short_response(RCA << 16 | (self.state as u32) << 9 | R1_READY_FOR_DATA);
+            }
+            // SET_BUS_WIDTH, any width goes
+            (true, 6, State::Tran) => self.short_response(self.card_status() | R1_APP_CMD),
+            // SELECT/DESELECT_CARD
+            (_, 7, State::Stby | State::Tran) => {
+                self.short_response(self.card_status());
+                self.state = if rca == RCA { State::Tran } else { State::Stby };
+            }
+            // SEND_IF_COND, R7 echoes the voltage and check pattern
+            (_, 8, State::Idle) if arg >> 8 & 0xf == 1 => self.short_response(arg & 0xfff),
+            // SEND_CSD, SEND_CID
+            (_, 9, State::Stby) if rca == RCA => self.long_response(self.csd()),
+            (_, 10, State::Stby) if rca == RCA => self.long_response(Self::cid()),
+            // STOP_TRANSMISSION
+            (_, 12, State::Data | State::Rcv) => {
+                self.short_response(self.card_status());
+                self.finish(0);
+            }
+            // SEND_STATUS
+            (_, 13, State::Stby | State::Tran | State::Data | State::Rcv) if rca == RCA => {
+                self.short_response(self.card_status())
+            }
+            // SET_BLOCKLEN, high-capacity cards only take 512
+            (_, 16, State::Tran) => {
+                let error = if arg as usize == BLOCK_SIZE { 0 } else { R1_BLOCK_LEN_ERROR };
+                self.short_response(self.card_status() | error);
+            }
+            // READ_SINGLE_BLOCK, READ_MULTIPLE_BLOCK, WRITE_BLOCK, WRITE_MULTIPLE_BLOCK
+            (false, 17 | 18 | 24 | 25, State::Tran) => {
+                let write = index >= 24;
+                let blocks = if index == 18 || index == 25 { self.block_count.max(1) } else { 1 };
+                let mut response = self.card_status();
+                if arg as u64 + blocks as u64 > self.sectors() {
+                    response |= R1_OUT_OF_RANGE;
+                } else if write && self.read_only() {
+                    response |= R1_WP_VIOLATION;
+                } else {
+                    self.start(write, arg as u64, blocks);
+                }
+                self.short_response(response);
+            }
+            // SD_SEND_OP_COND, R3. An argument without voltages only asks for the OCR.
+            (true, 41, State::Idle) => {
+                if arg & OCR_VOLTAGES != 0 {
+                    self.state = State::Ready;
+                }
+                self.short_response(OCR_READY | OCR_CCS | OCR_VOLTAGES);
+            }
+            // APP_CMD
+            (false, 55, _) => {
+                self.app = true;
+                self.short_response(self.card_status());
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn start(&mut self, write: bool, block: u64, blocks: u32) {
+        self.state = if write { State::Rcv } else { State::Data };
+        self.buffer.clear();
+        self.pos = 0;
+        let pio_write = write && self.control & CONTROL_DMA == 0;
+        // PIO writes need the block from the guest before the card gets busy
+        let busy = if pio_write { 0 } else { BLOCK_CYCLES };
+        self.transfer = Some(Transfer { write, block, blocks, busy });
+        if pio_write {
+            self.buffer.resize(BLOCK_SIZE, 0);
+            self.status |= STATUS_BUF_WRITE_READY;
+        }
+    }
+
+    /// Ends the transfer and returns to the transfer state with `STATUS_DATA_DONE` and `error`.
+    fn finish(&mut self, error: u32) {
+        self.transfer = None;
+        self.state = State::Tran;
+        self.buffer.clear();
+        self.status &= !(STATUS_BUF_READ_READY | STATUS_BUF_WRITE_READY);
+        self.status |= STATUS_DATA_DONE | error;
+    }
+
+    /// Moves on to the next block, or finishes after the last one.
+    fn next_block(&mut self) {
+        let Some(transfer) = self.transfer.as_mut() else {
+            return;
+        };
+        transfer.block += 1;
+        transfer.blocks -= 1;
+        if transfer.blocks == 0 {
+            self.finish(0);
+            return;
+        }
+        let (write, block, blocks) = (transfer.write, transfer.block, transfer.blocks);
+        self.start(write, block, blocks);
+    }
+
+    /// Lets the card work on the current block and copies DMA blocks to or from `mem`.
+    pub fn advance(&mut self, cycles: u64, mem: &mut dyn Device) {
+        let mut left = cycles;
+        while let Some(transfer) = self.transfer.as_mut() {
+            if transfer.busy == 0 || self.status & (STATUS_BUF_READ_READY | STATUS_BUF_WRITE_READY) != 0 {
+                break;
+            }
+            let step = transfer.busy.min(left);
+            transfer.busy -= step;
+            left -= step;
+            if transfer.busy > 0 {
+                break;
+            }
+            if let Err(error) = self.block_done(mem) {
+                self.finish(error);
+            }
+        }
+        self.update_irq();
+    }
+
+    /// The card finished reading or programming the current block.
+    fn block_done(&mut self, mem: &mut dyn Device) -> Result<(), u32> {
+        let Some(transfer) = self.transfer.as_ref() else {
+            return Ok(());
+        };
+        let (write, offset) = (transfer.write, transfer.block * SECTOR_SIZE);
+        let dma = self.control & CONTROL_DMA != 0;
+        let card = self.card.as_mut().ok_or(STATUS_DATA_ERROR)?;
+        if write {
+            if dma {
+                self.buffer = (0..BLOCK_SIZE as u32)
+                    .map(|i| mem.read(self.dma_addr.wrapping_add(i), Size::Byte).map(|byte| byte as u8))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| STATUS_DATA_ERROR)?;
+                self.dma_addr = self.dma_addr.wrapping_add(BLOCK_SIZE as u32);
+            }
+            card.write(offset, &self.buffer).map_err(|_| STATUS_DATA_ERROR)?;
+            self.next_block();
+            return Ok(());
+        }
+        self.buffer = vec![0; BLOCK_SIZE];
+        card.read(offset, &mut self.buffer).map_err(|_| STATUS_DATA_ERROR)?;
+        if dma {
+            for (i, byte) in self.buffer.iter().enumerate() {
+                mem.write(self.dma_addr.wrapping_add(i as u32), *byte as u32, Size::Byte).map_err(|_| STATUS_DATA_ERROR)?;
+            }
+            self.dma_addr = self.dma_addr.wrapping_add(BLOCK_SIZE as u32);
+            self.next_block();
+        } else {
+            self.pos = 0;
+            self.status |= STATUS_BUF_READ_READY;
+        }
+        Ok(())
+    }
+
+    /// Cycles until the card is done with the current block, if it is working on one.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        self.transfer.as_ref().filter(|transfer| transfer.busy > 0).map(|transfer| transfer.busy)
+    }
+
+    fn read_data(&mut self) -> u32 {
+        if self.status & STATUS_BUF_READ_READY == 0 {
+            return 0;
+        }
+        let word = u32::from_le_bytes(self.buffer[self.pos..self.pos + 4].try_into().unwrap());
+        self.pos += 4;
+        if self.pos == BLOCK_SIZE {
+            self.status &= !STATUS_BUF_READ_READY;
+            self.next_block();
+        }
+        word
+    }
+
+    fn write_data(&mut self, data: u32) {
+        if self.status & STATUS_BUF_WRITE_READY == 0 {
+            return;
+        }
+        self.buffer[self.pos..self.pos + 4].copy_from_slice(&data.to_le_bytes());
+        self.pos += 4;
+        if self.pos == BLOCK_SIZE {
+            self.status &= !STATUS_BUF_WRITE_READY;
+            if let Some(transfer) = self.transfer.as_mut() {
+                transfer.busy = BLOCK_CYCLES;
+            }
+        }
+    }
+}
+
+impl Device for SdHost {
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        if size != Size::Word {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        let value = match addr {
+            ARG => self.arg,
+            CMD => 0,
+            RESP0..=RESP3 if addr.is_multiple_of(4) => self.resp[((addr - RESP0) / 4) as usize],
+            STATUS => self.status,
+            IRQ_ENABLE => self.irq_enable,
+            DATA => self.read_data(),
+            DMA_ADDR => self.dma_addr,
+            BLOCK_COUNT => self.block_count,
+            CONTROL => self.control,
+            PRESENT => {
+                let card = if self.card.is_some() { PRESENT_CARD } else { 0 };
+                let wp = if self.read_only() { PRESENT_WRITE_PROTECT } else { 0 };
+                let busy = if self.transfer.is_some() { PRESENT_DATA_BUSY } else { 0 };
+                card | wp | busy
+            }
+            _ => return Err(Exception::LoadIllegalAddress),
+        };
+        self.update_irq();
+        Ok(value)
+    }
+
+    fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
+        if size != Size::Word {
+            return Err(Exception::StoreIllegalAddress);
+        }
+        match addr {
+            ARG => self.arg = data,
+            CMD => {
+                self.status |= if self.command(data & 0x3f) { STATUS_CMD_DONE } else { STATUS_CMD_ERROR };
+            }
+            STATUS => self.status &= !(data & !(STATUS_BUF_READ_READY | STATUS_BUF_WRITE_READY)),
+            IRQ_ENABLE => self.irq_enable = data,
+            DATA => self.write_data(data),
+            DMA_ADDR => self.dma_addr = data,
+            BLOCK_COUNT => self.block_count = data,
+            CONTROL => self.control = data & CONTROL_DMA,
+            RESP0..=RESP3 | PRESENT => {}
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+        self.update_irq();
+        Ok(())
+    }
+}
+
+/// CRC7 of SD commands and registers, polynomial x^7 + x^3 + 1.
+pub fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        for bit in (0..8).rev() {
+            let feedback = (byte >> bit & 1) ^ (crc >> 6 & 1);
+            crc = crc << 1 & 0x7f;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
@@ -0,0 +1,232 @@
+//! Host sides of a UART. A backend hands the UART one byte at a time in each direction; all host
+//! I/O happens on helper threads so the emulator never blocks.
+
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::io::AsRawFd;
+use std::process::{Command, Stdio as Inherit};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use super::chardev::{receive, Backend, CharDev};
+
+/// Where a UART is connected on the host.
+#[derive(Debug, Clone)]
+pub enum SerialLink {
+    /// The emulator's terminal, switched to raw mode while the UART exists.
+    Stdio,
+    /// A new pseudo-terminal, its name is printed at startup.
+    Pty,
+    /// A buffer the caller keeps a handle to, for tests.
+    #[cfg(test)]
+    Memory(Memory),
+    /// A file, pipe or socket, like the console ports.
+    Char(Backend),
+}
+
+impl SerialLink {
+    /// Parses `stdio`, `pty`, or any console port backend.
+    pub fn parse(spec: &str) -> Option<Self> {
+        match spec {
+            "stdio" => Some(SerialLink::Stdio),
+            "pty" => Some(SerialLink::Pty),
+            _ => Backend::parse(spec).map(SerialLink::Char),
+        }
+    }
+}
+
+/// The host side of a UART.
+pub trait SerialBackend: Send {
+    /// Returns true if a received byte is waiting.
+    fn pending(&self) -> bool;
+    /// Takes the next received byte, if any.
+    fn read(&mut self) -> Option<u8>;
+    fn write(&mut self, byte: u8);
+}
+
+pub fn open(link: &SerialLink) -> io::Result<Box<dyn SerialBackend>> {
+    Ok(match link {
+        SerialLink::Stdio => Box::new(Stdio::open()),
+        SerialLink::Pty => Box::new(Pty::open()?),
+        #[cfg(test)]
+        SerialLink::Memory(memory) => Box::new(memory.clone()),
+        SerialLink::Char(backend) => Box::new(CharDev::open(backend)?),
+    })
+}
+
+impl SerialBackend for CharDev {
+    fn pending(&self) -> bool {
+        CharDev::pending(self)
+    }
+    fn read(&mut self) -> Option<u8> {
+        CharDev::read(self, 1).first().copied()
+    }
+    fn write(&mut self, byte: u8) {
+        CharDev::write(self, &[byte]);
+    }
+}
+
+/// Bytes the guest sends are collected, bytes fed in are received by the guest.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    input: Arc<Mutex<VecDeque<u8>>>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl Memory {
+    /// Queues `data` for the guest to receive.
+    pub fn feed(&self, data: &[u8]) {
+        self.input.lock().unwrap().extend(data);
+    }
+    /// Takes everything the guest sent so far.
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.output.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+impl SerialBackend for Memory {
+    fn pending(&self) -> bool {
+        !self.input.lock().unwrap().is_empty()
+    }
+    fn read(&mut self) -> Option<u8> {
+        self.input.lock().unwrap().pop_front()
+    }
+    fn write(&mut self, byte: u8) {
+        self.output.lock().unwrap().push(byte);
+    }
+}
+
+/// Bytes read from the emulator's stdin, shared by every stdio UART since there is one stdin.
+fn stdin_queue() -> Arc<Mutex<VecDeque<u8>>> {
+    static QUEUE: OnceLock<Arc<Mutex<VecDeque<u8>>>> = OnceLock::new();
+    QUEUE
+        .get_or_init(|| {
+            let queue = Arc::new(Mutex::new(VecDeque::new()));
+            let input = queue.clone();
+            thread::spawn(move || receive(io::stdin(), input));
+            queue
+        })
+        .clone()
+}
+
+/// Terminal settings saved by `stty -g` before switching to raw mode, and how many stdio UARTs
+/// still use it. The last one to go restores the settings.
+static RAW_MODE: Mutex<(usize, Option<String>)> = Mutex::new((0, None));
+
+/// The emulator's own terminal. Keys reach the guest one by one without echo, but Ctrl-C still
+/// stops the emulator and its own messages keep their line endings.
+struct Stdio {
+    input: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl Stdio {
+    fn open() -> Self {
+        let mut raw = RAW_MODE.lock().unwrap();
+        if raw.0 == 0 && io::stdin().is_terminal() {
+            raw.1 = stty(&["-g"]).map(|saved| saved.trim().to_string());
+            stty(&["raw", "-echo", "isig", "opost"]);
+        }
+        raw.0 += 1;
+        Self { input: stdin_queue() }
+    }
+}
+
+impl Drop for Stdio {
+    fn drop(&mut self) {
+        let mut raw = RAW_MODE.lock().unwrap();
+        raw.0 -= 1;
+        if raw.0 == 0 {
+            if let Some(saved) = raw.1.take() {
+                stty(&[&saved]);
+            }
+        }
+    }
+}
+
+impl SerialBackend for Stdio {
+    fn pending(&self) -> bool {
+        !self.input.lock().unwrap().is_empty()
+    }
+    fn read(&mut self) -> Option<u8> {
+        self.input.lock().unwrap().pop_front()
+    }
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[byte]).and_then(|_| stdout.flush()).expect("failed to write stdout");
+    }
+}
+
+/// Runs stty on the emulator's terminal and returns its output.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty").args(args).stdin(Inherit::inherit()).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+extern "C" {
+    fn grantpt(fd: c_int) -> c_int;
+    fn unlockpt(fd: c_int) -> c_int;
+    fn ptsname_r(fd: c_int, buf: *mut c_char, buflen: usize) -> c_int;
+}
+
+/// A pseudo-terminal: the guest is on the master side, a terminal program such as screen opens
+/// the printed slave device.
+struct Pty {
+    input: Arc<Mutex<VecDeque<u8>>>,
+    output: Sender<u8>,
+    /// Holding the slave open keeps reads of the master from failing while no terminal program
+    /// is attached.
+    _slave: File,
+}
+
+impl Pty {
+    fn open() -> io::Result<Self> {
+        let mut master = OpenOptions::new().read(true).write(true).open("/dev/ptmx")?;
+        let fd = master.as_raw_fd();
+        let mut name = [0 as c_char; 64];
+        // SAFETY: fd is an open ptmx master and name is large enough for a pts path
+        let name = unsafe {
+            if grantpt(fd) != 0 || unlockpt(fd) != 0 || ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned()
+        };
+        let slave = OpenOptions::new().read(true).write(true).open(&name)?;
+        // the line discipline must not echo guest output back to the guest
+        Command::new("stty").args(["raw", "-echo"]).stdin(slave.try_clone()?).status()?;
+        eprintln!("uart: connected to {}", name);
+
+        let input = Arc::new(Mutex::new(VecDeque::new()));
+        let queue = input.clone();
+        let reader = master.try_clone()?;
+        thread::spawn(move || receive(reader, queue));
+        let (output, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in rx {
+                if master.write_all(&[byte]).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self { input, output, _slave: slave })
+    }
+}
+
+impl SerialBackend for Pty {
+    fn pending(&self) -> bool {
+        !self.input.lock().unwrap().is_empty()
+    }
+    fn read(&mut self) -> Option<u8> {
+        self.input.lock().unwrap().pop_front()
+    }
+    fn write(&mut self, byte: u8) {
+        // the writer thread is gone once the master failed, the output is dropped then
+        let _ = self.output.send(byte);
+    }
+}
@@ -0,0 +1,256 @@
+//! The spi module implements an SPI master with a few chip selects, and the slaves on its bus.
+//! Slaves are host-side models; test code attaches its own by implementing `SpiSlave`.
+
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::Size;
+use crate::exception::Exception;
+
+use super::device::Device;
+use super::irq::Irq;
+
+/// The interrupt level of the SPI master, shared with the UART.
+pub const SPI_IRQ: u8 = 2;
+/// Chip selects of the master.
+pub const CHIP_SELECTS: u32 = 4;
+/// The chip select of the SPI flash every machine has.
+pub const FLASH_CS: u32 = 0;
+
+/// `CONTROL_` bits.
+const CONTROL: u32 = 0x00;
+/// SCK runs at the 1 MHz virtual clock / (2 * (DIVIDER + 1)).
+const DIVIDER: u32 = 0x04;
+/// The selected slave. Values from `CHIP_SELECTS` up select none.
+const CS: u32 = 0x08;
+/// Writing sends a byte, reading returns the byte received meanwhile.
+const DATA: u32 = 0x0c;
+/// `STATUS_` bits.
+const STATUS: u32 = 0x10;
+
+pub const CONTROL_ENABLE: u32 = 1;
+pub const CONTROL_IRQ: u32 = 1 << 1;
+
+pub const STATUS_BUSY: u32 = 1;
+/// A byte was exchanged, writing 1 clears it.
+pub const STATUS_DONE: u32 = 1 << 1;
+
+/// A device on the SPI bus.
+pub trait SpiSlave: Send {
+    /// The chip select went active.
+    fn select(&mut self);
+    /// Exchanges a byte: takes the one the master sends and returns the one it receives.
+    fn transfer(&mut self, byte: u8) -> u8;
+    /// The chip select went inactive.
+    fn deselect(&mut self);
+}
+
+pub struct Spi {
+    control: u32,
+    divider: u32,
+    cs: u32,
+    rx: u8,
+    status: u32,
+    /// The byte being sent and the cycles left.
+    transfer: Option<(u8, u64)>,
+    slaves: Vec<Option<Box<dyn SpiSlave>>>,
+    irq: Irq,
+}
+
+impl Spi {
+    pub fn new(irq: Irq) -> Self {
+        let slaves = (0..CHIP_SELECTS).map(|_| None).collect();
+        Self { control: 0, divider: 0, cs: CHIP_SELECTS, rx: 0, status: 0, transfer: None, slaves, irq }
+    }
+
+    /// Connects `slave` to chip select `cs`, replacing the slave there.
+    pub fn attach(&mut self, cs: u32, slave: Box<dyn SpiSlave>) {
+        self.slaves[cs as usize] = Some(slave);
+    }
+
+    fn selected(&mut self) -> Option<&mut Box<dyn SpiSlave>> {
+        self.slaves.get_mut(self.cs as usize).and_then(Option::as_mut)
+    }
+
+    fn update_irq(&self) {
+        if self.status & STATUS_DONE != 0 && self.control & CONTROL_IRQ != 0 {
+            self.irq.raise();
+        } else {
+            self.irq.lower();
+        }
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        if let Some((byte, left)) = self.transfer {
+            if left <= cycles {
+                self.transfer = None;
+                // nothing drives MISO without a slave, it floats high
+                self.rx = self.selected().map_or(0xff, |slave| slave.transfer(byte));
+                self.status = self.status & !STATUS_BUSY | STATUS_DONE;
+            } else {
+                self.transfer = Some((byte, left - cycles));
+            }
+        }
+        self.update_irq();
+    }
+
+    /// Cycles until the byte in flight is exchanged, if any.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        self.transfer.map(|(_, left)| left.max(1))
+    }
+}
+
+impl Device for Spi {
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        if size != Size::Word {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        match addr {
+            CONTROL => Ok(self.control),
+            DIVIDER => Ok(self.divider),
+            CS => Ok(self.cs),
+            DATA => Ok(self.rx as u32),
+            STATUS => Ok(self.status),
+            _ => Err(Exception::LoadIllegalAddress),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
+        if size != Size::Word {
+            return Err(Exception::StoreIllegalAddress);
+        }
+        match addr {
+            CONTROL => self.control = data & (CONTROL_ENABLE | CONTROL_IRQ),
+            DIVIDER => self.divider = data & 0xffff,
+            CS if data != self.cs => {
+                if let Some(slave) = self.selected() {
+                    slave.deselect();
+                }
+                self.cs = data;
+                if let Some(slave) = self.selected() {
+                    slave.select();
+                }
+            }
+            CS => {}
+            DATA if self.control & CONTROL_ENABLE != 0 && self.transfer.is_none() => {
+                self.status |= STATUS_BUSY;
+                self.transfer = Some((data as u8, 16 * (self.divider as u64 + 1)));
+            }
+            DATA => {}
+            STATUS => self.status &= !(data & STATUS_DONE),
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+        self.update_irq();
+        Ok(())
+    }
+}
+
+struct FlashState {
+    data: Vec<u8>,
+    /// The first bytes of the current command, command byte first.
+    command: Vec<u8>,
+    /// Bytes exchanged since the chip select went active.
+    count: usize,
+    write_enabled: bool,
+}
+
+/// A 25-series SPI NOR flash of 1 MiB answering the common commands: read ID (0x9f), read
+/// (0x03), fast read (0x0b), read status (0x05), write enable/disable (0x06/0x04), page program
+/// (0x02), 4 KiB sector erase (0x20), 64 KiB block erase (0xd8) and chip erase (0xc7/0x60).
+/// Programs and erases finish at once. Clones share the contents.
+#[derive(Clone)]
+pub struct SpiFlash(Arc<Mutex<FlashState>>);
+
+/// JEDEC ID of a W25Q80.
+pub const FLASH_ID: [u8; 3] = [0xef, 0x40, 0x14];
+pub const FLASH_SIZE: usize = 1 << 20;
+
+impl Default for SpiFlash {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(FlashState { data: vec![0xff; FLASH_SIZE], command: Vec::new(), count: 0, write_enabled: false })))
+    }
+}
+
+impl SpiFlash {
+    #[cfg(test)]
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().data.clone()
+    }
+    /// Stores `data` at `offset`, as a programmer would.
+    pub fn load(&self, offset: usize, data: &[u8]) {
+        self.0.lock().unwrap().data[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
+impl FlashState {
+    /// The 24-bit address after the command byte.
+    fn address(&self) -> usize {
+        (self.command[1] as usize) << 16 | (self.command[2] as usize) << 8 | self.command[3] as usize
+    }
+
+    fn erase(&mut self, size: usize) {
+        if std::mem::take(&mut self.write_enabled) {
+            let start = (self.address() % FLASH_SIZE) & !(size - 1);
+            self.data[start..start + size].fill(0xff);
+        }
+    }
+}
+
+impl SpiSlave for SpiFlash {
+    fn select(&mut self) {
+        let mut flash = self.0.lock().unwrap();
+        flash.command.clear();
+        flash.count = 0;
+    }
+
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut flash = self.0.lock().unwrap();
+        if flash.command.len() < 6 {
+            flash.command.push(byte);
+        }
+        flash.count += 1;
+        let n = flash.count;
+        match flash.command[0] {
+            0x9f if (2..=4).contains(&n) => FLASH_ID[n - 2],
+            0x05 if n > 1 => (flash.write_enabled as u8) << 1,
+            0x06 => {
+                flash.write_enabled = true;
+                0xff
+            }
+            0x04 => {
+                flash.write_enabled = false;
+                0xff
+            }
+            // data follows the address, after a dummy byte for fast read
+            0x03 | 0x0b => {
+                let start = if flash.command[0] == 0x0b { 6 } else { 5 };
+                if n < start {
+                    return 0xff;
+                }
+                let addr = (flash.address() + n - start) % FLASH_SIZE;
+                flash.data[addr]
+            }
+            // programming only clears bits, the address wraps within the 256-byte page
+            0x02 if n > 4 && flash.write_enabled => {
+                let base = flash.address() % FLASH_SIZE;
+                let addr = base & !0xff | (base + n - 5) & 0xff;
+                flash.data[addr] &= byte;
+                0xff
+            }
+            _ => 0xff,
+        }
+    }
+
+    fn deselect(&mut self) {
+        let mut flash = self.0.lock().unwrap();
+        match (flash.command.first(), flash.count) {
+            (Some(0x02), n) if n > 4 => flash.write_enabled = false,
+            (Some(0x20), 4) => flash.erase(4 << 10),
+            (Some(0xd8), 4) => flash.erase(64 << 10),
+            (Some(0xc7 | 0x60), 1) if flash.write_enabled => {
+                flash.write_enabled = false;
+                flash.data.fill(0xff);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! The syscon module implements the power and reset controller, through which a guest stops the
+//! emulator with an exit status or reboots the machine.
+
+use crate::cpu::Size;
+use crate::exception::Exception;
+
+use super::device::Device;
+
+/// Exit status reported when the machine powers off or halts.
+const EXIT_STATUS: u32 = 0x0;
+/// Writing a command carries it out.
+const COMMAND: u32 = 0x4;
+
+pub const COMMAND_POWEROFF: u32 = 1;
+pub const COMMAND_REBOOT: u32 = 2;
+pub const COMMAND_HALT: u32 = 3;
+
+/// What the guest asked the machine to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Power {
+    /// Stop and exit with the status.
+    Off(u32),
+    /// Reset the CPU and start over at the reset vector.
+    Reboot,
+    /// Stop with a report of the CPU state and exit with the status.
+    Halt(u32),
+}
+
+#[derive(Default)]
+pub struct Syscon {
+    exit_status: u32,
+    request: Option<Power>,
+}
+
+impl Syscon {
+    /// Takes the request the guest made, if any.
+    pub fn take_request(&mut self) -> Option<Power> {
+        self.request.take()
+    }
+}
+
+impl Device for Syscon {
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        match (addr, size) {
+            (EXIT_STATUS, Size::Word) => Ok(self.exit_status),
+            (COMMAND, Size::Word) => Ok(0),
+            _ => Err(Exception::LoadIllegalAddress),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
+        match (addr, size) {
+            (EXIT_STATUS, Size::Word) => self.exit_status = data,
+            (COMMAND, Size::Word) => {
+                self.request = match data {
+                    COMMAND_POWEROFF => Some(Power::Off(self.exit_status)),
+                    COMMAND_REBOOT => Some(Power::Reboot),
+                    COMMAND_HALT => Some(Power::Halt(self.exit_status)),
+                    // unknown commands are ignored like on most syscon blocks
+                    _ => self.request,
+                }
+            }
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+        Ok(())
+    }
+}
@@ -3,40 +3,45 @@
 //! The uart module contains the implementation of a universal asynchronous receiver-transmitter
 //! (UART) for the CLI tool. The device is 16550A UART, which is used in the QEMU virt machine.
 //! See more information in http://byterunner.com/16550.html.
+//!
+//! Characters move at the programmed line rate: the transmitter shifts one character out of the
+//! TX FIFO per character time, and host input enters the RX FIFO at the same pace.
 
-use std::io;
-use std::io::prelude::*;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Condvar, Mutex,
-};
-use std::thread;
+use std::collections::VecDeque;
 
-use crate::bus::{UART_SIZE};
+use crate::coprocessor::CYCLES_PER_SECOND;
 use crate::cpu::Size;
 use crate::exception::Exception;
 
 use super::device::Device;
+use super::irq::Irq;
+use super::serial::SerialBackend;
 
-/// The interrupt request of UART.
-pub const UART_IRQ: u32 = 10;
+/// The interrupt level of UART.
+pub const UART_IRQ: u8 = 2;
 
 /// Receive holding register (for input bytes).
 const UART_RHR: u32 = 0;
 /// Transmit holding register (for output bytes).
 const UART_THR: u32 = 0;
+/// Divisor latch, low byte. Replaces RHR/THR while LCR.DLAB is set.
+const UART_DLL: u32 = 0;
 /// Interrupt enable register.
-const _UART_IER: u32 = 1;
+const UART_IER: u32 = 1;
+/// Divisor latch, high byte. Replaces IER while LCR.DLAB is set.
+const UART_DLM: u32 = 1;
 /// FIFO control register.
-const _UART_FCR: u32 = 2;
+const UART_FCR: u32 = 2;
 /// Interrupt status register.
 /// ISR BIT-0:
 ///     0 = an interrupt is pending and the ISR contents may be used as a pointer to the appropriate
 /// interrupt service routine.
 ///     1 = no interrupt is pending.
-const _UART_ISR: u32 = 2;
+const UART_ISR: u32 = 2;
 /// Line control register.
-const _UART_LCR: u32 = 3;
+const UART_LCR: u32 = 3;
+/// Modem control register.
+const UART_MCR: u32 = 4;
 /// Line status register.
 /// LSR BIT 0:
 ///     0 = no data in receive holding register or FIFO.
@@ -45,85 +50,341 @@ const _UART_LCR: u32 = 3;
 ///     0 = transmit holding register is full. 16550 will not accept any data for transmission.
 ///     1 = transmitter hold register (or FIFO) is empty. CPU can load the next character.
 const UART_LSR: u32 = 5;
+/// Modem status register.
+const UART_MSR: u32 = 6;
+/// Scratch pad register.
+const UART_SPR: u32 = 7;
 
+// IER bits.
+/// Receive data available (and character timeout).
+const IER_RDI: u8 = 1;
+/// Transmit holding register empty.
+const IER_THRI: u8 = 1 << 1;
+/// Receiver line status.
+const IER_RLSI: u8 = 1 << 2;
+/// Modem status.
+const IER_MSI: u8 = 1 << 3;
+
+// ISR interrupt identification, highest priority first.
+const ISR_NO_INT: u8 = 0x01;
+const ISR_RLSI: u8 = 0x06;
+const ISR_RDI: u8 = 0x04;
+const ISR_TIMEOUT: u8 = 0x0c;
+const ISR_THRI: u8 = 0x02;
+const ISR_MSI: u8 = 0x00;
+/// Both FIFO bits read as set while the FIFOs are enabled.
+const ISR_FIFOS: u8 = 0xc0;
+
+// FCR bits.
+const FCR_ENABLE: u8 = 1;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+// LCR bits.
+/// Divisor latch access bit.
+const LCR_DLAB: u8 = 1 << 7;
+const LCR_PARITY: u8 = 1 << 3;
+const LCR_STOP: u8 = 1 << 2;
+
+// MCR bits.
+const MCR_DTR: u8 = 1;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOP: u8 = 1 << 4;
+
+// LSR bits.
 /// The receiver (RX).
 const UART_LSR_RX: u8 = 1;
+/// Overrun error.
+const LSR_OE: u8 = 1 << 1;
 /// The transmitter (TX).
 const UART_LSR_TX: u8 = 1 << 5;
+/// Transmitter empty: the TX FIFO and the shift register are empty.
+const LSR_TEMT: u8 = 1 << 6;
+
+// MSR bits.
+const MSR_DELTAS: u8 = 0x0f;
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+
+const FIFO_SIZE: usize = 16;
+/// The 1.8432 MHz crystal of PC serial ports. A divisor of 1 gives 115200 baud.
+const CLOCK_HZ: u64 = 1_843_200;
 
 /// The UART, the size of which is 0x100 (2**8).
 pub struct Uart {
-    uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
-    interrupting: Arc<AtomicBool>,
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    msr: u8,
+    spr: u8,
+    divisor: u16,
+    /// A THR-empty interrupt is pending. Reading ISR while it is the source, or writing THR,
+    /// clears it.
+    thr_interrupt: bool,
+    /// Cycles until the character being shifted out is on the line.
+    tx_cycles: u64,
+    /// Cycles until the next host character may enter the RX FIFO.
+    rx_cycles: u64,
+    /// Cycles since the RX FIFO was last read or written, for the character timeout.
+    rx_idle: u64,
+    /// The host side of the line.
+    backend: Box<dyn SerialBackend>,
+    irq: Irq,
 }
 
 impl Uart {
-    /// Create a new UART object.
-    pub fn new() -> Self {
-        let uart = Arc::new((Mutex::new([0; UART_SIZE as usize]), Condvar::new()));
-        let interrupting = Arc::new(AtomicBool::new(false));
-        {
-            let (uart, _cvar) = &*uart;
-            let mut uart = uart.lock().expect("failed to get an UART object");
+    /// Create a new UART object connected to `backend`.
+    pub fn new(backend: Box<dyn SerialBackend>, irq: Irq) -> Self {
+        Self {
+            rx: VecDeque::with_capacity(FIFO_SIZE),
+            tx: VecDeque::with_capacity(FIFO_SIZE),
+            ier: 0,
+            fcr: 0,
+            // 8 data bits, no parity, 1 stop bit
+            lcr: 0x03,
+            mcr: 0,
             // Transmitter hold register is empty. It allows input anytime.
-            uart[UART_LSR as usize] |= UART_LSR_TX;
-        }
-
-        // Create a new thread for waiting for input.
-        let mut byte = [0; 1];
-        let cloned_uart = uart.clone();
-        let cloned_interrupting = interrupting.clone();
-        let _uart_thread_for_read = thread::spawn(move || loop {
-            match io::stdin().read(&mut byte) {
-                Ok(_) => {
-                    let (uart, cvar) = &*cloned_uart;
-                    let mut uart = uart.lock().expect("failed to get an UART object");
-                    // Wait for the thread to start up.
-                    while (uart[UART_LSR as usize] & UART_LSR_RX) == 1 {
-                        uart = cvar.wait(uart).expect("the mutex is poisoned");
-                    }
-                    uart[0] = byte[0];
-                    cloned_interrupting.store(true, Ordering::Release);
-                    // Data has been receive.
-                    uart[UART_LSR as usize] |= UART_LSR_RX;
+            lsr: UART_LSR_TX | LSR_TEMT,
+            msr: MSR_CTS | MSR_DSR | MSR_DCD,
+            spr: 0,
+            divisor: 1,
+            thr_interrupt: false,
+            tx_cycles: 0,
+            rx_cycles: 0,
+            rx_idle: 0,
+            backend,
+            irq,
+        }
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_ENABLE != 0
+    }
+
+    /// FIFO depth, a single holding register while the FIFOs are disabled.
+    fn depth(&self) -> usize {
+        if self.fifo_enabled() {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    /// RX FIFO level that raises the receive data interrupt: 1, 4, 8 or 14 characters.
+    fn trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        [1, 4, 8, 14][(self.fcr >> 6) as usize]
+    }
+
+    /// Cycles one character takes on the line: start bit, data bits, parity and stop bits at the
+    /// baud rate given by the divisor.
+    fn char_cycles(&self) -> u64 {
+        let data = 5 + (self.lcr & 3) as u64;
+        let parity = (self.lcr & LCR_PARITY != 0) as u64;
+        let stop = if self.lcr & LCR_STOP != 0 { 2 } else { 1 };
+        let bits = 1 + data + parity + stop;
+        let divisor = (self.divisor as u64).max(1);
+        (bits * 16 * divisor * CYCLES_PER_SECOND / CLOCK_HZ).max(1)
+    }
+
+    /// The interrupt identification: the highest priority pending and enabled source.
+    fn interrupt_id(&self) -> u8 {
+        let timeout = !self.rx.is_empty() && self.rx_idle >= 4 * self.char_cycles();
+        if self.ier & IER_RLSI != 0 && self.lsr & LSR_OE != 0 {
+            ISR_RLSI
+        } else if self.ier & IER_RDI != 0 && self.rx.len() >= self.trigger_level() {
+            ISR_RDI
+        } else if self.ier & IER_RDI != 0 && self.fifo_enabled() && timeout {
+            ISR_TIMEOUT
+        } else if self.ier & IER_THRI != 0 && self.thr_interrupt {
+            ISR_THRI
+        } else if self.ier & IER_MSI != 0 && self.msr & MSR_DELTAS != 0 {
+            ISR_MSI
+        } else {
+            ISR_NO_INT
+        }
+    }
+
+    fn update_interrupt(&mut self) {
+        if self.interrupt_id() == ISR_NO_INT {
+            self.irq.lower();
+        } else {
+            self.irq.raise();
+        }
+    }
+
+    fn update_lsr(&mut self) {
+        self.lsr &= !(UART_LSR_RX | UART_LSR_TX | LSR_TEMT);
+        if !self.rx.is_empty() {
+            self.lsr |= UART_LSR_RX;
+        }
+        if self.tx.is_empty() {
+            self.lsr |= UART_LSR_TX;
+            if self.tx_cycles == 0 {
+                self.lsr |= LSR_TEMT;
+            }
+        }
+    }
+
+    /// Puts a character into the RX FIFO, flagging an overrun if it is full.
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() >= self.depth() {
+            self.lsr |= LSR_OE;
+        } else {
+            self.rx.push_back(byte);
+        }
+        self.rx_idle = 0;
+    }
+
+    /// The modem inputs follow the outputs in loopback mode and are asserted otherwise.
+    fn update_msr(&mut self) {
+        let lines = if self.mcr & MCR_LOOP != 0 {
+            let mut lines = 0;
+            if self.mcr & MCR_RTS != 0 {
+                lines |= MSR_CTS;
+            }
+            if self.mcr & MCR_DTR != 0 {
+                lines |= MSR_DSR;
+            }
+            if self.mcr & MCR_OUT1 != 0 {
+                lines |= MSR_RI;
+            }
+            if self.mcr & MCR_OUT2 != 0 {
+                lines |= MSR_DCD;
+            }
+            lines
+        } else {
+            MSR_CTS | MSR_DSR | MSR_DCD
+        };
+        let changed = (self.msr ^ lines) & 0xf0;
+        // delta bits: CTS, DSR, trailing edge of RI, DCD
+        let mut deltas = changed >> 4;
+        if self.msr & MSR_RI == 0 {
+            deltas &= !(MSR_RI >> 4);
+        }
+        self.msr = (self.msr & MSR_DELTAS) | deltas | lines;
+    }
+
+    /// Lets `cycles` pass on the line: shifts characters out and host input in.
+    pub fn advance(&mut self, cycles: u64) {
+        let char_cycles = self.char_cycles();
+        let mut left = cycles;
+        while left > 0 {
+            if self.tx_cycles == 0 {
+                let Some(byte) = self.tx.pop_front() else {
+                    break;
+                };
+                self.tx_cycles = char_cycles;
+                if self.tx.is_empty() {
+                    self.thr_interrupt = true;
                 }
-                Err(e) => {
-                    println!("input via UART is error: {}", e);
+                if self.mcr & MCR_LOOP != 0 {
+                    self.receive(byte);
+                } else {
+                    self.backend.write(byte);
                 }
             }
-        });
+            let step = left.min(self.tx_cycles);
+            self.tx_cycles -= step;
+            left -= step;
+        }
 
-        Self { uart, interrupting }
+        // no host input reaches the receiver in loopback mode
+        if self.mcr & MCR_LOOP == 0 {
+            self.rx_cycles = self.rx_cycles.saturating_sub(cycles);
+            while self.rx_cycles == 0 && self.rx.len() < self.depth() {
+                let Some(byte) = self.backend.read() else {
+                    break;
+                };
+                self.rx.push_back(byte);
+                self.rx_idle = 0;
+                self.rx_cycles = char_cycles;
+            }
+        }
+        self.rx_idle = self.rx_idle.saturating_add(cycles);
+        self.update_lsr();
+        self.update_interrupt();
     }
 
-    /// Return true if an interrupt is pending. Clear the interrupting flag by swapping a value.
-    pub fn is_interrupting(&self) -> bool {
-        self.interrupting.swap(false, Ordering::Acquire)
+    /// Cycles until the line does something the guest may be waiting for.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        let char_cycles = self.char_cycles();
+        let mut next: Option<u64> = None;
+        let mut consider = |cycles: u64| next = Some(next.map_or(cycles, |n| n.min(cycles)));
+        if !self.tx.is_empty() || self.tx_cycles > 0 {
+            consider(self.tx_cycles.max(1));
+        }
+        if self.mcr & MCR_LOOP == 0 && self.rx.len() < self.depth() && self.backend.pending() {
+            consider(self.rx_cycles.max(1));
+        }
+        if !self.rx.is_empty() && self.rx_idle < 4 * char_cycles {
+            consider(4 * char_cycles - self.rx_idle);
+        }
+        next
     }
-
 }
 
-
 impl Device for Uart {
-    /// Read a byte from the receive holding register.
+    /// Read a byte from a UART register.
     fn read(&mut self, index: u32, size: Size) -> Result<u32, Exception> {
         if size != Size::Byte {
             return Err(Exception::LoadIllegalAddress);
         }
 
-        let (uart, cvar) = &*self.uart;
-        let mut uart = uart.lock().expect("failed to get an UART object");
-        match index {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match index {
+            UART_DLL if dlab => self.divisor as u8,
+            UART_DLM if dlab => (self.divisor >> 8) as u8,
             UART_RHR => {
-                cvar.notify_one();
-                uart[UART_LSR as usize] &= !UART_LSR_RX;
-                Ok(uart[UART_RHR as usize] as u32)
+                let byte = self.rx.pop_front().unwrap_or(0);
+                self.rx_idle = 0;
+                self.update_lsr();
+                byte
             }
-            _ => Ok(uart[index as usize] as u32),
-        }
+            UART_IER => self.ier,
+            UART_ISR => {
+                let id = self.interrupt_id();
+                // reading the identification acknowledges a THR-empty interrupt
+                if id == ISR_THRI {
+                    self.thr_interrupt = false;
+                }
+                if self.fifo_enabled() {
+                    id | ISR_FIFOS
+                } else {
+                    id
+                }
+            }
+            UART_LCR => self.lcr,
+            UART_MCR => self.mcr,
+            UART_LSR => {
+                let lsr = self.lsr;
+                // error bits clear on read
+                self.lsr &= !LSR_OE;
+                lsr
+            }
+            UART_MSR => {
+                let msr = self.msr;
+                self.msr &= !MSR_DELTAS;
+                msr
+            }
+            UART_SPR => self.spr,
+            _ => return Err(Exception::LoadIllegalAddress),
+        };
+        self.update_interrupt();
+        Ok(value as u32)
     }
 
-    /// Write a byte to the transmit holding register.
+    /// Write a byte to a UART register.
     fn write(&mut self, index: u32, value: u32, size: Size) -> Result<(), Exception> {
         if size != Size::Byte {
             return Err(Exception::StoreIllegalAddress);
@@ -139,17 +400,54 @@ impl Device for Uart {
         // e.g. (riscv-pk):
         //   while ((uart16550[UART_REG_LSR << uart16550_reg_shift] & UART_REG_STATUS_TX) == 0);
         //   uart16550[UART_REG_QUEUE << uart16550_reg_shift] = ch;
-        let (uart, _cvar) = &*self.uart;
-        let mut uart = uart.lock().expect("failed to get an UART object");
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
         match index {
+            UART_DLL if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            UART_DLM if dlab => self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8,
             UART_THR => {
-                print!("{}", value as u8 as char);
-                io::stdout().flush().expect("failed to flush stdout");
+                // a full FIFO drops the character like the hardware does
+                if self.tx.len() < self.depth() {
+                    self.tx.push_back(value);
+                }
+                self.thr_interrupt = false;
+            }
+            UART_IER => {
+                let enabled = value & !self.ier & IER_THRI != 0;
+                self.ier = value & 0x0f;
+                // enabling the interrupt while THR is empty raises it right away
+                if enabled && self.tx.is_empty() {
+                    self.thr_interrupt = true;
+                }
+            }
+            UART_FCR => {
+                if value & FCR_ENABLE != self.fcr & FCR_ENABLE {
+                    // switching the FIFOs on or off empties them
+                    self.rx.clear();
+                    self.tx.clear();
+                }
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                if value & FCR_CLEAR_TX != 0 {
+                    self.tx.clear();
+                    self.thr_interrupt = self.ier & IER_THRI != 0;
+                }
+                // the clear bits are self-clearing
+                self.fcr = value & !(FCR_CLEAR_RX | FCR_CLEAR_TX);
             }
-            _ => {
-                uart[index as usize] = value as u8;
+            UART_LCR => self.lcr = value,
+            UART_MCR => {
+                self.mcr = value & 0x1f;
+                self.update_msr();
             }
+            // LSR and MSR are read-only
+            UART_LSR | UART_MSR => {}
+            UART_SPR => self.spr = value,
+            _ => return Err(Exception::StoreIllegalAddress),
         }
+        self.update_lsr();
+        self.update_interrupt();
         Ok(())
     }
-}
\ No newline at end of file
+}
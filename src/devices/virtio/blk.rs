@@ -0,0 +1,157 @@
+//! The blk module implements a virtio block device backed by a `Disk`.
+//!
+//! 5.2 Block Device:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2390002
+
+use crate::devices::device::Device;
+use crate::devices::disk::{Disk, SECTOR_SIZE};
+use crate::exception::Exception;
+
+use super::queue::{DescChain, Virtqueue, MAX_DESC_LEN};
+use super::VirtioDevice;
+
+// 5.2.6 Device Operation
+/// Read sectors from the disk into the device-writable buffers.
+const VIRTIO_BLK_T_IN: u32 = 0;
+/// Write the device-readable buffers to the disk.
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+/// Size of the `virtio_blk_req` header preceding the data.
+const BLK_REQ_HEADER_SIZE: usize = 16;
+/// Default capacity in sectors when no disk image is given.
+///
+/// The value is based on QEMU's output:
+/// "virtio_blk virtio0: [vda] 204800 512-byte logical blocks (105 MB/100 MiB)"
+/// 204800 --> 0x32000
+pub const DEFAULT_CAPACITY: u64 = 0x32000;
+
+// 5.2.3 Feature bits
+/// Maximum size of any single segment is in `size_max`.
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+/// Device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+pub struct Blk {
+    disk: Disk,
+}
+
+impl Blk {
+    pub fn new(disk: Disk) -> Self {
+        Self { disk }
+    }
+
+    /// Capacity in 512-byte sectors, as advertised in the configuration space.
+    fn capacity(&self) -> u64 {
+        self.disk.sectors()
+    }
+
+    /// Executes the block request in `chain` and returns the number of bytes written to
+    /// device-writable buffers.
+    fn request(&mut self, chain: &DescChain, mem: &mut dyn Device) -> Result<u32, Exception> {
+        // Device-readable buffers hold the header (and data for writes), device-writable buffers
+        // receive data for reads and end with the status byte.
+        let readable = chain.read(mem)?;
+        let writable_len = chain.writable_len();
+        if readable.len() < BLK_REQ_HEADER_SIZE || writable_len == 0 {
+            return Err(Exception::DataBusError);
+        }
+
+        // 5.2.6 Device Operation
+        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2500006
+        // struct virtio_blk_req {
+        //   le32 type;
+        //   le32 reserved;
+        //   le64 sector;
+        //   u8 data[][512];
+        //   u8 status;
+        // };
+        let req_type = u32::from_le_bytes(readable[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(readable[8..16].try_into().unwrap());
+        let capacity = self.capacity() * SECTOR_SIZE;
+        // the guest picks the sector, an access past the end must not wrap around
+        let in_range = |len: usize| {
+            sector.checked_mul(SECTOR_SIZE).and_then(|offset| offset.checked_add(len as u64)).is_some_and(|end| end <= capacity)
+        };
+        let offset = sector.wrapping_mul(SECTOR_SIZE);
+        let mut response = Vec::new();
+        let status = match req_type {
+            VIRTIO_BLK_T_IN => {
+                let mut data = vec![0; (writable_len - 1) as usize];
+                if !in_range(data.len()) || self.disk.read(offset, &mut data).is_err() {
+                    VIRTIO_BLK_S_IOERR
+                } else {
+                    response = data;
+                    VIRTIO_BLK_S_OK
+                }
+            }
+            VIRTIO_BLK_T_OUT => {
+                let data = &readable[BLK_REQ_HEADER_SIZE..];
+                if !in_range(data.len()) || self.disk.write(offset, data).is_err() {
+                    VIRTIO_BLK_S_IOERR
+                } else {
+                    VIRTIO_BLK_S_OK
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => match self.disk.flush() {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(_) => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                // 20-byte zero-padded identification string
+                response.extend_from_slice(b"mips-emu-virtio-blk\0");
+                response.truncate((writable_len - 1) as usize);
+                VIRTIO_BLK_S_OK
+            }
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        // The status byte lands last.
+        response.resize((writable_len - 1) as usize, 0);
+        response.push(status);
+        chain.write(mem, &response)
+    }
+}
+
+impl VirtioDevice for Blk {
+    fn device_id(&self) -> u32 {
+        2
+    }
+
+    fn features(&self) -> u64 {
+        // segments stay within what a descriptor may hold
+        if self.disk.read_only() {
+            VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_RO
+        } else {
+            VIRTIO_BLK_F_SIZE_MAX
+        }
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    /// 5.2.4 Device configuration layout
+    /// struct virtio_blk_config {
+    ///   le64 capacity;
+    ///   le32 size_max;
+    /// }
+    fn read_config(&self, offset: u32) -> u8 {
+        match offset {
+            0..=7 => self.capacity().to_le_bytes()[offset as usize],
+            8..=11 => MAX_DESC_LEN.to_le_bytes()[offset as usize - 8],
+            _ => 0,
+        }
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut dyn Device) -> Result<(), Exception> {
+        let queue = &mut queues[queue];
+        while let Some(chain) = queue.pop(mem)? {
+            let len = self.request(&chain, mem)?;
+            queue.push(mem, &chain, len)?;
+        }
+        Ok(())
+    }
+}
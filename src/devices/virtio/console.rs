@@ -0,0 +1,238 @@
+//! The console module implements a virtio console device with multiport support. Each port is
+//! connected to a host character backend.
+//!
+//! 5.3 Console Device:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2550003
+
+use std::collections::VecDeque;
+use std::io;
+
+use crate::devices::chardev::{Backend, CharDev};
+use crate::devices::device::Device;
+use crate::exception::Exception;
+
+use super::queue::Virtqueue;
+use super::VirtioDevice;
+
+// 5.3.3 Feature bits
+/// Device has support for multiple ports; max_nr_ports is valid and control virtqueues will be
+/// used.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+// 5.3.6.2 Multiport Device Operation
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// The most ports a console offers. Each takes two queues.
+pub const MAX_PORTS: usize = 16;
+
+/// Queues of the control channel.
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+/// A port as given on the command line.
+#[derive(Debug, Clone)]
+pub struct PortConfig {
+    /// Name the guest sees, e.g. in /dev/virtio-ports/.
+    pub name: String,
+    pub backend: Backend,
+}
+
+struct Port {
+    name: String,
+    chardev: CharDev,
+    /// The guest has the port open.
+    guest_open: bool,
+}
+
+pub struct Console {
+    ports: Vec<Port>,
+    /// Control messages waiting for buffers in the control receiveq.
+    control: VecDeque<Vec<u8>>,
+}
+
+impl Console {
+    /// Opens the backend of every port. Port 0 is the console.
+    pub fn new(ports: &[PortConfig]) -> io::Result<Self> {
+        let ports = ports
+            .iter()
+            .map(|port| {
+                Ok(Port { name: port.name.clone(), chardev: CharDev::open(&port.backend)?, guest_open: false })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { ports, control: VecDeque::new() })
+    }
+
+    /// "receiveq(port0), transmitq(port0), control receiveq, control transmitq, receiveq(port1),
+    /// transmitq(port1), ..."
+    fn receiveq(port: usize) -> usize {
+        if port == 0 {
+            0
+        } else {
+            2 + 2 * port
+        }
+    }
+
+    /// Returns the port a transmitq belongs to.
+    fn port_of_transmitq(queue: usize) -> usize {
+        if queue == 1 {
+            0
+        } else {
+            (queue - 3) / 2
+        }
+    }
+
+    /// ```c
+    /// struct virtio_console_control {
+    ///   le32 id;    /* Port number */
+    ///   le16 event; /* The kind of control event */
+    ///   le16 value; /* Extra information for the event */
+    /// };
+    /// ```
+    fn send_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let mut message = Vec::with_capacity(8 + extra.len());
+        message.extend_from_slice(&id.to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(extra);
+        self.control.push_back(message);
+    }
+
+    fn handle_control(&mut self, message: &[u8]) {
+        if message.len() < 8 {
+            return;
+        }
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() as u32 {
+                    self.send_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 => {
+                let Some(port) = self.ports.get(id as usize) else {
+                    return;
+                };
+                let name = port.name.clone().into_bytes();
+                if id == 0 {
+                    self.send_control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                }
+                self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 0, &name);
+                // the host side of every port is always connected
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(id as usize) {
+                    port.guest_open = value == 1;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl VirtioDevice for Console {
+    fn device_id(&self) -> u32 {
+        3
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn num_queues(&self) -> usize {
+        2 * (self.ports.len() + 1)
+    }
+
+    /// 5.3.4 Device configuration layout
+    /// ```c
+    /// struct virtio_console_config {
+    ///   le16 cols;
+    ///   le16 rows;
+    ///   le32 max_nr_ports;
+    ///   le32 emerg_wr;
+    /// };
+    /// ```
+    fn read_config(&self, offset: u32) -> u8 {
+        match offset {
+            4..=7 => (self.ports.len() as u32).to_le_bytes()[offset as usize - 4],
+            _ => 0,
+        }
+    }
+
+    /// emerg_wr: "a write to this field will cause the character to be written to port 0".
+    fn write_config(&mut self, offset: u32, value: u8) {
+        if offset == 8 {
+            if let Some(port) = self.ports.first() {
+                port.chardev.write(&[value]);
+            }
+        }
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut dyn Device) -> Result<(), Exception> {
+        match queue {
+            CONTROL_TX => {
+                while let Some(chain) = queues[queue].pop(mem)? {
+                    let message = chain.read(mem)?;
+                    self.handle_control(&message);
+                    queues[queue].push(mem, &chain, 0)?;
+                }
+            }
+            // buffers offered in receive queues are filled when data arrives
+            q if q.is_multiple_of(2) => {}
+            _ => {
+                let port = Self::port_of_transmitq(queue);
+                while let Some(chain) = queues[queue].pop(mem)? {
+                    let data = chain.read(mem)?;
+                    if let Some(port) = self.ports.get(port) {
+                        port.chardev.write(&data);
+                    }
+                    queues[queue].push(mem, &chain, 0)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves pending control messages and host input into the receive queues.
+    fn poll(&mut self, queues: &mut [Virtqueue], mem: &mut dyn Device) -> Result<(), Exception> {
+        while !self.control.is_empty() {
+            let Some(chain) = queues[CONTROL_RX].pop(mem)? else {
+                break;
+            };
+            let message = self.control.pop_front().unwrap();
+            let len = chain.write(mem, &message)?;
+            queues[CONTROL_RX].push(mem, &chain, len)?;
+        }
+        for (i, port) in self.ports.iter().enumerate() {
+            // input for a port the guest has not opened stays on the host; port 0 works without
+            // multiport, where nothing gets opened
+            if i != 0 && !port.guest_open {
+                continue;
+            }
+            let queue = &mut queues[Self::receiveq(i)];
+            while port.chardev.pending() {
+                let Some(chain) = queue.pop(mem)? else {
+                    break;
+                };
+                let data = port.chardev.read(chain.writable_len() as usize);
+                let len = chain.write(mem, &data)?;
+                queue.push(mem, &chain, len)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+        for port in self.ports.iter_mut() {
+            port.guest_open = false;
+        }
+    }
+}
@@ -0,0 +1,561 @@
+// The code is from https://github.com/d0iasm/rvemu.
+
+//! The virtio module implements the virtio-mmio transport. Device types sit behind it through the
+//! `VirtioDevice` trait and get their requests from split virtqueues.
+//!
+//! The spec for Virtual I/O Device (VIRTIO) Version 1.1:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html
+//! 4.2 Virtio Over MMIO:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1440002
+
+pub mod blk;
+pub mod console;
+pub mod net;
+pub mod p9;
+pub mod queue;
+pub mod rng;
+
+use crate::cpu::Size;
+use crate::exception::Exception;
+use crate::devices::device::Device;
+use crate::devices::irq::Irq;
+use queue::{Virtqueue, QUEUE_SIZE};
+
+/// The interrupt level shared by every virtio device.
+pub const VIRTIO_IRQ: u8 = 3;
+
+// 2.1 Device Status Field
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+const STATUS_FAILED: u32 = 128;
+
+// 6 Reserved Feature Bits
+/// All buffers are used by the device in the same order in which they have been made available.
+const VIRTIO_F_IN_ORDER: u64 = 1 << 35;
+/// Compliance with the virtio 1.x specification. Only offered by the version 2 transport.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// 4.2.2 Interrupt status bits
+/// The device has used a buffer in at least one of the active virtual queues.
+const INTERRUPT_USED_BUFFER: u32 = 1;
+/// The configuration of the device has changed.
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+// 4.2.2 MMIO Device Register Layout
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
+/// Magic value. Always return 0x74726976 (a Little Endian equivalent of the "virt" string).
+const MAGIC: u32 = 0;
+const MAGIC_END: u32 = 0x3;
+
+/// Device version number. 1 is legacy, 2 is the virtio 1.x interface.
+const VERSION: u32 = 0x4;
+const VERSION_END: u32 = 0x7;
+
+/// Virtio Subsystem Device ID. 1 is network, 2 is block device.
+const DEVICE_ID: u32 = 0x8;
+const DEVICE_ID_END: u32 = 0xb;
+
+/// Virtio Subsystem Vendor ID. Always return 0x554d4551
+const VENDOR_ID: u32 = 0xc;
+const VENDOR_ID_END: u32 = 0xf;
+
+/// Flags representing features the device supports. Access to this register returns bits
+/// DeviceFeaturesSel ∗ 32 to (DeviceFeaturesSel ∗ 32) + 31.
+const DEVICE_FEATURES: u32 = 0x10;
+const DEVICE_FEATURES_END: u32 = 0x13;
+
+/// Device (host) features word selection.
+const DEVICE_FEATURES_SEL: u32 = 0x14;
+const DEVICE_FEATURES_SEL_END: u32 = 0x17;
+
+/// Flags representing device features understood and activated by the driver. Access to this
+/// register sets bits DriverFeaturesSel ∗ 32 to (DriverFeaturesSel ∗ 32) + 31.
+const DRIVER_FEATURES: u32 = 0x20;
+const DRIVER_FEATURES_END: u32 = 0x23;
+
+/// Activated (guest) features word selection.
+const DRIVER_FEATURES_SEL: u32 = 0x24;
+const DRIVER_FEATURES_SEL_END: u32 = 0x27;
+
+// 4.2.4 Legacy interface
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1560004
+/// Guest page size. The driver writes the guest page size in bytes to the register during
+/// initialization, before any queues are used. This value should be a power of 2 and is used by
+/// the device to calculate the Guest address of the first queue page. Write-only.
+const GUEST_PAGE_SIZE: u32 = 0x28;
+const GUEST_PAGE_SIZE_END: u32 = 0x2b;
+
+/// Virtual queue index. Writing to this register selects the virtual queue that the following
+/// operations on the QueueNumMax, QueueNum, QueueAlign, QueuePFN, QueueReady, QueueDesc,
+/// QueueDriver and QueueDevice registers apply to. The index number of the first queue is zero
+/// (0x0). Write-only.
+const QUEUE_SEL: u32 = 0x30;
+const QUEUE_SEL_END: u32 = 0x33;
+
+/// Maximum virtual queue size. Reading from the register returns the maximum size of the queue the
+/// device is ready to process or zero (0x0) if the queue is not available. This applies to the
+/// queue selected by writing to QueueSel and is allowed only when QueuePFN is set to zero (0x0),
+/// so when the queue is not actively used. Read-only. In QEMU, `VIRTIO_COUNT = 8`.
+const QUEUE_NUM_MAX: u32 = 0x34;
+const QUEUE_NUM_MAX_END: u32 = 0x37;
+
+/// Virtual queue size. Queue size is the number of elements in the queue, therefore size of the
+/// descriptor table and both available and used rings. Writing to this register notifies the
+/// device what size of the queue the driver will use. This applies to the queue selected by
+/// writing to QueueSel. Write-only.
+const QUEUE_NUM: u32 = 0x38;
+const QUEUE_NUM_END: u32 = 0x3b;
+
+/// Used Ring alignment in the virtual queue.
+const QUEUE_ALIGN: u32 = 0x3c;
+const QUEUE_ALIGN_END: u32 = 0x3f;
+
+/// Guest physical page number of the virtual queue. Writing to this register notifies the device
+/// about location of the virtual queue in the Guest’s physical address space. This value is the
+/// index number of a page starting with the queue Descriptor Table. Value zero (0x0) means
+/// physical address zero (0x00000000) and is illegal. When the driver stops using the queue it
+/// writes zero (0x0) to this register. Reading from this register returns the currently used page
+/// number of the queue, therefore a value other than zero (0x0) means that the queue is in use.
+/// Both read and write accesses apply to the queue selected by writing to QueueSel.
+const QUEUE_PFN: u32 = 0x40;
+const QUEUE_PFN_END: u32 = 0x43;
+
+/// Virtual queue ready bit. Writing one (0x1) to this register notifies the device that it can
+/// execute requests from this virtual queue. Reading from this register returns the last value
+/// written to it. Both read and write accesses apply to the queue selected by writing to QueueSel.
+const QUEUE_READY: u32 = 0x44;
+const QUEUE_READY_END: u32 = 0x47;
+
+// 4.2.2 MMIO Device Register Layout
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
+/// Queue notifier. Writing a queue index to this register notifies the device that there are new
+/// buffers to process in the queue. Write-only.
+const QUEUE_NOTIFY: u32 = 0x50;
+const QUEUE_NOTIFY_END: u32 = 0x53;
+
+/// Interrupt status. Reading from this register returns a bit mask of events that caused the
+/// device interrupt to be asserted.
+const INTERRUPT_STATUS: u32 = 0x60;
+const INTERRUPT_STATUS_END: u32 = 0x63;
+
+/// Interrupt acknowledge. Writing a value with bits set as defined in InterruptStatus to this
+/// register notifies the device that events causing the interrupt have been handled.
+const INTERRUPT_ACK: u32 = 0x64;
+const INTERRUPT_ACK_END: u32 = 0x67;
+
+/// Device status. Reading from this register returns the current device status flags. Writing
+/// non-zero values to this register sets the status flags, indicating the driver progress. Writing
+/// zero (0x0) to this register triggers a device reset.
+const STATUS: u32 = 0x70;
+const STATUS_END: u32 = 0x73;
+
+/// Virtual queue's Descriptor Area 64 bit long physical address. Writing to these two registers
+/// (lower 32 bits of the address to QueueDescLow, higher 32 bits to QueueDescHigh) notifies the
+/// device about location of the Descriptor Area of the queue selected by writing to QueueSel
+/// register.
+const QUEUE_DESC_LOW: u32 = 0x80;
+const QUEUE_DESC_HIGH: u32 = 0x84;
+
+/// Virtual queue's Driver Area 64 bit long physical address.
+const QUEUE_DRIVER_LOW: u32 = 0x90;
+const QUEUE_DRIVER_HIGH: u32 = 0x94;
+
+/// Virtual queue's Device Area 64 bit long physical address.
+const QUEUE_DEVICE_LOW: u32 = 0xa0;
+const QUEUE_DEVICE_HIGH: u32 = 0xa4;
+
+/// Configuration atomicity value. Reading from this register returns a value describing a version
+/// of the device-specific configuration space. The driver can then access the configuration space
+/// and, when finished, read ConfigGeneration again. If no part of the configuration space has
+/// changed between these two ConfigGeneration reads, the returned values are identical.
+const CONFIG_GENERATION: u32 = 0xfc;
+const CONFIG_GENERATION_END: u32 = 0xff;
+
+/// Configuration space.
+const CONFIG: u32 = 0x100;
+const CONFIG_END: u32 = 0x1ff;
+
+/// A device type behind the virtio-mmio transport. The transport owns the virtqueues and hands
+/// them to the device together with guest memory (DMA) when there is work to do.
+pub trait VirtioDevice {
+    /// Virtio Subsystem Device ID. 1 is network, 2 is block device, 3 is console, 4 is entropy,
+    /// 9 is 9P transport.
+    fn device_id(&self) -> u32;
+    /// Device-specific feature bits. The transport adds its own.
+    fn features(&self) -> u64;
+    /// Tells the device which features the driver accepted.
+    fn ack_features(&mut self, _features: u64) {}
+    fn num_queues(&self) -> usize;
+    /// Reads a byte of the device configuration space.
+    fn read_config(&self, offset: u32) -> u8;
+    /// Writes a byte of the device configuration space. Read-only by default.
+    fn write_config(&mut self, _offset: u32, _value: u8) {}
+    /// Changes whenever the device updates its configuration space.
+    fn config_generation(&self) -> u32 {
+        0
+    }
+    /// Serves the buffers the driver made available in `queues[queue]`.
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut dyn Device) -> Result<(), Exception>;
+    /// Lets the device fill buffers from host-side sources without a notification.
+    fn poll(&mut self, _queues: &mut [Virtqueue], _mem: &mut dyn Device) -> Result<(), Exception> {
+        Ok(())
+    }
+    /// Called when the driver resets the device, after the queues are reset.
+    fn reset(&mut self) {}
+}
+
+/// Paravirtualized drivers for IO virtualization.
+pub struct Virtio {
+    device: Box<dyn VirtioDevice>,
+    /// 1 for the legacy interface, 2 for the virtio 1.x one.
+    version: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    guest_page_size: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    /// Queues notified by the driver and not served yet.
+    notified: Vec<bool>,
+    interrupt_status: u32,
+    status: u32,
+    irq: Irq,
+}
+
+impl Virtio {
+    /// Puts `device` behind a transport of the given `version`.
+    pub fn new(device: Box<dyn VirtioDevice>, version: u32, irq: Irq) -> Self {
+        assert!(version == 1 || version == 2, "virtio-mmio version must be 1 or 2");
+        let queues = vec![Virtqueue::new(); device.num_queues()];
+        let notified = vec![false; queues.len()];
+        Self {
+            device,
+            version,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            guest_page_size: 0,
+            queue_sel: 0,
+            queues,
+            notified,
+            interrupt_status: 0,
+            status: 0,
+            irq,
+        }
+    }
+
+    /// Returns the features offered to the driver.
+    fn device_features(&self) -> u64 {
+        let mut features = self.device.features() | VIRTIO_F_IN_ORDER;
+        if self.version == 2 {
+            features |= VIRTIO_F_VERSION_1;
+        }
+        features
+    }
+
+    /// Returns the queue selected by QueueSel, if the device has it.
+    fn queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Resets the device when `status` is written to 0.
+    fn reset(&mut self) {
+        // 4.2.2.1 Device Requirements: MMIO Device Register Layout
+        // "Upon reset, the device MUST clear all bits in InterruptStatus and ready bits in the
+        // QueueReady register for all queues in the device."
+        self.interrupt_status = 0;
+        self.irq.lower();
+        self.notified.fill(false);
+        self.driver_features = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues.iter_mut().for_each(Virtqueue::reset);
+        self.device.reset();
+    }
+
+    /// 3.1.1 Driver Requirements: Device Initialization
+    /// "The driver MUST NOT accept a feature which the device did not offer". The version 2
+    /// transport also refuses drivers which do not accept VIRTIO_F_VERSION_1.
+    fn features_acceptable(&self) -> bool {
+        let offered = self.device_features();
+        self.driver_features & !offered == 0
+            && (self.version == 1 || self.driver_features & VIRTIO_F_VERSION_1 != 0)
+    }
+
+    /// 3.1.1 Driver Requirements: Device Initialization
+    /// The version 2 transport takes queue setup and DRIVER_OK only after FEATURES_OK.
+    fn features_negotiated(&self) -> bool {
+        self.version == 1 || self.status & STATUS_FEATURES_OK != 0
+    }
+
+    /// Flags the device as broken after a malformed request; the driver has to reset it.
+    pub fn needs_reset(&mut self) {
+        self.status |= STATUS_DEVICE_NEEDS_RESET;
+        // "the device MUST send a device configuration change notification to the driver"
+        self.interrupt(INTERRUPT_CONFIG_CHANGE);
+    }
+
+    fn interrupt(&mut self, reason: u32) {
+        self.interrupt_status |= reason;
+        self.irq.raise();
+    }
+
+    /// Serves notified queues and lets the device poll its host-side sources, then interrupts the
+    /// driver if buffers were used. `mem` is guest memory, accessed directly (DMA).
+    pub fn process(&mut self, mem: &mut dyn Device) -> Result<(), Exception> {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_DEVICE_NEEDS_RESET != 0 {
+            return Ok(());
+        }
+        for queue in 0..self.notified.len() {
+            if std::mem::take(&mut self.notified[queue]) {
+                self.device.notify(queue, &mut self.queues, mem)?;
+            }
+        }
+        self.device.poll(&mut self.queues, mem)?;
+
+        let mut used = false;
+        for queue in self.queues.iter_mut() {
+            // 2.6.7.2 Device Requirements: Used Buffer Notification Suppression
+            // "If flags is 1, the device SHOULD NOT send a notification."
+            if queue.take_used() && !queue.interrupt_suppressed(mem)? {
+                used = true;
+            }
+        }
+        if used {
+            self.interrupt(INTERRUPT_USED_BUFFER);
+        }
+        Ok(())
+    }
+}
+
+impl Device for Virtio {
+    /// Loads `size`-bit data from a register located at `addr` in the virtio device.
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        let version = self.version;
+        // `reg` is the value of a target register in the virtio device and `offset` is the byte
+        // of the start position in the register.
+        let (reg, offset) = match addr {
+            // A Little Endian equivalent of the “virt” string.
+            MAGIC..=MAGIC_END => (0x74726976, addr - MAGIC),
+            // Legacy devices (see 4.2.4 Legacy interface) used 0x1.
+            VERSION..=VERSION_END => (version, addr - VERSION),
+            DEVICE_ID..=DEVICE_ID_END => (self.device.device_id(), addr - DEVICE_ID),
+            // See https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c#L86
+            VENDOR_ID..=VENDOR_ID_END => (0x554d4551, addr - VENDOR_ID),
+            DEVICE_FEATURES..=DEVICE_FEATURES_END => {
+                let features = match self.device_features_sel {
+                    0 => self.device_features() as u32,
+                    1 => (self.device_features() >> 32) as u32,
+                    _ => 0,
+                };
+                (features, addr - DEVICE_FEATURES)
+            }
+            QUEUE_NUM_MAX..=QUEUE_NUM_MAX_END => {
+                let max = if self.queue().is_some() { QUEUE_SIZE } else { 0 };
+                (max, addr - QUEUE_NUM_MAX)
+            }
+            QUEUE_PFN..=QUEUE_PFN_END if version == 1 => {
+                (self.queue().map_or(0, |queue| queue.pfn), addr - QUEUE_PFN)
+            }
+            QUEUE_READY..=QUEUE_READY_END if version == 2 => {
+                (self.queue().map_or(0, |queue| queue.ready as u32), addr - QUEUE_READY)
+            }
+            INTERRUPT_STATUS..=INTERRUPT_STATUS_END => {
+                (self.interrupt_status, addr - INTERRUPT_STATUS)
+            }
+            STATUS..=STATUS_END => (self.status, addr - STATUS),
+            CONFIG_GENERATION..=CONFIG_GENERATION_END if version == 2 => {
+                (self.device.config_generation(), addr - CONFIG_GENERATION)
+            }
+            CONFIG..=CONFIG_END => {
+                let index = addr - CONFIG;
+                let mut bytes = [0; 4];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.device.read_config(index + i as u32);
+                }
+                (u32::from_le_bytes(bytes), 0)
+            }
+            _ => return Err(Exception::LoadIllegalAddress),
+        };
+
+        let value = match size {
+            Size::Byte => (reg >> (offset * 8)) & 0xff,
+            Size::Halfword => (reg >> (offset * 8)) & 0xffff,
+            Size::Word => reg >> (offset * 8),
+        };
+        Ok(value)
+    }
+
+    /// Stores `size`-bit data to a register located at `addr` in the virtio device.
+    fn write(&mut self, addr: u32, value: u32, size: Size) -> Result<(), Exception> {
+        let version = self.version;
+        // The configuration space is byte-addressed and owned by the device.
+        if let CONFIG..=CONFIG_END = addr {
+            let bytes = match size {
+                Size::Byte => 1,
+                Size::Halfword => 2,
+                Size::Word => 4,
+            };
+            for i in 0..bytes {
+                self.device.write_config(addr - CONFIG + i, (value >> (i * 8)) as u8);
+            }
+            return Ok(());
+        }
+        // Queue addresses are 64-bit but the bus is 32-bit, so the high words are ignored.
+        let negotiated = self.features_negotiated();
+        if version == 2 {
+            match addr {
+                QUEUE_DESC_LOW | QUEUE_DRIVER_LOW | QUEUE_DEVICE_LOW => {
+                    if let Some(queue) = self.queue().filter(|_| negotiated) {
+                        match addr {
+                            QUEUE_DESC_LOW => queue.desc_addr = value,
+                            QUEUE_DRIVER_LOW => queue.avail_addr = value,
+                            _ => queue.used_addr = value,
+                        }
+                    }
+                    return Ok(());
+                }
+                QUEUE_DESC_HIGH | QUEUE_DRIVER_HIGH | QUEUE_DEVICE_HIGH => return Ok(()),
+                _ => {}
+            }
+        }
+
+        // `reg` is the value of a target register in the virtio device and `offset` is the byte
+        // of the start position in the register.
+        let (mut reg, offset) = match addr {
+            DEVICE_FEATURES_SEL..=DEVICE_FEATURES_SEL_END => {
+                (self.device_features_sel, addr - DEVICE_FEATURES_SEL)
+            }
+            DRIVER_FEATURES..=DRIVER_FEATURES_END => {
+                let features = match self.driver_features_sel {
+                    0 => self.driver_features as u32,
+                    1 => (self.driver_features >> 32) as u32,
+                    _ => 0,
+                };
+                (features, addr - DRIVER_FEATURES)
+            }
+            DRIVER_FEATURES_SEL..=DRIVER_FEATURES_SEL_END => {
+                (self.driver_features_sel, addr - DRIVER_FEATURES_SEL)
+            }
+            GUEST_PAGE_SIZE..=GUEST_PAGE_SIZE_END if version == 1 => {
+                (self.guest_page_size, addr - GUEST_PAGE_SIZE)
+            }
+            QUEUE_SEL..=QUEUE_SEL_END => (self.queue_sel, addr - QUEUE_SEL),
+            QUEUE_NUM..=QUEUE_NUM_END => (self.queue().map_or(0, |queue| queue.num), addr - QUEUE_NUM),
+            QUEUE_ALIGN..=QUEUE_ALIGN_END if version == 1 => {
+                (self.queue().map_or(0, |queue| queue.align), addr - QUEUE_ALIGN)
+            }
+            QUEUE_PFN..=QUEUE_PFN_END if version == 1 => {
+                (self.queue().map_or(0, |queue| queue.pfn), addr - QUEUE_PFN)
+            }
+            QUEUE_READY..=QUEUE_READY_END if version == 2 => {
+                (self.queue().map_or(0, |queue| queue.ready as u32), addr - QUEUE_READY)
+            }
+            QUEUE_NOTIFY..=QUEUE_NOTIFY_END => (0, addr - QUEUE_NOTIFY),
+            INTERRUPT_ACK..=INTERRUPT_ACK_END => {
+                self.interrupt_status &= !(value << ((addr - INTERRUPT_ACK) * 8));
+                if self.interrupt_status == 0 {
+                    self.irq.lower();
+                }
+                return Ok(());
+            }
+            STATUS..=STATUS_END => (self.status, addr - STATUS),
+            _ => return Err(Exception::StoreIllegalAddress),
+        };
+
+        // Calculate the new value of the target register based on `size` and `offset`.
+        match size {
+            Size::Byte => {
+                // Clear the target byte.
+                reg = reg & (!(0xff << (offset * 8)));
+                // Set the new `value` to the target byte.
+                reg = reg | ((value & 0xff) << (offset * 8));
+            }
+            Size::Halfword => {
+                reg = reg & (!(0xffff << (offset * 8)));
+                reg = reg | ((value & 0xffff) << (offset * 8));
+            }
+            Size::Word => {
+                reg = value;
+            }
+        }
+
+        // Store the new register value to the target register.
+        match addr {
+            DEVICE_FEATURES_SEL..=DEVICE_FEATURES_SEL_END => self.device_features_sel = reg,
+            DRIVER_FEATURES..=DRIVER_FEATURES_END => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xffffffff) | reg as u64,
+                1 => self.driver_features = (self.driver_features & 0xffffffff) | (reg as u64) << 32,
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL..=DRIVER_FEATURES_SEL_END => self.driver_features_sel = reg,
+            GUEST_PAGE_SIZE..=GUEST_PAGE_SIZE_END => self.guest_page_size = reg,
+            QUEUE_SEL..=QUEUE_SEL_END => self.queue_sel = reg,
+            QUEUE_NUM..=QUEUE_NUM_END => {
+                if let Some(queue) = self.queue().filter(|_| negotiated) {
+                    queue.num = reg;
+                }
+            }
+            QUEUE_ALIGN..=QUEUE_ALIGN_END => {
+                // the used ring is aligned to a power of two, other values are ignored
+                if let Some(queue) = self.queue().filter(|_| reg.is_power_of_two()) {
+                    queue.align = reg;
+                }
+            }
+            QUEUE_PFN..=QUEUE_PFN_END => {
+                let page_size = self.guest_page_size;
+                if let Some(queue) = self.queue() {
+                    queue.pfn = reg;
+                    if !queue.legacy_layout(page_size) {
+                        self.needs_reset();
+                    }
+                }
+            }
+            QUEUE_READY..=QUEUE_READY_END => {
+                if let Some(queue) = self.queue().filter(|_| negotiated) {
+                    queue.ready = reg & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY..=QUEUE_NOTIFY_END => {
+                if let Some(notified) = self.notified.get_mut(reg as usize) {
+                    *notified = true;
+                }
+            }
+            STATUS..=STATUS_END => {
+                // "Writing 0 into this field resets the device."
+                if reg == 0 {
+                    self.status = 0;
+                    self.reset();
+                    return Ok(());
+                }
+                // 3.1.1: the device clears FEATURES_OK if it does not support the features
+                // the driver accepted.
+                if reg & STATUS_FEATURES_OK != 0 && self.status & STATUS_FEATURES_OK == 0 {
+                    if self.features_acceptable() {
+                        self.device.ack_features(self.driver_features);
+                    } else {
+                        reg &= !STATUS_FEATURES_OK;
+                    }
+                }
+                // Legacy drivers have no FEATURES_OK step, their features are final at DRIVER_OK.
+                if self.version == 1 && reg & STATUS_DRIVER_OK != 0 && self.status & STATUS_DRIVER_OK == 0 {
+                    self.device.ack_features(self.driver_features);
+                }
+                if self.version == 2 && reg & STATUS_FEATURES_OK == 0 {
+                    reg &= !STATUS_DRIVER_OK;
+                }
+                // DEVICE_NEEDS_RESET is the device's, only a reset clears it
+                self.status = reg | self.status & STATUS_DEVICE_NEEDS_RESET;
+                // FAILED (128) bit. Indicates that something went wrong in the guest.
+                if self.status & STATUS_FAILED != 0 {
+                    eprintln!("virtio: driver set status FAILED");
+                }
+            }
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+
+        Ok(())
+    }
+}
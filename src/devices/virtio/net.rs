@@ -0,0 +1,113 @@
+//! The net module implements a virtio network device on top of a host `NetBackend`.
+//!
+//! 5.1 Network Device:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1940001
+
+use crate::devices::device::Device;
+use crate::devices::netdev::NetBackend;
+use crate::exception::Exception;
+
+use super::queue::Virtqueue;
+use super::{VirtioDevice, VIRTIO_F_VERSION_1};
+
+// 5.1.3 Feature bits
+/// Device has given MAC address.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+/// Size of `virtio_net_hdr` for legacy drivers. "The legacy driver only presented num_buffers in
+/// the struct virtio_net_hdr when VIRTIO_NET_F_MRG_RXBUF was negotiated; without that feature the
+/// structure was 2 bytes shorter."
+const LEGACY_HEADER_SIZE: usize = 10;
+const HEADER_SIZE: usize = 12;
+
+pub struct Net {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    header_size: usize,
+    /// A received frame waiting for a buffer in the receiveq.
+    pending: Option<Vec<u8>>,
+}
+
+impl Net {
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> Self {
+        Self { mac, backend, header_size: LEGACY_HEADER_SIZE, pending: None }
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_id(&self) -> u32 {
+        1
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC
+    }
+
+    fn ack_features(&mut self, features: u64) {
+        self.header_size = if features & VIRTIO_F_VERSION_1 != 0 { HEADER_SIZE } else { LEGACY_HEADER_SIZE };
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    /// 5.1.4 Device configuration layout
+    /// ```c
+    /// struct virtio_net_config {
+    ///   u8 mac[6];
+    ///   le16 status;
+    ///   le16 max_virtqueue_pairs;
+    ///   le16 mtu;
+    /// };
+    /// ```
+    fn read_config(&self, offset: u32) -> u8 {
+        *self.mac.get(offset as usize).unwrap_or(&0)
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut dyn Device) -> Result<(), Exception> {
+        // buffers offered in the receiveq are filled when frames arrive
+        if queue != TRANSMITQ {
+            return Ok(());
+        }
+        while let Some(chain) = queues[TRANSMITQ].pop(mem)? {
+            // 5.1.6.2 Packet Transmission: the header comes first, no offloads are offered
+            let packet = chain.read(mem)?;
+            if packet.len() > self.header_size {
+                self.backend.send(&packet[self.header_size..]);
+            }
+            queues[TRANSMITQ].push(mem, &chain, 0)?;
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], mem: &mut dyn Device) -> Result<(), Exception> {
+        loop {
+            if self.pending.is_none() {
+                self.pending = self.backend.recv();
+            }
+            let Some(frame) = &self.pending else {
+                return Ok(());
+            };
+            let Some(chain) = queues[RECEIVEQ].pop(mem)? else {
+                return Ok(());
+            };
+            // 5.1.6.4 Processing of Incoming Packets: a zeroed header, num_buffers is 1
+            let mut packet = vec![0; self.header_size];
+            if self.header_size == HEADER_SIZE {
+                packet[10] = 1;
+            }
+            packet.extend_from_slice(frame);
+            let len = chain.write(mem, &packet)?;
+            queues[RECEIVEQ].push(mem, &chain, len)?;
+            self.pending = None;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.header_size = LEGACY_HEADER_SIZE;
+        self.pending = None;
+    }
+}
@@ -0,0 +1,567 @@
+//! The p9 module implements a virtio 9P transport device serving a host directory with the
+//! 9P2000.L protocol, so the guest can mount it (`mount -t 9p -o trans=virtio,version=9p2000.L`).
+//!
+//! The 9P transport is device ID 9 but is not described in the 1.1 spec. The 9P2000.L messages
+//! are documented in https://github.com/chaos/diod/blob/master/protocol.md.
+//!
+//! Walks cannot leave the shared directory through `..`. Symbolic links are the guest's to follow:
+//! the host only resolves links in the directories leading to a file, refuses paths that resolve
+//! outside the shared directory, and does not open a link itself.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::devices::device::Device;
+use crate::exception::Exception;
+
+use super::queue::Virtqueue;
+use super::VirtioDevice;
+
+/// The mount tag is in the configuration space.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+const PROTOCOL_VERSION: &str = "9P2000.L";
+/// Largest message size offered to the driver.
+const MAX_MSIZE: u32 = 0x10000;
+/// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+
+// Message types. Each R-message is its T-message plus one.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+// Linux errno values carried by Rlerror.
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const EINVAL: u32 = 22;
+const EROFS: u32 = 30;
+const ELOOP: u32 = 40;
+const EPROTO: u32 = 71;
+const EOPNOTSUPP: u32 = 95;
+
+// Flags of Tlopen and Tlcreate, Linux values.
+const O_ACCMODE: u32 = 3;
+const O_RDONLY: u32 = 0;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+// Tsetattr valid bits.
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_SIZE: u32 = 0x8;
+
+/// Tunlinkat flag to remove a directory.
+const AT_REMOVEDIR: u32 = 0x200;
+
+// Qid types.
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0;
+
+// Directory entry types.
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+/// The file system magic number reported by Rstatfs.
+const V9FS_MAGIC: u32 = 0x01021997;
+
+/// A file the driver refers to by number.
+struct Fid {
+    /// Path relative to the shared directory.
+    path: PathBuf,
+    file: Option<File>,
+    /// Directory entries as `(qid, type, name)`, read by the first Treaddir.
+    entries: Option<Vec<([u8; 13], u8, String)>>,
+}
+
+pub struct P9 {
+    root: PathBuf,
+    tag: String,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+/// Reads the fields of a T-message.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], u32> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(EPROTO)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> Result<u8, u32> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, u32> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+    fn u32(&mut self) -> Result<u32, u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> Result<u64, u32> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+    /// s: len[2] followed by UTF-8 text.
+    fn string(&mut self) -> Result<String, u32> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| EINVAL)
+    }
+}
+
+/// Appends R-message fields.
+trait Writer {
+    fn put_u16(&mut self, value: u16);
+    fn put_u32(&mut self, value: u32);
+    fn put_u64(&mut self, value: u64);
+    fn put_string(&mut self, value: &str);
+}
+
+impl Writer for Vec<u8> {
+    fn put_u16(&mut self, value: u16) {
+        self.extend_from_slice(&value.to_le_bytes());
+    }
+    fn put_u32(&mut self, value: u32) {
+        self.extend_from_slice(&value.to_le_bytes());
+    }
+    fn put_u64(&mut self, value: u64) {
+        self.extend_from_slice(&value.to_le_bytes());
+    }
+    fn put_string(&mut self, value: &str) {
+        self.put_u16(value.len() as u16);
+        self.extend_from_slice(value.as_bytes());
+    }
+}
+
+fn errno(e: io::Error) -> u32 {
+    e.raw_os_error().map_or(EIO, |code| code as u32)
+}
+
+/// qid: type[1] version[4] path[8]. The inode number identifies the file.
+fn qid(meta: &fs::Metadata) -> [u8; 13] {
+    let mut qid = [0; 13];
+    qid[0] = if meta.is_dir() {
+        QTDIR
+    } else if meta.file_type().is_symlink() {
+        QTSYMLINK
+    } else {
+        QTFILE
+    };
+    qid[5..].copy_from_slice(&meta.ino().to_le_bytes());
+    qid
+}
+
+fn dirent_type(meta: &fs::Metadata) -> u8 {
+    if meta.is_dir() {
+        DT_DIR
+    } else if meta.file_type().is_symlink() {
+        DT_LNK
+    } else {
+        DT_REG
+    }
+}
+
+/// Refuses to follow a link at `path` on the host.
+fn not_link(path: &Path) -> Result<(), u32> {
+    if fs::symlink_metadata(path).map_err(errno)?.file_type().is_symlink() {
+        return Err(ELOOP);
+    }
+    Ok(())
+}
+
+/// A single path component: no separators and nothing that walks elsewhere.
+fn check_name(name: &str) -> Result<(), u32> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(EINVAL);
+    }
+    Ok(())
+}
+
+impl P9 {
+    pub fn new(root: &str, tag: &str, read_only: bool) -> io::Result<Self> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "shared path is not a directory"));
+        }
+        Ok(Self { root, tag: tag.to_string(), read_only, msize: MAX_MSIZE, fids: HashMap::new() })
+    }
+
+    fn fid(&mut self, fid: u32) -> Result<&mut Fid, u32> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    /// Resolves `path` in the share with every link followed, as long as it stays inside.
+    fn resolve(&self, path: &Path) -> Result<PathBuf, u32> {
+        let resolved = fs::canonicalize(self.root.join(path)).map_err(errno)?;
+        if !resolved.starts_with(&self.root) {
+            return Err(EACCES);
+        }
+        Ok(resolved)
+    }
+
+    /// The host path of `path` in the share. The directories leading to it are resolved, its last
+    /// component is left alone so a link stays a link.
+    fn locate(&self, path: &Path) -> Result<PathBuf, u32> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok(self.resolve(parent)?.join(name)),
+            _ => Ok(self.root.clone()),
+        }
+    }
+
+    fn host_path(&self, fid: u32) -> Result<PathBuf, u32> {
+        self.locate(&self.fids.get(&fid).ok_or(EBADF)?.path)
+    }
+
+    /// The host path of `fid` for opening or changing the file, which must not be a link.
+    fn file_path(&self, fid: u32) -> Result<PathBuf, u32> {
+        let path = self.host_path(fid)?;
+        not_link(&path)?;
+        Ok(path)
+    }
+
+    /// The host path of entry `name` in directory `fid`.
+    fn entry_path(&self, fid: u32, name: &str) -> Result<PathBuf, u32> {
+        check_name(name)?;
+        Ok(self.resolve(&self.fids.get(&fid).ok_or(EBADF)?.path)?.join(name))
+    }
+
+    fn writable(&self) -> Result<(), u32> {
+        if self.read_only {
+            return Err(EROFS);
+        }
+        Ok(())
+    }
+
+    /// Serves one T-message and returns the R-message, which must fit in `max` bytes.
+    fn handle(&mut self, request: &[u8], max: usize) -> Vec<u8> {
+        let mut r = Reader { data: request, pos: 0 };
+        let header = (r.u32(), r.u8(), r.u16());
+        let (Ok(_), Ok(kind), Ok(tag)) = header else {
+            return Vec::new();
+        };
+        let (kind, body) = match self.op(kind, &mut r, max.saturating_sub(HEADER_SIZE)) {
+            Ok(body) => (kind + 1, body),
+            Err(code) => (RLERROR, code.to_le_bytes().to_vec()),
+        };
+        let mut response = Vec::with_capacity(HEADER_SIZE + body.len());
+        response.put_u32((HEADER_SIZE + body.len()) as u32);
+        response.push(kind);
+        response.put_u16(tag);
+        response.extend_from_slice(&body);
+        response
+    }
+
+    /// Executes a T-message and returns the body of the R-message.
+    fn op(&mut self, kind: u8, r: &mut Reader, max: usize) -> Result<Vec<u8>, u32> {
+        let mut body = Vec::new();
+        match kind {
+            TVERSION => {
+                // a new session: everything from the old one is forgotten
+                self.msize = r.u32()?.min(MAX_MSIZE);
+                let version = r.string()?;
+                self.fids.clear();
+                body.put_u32(self.msize);
+                body.put_string(if version.starts_with(PROTOCOL_VERSION) { PROTOCOL_VERSION } else { "unknown" });
+            }
+            TATTACH => {
+                let fid = r.u32()?;
+                let meta = fs::metadata(&self.root).map_err(errno)?;
+                self.fids.insert(fid, Fid { path: PathBuf::new(), file: None, entries: None });
+                body.extend_from_slice(&qid(&meta));
+            }
+            TFLUSH => {}
+            TWALK => {
+                let (fid, newfid, count) = (r.u32()?, r.u32()?, r.u16()?);
+                let mut path = self.fid(fid)?.path.clone();
+                let mut qids = Vec::new();
+                for _ in 0..count {
+                    match r.string()?.as_str() {
+                        ".." => {
+                            path.pop();
+                        }
+                        "." => {}
+                        name => {
+                            check_name(name)?;
+                            path.push(name);
+                        }
+                    }
+                    match self.locate(&path).and_then(|path| fs::symlink_metadata(path).map_err(errno)) {
+                        Ok(meta) => qids.push(qid(&meta)),
+                        Err(code) if qids.is_empty() => return Err(code),
+                        // a partial walk reports how far it got and leaves newfid alone
+                        Err(_) => break,
+                    }
+                }
+                if qids.len() == count as usize {
+                    self.fids.insert(newfid, Fid { path, file: None, entries: None });
+                }
+                body.put_u16(qids.len() as u16);
+                qids.iter().for_each(|qid| body.extend_from_slice(qid));
+            }
+            TGETATTR => {
+                let fid = r.u32()?;
+                let meta = fs::symlink_metadata(self.host_path(fid)?).map_err(errno)?;
+                // P9_GETATTR_BASIC
+                body.put_u64(0x7ff);
+                body.extend_from_slice(&qid(&meta));
+                body.put_u32(meta.mode());
+                body.put_u32(meta.uid());
+                body.put_u32(meta.gid());
+                body.put_u64(meta.nlink());
+                body.put_u64(meta.rdev());
+                body.put_u64(meta.size());
+                body.put_u64(meta.blksize());
+                body.put_u64(meta.blocks());
+                body.put_u64(meta.atime() as u64);
+                body.put_u64(meta.atime_nsec() as u64);
+                body.put_u64(meta.mtime() as u64);
+                body.put_u64(meta.mtime_nsec() as u64);
+                body.put_u64(meta.ctime() as u64);
+                body.put_u64(meta.ctime_nsec() as u64);
+                // btime, gen and data_version are not reported
+                body.extend_from_slice(&[0; 32]);
+            }
+            TSETATTR => {
+                let (fid, valid, mode) = (r.u32()?, r.u32()?, r.u32()?);
+                let _uid_gid = (r.u32()?, r.u32()?);
+                let size = r.u64()?;
+                self.writable()?;
+                let path = self.file_path(fid)?;
+                if valid & SETATTR_MODE != 0 {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+                }
+                if valid & SETATTR_SIZE != 0 {
+                    OpenOptions::new().write(true).open(&path).and_then(|file| file.set_len(size)).map_err(errno)?;
+                }
+                // ownership and times stay as the host sets them
+            }
+            TSTATFS => {
+                let fid = r.u32()?;
+                self.fid(fid)?;
+                // std has no statvfs, the numbers only need to look sane to df
+                body.put_u32(V9FS_MAGIC);
+                body.put_u32(4096);
+                body.put_u64(1 << 20);
+                body.put_u64(1 << 19);
+                body.put_u64(1 << 19);
+                body.put_u64(1 << 20);
+                body.put_u64(1 << 19);
+                body.put_u64(0);
+                body.put_u32(255);
+            }
+            TLOPEN => {
+                let (fid, flags) = (r.u32()?, r.u32()?);
+                if flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0 {
+                    self.writable()?;
+                }
+                let path = self.file_path(fid)?;
+                let meta = fs::metadata(&path).map_err(errno)?;
+                let file = if meta.is_dir() {
+                    None
+                } else {
+                    Some(open_options(flags).open(&path).map_err(errno)?)
+                };
+                let entry = self.fid(fid)?;
+                entry.file = file;
+                entry.entries = None;
+                body.extend_from_slice(&qid(&meta));
+                body.put_u32(0);
+            }
+            TLCREATE => {
+                let (fid, name, flags, mode, _gid) = (r.u32()?, r.string()?, r.u32()?, r.u32()?, r.u32()?);
+                self.writable()?;
+                let path = self.entry_path(fid, &name)?;
+                // creating through a dangling link would make its target
+                if path.symlink_metadata().is_ok_and(|meta| meta.file_type().is_symlink()) {
+                    return Err(ELOOP);
+                }
+                let file = open_options(flags).create(true).mode(mode & 0o7777).open(&path).map_err(errno)?;
+                let meta = file.metadata().map_err(errno)?;
+                let entry = self.fid(fid)?;
+                entry.path.push(&name);
+                entry.file = Some(file);
+                body.extend_from_slice(&qid(&meta));
+                body.put_u32(0);
+            }
+            TMKDIR => {
+                let (fid, name, mode, _gid) = (r.u32()?, r.string()?, r.u32()?, r.u32()?);
+                self.writable()?;
+                let path = self.entry_path(fid, &name)?;
+                fs::create_dir(&path).map_err(errno)?;
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+                body.extend_from_slice(&qid(&fs::metadata(&path).map_err(errno)?));
+            }
+            TUNLINKAT => {
+                let (fid, name, flags) = (r.u32()?, r.string()?, r.u32()?);
+                self.writable()?;
+                let path = self.entry_path(fid, &name)?;
+                if flags & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(&path).map_err(errno)?;
+                } else {
+                    fs::remove_file(&path).map_err(errno)?;
+                }
+            }
+            TRENAMEAT => {
+                let (old_fid, old_name, new_fid, new_name) = (r.u32()?, r.string()?, r.u32()?, r.string()?);
+                self.writable()?;
+                let from = self.entry_path(old_fid, &old_name)?;
+                let to = self.entry_path(new_fid, &new_name)?;
+                fs::rename(from, to).map_err(errno)?;
+            }
+            TREADLINK => {
+                let fid = r.u32()?;
+                let target = fs::read_link(self.host_path(fid)?).map_err(errno)?;
+                body.put_string(&target.to_string_lossy());
+            }
+            TREAD => {
+                let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+                let count = (count as usize).min(max.saturating_sub(4));
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                let mut data = vec![0; count];
+                let len = file.read_at(&mut data, offset).map_err(errno)?;
+                body.put_u32(len as u32);
+                body.extend_from_slice(&data[..len]);
+            }
+            TWRITE => {
+                let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+                let data = r.bytes(count as usize)?;
+                self.writable()?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                let len = file.write_at(data, offset).map_err(errno)?;
+                body.put_u32(len as u32);
+            }
+            TREADDIR => {
+                let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+                let count = (count as usize).min(max.saturating_sub(4));
+                let path = self.file_path(fid)?;
+                let entry = self.fid(fid)?;
+                if entry.entries.is_none() || offset == 0 {
+                    entry.entries = Some(read_dir(&path).map_err(errno)?);
+                }
+                // entry offsets are indices plus one, the offset of the next entry
+                let mut data = Vec::new();
+                for (i, (qid, kind, name)) in entry.entries.as_ref().unwrap().iter().enumerate().skip(offset as usize) {
+                    // qid[13] offset[8] type[1] name[s]
+                    if data.len() + 13 + 8 + 1 + 2 + name.len() > count {
+                        break;
+                    }
+                    data.extend_from_slice(qid);
+                    data.put_u64(i as u64 + 1);
+                    data.push(*kind);
+                    data.put_string(name);
+                }
+                body.put_u32(data.len() as u32);
+                body.extend_from_slice(&data);
+            }
+            TFSYNC => {
+                let fid = r.u32()?;
+                if let Some(file) = &self.fid(fid)?.file {
+                    file.sync_all().map_err(errno)?;
+                }
+            }
+            TCLUNK => {
+                let fid = r.u32()?;
+                self.fids.remove(&fid).ok_or(EBADF)?;
+            }
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(body)
+    }
+}
+
+fn open_options(flags: u32) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    match flags & O_ACCMODE {
+        O_RDONLY => options.read(true),
+        1 => options.write(true),
+        _ => options.read(true).write(true),
+    };
+    options.truncate(flags & O_TRUNC != 0).append(flags & O_APPEND != 0);
+    options
+}
+
+/// Lists a directory, `.` and `..` first and the rest sorted so listings are reproducible.
+fn read_dir(path: &Path) -> io::Result<Vec<([u8; 13], u8, String)>> {
+    let meta = fs::metadata(path)?;
+    let mut entries = vec![(qid(&meta), DT_DIR, String::from(".")), (qid(&meta), DT_DIR, String::from(".."))];
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.path().symlink_metadata()?;
+        names.push((qid(&meta), dirent_type(&meta), entry.file_name().to_string_lossy().into_owned()));
+    }
+    names.sort_by(|a, b| a.2.cmp(&b.2));
+    entries.extend(names);
+    Ok(entries)
+}
+
+impl VirtioDevice for P9 {
+    fn device_id(&self) -> u32 {
+        9
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    /// ```c
+    /// struct virtio_9p_config {
+    ///   le16 tag_len;
+    ///   u8 tag[];
+    /// };
+    /// ```
+    fn read_config(&self, offset: u32) -> u8 {
+        let tag = self.tag.as_bytes();
+        match offset {
+            0..=1 => (tag.len() as u16).to_le_bytes()[offset as usize],
+            _ => *tag.get(offset as usize - 2).unwrap_or(&0),
+        }
+    }
+
+    /// Each request is a T-message in the device-readable buffers, answered with an R-message in
+    /// the device-writable ones.
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut dyn Device) -> Result<(), Exception> {
+        while let Some(chain) = queues[queue].pop(mem)? {
+            let request = chain.read(mem)?;
+            let max = (chain.writable_len() as usize).min(self.msize as usize);
+            let response = self.handle(&request, max);
+            let len = chain.write(mem, &response)?;
+            queues[queue].push(mem, &chain, len)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.msize = MAX_MSIZE;
+        self.fids.clear();
+    }
+}
@@ -0,0 +1,265 @@
+// The code is from https://github.com/d0iasm/rvemu.
+
+//! Split virtqueues, shared by every virtio device.
+//!
+//! 2.6 Split Virtqueues:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-230005
+
+use crate::cpu::Size;
+use crate::devices::device::Device;
+use crate::exception::Exception;
+
+/// The size of `VRingDesc` struct.
+const VRING_DESC_SIZE: u32 = 16;
+/// The maximum number of descriptors in a queue. It must be a power of two.
+pub const QUEUE_SIZE: u32 = 8;
+/// The longest buffer a descriptor may describe.
+pub const MAX_DESC_LEN: u32 = 0x100000;
+/// The most a chain may hold in total, so the guest cannot make the host allocate gigabytes.
+pub const MAX_CHAIN_LEN: u32 = QUEUE_SIZE * MAX_DESC_LEN;
+
+/// This marks a buffer as continuing via the next field.
+const VIRTQ_DESC_F_NEXT: u32 = 1;
+/// This marks a buffer as device write-only (otherwise device read-only).
+const VIRTQ_DESC_F_WRITE: u32 = 2;
+/// This means the buffer contains a list of buffer descriptors.
+const _VIRTQ_DESC_F_INDIRECT: u32 = 4;
+/// The driver does not want an interrupt when a buffer is used.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u32 = 1;
+
+/// "Each virtqueue can consist of up to 3 parts:
+///     Descriptor Area - used for describing buffers
+///     Driver Area - extra data supplied by driver to the device
+///     Device Area - extra data supplied by device to driver"
+/// "Note: Note that previous versions of this spec used different names for these parts
+///     Descriptor Table - for the Descriptor Area
+///     Available Ring - for the Driver Area
+///     Used Ring - for the Device Area"
+#[derive(Debug, Clone, Default)]
+pub struct Virtqueue {
+    /// Queue size chosen by the driver.
+    pub num: u32,
+    pub ready: bool,
+    /// Guest-physical address of the descriptor table.
+    pub desc_addr: u32,
+    /// Guest-physical address of the available ring.
+    pub avail_addr: u32,
+    /// Guest-physical address of the used ring.
+    pub used_addr: u32,
+    /// Legacy interface: used ring alignment and page number of the whole queue.
+    pub align: u32,
+    pub pfn: u32,
+    /// The next available ring entry to consume.
+    last_avail: u16,
+    /// The next used ring entry to fill.
+    used_idx: u16,
+    /// Buffers were used since the transport last checked.
+    used: bool,
+}
+
+/// "The descriptor table refers to the buffers the driver is using for the device. addr is a
+/// physical address, and the buffers can be chained via next. Each descriptor describes a buffer
+/// which is read-only for the device (“device-readable”) or write-only for the device
+/// (“device-writable”), but a chain of descriptors can contain both device-readable and
+/// device-writable buffers."
+///
+/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-320005
+///
+/// ```c
+/// struct virtq_desc {
+///   le64 addr;
+///   le32 len;
+///   le16 flags;
+///   le16 next;
+/// };
+/// ```
+#[derive(Debug)]
+struct VirtqDesc {
+    /// Address (guest-physical). Only the low word of the 64-bit address is used.
+    addr: u32,
+    /// Length.
+    len: u32,
+    /// The flags as indicated VIRTQ_DESC_F_NEXT/VIRTQ_DESC_F_WRITE/VIRTQ_DESC_F_INDIRECT.
+    flags: u32,
+    /// Next field if flags & NEXT.
+    next: u32,
+}
+
+impl VirtqDesc {
+    /// Creates a new virtqueue descriptor based on the address that stores the content of the
+    /// descriptor.
+    fn new(mem: &mut dyn Device, addr: u32) -> Result<Self, Exception> {
+        Ok(Self {
+            addr: mem.read(addr, Size::Word)?,
+            len: mem.read(addr.wrapping_add(8), Size::Word)?,
+            flags: mem.read(addr.wrapping_add(12), Size::Halfword)?,
+            next: mem.read(addr.wrapping_add(14), Size::Halfword)?,
+        })
+    }
+}
+
+/// A descriptor chain taken from the available ring, split into the buffers the device reads and
+/// the ones it writes, as `(addr, len)` pairs.
+#[derive(Debug)]
+pub struct DescChain {
+    pub head: u32,
+    pub readable: Vec<(u32, u32)>,
+    pub writable: Vec<(u32, u32)>,
+}
+
+impl DescChain {
+    /// Concatenates the device-readable buffers.
+    pub fn read(&self, mem: &mut dyn Device) -> Result<Vec<u8>, Exception> {
+        let mut data = Vec::new();
+        for &(addr, len) in &self.readable {
+            for i in 0..len {
+                data.push(mem.read(addr.wrapping_add(i), Size::Byte)? as u8);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Scatters `data` over the device-writable buffers and returns the number of bytes written.
+    pub fn write(&self, mem: &mut dyn Device, data: &[u8]) -> Result<u32, Exception> {
+        let mut bytes = data.iter();
+        let mut written = 0;
+        for &(addr, len) in &self.writable {
+            for i in 0..len {
+                let Some(&byte) = bytes.next() else {
+                    return Ok(written);
+                };
+                mem.write(addr.wrapping_add(i), byte as u32, Size::Byte)?;
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    pub fn writable_len(&self) -> u32 {
+        self.writable.iter().fold(0, |total: u32, &(_, len)| total.saturating_add(len))
+    }
+}
+
+impl Virtqueue {
+    pub fn new() -> Self {
+        // default value to avoid division by 0.
+        Self { align: 0x1000, ..Self::default() }
+    }
+
+    /// Computes the ring addresses of a legacy queue from its page number. Returns false and
+    /// leaves the queue off if the rings do not fit below 4 GiB.
+    pub fn legacy_layout(&mut self, guest_page_size: u32) -> bool {
+        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-240006
+        // Virtqueue Part   | Alignment | Size
+        // -------------------------------------------------
+        // Descriptor Table | 16        | 16∗(Queue Size)
+        // Available Ring   | 2         | 6 + 2∗(Queue Size)
+        // Used Ring        | 4         | 6 + 8∗(Queue Size)
+        let size = self.size();
+        let layout = self.pfn.checked_mul(guest_page_size).and_then(|base_addr| {
+            let avail_addr = base_addr.checked_add(VRING_DESC_SIZE * size)?;
+            let avail_ring_end = avail_addr.checked_add(6 + 2 * size)?;
+            // Used ring starts with the `queue_align` boundary after the available ring ends.
+            let used_addr = avail_ring_end.checked_next_multiple_of(self.align)?;
+            used_addr.checked_add(6 + 8 * size)?;
+            Some((base_addr, avail_addr, used_addr))
+        });
+        let Some((desc_addr, avail_addr, used_addr)) = layout else {
+            self.ready = false;
+            return false;
+        };
+        self.desc_addr = desc_addr;
+        self.avail_addr = avail_addr;
+        self.used_addr = used_addr;
+        self.ready = self.pfn != 0;
+        true
+    }
+
+    /// Forgets the queue configuration and progress.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn size(&self) -> u32 {
+        self.num.clamp(1, QUEUE_SIZE)
+    }
+
+    /// Takes the next descriptor chain the driver made available, if any.
+    ///
+    /// ```c
+    /// struct virtq_avail {
+    ///   le16 flags;
+    ///   le16 idx;
+    ///   le16 ring[ /* Queue Size */ ];
+    ///   le16 used_event; /* Only if VIRTIO_F_EVENT_IDX */
+    /// };
+    /// ```
+    pub fn pop(&mut self, mem: &mut dyn Device) -> Result<Option<DescChain>, Exception> {
+        if !self.ready {
+            return Ok(None);
+        }
+        let avail_idx = mem.read(self.avail_addr.wrapping_add(2), Size::Halfword)? as u16;
+        if self.last_avail == avail_idx {
+            return Ok(None);
+        }
+        let ring_entry = self.avail_addr.wrapping_add(4 + 2 * (self.last_avail as u32 % self.size()));
+        let head = mem.read(ring_entry, Size::Halfword)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = DescChain { head, readable: Vec::new(), writable: Vec::new() };
+        let mut index = head;
+        let mut total: u32 = 0;
+        for _ in 0..self.size() {
+            let desc = VirtqDesc::new(mem, self.desc_addr.wrapping_add(VRING_DESC_SIZE * index))?;
+            total = total.saturating_add(desc.len);
+            if desc.len > MAX_DESC_LEN || total > MAX_CHAIN_LEN {
+                return Err(Exception::DataBusError);
+            }
+            if desc.flags & VIRTQ_DESC_F_WRITE == 0 {
+                chain.readable.push((desc.addr, desc.len));
+            } else {
+                chain.writable.push((desc.addr, desc.len));
+            }
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(Some(chain));
+            }
+            index = desc.next % self.size();
+        }
+        // a chain longer than the queue must contain a loop
+        Err(Exception::DataBusError)
+    }
+
+    /// Returns a chain to the driver through the used ring.
+    ///
+    /// ```c
+    /// struct virtq_used {
+    ///   le16 flags;
+    ///   le16 idx;
+    ///   struct virtq_used_elem ring[ /* Queue Size */];
+    ///   le16 avail_event; /* Only if VIRTIO_F_EVENT_IDX */
+    /// };
+    /// struct virtq_used_elem {
+    ///   le32 id;
+    ///   le32 len;
+    /// };
+    /// ```
+    pub fn push(&mut self, mem: &mut dyn Device, chain: &DescChain, len: u32) -> Result<(), Exception> {
+        let elem = self.used_addr.wrapping_add(4 + 8 * (self.used_idx as u32 % self.size()));
+        mem.write(elem, chain.head, Size::Word)?;
+        mem.write(elem.wrapping_add(4), len, Size::Word)?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        self.used = true;
+        mem.write(self.used_addr.wrapping_add(2), self.used_idx as u32, Size::Halfword)
+    }
+
+    /// Returns whether buffers were used since the last call.
+    pub fn take_used(&mut self) -> bool {
+        std::mem::take(&mut self.used)
+    }
+
+    /// 2.6.7.2 Device Requirements: Used Buffer Notification Suppression
+    /// "If flags is 1, the device SHOULD NOT send a notification."
+    pub fn interrupt_suppressed(&self, mem: &mut dyn Device) -> Result<bool, Exception> {
+        Ok(mem.read(self.avail_addr, Size::Halfword)? & VIRTQ_AVAIL_F_NO_INTERRUPT != 0)
+    }
+}
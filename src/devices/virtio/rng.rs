@@ -0,0 +1,93 @@
+//! The rng module implements a virtio entropy device.
+//!
+//! 5.4 Entropy Device:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2880004
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use crate::devices::device::Device;
+use crate::exception::Exception;
+
+use super::queue::Virtqueue;
+use super::VirtioDevice;
+
+/// Where the entropy comes from.
+enum Source {
+    /// The host's /dev/urandom.
+    Host(File),
+    /// A splitmix64 generator, so runs are reproducible.
+    Seeded(u64),
+}
+
+pub struct Rng {
+    source: Source,
+    seed: Option<u64>,
+}
+
+impl Rng {
+    /// Uses host entropy, or a generator started from `seed` if given.
+    pub fn new(seed: Option<u64>) -> io::Result<Self> {
+        let source = match seed {
+            Some(seed) => Source::Seeded(seed),
+            None => Source::Host(File::open("/dev/urandom")?),
+        };
+        Ok(Self { source, seed })
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        match &mut self.source {
+            Source::Host(file) => file.read_exact(buf).expect("cannot read /dev/urandom"),
+            Source::Seeded(state) => {
+                for chunk in buf.chunks_mut(8) {
+                    // https://prng.di.unimi.it/splitmix64.c
+                    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            }
+        }
+    }
+}
+
+impl VirtioDevice for Rng {
+    fn device_id(&self) -> u32 {
+        4
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    /// The entropy device has no configuration space.
+    fn read_config(&self, _offset: u32) -> u8 {
+        0
+    }
+
+    /// 5.4.6 Device Operation: "When the driver requires random bytes, it places the descriptor of
+    /// one or more buffers in the queue. It will be completely filled by random data by the
+    /// device."
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut dyn Device) -> Result<(), Exception> {
+        while let Some(chain) = queues[queue].pop(mem)? {
+            let mut data = vec![0; chain.writable_len() as usize];
+            self.fill(&mut data);
+            let len = chain.write(mem, &data)?;
+            queues[queue].push(mem, &chain, len)?;
+        }
+        Ok(())
+    }
+
+    /// A reset replays the same sequence in seeded mode.
+    fn reset(&mut self) {
+        if let Some(seed) = self.seed {
+            self.source = Source::Seeded(seed);
+        }
+    }
+}
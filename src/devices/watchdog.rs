@@ -0,0 +1,173 @@
+//! The watchdog module implements a watchdog timer. Once enabled, the guest has to kick it
+//! within the timeout, measured in emulated time, or the machine resets, takes an NMI or halts
+//! with a report, depending on how the emulator was started.
+
+use crate::coprocessor::CYCLES_PER_SECOND;
+use crate::cpu::Size;
+use crate::exception::Exception;
+
+use super::device::Device;
+
+/// Bit 0 enables the watchdog. Enabling it starts a new timeout.
+const CONTROL: u32 = 0x00;
+/// Timeout in milliseconds.
+const TIMEOUT: u32 = 0x04;
+/// Any write starts a new timeout.
+const KICK: u32 = 0x08;
+/// Milliseconds left, rounded up.
+const REMAINING: u32 = 0x0c;
+/// Bit 0 is set when the watchdog expired, writing 1 clears it. It survives the reset so the
+/// guest can tell why it restarted.
+const STATUS: u32 = 0x10;
+
+/// Timeout until the guest programs another one.
+pub const DEFAULT_TIMEOUT_MS: u32 = 1000;
+/// Exit status of a halt by the watchdog, like timeout(1).
+pub const WATCHDOG_EXIT_STATUS: u32 = 124;
+
+/// What happens when the watchdog expires.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Soft reset of the CPU.
+    Reset,
+    /// A non-maskable interrupt, the handler finds Status.NMI set.
+    Nmi,
+    /// Stop with a report and exit with `WATCHDOG_EXIT_STATUS`.
+    Halt,
+}
+
+impl Action {
+    /// Parses `reset`, `nmi` or `halt`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        match spec {
+            "reset" => Some(Action::Reset),
+            "nmi" => Some(Action::Nmi),
+            "halt" => Some(Action::Halt),
+            _ => None,
+        }
+    }
+}
+
+pub struct Watchdog {
+    action: Action,
+    enabled: bool,
+    timeout_ms: u32,
+    /// Cycles until expiry while enabled.
+    remaining: u64,
+    expired: bool,
+    /// The expiry the CPU has not acted on yet.
+    pending: bool,
+    /// Emulated time since the machine started, for the report.
+    cycles: u64,
+    last_kick: u64,
+    kicks: u64,
+}
+
+impl Watchdog {
+    /// A watchdog taking `action` on expiry. With `armed`, it runs from power-on with that
+    /// timeout, before the guest has set it up.
+    pub fn new(action: Action, armed: Option<u32>) -> Self {
+        let mut watchdog = Self {
+            action,
+            enabled: false,
+            timeout_ms: armed.unwrap_or(DEFAULT_TIMEOUT_MS),
+            remaining: 0,
+            expired: false,
+            pending: false,
+            cycles: 0,
+            last_kick: 0,
+            kicks: 0,
+        };
+        if armed.is_some() {
+            watchdog.enabled = true;
+            watchdog.kick();
+        }
+        watchdog
+    }
+
+    fn timeout_cycles(&self) -> u64 {
+        (self.timeout_ms as u64 * CYCLES_PER_SECOND / 1000).max(1)
+    }
+
+    fn kick(&mut self) {
+        self.remaining = self.timeout_cycles();
+        self.last_kick = self.cycles;
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        if !self.enabled {
+            return;
+        }
+        self.remaining = self.remaining.saturating_sub(cycles);
+        if self.remaining == 0 {
+            // it fires once, the guest enables it again after recovering
+            self.enabled = false;
+            self.expired = true;
+            self.pending = true;
+        }
+    }
+
+    /// Cycles until the watchdog expires, if enabled.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        self.enabled.then_some(self.remaining.max(1))
+    }
+
+    /// Takes the action to carry out for an expiry the CPU has not seen yet.
+    pub fn take_expiry(&mut self) -> Option<Action> {
+        std::mem::take(&mut self.pending).then_some(self.action)
+    }
+
+    /// Describes the expiry for the halt report.
+    pub fn report(&self) -> String {
+        let silent = (self.cycles - self.last_kick) * 1000 / CYCLES_PER_SECOND;
+        format!(
+            "watchdog expired: no kick for {} ms of emulated time (timeout {} ms), {} kicks before, {} ms after power-on",
+            silent,
+            self.timeout_ms,
+            self.kicks,
+            self.cycles * 1000 / CYCLES_PER_SECOND
+        )
+    }
+}
+
+impl Device for Watchdog {
+    fn read(&mut self, addr: u32, size: Size) -> Result<u32, Exception> {
+        if size != Size::Word {
+            return Err(Exception::LoadIllegalAddress);
+        }
+        match addr {
+            CONTROL => Ok(self.enabled as u32),
+            TIMEOUT => Ok(self.timeout_ms),
+            KICK => Ok(0),
+            REMAINING if self.enabled => Ok((self.remaining * 1000).div_ceil(CYCLES_PER_SECOND) as u32),
+            REMAINING => Ok(0),
+            STATUS => Ok(self.expired as u32),
+            _ => Err(Exception::LoadIllegalAddress),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u32, size: Size) -> Result<(), Exception> {
+        if size != Size::Word {
+            return Err(Exception::StoreIllegalAddress);
+        }
+        match addr {
+            CONTROL => {
+                let enable = data & 1 != 0;
+                if enable && !self.enabled {
+                    self.kick();
+                }
+                self.enabled = enable;
+            }
+            TIMEOUT => self.timeout_ms = data,
+            KICK => {
+                self.kicks += 1;
+                self.kick();
+            }
+            STATUS if data & 1 != 0 => self.expired = false,
+            REMAINING | STATUS => {}
+            _ => return Err(Exception::StoreIllegalAddress),
+        }
+        Ok(())
+    }
+}
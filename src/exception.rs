@@ -9,5 +9,7 @@ pub enum Exception {
     Syscall = 8,
     Break = 9,
     Reserved = 10,
-    Overflow = 12
+    Overflow = 12,
+    Trap = 13,
+    Watch = 23
 }
\ No newline at end of file
@@ -0,0 +1,116 @@
+//! The hostio module keeps the host files a guest opened through one of the emulator's syscall
+//! layers, by descriptor number. Errors are errno values, which newlib and Linux share below 35.
+
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+pub const EIO: u32 = 5;
+pub const EBADF: u32 = 9;
+pub const EINVAL: u32 = 22;
+pub const ESPIPE: u32 = 29;
+
+pub fn errno(e: io::Error) -> u32 {
+    e.raw_os_error().filter(|code| *code < 35).map_or(EIO, |code| code as u32)
+}
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Open descriptors; 0, 1 and 2 are the emulator's standard streams.
+pub struct Files(Vec<Option<Handle>>);
+
+impl Default for Files {
+    fn default() -> Self {
+        Self(vec![Some(Handle::Stdin), Some(Handle::Stdout), Some(Handle::Stderr)])
+    }
+}
+
+impl Files {
+    fn handle(&mut self, fd: u32) -> Result<&mut Handle, u32> {
+        self.0.get_mut(fd as usize).and_then(Option::as_mut).ok_or(EBADF)
+    }
+
+    /// Opens `path` on the lowest free descriptor, as POSIX hands them out.
+    pub fn open(&mut self, path: impl AsRef<Path>, options: &OpenOptions) -> Result<u32, u32> {
+        let file = options.open(path).map_err(errno)?;
+        let fd = self.0.iter().position(Option::is_none).unwrap_or(self.0.len());
+        if fd == self.0.len() {
+            self.0.push(None);
+        }
+        self.0[fd] = Some(Handle::File(file));
+        Ok(fd as u32)
+    }
+
+    pub fn close(&mut self, fd: u32) -> Result<u32, u32> {
+        self.handle(fd)?;
+        // the standard streams stay open
+        if fd > 2 {
+            self.0[fd as usize] = None;
+        }
+        Ok(0)
+    }
+
+    /// Reads at most `len` bytes.
+    pub fn read(&mut self, fd: u32, len: u32) -> Result<Vec<u8>, u32> {
+        let mut buf = vec![0; len as usize];
+        let n = match self.handle(fd)? {
+            Handle::Stdin => io::stdin().read(&mut buf),
+            Handle::File(file) => file.read(&mut buf),
+            Handle::Stdout | Handle::Stderr => return Err(EBADF),
+        }
+        .map_err(errno)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    /// Reads at most `len` bytes at `offset` without moving the file offset.
+    pub fn read_at(&mut self, fd: u32, offset: u64, len: u32) -> Result<Vec<u8>, u32> {
+        let Handle::File(file) = self.handle(fd)? else {
+            return Err(ESPIPE);
+        };
+        let mut buf = vec![0; len as usize];
+        let n = file.read_at(&mut buf, offset).map_err(errno)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    pub fn write(&mut self, fd: u32, data: &[u8]) -> Result<u32, u32> {
+        match self.handle(fd)? {
+            Handle::Stdout => io::stdout().write_all(data).and_then(|_| io::stdout().flush()),
+            Handle::Stderr => io::stderr().write_all(data),
+            Handle::File(file) => file.write_all(data),
+            Handle::Stdin => return Err(EBADF),
+        }
+        .map_err(errno)?;
+        Ok(data.len() as u32)
+    }
+
+    /// Moves the offset of `fd` to `offset` from the start, the current offset or the end for
+    /// `whence` 0, 1 or 2, and returns the new offset.
+    pub fn lseek(&mut self, fd: u32, offset: i64, whence: u32) -> Result<u64, u32> {
+        let Handle::File(file) = self.handle(fd)? else {
+            return Err(ESPIPE);
+        };
+        let pos = match whence {
+            0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+        file.seek(pos).map_err(errno)
+    }
+
+    /// The host file behind `fd`, none for the standard streams.
+    pub fn metadata(&mut self, fd: u32) -> Result<Option<Metadata>, u32> {
+        match self.handle(fd)? {
+            Handle::File(file) => file.metadata().map(Some).map_err(errno),
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,556 @@
+//! The linux module runs statically linked MIPS32 Linux executables without a guest kernel. It
+//! loads the PT_LOAD segments of the ELF file, builds the initial stack with argv, envp and the
+//! auxiliary vector, and runs the program in user mode, translating its o32 system calls to host
+//! calls. The call number goes in $v0 and the arguments in $a0-$a3, then at 16($sp) and 20($sp).
+//! The result comes back in $v0 with $a3 clear, or the errno in $v0 with $a3 set. Exceptions have
+//! no kernel to go to and end the run with the signal Linux would have sent.
+//! https://www.linux-mips.org/wiki/Syscall
+
+use std::fs::{self, Metadata, OpenOptions};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+use elf::abi::{EM_MIPS, PT_INTERP, PT_LOAD, PT_PHDR};
+use elf::endian::AnyEndian;
+use elf::file::Class;
+use elf::ElfBytes;
+
+use crate::config::{Config, TimeMode, DEFAULT_RNG_SEED};
+use crate::coprocessor::{CYCLES_PER_SECOND, SR, SR_IE, SR_UM};
+use crate::cpu::{Cpu, A0, A1, A2, A3, SP, V0};
+use crate::devices::virtio::rng::Rng;
+use crate::exception::Exception;
+use crate::hostio::{errno, Files, EINVAL};
+use crate::memory::{read_bytes, read_string, write_bytes, Allocator};
+
+/// o32 system calls, in $v0.
+pub const EXIT: u32 = 4001;
+pub const READ: u32 = 4003;
+pub const WRITE: u32 = 4004;
+pub const OPEN: u32 = 4005;
+pub const CLOSE: u32 = 4006;
+pub const UNLINK: u32 = 4010;
+pub const TIME: u32 = 4013;
+pub const LSEEK: u32 = 4019;
+pub const GETPID: u32 = 4020;
+pub const GETUID: u32 = 4024;
+pub const BRK: u32 = 4045;
+pub const GETGID: u32 = 4047;
+pub const GETEUID: u32 = 4049;
+pub const GETEGID: u32 = 4050;
+pub const IOCTL: u32 = 4054;
+pub const GETPPID: u32 = 4064;
+pub const GETTIMEOFDAY: u32 = 4078;
+pub const MMAP: u32 = 4090;
+pub const MUNMAP: u32 = 4091;
+pub const UNAME: u32 = 4122;
+pub const MPROTECT: u32 = 4125;
+pub const LLSEEK: u32 = 4140;
+pub const READV: u32 = 4145;
+pub const WRITEV: u32 = 4146;
+pub const RT_SIGACTION: u32 = 4194;
+pub const RT_SIGPROCMASK: u32 = 4195;
+/// Like `MMAP`, with the offset in pages.
+pub const MMAP2: u32 = 4210;
+pub const STAT64: u32 = 4213;
+pub const LSTAT64: u32 = 4214;
+pub const FSTAT64: u32 = 4215;
+pub const MADVISE: u32 = 4218;
+pub const GETTID: u32 = 4222;
+pub const EXIT_GROUP: u32 = 4246;
+pub const SET_TID_ADDRESS: u32 = 4252;
+pub const CLOCK_GETTIME: u32 = 4263;
+/// Sets the thread pointer `rdhwr $29` reads.
+pub const SET_THREAD_AREA: u32 = 4283;
+pub const OPENAT: u32 = 4288;
+pub const GETRANDOM: u32 = 4353;
+/// Like `CLOCK_GETTIME`, with 64-bit seconds and nanoseconds.
+pub const CLOCK_GETTIME64: u32 = 4403;
+
+/// `open` flags, as MIPS numbers them. Access mode in the low two bits.
+pub const O_WRONLY: u32 = 0x1;
+pub const O_RDWR: u32 = 0x2;
+pub const O_APPEND: u32 = 0x8;
+pub const O_CREAT: u32 = 0x100;
+pub const O_TRUNC: u32 = 0x200;
+pub const O_EXCL: u32 = 0x400;
+/// `openat` directory for paths relative to the working directory.
+pub const AT_FDCWD: u32 = -100i32 as u32;
+
+/// `mmap` flags, as MIPS numbers them.
+pub const MAP_FIXED: u32 = 0x10;
+pub const MAP_ANONYMOUS: u32 = 0x800;
+
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+/// Errors Linux numbers differently from newlib, or that the host files never raise.
+pub const ENOMEM: u32 = 12;
+pub const EFAULT: u32 = 14;
+pub const ENOTTY: u32 = 25;
+pub const EOVERFLOW: u32 = 79;
+pub const ENOSYS: u32 = 89;
+
+/// Auxiliary vector entries.
+pub const AT_NULL: u32 = 0;
+pub const AT_PHDR: u32 = 3;
+pub const AT_PHENT: u32 = 4;
+pub const AT_PHNUM: u32 = 5;
+pub const AT_PAGESZ: u32 = 6;
+pub const AT_ENTRY: u32 = 9;
+pub const AT_UID: u32 = 11;
+pub const AT_EUID: u32 = 12;
+pub const AT_GID: u32 = 13;
+pub const AT_EGID: u32 = 14;
+pub const AT_CLKTCK: u32 = 17;
+pub const AT_RANDOM: u32 = 25;
+pub const AT_EXECFN: u32 = 31;
+
+/// Top of the stack, with argv and envp right below.
+pub const STACK_TOP: u32 = 0x7fff0000;
+/// Stack mapped below `STACK_TOP`.
+const STACK_SIZE: u32 = 0x800000;
+/// Where `mmap` places mappings, TASK_UNMAPPED_BASE on MIPS.
+pub const MMAP_BASE: u32 = 0x2aaaa000;
+/// The process and thread ID the program sees.
+pub const PID: u32 = 1;
+/// Longest transfer of a single read or write, calls with more transfer less.
+const MAX_IO: u32 = 0x100000;
+const IOV_MAX: u32 = 1024;
+
+const SIGILL: u32 = 4;
+const SIGTRAP: u32 = 5;
+const SIGFPE: u32 = 8;
+const SIGBUS: u32 = 10;
+const SIGSEGV: u32 = 11;
+
+pub struct Linux {
+    /// Maps pages for the heap and `mmap`.
+    allocator: Allocator,
+    /// End of the loaded segments, `brk` does not go below it.
+    brk_start: u32,
+    brk: u32,
+    /// Where the next `mmap` without MAP_FIXED goes.
+    mmap: u32,
+    files: Files,
+    /// Source of AT_RANDOM and `getrandom`.
+    rng: Rng,
+    /// Thread pointer, read with `rdhwr $29`.
+    pub tls: u32,
+    exit: Option<u32>,
+}
+
+/// Where `load` put the program.
+struct Image {
+    entry: u32,
+    /// Address of the program headers, 0 if no segment loads them.
+    phdr: u32,
+    phnum: u32,
+    /// End of the highest segment.
+    end: u32,
+}
+
+fn fault(_: Exception) -> u32 {
+    EFAULT
+}
+
+/// Reads word `n` of the o32 argument area, where arguments after the fourth go.
+fn stack_arg(cpu: &mut Cpu, n: u32) -> Result<u32, u32> {
+    let bytes = read_bytes(cpu, cpu.registers[SP as usize].wrapping_add(4 * n), 4).map_err(fault)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn words(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+/// `struct stat64` of the o32 ABI. The standard streams show up as a terminal.
+fn stat64(metadata: Option<&Metadata>) -> Vec<u8> {
+    let mut stat = vec![0; 104];
+    let mut put = |offset: usize, bytes: &[u8]| stat[offset..offset + bytes.len()].copy_from_slice(bytes);
+    let Some(meta) = metadata else {
+        // character device, rw--w----
+        put(24, &0o20620u32.to_le_bytes());
+        put(28, &1u32.to_le_bytes());
+        put(88, &1024u32.to_le_bytes());
+        return stat;
+    };
+    put(0, &(meta.dev() as u32).to_le_bytes());
+    put(16, &meta.ino().to_le_bytes());
+    put(24, &meta.mode().to_le_bytes());
+    put(28, &(meta.nlink() as u32).to_le_bytes());
+    put(32, &meta.uid().to_le_bytes());
+    put(36, &meta.gid().to_le_bytes());
+    put(40, &(meta.rdev() as u32).to_le_bytes());
+    put(56, &meta.size().to_le_bytes());
+    put(64, &words(&[meta.atime() as u32, meta.atime_nsec() as u32, meta.mtime() as u32, meta.mtime_nsec() as u32]));
+    put(80, &words(&[meta.ctime() as u32, meta.ctime_nsec() as u32, meta.blksize() as u32]));
+    put(96, &meta.blocks().to_le_bytes());
+    stat
+}
+
+/// Loads the PT_LOAD segments of the static little-endian executable at `path`.
+fn load(cpu: &mut Cpu, allocator: &mut Allocator, path: &str) -> Image {
+    let buf = fs::read(path).unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+    let file = ElfBytes::<AnyEndian>::minimal_parse(&buf).unwrap_or_else(|e| panic!("{} is not an ELF file: {}", path, e));
+    let ehdr = file.ehdr;
+    if ehdr.class != Class::ELF32 || ehdr.e_machine != EM_MIPS || ehdr.endianness != AnyEndian::Little {
+        panic!("{} is not a little-endian MIPS32 executable", path);
+    }
+    let segments = file.segments().expect("no program headers");
+    if segments.iter().any(|phdr| phdr.p_type == PT_INTERP) {
+        panic!("{} is dynamically linked, link it statically", path);
+    }
+    let mut image = Image { entry: ehdr.e_entry as u32, phdr: 0, phnum: ehdr.e_phnum as u32, end: 0 };
+    for phdr in segments.iter() {
+        let (offset, vaddr, filesz, memsz) = (phdr.p_offset, phdr.p_vaddr as u32, phdr.p_filesz, phdr.p_memsz as u32);
+        if phdr.p_type == PT_PHDR {
+            image.phdr = vaddr;
+        }
+        if phdr.p_type != PT_LOAD {
+            continue;
+        }
+        if image.phdr == 0 && (offset..offset + filesz).contains(&ehdr.e_phoff) {
+            image.phdr = vaddr + (ehdr.e_phoff - offset) as u32;
+        }
+        // fresh pages are zero, which leaves .bss cleared
+        allocator.map(&mut cpu.bus.dram, vaddr, vaddr + memsz);
+        write_bytes(cpu, vaddr, file.segment_data(&phdr).unwrap()).unwrap();
+        image.end = image.end.max(vaddr + memsz);
+    }
+    image
+}
+
+/// Pushes `bytes` onto the stack being built at `sp` and returns their address.
+fn push(cpu: &mut Cpu, sp: &mut u32, bytes: &[u8]) -> u32 {
+    *sp -= bytes.len() as u32;
+    write_bytes(cpu, *sp, bytes).unwrap();
+    *sp
+}
+
+fn c_string(text: &str) -> Vec<u8> {
+    let mut bytes = text.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+impl Linux {
+    /// Takes the exit status the program asked for, if any.
+    pub fn take_exit(&mut self) -> Option<u32> {
+        self.exit.take()
+    }
+
+    fn open(&mut self, path: &str, flags: u32, mode: u32) -> Result<u32, u32> {
+        let mut options = OpenOptions::new();
+        match flags & 3 {
+            0 => options.read(true),
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => return Err(EINVAL),
+        };
+        options.append(flags & O_APPEND != 0).truncate(flags & O_TRUNC != 0).mode(mode & 0o7777);
+        if flags & O_EXCL != 0 {
+            options.create_new(true);
+        } else {
+            options.create(flags & O_CREAT != 0);
+        }
+        self.files.open(path, &options)
+    }
+
+    /// Moves the break to `addr` if it lies between the end of the program and the mappings, and
+    /// returns the break.
+    fn brk(&mut self, cpu: &mut Cpu, addr: u32) -> u32 {
+        if addr >= self.brk_start && addr < MMAP_BASE {
+            self.allocator.map(&mut cpu.bus.dram, self.brk_start, addr);
+            self.brk = addr;
+        }
+        self.brk
+    }
+
+    /// Maps `len` bytes, zeroed or a private copy of `fd` from `offset`. Mappings are never
+    /// unmapped, they stay until the program exits.
+    fn mmap(&mut self, cpu: &mut Cpu, addr: u32, len: u32, flags: u32, fd: u32, offset: u64) -> Result<u32, u32> {
+        let size = len.checked_add(0xfff).filter(|_| len > 0).ok_or(EINVAL)? & !0xfff;
+        let data = match flags & MAP_ANONYMOUS {
+            0 => self.files.read_at(fd, offset, len)?,
+            _ => Vec::new(),
+        };
+        let start = if flags & MAP_FIXED != 0 {
+            if addr & 0xfff != 0 {
+                return Err(EINVAL);
+            }
+            addr
+        } else {
+            self.mmap
+        };
+        let end = start.checked_add(size).filter(|end| *end <= STACK_TOP - STACK_SIZE).ok_or(ENOMEM)?;
+        self.allocator.map(&mut cpu.bus.dram, start, end);
+        if flags & MAP_FIXED != 0 {
+            // the pages may have been in use
+            write_bytes(cpu, start, &vec![0; size as usize]).map_err(fault)?;
+        } else {
+            self.mmap = end;
+        }
+        write_bytes(cpu, start, &data).map_err(fault)?;
+        Ok(start)
+    }
+
+    /// Nanoseconds on `clock`: the RTC for wall-clock time, virtual time since power-on otherwise.
+    fn clock(&self, cpu: &Cpu, clock: u32) -> Result<u64, u32> {
+        match clock {
+            // CLOCK_REALTIME_COARSE
+            CLOCK_REALTIME | 5 => Ok(cpu.bus.rtc.now()),
+            // the monotonic, boot time and CPU time clocks
+            CLOCK_MONOTONIC..=7 => Ok(cpu.stats.cycles * (1_000_000_000 / CYCLES_PER_SECOND)),
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// Carries out system call `number` with the arguments in $a0-$a3.
+    fn service(&mut self, cpu: &mut Cpu, number: u32) -> Result<u32, u32> {
+        let [a0, a1, a2, a3] = [A0, A1, A2, A3].map(|reg| cpu.registers[reg as usize]);
+        match number {
+            EXIT | EXIT_GROUP => {
+                self.exit = Some(a0 & 0xff);
+                Ok(0)
+            }
+            READ => {
+                let data = self.files.read(a0, a2.min(MAX_IO))?;
+                write_bytes(cpu, a1, &data).map_err(fault)?;
+                Ok(data.len() as u32)
+            }
+            WRITE => {
+                let data = read_bytes(cpu, a1, a2.min(MAX_IO) as usize).map_err(fault)?;
+                self.files.write(a0, &data)
+            }
+            READV | WRITEV => {
+                if a2 > IOV_MAX {
+                    return Err(EINVAL);
+                }
+                let iov = read_bytes(cpu, a1, 8 * a2 as usize).map_err(fault)?;
+                let iov: Vec<(u32, u32)> = iov
+                    .chunks(8)
+                    .map(|entry| (u32::from_le_bytes(entry[..4].try_into().unwrap()), u32::from_le_bytes(entry[4..].try_into().unwrap())))
+                    .collect();
+                let total = iov.iter().fold(0u32, |total, (_, len)| total.saturating_add(*len)).min(MAX_IO);
+                if number == WRITEV {
+                    let mut data = Vec::new();
+                    for (base, len) in iov {
+                        data.extend(read_bytes(cpu, base, len.min(total - data.len() as u32) as usize).map_err(fault)?);
+                    }
+                    return self.files.write(a0, &data);
+                }
+                // one host read, spread over the buffers
+                let data = self.files.read(a0, total)?;
+                let mut rest = data.as_slice();
+                for (base, len) in iov {
+                    let (chunk, tail) = rest.split_at(rest.len().min(len as usize));
+                    write_bytes(cpu, base, chunk).map_err(fault)?;
+                    rest = tail;
+                }
+                Ok(data.len() as u32)
+            }
+            OPEN => {
+                let path = read_string(cpu, a0).map_err(fault)?;
+                self.open(&path, a1, a2)
+            }
+            OPENAT => {
+                let path = read_string(cpu, a1).map_err(fault)?;
+                // only the working directory stands in for directory descriptors
+                if a0 != AT_FDCWD && !path.starts_with('/') {
+                    return Err(EINVAL);
+                }
+                self.open(&path, a2, a3)
+            }
+            CLOSE => self.files.close(a0),
+            UNLINK => {
+                let path = read_string(cpu, a0).map_err(fault)?;
+                fs::remove_file(path).map(|_| 0).map_err(errno)
+            }
+            LSEEK => self.files.lseek(a0, a1 as i32 as i64, a2).and_then(|pos| u32::try_from(pos).map_err(|_| EOVERFLOW)),
+            LLSEEK => {
+                // offset high and low word, result pointer, whence on the stack
+                let whence = stack_arg(cpu, 4)?;
+                let pos = self.files.lseek(a0, ((a1 as u64) << 32 | a2 as u64) as i64, whence)?;
+                write_bytes(cpu, a3, &pos.to_le_bytes()).map_err(fault)?;
+                Ok(0)
+            }
+            STAT64 | LSTAT64 => {
+                let path = read_string(cpu, a0).map_err(fault)?;
+                let meta = if number == STAT64 { fs::metadata(path) } else { fs::symlink_metadata(path) };
+                write_bytes(cpu, a1, &stat64(Some(&meta.map_err(errno)?))).map_err(fault)?;
+                Ok(0)
+            }
+            FSTAT64 => {
+                let meta = self.files.metadata(a0)?;
+                write_bytes(cpu, a1, &stat64(meta.as_ref())).map_err(fault)?;
+                Ok(0)
+            }
+            BRK => Ok(self.brk(cpu, a0)),
+            MMAP | MMAP2 => {
+                // prot in $a2 is not enforced, every page is readable and writable
+                let fd = stack_arg(cpu, 4)?;
+                let offset = stack_arg(cpu, 5)? as u64;
+                let offset = if number == MMAP2 { offset << 12 } else { offset };
+                self.mmap(cpu, a0, a1, a3, fd, offset)
+            }
+            MUNMAP | MPROTECT | MADVISE => Ok(0),
+            CLOCK_GETTIME | CLOCK_GETTIME64 => {
+                let now = self.clock(cpu, a0)?;
+                let (seconds, nanos) = (now / 1_000_000_000, now % 1_000_000_000);
+                let timespec = match number {
+                    CLOCK_GETTIME => words(&[seconds as u32, nanos as u32]),
+                    _ => [seconds.to_le_bytes(), nanos.to_le_bytes()].concat(),
+                };
+                write_bytes(cpu, a1, &timespec).map_err(fault)?;
+                Ok(0)
+            }
+            GETTIMEOFDAY => {
+                let now = cpu.bus.rtc.now();
+                if a0 != 0 {
+                    write_bytes(cpu, a0, &words(&[(now / 1_000_000_000) as u32, (now % 1_000_000_000 / 1000) as u32])).map_err(fault)?;
+                }
+                // the timezone is UTC
+                if a1 != 0 {
+                    write_bytes(cpu, a1, &[0; 8]).map_err(fault)?;
+                }
+                Ok(0)
+            }
+            TIME => {
+                let seconds = (cpu.bus.rtc.now() / 1_000_000_000) as u32;
+                if a0 != 0 {
+                    write_bytes(cpu, a0, &seconds.to_le_bytes()).map_err(fault)?;
+                }
+                Ok(seconds)
+            }
+            UNAME => {
+                // sysname, nodename, release, version, machine and domainname, 65 bytes each
+                let mut utsname = vec![0; 6 * 65];
+                for (i, field) in ["Linux", "mips-emu", "6.1.0", "#1", "mips", "(none)"].iter().enumerate() {
+                    utsname[65 * i..65 * i + field.len()].copy_from_slice(field.as_bytes());
+                }
+                write_bytes(cpu, a0, &utsname).map_err(fault)?;
+                Ok(0)
+            }
+            GETRANDOM => {
+                let mut data = vec![0; a1.min(MAX_IO) as usize];
+                self.rng.fill(&mut data);
+                write_bytes(cpu, a0, &data).map_err(fault)?;
+                Ok(data.len() as u32)
+            }
+            SET_THREAD_AREA => {
+                self.tls = a0;
+                Ok(0)
+            }
+            // signals are never delivered: no handlers, nothing blocked
+            RT_SIGACTION => {
+                if a2 != 0 {
+                    write_bytes(cpu, a2, &[0; 24]).map_err(fault)?;
+                }
+                Ok(0)
+            }
+            RT_SIGPROCMASK => {
+                if a2 != 0 {
+                    write_bytes(cpu, a2, &vec![0; a3.min(16) as usize]).map_err(fault)?;
+                }
+                Ok(0)
+            }
+            // the standard streams are not terminals
+            IOCTL => self.files.metadata(a0).and(Err(ENOTTY)),
+            GETPID | GETTID | SET_TID_ADDRESS => Ok(PID),
+            GETPPID | GETUID | GETEUID | GETGID | GETEGID => Ok(0),
+            _ => Err(ENOSYS),
+        }
+    }
+}
+
+/// Loads the executable `config.kernel` into `cpu` with the command line and environment from
+/// `config`, ready to run in user mode.
+pub fn start(cpu: &mut Cpu, config: &Config) {
+    let mut allocator = Allocator::new();
+    let image = load(cpu, &mut allocator, &config.kernel);
+    allocator.map(&mut cpu.bus.dram, STACK_TOP - STACK_SIZE, STACK_TOP);
+    let seed = match config.time_mode {
+        TimeMode::Deterministic => Some(config.rng_seed.unwrap_or(DEFAULT_RNG_SEED)),
+        TimeMode::RealTime => config.rng_seed,
+    };
+    let mut rng = Rng::new(seed).unwrap_or_else(|e| panic!("cannot open host entropy: {}", e));
+
+    // strings and AT_RANDOM's bytes at the top, then argc, argv, envp and auxv from $sp up
+    let mut sp = STACK_TOP;
+    let mut random = [0; 16];
+    rng.fill(&mut random);
+    let random = push(cpu, &mut sp, &random);
+    let args: Vec<u32> = std::iter::once(&config.kernel)
+        .chain(&config.args)
+        .map(|arg| push(cpu, &mut sp, &c_string(arg)))
+        .collect();
+    let env: Vec<u32> = config.env.iter().map(|var| push(cpu, &mut sp, &c_string(var))).collect();
+    let mut vector = vec![args.len() as u32];
+    vector.extend(&args);
+    vector.push(0);
+    vector.extend(&env);
+    vector.push(0);
+    let auxv = [
+        (AT_PHDR, image.phdr),
+        (AT_PHENT, 32),
+        (AT_PHNUM, image.phnum),
+        (AT_PAGESZ, 0x1000),
+        (AT_ENTRY, image.entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_CLKTCK, 100),
+        (AT_RANDOM, random),
+        (AT_EXECFN, args[0]),
+        (AT_NULL, 0),
+    ];
+    vector.extend(auxv.iter().flat_map(|(key, value)| [*key, *value]));
+    sp = (sp - 4 * vector.len() as u32) & !0xf;
+    write_bytes(cpu, sp, &words(&vector)).unwrap();
+
+    // user mode, and nothing handles interrupts
+    let sr = cpu.load_coprocessor0(SR).unwrap();
+    cpu.write_coprocessor0(SR, sr & !SR_IE | SR_UM).unwrap();
+    cpu.registers[SP as usize] = sp;
+    cpu.pc = image.entry;
+    let brk = (image.end + 0xfff) & !0xfff;
+    cpu.linux = Some(Linux {
+        allocator,
+        brk_start: brk,
+        brk,
+        mmap: MMAP_BASE,
+        files: Files::default(),
+        rng,
+        tls: 0,
+        exit: None,
+    });
+}
+
+/// Services the system call the program made.
+pub fn call(cpu: &mut Cpu) {
+    let mut linux = cpu.linux.take().expect("Linux system call outside Linux mode");
+    let number = cpu.registers[V0 as usize];
+    let result = linux.service(cpu, number);
+    cpu.linux = Some(linux);
+    let (value, error) = match result {
+        Ok(value) => (value, 0),
+        Err(code) => (code, 1),
+    };
+    cpu.registers[V0 as usize] = value;
+    cpu.registers[A3 as usize] = error;
+}
+
+/// Ends the run the way the signal Linux sends for `exception` would, with the shell's status.
+pub fn kill(cpu: &mut Cpu, exception: Exception) {
+    let signal = match exception {
+        Exception::InstructionBusError | Exception::Reserved => SIGILL,
+        Exception::Break | Exception::Watch => SIGTRAP,
+        // compilers check divisors with `teq`
+        Exception::Overflow | Exception::Trap => SIGFPE,
+        Exception::DataBusError => SIGBUS,
+        _ => SIGSEGV,
+    };
+    eprintln!("killed by signal {} at pc {:#x}", signal, cpu.pc);
+    cpu.linux.as_mut().expect("Linux signal outside Linux mode").exit = Some(128 + signal);
+}
@@ -1,8 +1,10 @@
-use std::{thread, sync::{Arc, Mutex}};
+use std::{process, thread, sync::{Arc, Mutex}};
 use crate::bus::{UART_BASE, UART_END, VIRTIO_BASE, VIRTIO_END};
 
+use crate::config::Config;
 use crate::cpu::{Cpu, Instruction};
 
+mod config;
 mod cpu;
 mod coprocessor;
 mod bus;
@@ -12,20 +14,24 @@ mod devices;
 mod exception;
 mod utils;
 mod memory;
+mod hostio;
+mod linux;
+mod semihosting;
+mod spim;
+mod stats;
 
 
 #[cfg(test)]
 mod test;
 
 fn main() {
-    print!("Hello world!");
-    let mut cpu = Cpu::new("os/main.o");
-    // let stop_signal = Arc::new(Mutex::new(false));
-    // let ss_main = stop_signal.clone();
-    // let cause = cpu.bus.load_raw_cause();
-    // thread::spawn(move || {
-    //     cpu.run(ss_main);
-    // });
-    cpu.debug(3087);
-    // cpu.print_status();
+    let config = Config::from_args();
+    let mut cpu = Cpu::new(&config);
+    let status = cpu.run();
+    if config.stats {
+        eprintln!("{}", cpu.stats);
+    }
+    // exit skips destructors, dropping the machine first saves disk deltas and restores the terminal
+    drop(cpu);
+    process::exit(status as i32);
 }
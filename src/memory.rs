@@ -1,8 +1,8 @@
 use std::fs;
-use elf::{ElfBytes, endian::{AnyEndian, BigEndian, EndianParse}};
+use elf::{ElfBytes, endian::{AnyEndian, EndianParse}};
 
-use crate::{cpu::{Cpu, Size}, exception::Exception, devices::device::Device, coprocessor::{PTBASE}, bus::ROM_BASE};
-use crate::bus::{UART_BASE, VIRTIO_BASE};
+use crate::{cpu::{Cpu, Size}, exception::Exception, devices::device::Device, coprocessor::{PTBASE, PERF_PAGE_WALKS}, bus::ROM_BASE};
+use crate::bus::{RTC_BASE, UART_BASE, VIRTIO_BASE};
 use crate::dram::Dram;
 
 pub const HUGE: u32 = 0x40;
@@ -44,6 +44,9 @@ impl PTE {
     }
     
 }
+/// Longest string `read_string` reads from the guest.
+const MAX_STRING: usize = 4096;
+
 pub struct Paddr {
     pub paddr: u32,
     pub user: bool,
@@ -51,6 +54,43 @@ pub struct Paddr {
     pub write: bool
 }
 pub fn walkpgdir(cpu: &mut Cpu, vaddr: u32) -> Result<Paddr, Exception> {
+    cpu.bus.coprocessor.count_event(PERF_PAGE_WALKS, 1);
+    let paddr = walk(cpu, vaddr);
+    if paddr.is_err() {
+        cpu.bus.coprocessor.set_bad_vaddr(vaddr);
+    }
+    paddr
+}
+/// Reads `len` bytes of guest memory at virtual address `vaddr`, for the emulator's syscall layers.
+pub fn read_bytes(cpu: &mut Cpu, vaddr: u32, len: usize) -> Result<Vec<u8>, Exception> {
+    (0..len as u32)
+        .map(|i| {
+            let paddr = walkpgdir(cpu, vaddr.wrapping_add(i))?.paddr;
+            Ok(cpu.bus.read(paddr, Size::Byte)? as u8)
+        })
+        .collect()
+}
+/// Reads the NUL-terminated string at virtual address `vaddr`.
+pub fn read_string(cpu: &mut Cpu, vaddr: u32) -> Result<String, Exception> {
+    let mut bytes = Vec::new();
+    for i in 0..MAX_STRING as u32 {
+        let paddr = walkpgdir(cpu, vaddr.wrapping_add(i))?.paddr;
+        match cpu.bus.read(paddr, Size::Byte)? {
+            0 => break,
+            byte => bytes.push(byte as u8),
+        }
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+/// Writes `bytes` to guest memory at virtual address `vaddr`.
+pub fn write_bytes(cpu: &mut Cpu, vaddr: u32, bytes: &[u8]) -> Result<(), Exception> {
+    for (i, byte) in bytes.iter().enumerate() {
+        let paddr = walkpgdir(cpu, vaddr.wrapping_add(i as u32))?.paddr;
+        cpu.bus.write(paddr, *byte as u32, Size::Byte)?;
+    }
+    Ok(())
+}
+fn walk(cpu: &mut Cpu, vaddr: u32) -> Result<Paddr, Exception> {
     let mut pte = PTE{entry: cpu.load_coprocessor0(PTBASE)?};
     let mut user = true;
     let mut read = true;
@@ -68,8 +108,9 @@ pub fn walkpgdir(cpu: &mut Cpu, vaddr: u32) -> Result<Paddr, Exception> {
         read &= pte.read();
         write &= pte.write();
         if pte.huge() && bit_shift == 22 {
+            // a 4 MiB page: the entry's low bits are flags, the address takes the top 10 bits
             return Ok(Paddr {
-                paddr: pte.entry | (vaddr & 0x3fffff),
+                paddr: (pte.entry & 0xffc00000) | (vaddr & 0x3fffff),
                 user,
                 read,
                 write,
@@ -99,7 +140,13 @@ pub fn set_page_dirty(cpu: &mut Cpu, vaddr: u32) -> Result<(), Exception> {
     }
     Ok(())
 }
-struct Allocator {
+/// Whether the meta page table maps the page of `vaddr`.
+pub fn mapped(dram: &mut Dram, vaddr: u32) -> bool {
+    let pde = PTE {entry: dram.read(((vaddr >> 22) & 0x3ff) << 2, Size::Word).unwrap()};
+    pde.valid() && (pde.huge() || PTE {entry: dram.read(pde.pfn() | ((vaddr >> 12) & 0x3ff) << 2, Size::Word).unwrap()}.valid())
+}
+/// Hands out physical pages after the meta page table and maps them into it.
+pub struct Allocator {
     allocated: u32
 }
 impl Allocator {
@@ -137,19 +184,59 @@ impl Allocator {
         self.allocated += 1;
         new_pfn
     }
+    /// Maps the pages from `start` up to `end` that are not mapped yet, with user access.
+    pub fn map(&mut self, dram: &mut Dram, start: u32, end: u32) {
+        let mut vaddr = start & !0xfff;
+        while vaddr < end {
+            if !mapped(dram, vaddr) {
+                self.kalloc(dram, vaddr);
+                self.set_user(dram, vaddr);
+            }
+            vaddr = match vaddr.checked_add(0x1000) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+    }
+    fn set_user(&mut self, dram: &mut Dram, vaddr: u32) {
+        let pde_addr = ((vaddr >> 22) & 0x3ff) << 2;
+        let pde = dram.read(pde_addr, Size::Word).unwrap();
+        dram.write(pde_addr, pde | USER, Size::Word).unwrap();
+        let pte_addr = (pde & 0xfffff000) | ((vaddr >> 12) & 0x3ff) << 2;
+        let pte = dram.read(pte_addr, Size::Word).unwrap();
+        dram.write(pte_addr, pte | USER, Size::Word).unwrap();
+    }
 }
 pub fn create_meta_page_table(dram: &mut Dram) {
     dram.write(0, 0x00001000 | PRESENT | VALID | READ | WRITE, Size::Word).unwrap();
     dram.write(0x00001000, ROM_BASE | PRESENT | VALID | READ, Size::Word).unwrap(); // map uart
     dram.write(0x00001004, VIRTIO_BASE | PRESENT | VALID | READ | WRITE, Size::Word).unwrap();
     dram.write(0x00001008, UART_BASE | PRESENT | VALID | READ | WRITE, Size::Word).unwrap();
+    dram.write(0x0000100c, RTC_BASE | PRESENT | VALID | READ | WRITE, Size::Word).unwrap();
     // identity mapping from 0x80000000 to 0x00000000
     for i in 0x200..0x400 {
         let addr = i << 2;
         dram.write(addr, ((i - 0x200) << 22) | HUGE | PRESENT | VALID | READ | WRITE, Size::Word).unwrap();
     }
 }
-pub fn load_kernel(dram: &mut Dram, filename: &str) {
+/// Copies an ELF section to fresh pages from `vaddr` on, a word at a time in the file's byte order.
+fn load_section(dram: &mut Dram, allocator: &mut Allocator, vaddr: u32, data: &[u8], endianness: AnyEndian) {
+    let mut pbase = 0;
+    for ptr in (0..data.len()).step_by(4) {
+        if ptr & 0xfff == 0 {
+            // page start, allocate page;
+            pbase = allocator.kalloc(dram, vaddr + ptr as u32);
+        }
+        // a short last word is padded with zeroes
+        let mut word = [0; 4];
+        let len = (data.len() - ptr).min(4);
+        word[..len].copy_from_slice(&data[ptr..ptr + len]);
+        let inst = endianness.parse_u32_at(&mut 0, &word).unwrap();
+        dram.write(pbase | (ptr & 0xfff) as u32, inst, Size::Word).unwrap();
+    }
+}
+/// Loads .text at `TEXT` and .data at `DATA`, and returns the allocator to map more pages with.
+pub fn load_kernel(dram: &mut Dram, filename: &str) -> Allocator {
     let mut allocator = Allocator::new();
     let buf = fs::read(filename).unwrap();
     let slice = buf.as_slice();
@@ -157,35 +244,10 @@ pub fn load_kernel(dram: &mut Dram, filename: &str) {
     // .text and .data segment
     let text_header = file.section_header_by_name(".text").unwrap().expect("no .text section");
     let (text_segment, _) = file.section_data(&text_header).unwrap();
-    let mut pbase = 0;
-    let mut ptr: usize = 0;
-    while ptr < text_segment.len() {
-        let vaddr = TEXT + ptr as u32;
-        if ptr & 0xfff == 0 {
-            // page start, allocate page;
-            pbase = allocator.kalloc(dram, vaddr);
-        }
-        let inst = BigEndian.parse_u32_at(&mut ptr, text_segment).unwrap();
-        let paddr = pbase + ptr as u32;
-        dram.write(paddr, inst, Size::Word).unwrap();
-        ptr += 4;
-    }
-
+    load_section(dram, &mut allocator, TEXT, text_segment, file.ehdr.endianness);
     if let Some(data_header) = file.section_header_by_name(".data").unwrap() {
         let (data_segment, _) = file.section_data(&data_header).unwrap();
-        let mut pbase = 0;
-        let mut ptr: usize = 0;
-        while ptr < data_segment.len() {
-            let vaddr = DATA + ptr as u32;
-            if ptr & 0xfff == 0 {
-                // page start, allocate page;
-                pbase = allocator.kalloc(dram, vaddr);
-            }
-            let inst = BigEndian.parse_u32_at(&mut ptr, data_segment).unwrap();
-            let paddr = pbase + ptr as u32;
-            dram.write(paddr, inst, Size::Word).unwrap();
-            ptr += 4;
-        }
-
+        load_section(dram, &mut allocator, DATA, data_segment, file.ehdr.endianness);
     }
-}
\ No newline at end of file
+    allocator
+}
@@ -0,0 +1,151 @@
+//! The semihosting module services the MIPS Unified Hosting Interface (UHI), through which a
+//! bare-metal program reaches host files and the console with `sdbbp 1` instead of device drivers.
+//! The operation goes in $t9 and its arguments in $a0-$a3. The result comes back in $v0, -1 on
+//! failure with the errno in $v1, and the program continues after the `sdbbp`.
+//! https://www.mips.com/?do-download=unified-hosting-interface-reference-manual
+
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::cpu::{Cpu, A0, A1, A2, T9, V0, V1};
+use crate::exception::Exception;
+use crate::hostio::{errno, Files, EINVAL};
+use crate::memory::{read_bytes, read_string, write_bytes};
+
+/// The `sdbbp` code of a UHI call.
+pub const UHI_CODE: u32 = 1;
+
+/// Operations, in $t9.
+pub const EXIT: u32 = 1;
+pub const OPEN: u32 = 2;
+pub const CLOSE: u32 = 3;
+pub const READ: u32 = 4;
+pub const WRITE: u32 = 5;
+pub const LSEEK: u32 = 6;
+pub const UNLINK: u32 = 7;
+pub const ARGC: u32 = 9;
+pub const ARGNLEN: u32 = 10;
+pub const ARGN: u32 = 11;
+/// Prints the string at $a0 with its first `%d` replaced by $a1.
+pub const PLOG: u32 = 13;
+/// Not part of UHI: seconds since the Unix epoch on the RTC in $v0, nanoseconds in $v1.
+pub const TIME: u32 = 0x100;
+
+/// `open` flags, as newlib numbers them. Access mode in the low two bits.
+pub const O_WRONLY: u32 = 0x1;
+pub const O_RDWR: u32 = 0x2;
+pub const O_APPEND: u32 = 0x8;
+pub const O_CREAT: u32 = 0x200;
+pub const O_TRUNC: u32 = 0x400;
+pub const O_EXCL: u32 = 0x800;
+
+const ENOSYS: u32 = 88;
+
+pub struct Semihosting {
+    files: Files,
+    /// The command line, program name first.
+    args: Vec<String>,
+    exit: Option<u32>,
+}
+
+impl Semihosting {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { files: Files::default(), args, exit: None }
+    }
+
+    /// Takes the exit status the program asked for, if any.
+    pub fn take_exit(&mut self) -> Option<u32> {
+        self.exit.take()
+    }
+
+    fn open(&mut self, path: &str, flags: u32, mode: u32) -> Result<u32, u32> {
+        let mut options = OpenOptions::new();
+        match flags & 3 {
+            0 => options.read(true),
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => return Err(EINVAL),
+        };
+        options.append(flags & O_APPEND != 0).truncate(flags & O_TRUNC != 0).mode(mode & 0o7777);
+        if flags & O_EXCL != 0 {
+            options.create_new(true);
+        } else {
+            options.create(flags & O_CREAT != 0);
+        }
+        self.files.open(path, &options)
+    }
+
+    fn arg(&self, n: u32) -> Result<&String, u32> {
+        self.args.get(n as usize).ok_or(EINVAL)
+    }
+
+    /// Carries out operation `op`. The outer error is raised when a guest buffer is not mapped.
+    fn service(&mut self, cpu: &mut Cpu, op: u32) -> Result<Result<u32, u32>, Exception> {
+        let [a0, a1, a2] = [A0, A1, A2].map(|reg| cpu.registers[reg as usize]);
+        Ok(match op {
+            EXIT => {
+                self.exit = Some(a0);
+                Ok(0)
+            }
+            OPEN => {
+                let path = read_string(cpu, a0)?;
+                self.open(&path, a1, a2)
+            }
+            CLOSE => self.files.close(a0),
+            READ => match self.files.read(a0, a2) {
+                Ok(data) => {
+                    write_bytes(cpu, a1, &data)?;
+                    Ok(data.len() as u32)
+                }
+                Err(e) => Err(e),
+            },
+            WRITE => {
+                let data = read_bytes(cpu, a1, a2 as usize)?;
+                self.files.write(a0, &data)
+            }
+            LSEEK => self.files.lseek(a0, a1 as i32 as i64, a2).and_then(|pos| u32::try_from(pos).map_err(|_| EINVAL)),
+            UNLINK => {
+                let path = read_string(cpu, a0)?;
+                fs::remove_file(path).map(|_| 0).map_err(errno)
+            }
+            ARGC => Ok(self.args.len() as u32),
+            ARGNLEN => self.arg(a0).map(|arg| arg.len() as u32),
+            ARGN => match self.arg(a0) {
+                Ok(arg) => {
+                    let mut bytes = arg.clone().into_bytes();
+                    bytes.push(0);
+                    write_bytes(cpu, a1, &bytes)?;
+                    Ok(0)
+                }
+                Err(e) => Err(e),
+            },
+            PLOG => {
+                let format = read_string(cpu, a0)?;
+                let line = format.replacen("%d", &(a1 as i32).to_string(), 1);
+                self.files.write(1, line.as_bytes())
+            }
+            TIME => {
+                let now = cpu.bus.rtc.now();
+                cpu.registers[V1 as usize] = (now % 1_000_000_000) as u32;
+                Ok((now / 1_000_000_000) as u32)
+            }
+            _ => Err(ENOSYS),
+        })
+    }
+}
+
+/// Services the UHI call the guest made with `sdbbp`.
+pub fn call(cpu: &mut Cpu) -> Result<(), Exception> {
+    let mut host = cpu.semihosting.take().expect("semihosting call without semihosting");
+    let op = cpu.registers[T9 as usize];
+    let result = host.service(cpu, op);
+    cpu.semihosting = Some(host);
+    match result? {
+        Ok(value) => cpu.registers[V0 as usize] = value,
+        Err(code) => {
+            cpu.registers[V0 as usize] = u32::MAX;
+            cpu.registers[V1 as usize] = code;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,229 @@
+//! The spim module services `syscall` the way the SPIM and MARS simulators do, so classroom
+//! assembly programs run without an operating system. The service number goes in $v0 and the
+//! arguments in $a0-$a2; results come back in $v0. The emulator maps the program's memory: it
+//! runs in kernel mode from the start of .text, with a stack below `STACK_POINTER` and a heap
+//! that `sbrk` grows after .data. Services the emulator does not know trap to the guest as before.
+//! https://spimsimulator.sourceforge.net/HP_AppA.pdf
+
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+
+use crate::coprocessor::{SR, SR_IE};
+use crate::cpu::{Cpu, A0, A1, A2, GP, SP, V0};
+use crate::exception::Exception;
+use crate::hostio::{Files, EINVAL};
+use crate::memory::{self, read_bytes, read_string, write_bytes, Allocator, HEAP, TEXT};
+
+/// Services, in $v0.
+pub const PRINT_INT: u32 = 1;
+pub const PRINT_STRING: u32 = 4;
+pub const READ_INT: u32 = 5;
+pub const READ_STRING: u32 = 8;
+pub const SBRK: u32 = 9;
+pub const EXIT: u32 = 10;
+pub const PRINT_CHAR: u32 = 11;
+pub const READ_CHAR: u32 = 12;
+pub const OPEN: u32 = 13;
+pub const READ: u32 = 14;
+pub const WRITE: u32 = 15;
+pub const CLOSE: u32 = 16;
+pub const EXIT2: u32 = 17;
+/// MARS additions: milliseconds since the Unix epoch on the RTC, low word in $a0, high in $a1.
+pub const TIME: u32 = 30;
+pub const PRINT_HEX: u32 = 34;
+pub const PRINT_BINARY: u32 = 35;
+pub const PRINT_UNSIGNED: u32 = 36;
+
+/// `open` flags, as MARS takes them.
+pub const OPEN_READ: u32 = 0;
+/// Creates or truncates the file.
+pub const OPEN_WRITE: u32 = 1;
+/// Creates the file or appends to it.
+pub const OPEN_APPEND: u32 = 9;
+
+/// Initial $sp, as in SPIM.
+pub const STACK_POINTER: u32 = 0x7fffeffc;
+/// Stack mapped below `STACK_POINTER`.
+const STACK_SIZE: u32 = 0x100000;
+
+pub struct Spim {
+    /// Maps heap pages as the break moves up.
+    allocator: Allocator,
+    /// End of the heap, where `sbrk` hands out memory from.
+    brk: u32,
+    files: Files,
+    input: Box<dyn BufRead + Send>,
+    output: Box<dyn Write + Send>,
+    exit: Option<u32>,
+}
+
+impl Spim {
+    /// Connects the console services to `input` and `output` instead of the terminal.
+    #[cfg(test)]
+    pub fn set_console(&mut self, input: Box<dyn BufRead + Send>, output: Box<dyn Write + Send>) {
+        self.input = input;
+        self.output = output;
+    }
+
+    /// Takes the exit status the program asked for, if any.
+    pub fn take_exit(&mut self) -> Option<u32> {
+        self.exit.take()
+    }
+
+    fn print(&mut self, text: &[u8]) {
+        self.output.write_all(text).and_then(|_| self.output.flush()).expect("failed to write the console");
+    }
+
+    /// Reads a line, with its newline if there is one. Empty at the end of input.
+    fn read_line(&mut self) -> Vec<u8> {
+        let mut line = Vec::new();
+        self.input.read_until(b'\n', &mut line).expect("failed to read the console");
+        line
+    }
+
+    fn read_char(&mut self) -> u32 {
+        let input = self.input.fill_buf().expect("failed to read the console");
+        let Some(&byte) = input.first() else {
+            return 0;
+        };
+        self.input.consume(1);
+        byte as u32
+    }
+
+    /// Moves the break by `increment` bytes, rounded up to words, and returns the old one.
+    fn sbrk(&mut self, cpu: &mut Cpu, increment: u32) -> u32 {
+        let old = self.brk;
+        self.brk = old.wrapping_add(increment.wrapping_add(3) & !3);
+        if (increment as i32) > 0 {
+            self.allocator.map(&mut cpu.bus.dram, old, self.brk);
+        }
+        old
+    }
+
+    fn open(&mut self, path: &str, flags: u32) -> Result<u32, u32> {
+        let mut options = OpenOptions::new();
+        match flags {
+            OPEN_READ => options.read(true),
+            OPEN_WRITE => options.write(true).create(true).truncate(true),
+            OPEN_APPEND => options.append(true).create(true),
+            _ => return Err(EINVAL),
+        };
+        self.files.open(path, &options)
+    }
+
+    /// Carries out `service`, with `Exception::Syscall` for unknown ones. Errors of file services
+    /// return -1 in $v0.
+    fn service(&mut self, cpu: &mut Cpu, service: u32) -> Result<(), Exception> {
+        let [a0, a1, a2] = [A0, A1, A2].map(|reg| cpu.registers[reg as usize]);
+        let v0 = match service {
+            PRINT_INT => {
+                self.print((a0 as i32).to_string().as_bytes());
+                return Ok(());
+            }
+            PRINT_STRING => {
+                let text = read_string(cpu, a0)?;
+                self.print(text.as_bytes());
+                return Ok(());
+            }
+            PRINT_CHAR => {
+                self.print(&[a0 as u8]);
+                return Ok(());
+            }
+            PRINT_HEX => {
+                self.print(format!("0x{:08x}", a0).as_bytes());
+                return Ok(());
+            }
+            PRINT_BINARY => {
+                self.print(format!("{:032b}", a0).as_bytes());
+                return Ok(());
+            }
+            PRINT_UNSIGNED => {
+                self.print(a0.to_string().as_bytes());
+                return Ok(());
+            }
+            // anything that does not parse reads as 0
+            READ_INT => String::from_utf8_lossy(&self.read_line()).trim().parse::<i32>().unwrap_or(0) as u32,
+            READ_STRING => {
+                // like fgets: at most a1 - 1 bytes of a line, then a NUL
+                if a1 > 0 {
+                    let mut line = self.read_line();
+                    line.truncate(a1 as usize - 1);
+                    line.push(0);
+                    write_bytes(cpu, a0, &line)?;
+                }
+                return Ok(());
+            }
+            READ_CHAR => self.read_char(),
+            SBRK => self.sbrk(cpu, a0),
+            EXIT => {
+                self.exit = Some(0);
+                return Ok(());
+            }
+            EXIT2 => {
+                self.exit = Some(a0);
+                return Ok(());
+            }
+            OPEN => {
+                let path = read_string(cpu, a0)?;
+                self.open(&path, a1).unwrap_or(u32::MAX)
+            }
+            READ => match self.files.read(a0, a2) {
+                Ok(data) => {
+                    write_bytes(cpu, a1, &data)?;
+                    data.len() as u32
+                }
+                Err(_) => u32::MAX,
+            },
+            WRITE => {
+                let data = read_bytes(cpu, a1, a2 as usize)?;
+                self.files.write(a0, &data).unwrap_or(u32::MAX)
+            }
+            CLOSE => {
+                let _ = self.files.close(a0);
+                return Ok(());
+            }
+            TIME => {
+                let millis = cpu.bus.rtc.now() / 1_000_000;
+                cpu.registers[A0 as usize] = millis as u32;
+                cpu.registers[A1 as usize] = (millis >> 32) as u32;
+                return Ok(());
+            }
+            _ => return Err(Exception::Syscall),
+        };
+        cpu.registers[V0 as usize] = v0;
+        Ok(())
+    }
+}
+
+/// Sets `cpu` up to run the program `load_kernel` loaded under SPIM syscalls, on the terminal.
+pub fn start(cpu: &mut Cpu, mut allocator: Allocator) {
+    allocator.map(&mut cpu.bus.dram, STACK_POINTER - STACK_SIZE, STACK_POINTER + 4);
+    // the heap starts at the first page after .data
+    let mut brk = HEAP;
+    while memory::mapped(&mut cpu.bus.dram, brk) {
+        brk += 0x1000;
+    }
+    // nothing handles interrupts, the CP0 timer would otherwise jump to EBASE
+    let sr = cpu.load_coprocessor0(SR).unwrap();
+    cpu.write_coprocessor0(SR, sr & !SR_IE).unwrap();
+    cpu.registers[SP as usize] = STACK_POINTER;
+    cpu.registers[GP as usize] = HEAP;
+    cpu.pc = TEXT;
+    cpu.spim = Some(Spim {
+        allocator,
+        brk,
+        files: Files::default(),
+        input: Box::new(BufReader::new(io::stdin())),
+        output: Box::new(io::stdout()),
+        exit: None,
+    });
+}
+
+/// Services the syscall the program made.
+pub fn call(cpu: &mut Cpu) -> Result<(), Exception> {
+    let mut spim = cpu.spim.take().expect("SPIM syscall outside SPIM mode");
+    let service = cpu.registers[V0 as usize];
+    let result = spim.service(cpu, service);
+    cpu.spim = Some(spim);
+    result
+}
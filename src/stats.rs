@@ -0,0 +1,30 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Counters collected while the CPU runs.
+#[derive(Default)]
+pub struct Stats {
+    /// Instructions that completed without raising an exception.
+    pub instructions: u64,
+    /// Virtual cycles elapsed, including the ones skipped while idle.
+    pub cycles: u64,
+    /// Number of `wait` instructions executed.
+    pub waits: u64,
+    /// Virtual cycles skipped while waiting for an interrupt.
+    pub idle_cycles: u64,
+    /// Host time spent sleeping while waiting for an interrupt.
+    pub idle_time: Duration,
+    /// Cycles loads and stores waited for the bus while DMA transfers ran.
+    pub dma_stalls: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "instructions: {}", self.instructions)?;
+        writeln!(f, "cycles:       {}", self.cycles)?;
+        writeln!(f, "waits:        {}", self.waits)?;
+        writeln!(f, "idle cycles:  {}", self.idle_cycles)?;
+        writeln!(f, "idle time:    {:?}", self.idle_time)?;
+        write!(f, "dma stalls:   {}", self.dma_stalls)
+    }
+}
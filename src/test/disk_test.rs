@@ -0,0 +1,53 @@
+use std::{env, fs, process, thread};
+
+use crate::devices::disk::{Disk, Overlay};
+
+#[test]
+fn overlay_keeps_image_pristine() {
+    let dir = env::temp_dir().join(format!("mips-emu-disk-{}-{:?}", process::id(), thread::current().id()));
+    fs::create_dir_all(&dir).unwrap();
+    let image = dir.join("disk.img");
+    let delta = dir.join("disk.delta");
+    fs::write(&image, vec![0x11; 700]).unwrap();
+    let (image, delta) = (image.to_str().unwrap(), delta.to_str().unwrap().to_string());
+
+    {
+        let mut disk = Disk::open(image, false, Overlay::Save(delta.clone())).unwrap();
+        assert_eq!(disk.sectors(), 2);
+        disk.write(510, &[0x22; 4]).unwrap();
+        assert!(disk.write(1022, &[0; 4]).is_err());
+    }
+    assert_eq!(fs::read(image).unwrap(), vec![0x11; 700]);
+
+    // the saved delta is applied when the image is opened with it again
+    let mut disk = Disk::open(image, false, Overlay::Save(delta.clone())).unwrap();
+    let mut buf = [0; 6];
+    disk.read(509, &mut buf).unwrap();
+    assert_eq!(buf, [0x11, 0x22, 0x22, 0x22, 0x22, 0x11]);
+    let mut disk = Disk::open(image, true, Overlay::None).unwrap();
+    disk.read(509, &mut buf).unwrap();
+    assert_eq!(buf, [0x11; 6]);
+    assert!(disk.write(0, &[0]).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn blank_disk_is_sparse() {
+    let mut disk = Disk::blank(1 << 40);
+    assert_eq!(disk.sectors(), 1 << 40);
+    let mut buf = [0xff; 4];
+    disk.read(0x7f_0000_0000, &mut buf).unwrap();
+    assert_eq!(buf, [0; 4]);
+    disk.write(0x7f_0000_01fe, b"data").unwrap();
+    disk.read(0x7f_0000_01fe, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    // ranges that end past u64::MAX must not wrap around
+    assert!(disk.read(u64::MAX - 1, &mut buf).is_err());
+    assert!(disk.write(u64::MAX - 1, b"data").is_err());
+}
+
+#[test]
+pub fn test_all() {
+    overlay_keeps_image_pristine();
+    blank_disk_is_sparse();
+}
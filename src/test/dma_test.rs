@@ -0,0 +1,136 @@
+use crate::bus::{Bus, DMA_BASE, UART_BASE};
+use crate::config::{Config, TimeMode, UartConfig};
+use crate::cpu::{Instruction, Size, T0, T1, T2, ZERO};
+use crate::devices::device::Device;
+use crate::devices::dma::{CHANNEL_SIZE, CONTROL_BYTE, CONTROL_DST_FIXED, CONTROL_IRQ, CONTROL_START, DMA_IRQ, STATUS_DONE, STATUS_ERROR};
+use crate::devices::serial::{Memory, SerialLink};
+use crate::devices::uart::UART_IRQ;
+use super::cpu_with_program;
+
+const SRC: u32 = 0x00;
+const DST: u32 = 0x04;
+const LEN: u32 = 0x08;
+const NEXT: u32 = 0x0c;
+const CONTROL: u32 = 0x10;
+const STATUS: u32 = 0x14;
+const TRANSFERRED: u32 = 0x18;
+/// UART FIFO control register.
+const FCR: u32 = 2;
+
+fn set(bus: &mut Bus, channel: u32, reg: u32, value: u32) {
+    bus.write(DMA_BASE + channel * CHANNEL_SIZE + reg, value, Size::Word).unwrap();
+}
+
+fn get(bus: &mut Bus, channel: u32, reg: u32) -> u32 {
+    bus.read(DMA_BASE + channel * CHANNEL_SIZE + reg, Size::Word).unwrap()
+}
+
+fn interrupting(bus: &Bus) -> bool {
+    *bus.get_raw_cause().lock().unwrap() & 1 << (DMA_IRQ + 8) != 0
+}
+
+fn fill(bus: &mut Bus, addr: u32, data: &[u8]) {
+    for (i, byte) in data.iter().enumerate() {
+        bus.dram.write(addr + i as u32, *byte as u32, Size::Byte).unwrap();
+    }
+}
+
+fn dump(bus: &mut Bus, addr: u32, len: u32) -> Vec<u8> {
+    (0..len).map(|i| bus.dram.read(addr + i, Size::Byte).unwrap() as u8).collect()
+}
+
+#[test]
+fn dma_transfers() {
+    let memory = Memory::default();
+    let uarts = vec![UartConfig { base: UART_BASE, irq: UART_IRQ, link: SerialLink::Memory(memory.clone()) }];
+    let mut bus = Bus::new(&Config { time_mode: TimeMode::Deterministic, uarts, ..Config::default() });
+
+    // aligned copies move a word per cycle and interrupt when done
+    fill(&mut bus, 0x200000, b"0123456789abcdef");
+    set(&mut bus, 0, SRC, 0x200000);
+    set(&mut bus, 0, DST, 0x210000);
+    set(&mut bus, 0, LEN, 16);
+    set(&mut bus, 0, CONTROL, CONTROL_START | CONTROL_IRQ);
+    assert!(bus.dma_busy());
+    assert_eq!(bus.cycles_until_event(), Some(4));
+    bus.advance(3);
+    assert_eq!(get(&mut bus, 0, TRANSFERRED), 12);
+    assert!(!interrupting(&bus));
+    bus.advance(1);
+    assert_eq!(get(&mut bus, 0, CONTROL), CONTROL_IRQ);
+    assert_eq!(get(&mut bus, 0, STATUS), STATUS_DONE);
+    assert!(interrupting(&bus));
+    assert_eq!(dump(&mut bus, 0x210000, 16), b"0123456789abcdef");
+    set(&mut bus, 0, STATUS, STATUS_DONE);
+    assert!(!interrupting(&bus));
+
+    // a scatter-gather chain, the unaligned segment goes byte by byte
+    for (i, word) in [0x200001, 0x220000, 3, 0x300010, 0x200008, 0x220003, 8, 0].iter().enumerate() {
+        bus.dram.write(0x300000 + 4 * i as u32, *word, Size::Word).unwrap();
+    }
+    set(&mut bus, 1, NEXT, 0x300000);
+    set(&mut bus, 1, CONTROL, CONTROL_START);
+    bus.advance(100);
+    assert_eq!(get(&mut bus, 1, STATUS), STATUS_DONE);
+    assert_eq!(get(&mut bus, 1, TRANSFERRED), 11);
+    assert_eq!(dump(&mut bus, 0x220000, 11), b"12389abcdef");
+    assert!(!interrupting(&bus));
+
+    // into a byte-wide device FIFO
+    fill(&mut bus, 0x200100, b"hello");
+    bus.write(UART_BASE + FCR, 1, Size::Byte).unwrap();
+    set(&mut bus, 2, SRC, 0x200100);
+    set(&mut bus, 2, DST, UART_BASE);
+    set(&mut bus, 2, LEN, 5);
+    set(&mut bus, 2, CONTROL, CONTROL_START | CONTROL_DST_FIXED | CONTROL_BYTE);
+    bus.advance(5);
+    assert_eq!(get(&mut bus, 2, STATUS), STATUS_DONE);
+    for _ in 0..10 {
+        bus.advance(100);
+    }
+    assert_eq!(memory.take_output(), b"hello");
+
+    // bus errors stop the channel, so do misaligned descriptors
+    set(&mut bus, 3, SRC, 0x90000000);
+    set(&mut bus, 3, LEN, 4);
+    set(&mut bus, 3, CONTROL, CONTROL_START | CONTROL_IRQ);
+    bus.advance(1);
+    assert_eq!(get(&mut bus, 3, STATUS), STATUS_ERROR);
+    assert!(interrupting(&bus));
+    set(&mut bus, 3, STATUS, STATUS_ERROR);
+    set(&mut bus, 3, NEXT, 0x300002);
+    set(&mut bus, 3, CONTROL, CONTROL_START);
+    bus.advance(1);
+    assert_eq!(get(&mut bus, 3, STATUS), STATUS_ERROR);
+    assert!(!bus.dma_busy());
+}
+
+#[test]
+fn dma_bus_contention() {
+    // start a 100-cycle copy, then load from memory while it runs
+    let mut program = vec![
+        Instruction::ori(T0, ZERO, 0x3400),
+        Instruction::lui(T1, 0x20),
+        Instruction::sw(T1, T0, SRC as u16),
+        Instruction::lui(T1, 0x30),
+        Instruction::sw(T1, T0, DST as u16),
+        Instruction::ori(T1, ZERO, 400),
+        Instruction::sw(T1, T0, LEN as u16),
+        Instruction::ori(T1, ZERO, CONTROL_START as u16),
+        Instruction::sw(T1, T0, CONTROL as u16),
+    ];
+    program.extend((0..5).map(|_| Instruction::lw(T2, T0, TRANSFERRED as u16)));
+    let mut cpu = cpu_with_program(&program);
+    assert_eq!(cpu.debug(program.len()), None);
+    // every load waited a cycle for the bus, the DMA engine never waited
+    assert_eq!(cpu.stats.dma_stalls, 5);
+    assert_eq!(cpu.stats.cycles, program.len() as u64 + 5);
+    assert_eq!(cpu.registers[T2 as usize], 40);
+    assert!(cpu.bus.dma_busy());
+}
+
+#[test]
+pub fn test_all() {
+    dma_transfers();
+    dma_bus_contention();
+}
@@ -0,0 +1,98 @@
+use std::{env, fs, process, thread};
+
+use crate::bus::{Bus, FRAMEBUFFER_BASE};
+use crate::config::{Config, TimeMode};
+use crate::coprocessor::CYCLES_PER_SECOND;
+use crate::cpu::Size;
+use crate::devices::device::Device;
+use crate::devices::framebuffer::{crc32, png, ppm, DumpConfig, ImageFormat, FORMAT_RGB565, FRAMEBUFFER_IRQ, REFRESH_RATE};
+
+const WIDTH: u32 = 0x00;
+const HEIGHT: u32 = 0x04;
+const FORMAT: u32 = 0x08;
+const ADDR: u32 = 0x10;
+const CONTROL: u32 = 0x14;
+const STATUS: u32 = 0x18;
+const FRAME: u32 = 0x1c;
+const DUMP: u32 = 0x20;
+const PIXELS: u32 = 0x200000;
+const FRAME_CYCLES: u64 = CYCLES_PER_SECOND / REFRESH_RATE;
+
+fn set(bus: &mut Bus, reg: u32, value: u32) {
+    bus.write(FRAMEBUFFER_BASE + reg, value, Size::Word).unwrap();
+}
+
+fn get(bus: &mut Bus, reg: u32) -> u32 {
+    bus.read(FRAMEBUFFER_BASE + reg, Size::Word).unwrap()
+}
+
+fn interrupting(bus: &Bus) -> bool {
+    *bus.get_raw_cause().lock().unwrap() & 1 << (FRAMEBUFFER_IRQ + 8) != 0
+}
+
+#[test]
+fn framebuffer_frames_and_dumps() {
+    let dir = env::temp_dir().join(format!("mips-emu-fb-{}-{:?}", process::id(), thread::current().id()));
+    fs::create_dir_all(&dir).unwrap();
+    let prefix = dir.join("frame-").to_str().unwrap().to_string();
+    let dump = DumpConfig { prefix: prefix.clone(), format: ImageFormat::Ppm, every: Some(2) };
+    let config = Config { time_mode: TimeMode::Deterministic, fb_dump: Some(dump), ..Config::default() };
+    let mut bus = Bus::new(&config);
+
+    // a 3x2 image: red, green, blue over white, black, grey
+    let pixels = [0xff0000, 0x00ff00, 0x0000ff, 0xffffff, 0x000000, 0x808080];
+    for (i, pixel) in pixels.iter().enumerate() {
+        bus.dram.write(PIXELS + 4 * i as u32, *pixel, Size::Word).unwrap();
+    }
+    let rgb = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 128, 128, 128];
+    set(&mut bus, WIDTH, 3);
+    set(&mut bus, HEIGHT, 2);
+    set(&mut bus, ADDR, PIXELS);
+    set(&mut bus, CONTROL, 3);
+    assert_eq!(bus.framebuffer.snapshot(&mut bus.dram).unwrap(), (3, 2, rgb.to_vec()));
+
+    // vsync at the refresh rate, acknowledged through STATUS
+    assert_eq!(bus.cycles_until_event(), Some(FRAME_CYCLES));
+    bus.advance(FRAME_CYCLES - 1);
+    assert!(!interrupting(&bus));
+    bus.advance(1);
+    assert!(interrupting(&bus));
+    assert_eq!(get(&mut bus, FRAME), 1);
+    set(&mut bus, STATUS, 1);
+    assert!(!interrupting(&bus));
+    assert!(!dir.join("frame-000001.ppm").exists());
+
+    // every second frame is dumped, and the guest can ask for one
+    bus.advance(FRAME_CYCLES);
+    assert_eq!(fs::read(dir.join("frame-000002.ppm")).unwrap(), ppm(3, 2, &rgb));
+    set(&mut bus, FORMAT, FORMAT_RGB565);
+    bus.dram.write(PIXELS, 0xffff, Size::Halfword).unwrap();
+    bus.dram.write(PIXELS + 2, 0xf800, Size::Halfword).unwrap();
+    set(&mut bus, DUMP, 1);
+    bus.advance(1);
+    let dumped = fs::read(dir.join("frame-000002.ppm")).unwrap();
+    assert_eq!(dumped[dumped.len() - 18..dumped.len() - 12], [255, 255, 255, 255, 0, 0]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn png_encoding() {
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    let rgb = [1, 2, 3, 4, 5, 6];
+    let image = png(1, 2, &rgb);
+    assert_eq!(image[..8], [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+    // IHDR: 1x2, 8-bit truecolor
+    assert_eq!(image[12..29], [b'I', b'H', b'D', b'R', 0, 0, 0, 1, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+    // IDAT holds a single stored block with the filter bytes
+    let idat = &image[37..];
+    assert_eq!(idat[..4], *b"IDAT");
+    assert_eq!(idat[4..11], [0x78, 0x01, 1, 8, 0, 0xf7, 0xff]);
+    assert_eq!(idat[11..19], [0, 1, 2, 3, 0, 4, 5, 6]);
+    assert_eq!(image[image.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+}
+
+#[test]
+pub fn test_all() {
+    framebuffer_frames_and_dumps();
+    png_encoding();
+}
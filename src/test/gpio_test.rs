@@ -0,0 +1,85 @@
+use crate::bus::{Bus, GPIO_BASE};
+use crate::config::{Config, TimeMode};
+use crate::cpu::{Instruction, Size, T0, T1, ZERO};
+use crate::devices::device::Device;
+use crate::devices::gpio::GPIO_IRQ;
+use super::cpu_with_program;
+
+const IN: u32 = 0x00;
+const OUT: u32 = 0x04;
+const DIR: u32 = 0x08;
+const RISE: u32 = 0x0c;
+const FALL: u32 = 0x10;
+const IRQ_STATUS: u32 = 0x14;
+
+fn set(bus: &mut Bus, reg: u32, value: u32) {
+    bus.write(GPIO_BASE + reg, value, Size::Word).unwrap();
+}
+
+fn get(bus: &mut Bus, reg: u32) -> u32 {
+    bus.read(GPIO_BASE + reg, Size::Word).unwrap()
+}
+
+fn interrupting(bus: &Bus) -> bool {
+    *bus.get_raw_cause().lock().unwrap() & 1 << (GPIO_IRQ + 8) != 0
+}
+
+#[test]
+fn gpio_pins_and_edges() {
+    let mut bus = Bus::new(&Config { time_mode: TimeMode::Deterministic, ..Config::default() });
+    let pins = bus.gpio.pins();
+
+    // outputs show up on the host side, and win over the host driving them
+    set(&mut bus, DIR, 0x0f);
+    set(&mut bus, OUT, 0x05);
+    pins.drive(0, false);
+    assert!(pins.level(0) && !pins.level(1));
+    assert_eq!(get(&mut bus, IN), 0x05);
+
+    // inputs follow the host, undriven ones read low
+    pins.drive(8, true);
+    pins.drive(1, true);
+    assert_eq!(get(&mut bus, IN), 0x105);
+    pins.release(8);
+    assert_eq!(get(&mut bus, IN), 0x05);
+
+    // a scheduled edge interrupts at its virtual time
+    set(&mut bus, RISE, 1 << 9);
+    set(&mut bus, FALL, 1 << 9);
+    pins.schedule(100, 9, Some(true));
+    pins.schedule(150, 9, None);
+    bus.advance(10);
+    assert_eq!(bus.gpio.cycles_until_event(), Some(90));
+    bus.advance(89);
+    assert!(!interrupting(&bus));
+    bus.advance(1);
+    assert!(interrupting(&bus));
+    assert_eq!(get(&mut bus, IRQ_STATUS), 1 << 9);
+    set(&mut bus, IRQ_STATUS, 1 << 9);
+    assert!(!interrupting(&bus));
+    bus.advance(50);
+    assert_eq!(get(&mut bus, IRQ_STATUS), 1 << 9);
+    assert_eq!(pins.history(), [(0, 0), (0, 0x05), (0, 0x105), (0, 0x05), (100, 0x205), (150, 0x05)]);
+}
+
+#[test]
+fn gpio_bit_banging() {
+    // the guest toggles pin 0, the history shows when
+    let mut cpu = cpu_with_program(&[
+        Instruction::ori(T0, ZERO, 0x3600),
+        Instruction::ori(T1, ZERO, 1),
+        Instruction::sw(T1, T0, DIR as u16),
+        Instruction::sw(T1, T0, OUT as u16),
+        Instruction::sw(ZERO, T0, OUT as u16),
+        Instruction::sw(T1, T0, OUT as u16),
+    ]);
+    let pins = cpu.bus.gpio.pins();
+    assert_eq!(cpu.debug(6), None);
+    assert_eq!(pins.history(), [(0, 0), (4, 1), (5, 0), (6, 1)]);
+}
+
+#[test]
+pub fn test_all() {
+    gpio_pins_and_edges();
+    gpio_bit_banging();
+}
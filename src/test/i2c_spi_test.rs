@@ -0,0 +1,150 @@
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{Bus, I2C_BASE, SPI_BASE};
+use crate::config::{Config, TimeMode};
+use crate::cpu::Size;
+use crate::devices::device::Device;
+use crate::devices::i2c::{Eeprom, Lm75, CR_ACK, CR_IACK, CR_RD, CR_STA, CR_STO, CR_WR, CTR_EN, CTR_IEN, EEPROM_ADDRESS, I2C_IRQ, LM75_ADDRESS, SR_BUSY, SR_IF, SR_RXACK, SR_TIP};
+use crate::devices::spi::{SpiFlash, SpiSlave, CHIP_SELECTS, CONTROL_ENABLE, FLASH_CS, FLASH_ID, STATUS_DONE};
+
+const PRER_LO: u32 = 0x00;
+const PRER_HI: u32 = 0x04;
+const CTR: u32 = 0x08;
+const TXR_RXR: u32 = 0x0c;
+const CR_SR: u32 = 0x10;
+
+const CONTROL: u32 = 0x00;
+const CS: u32 = 0x08;
+const DATA: u32 = 0x0c;
+const STATUS: u32 = 0x10;
+
+fn machine() -> Bus {
+    Bus::new(&Config { time_mode: TimeMode::Deterministic, ..Config::default() })
+}
+
+/// Runs an I2C command to completion and returns the status register.
+fn i2c(bus: &mut Bus, cr: u32, txr: u8) -> u32 {
+    bus.write(I2C_BASE + TXR_RXR, txr as u32, Size::Byte).unwrap();
+    bus.write(I2C_BASE + CR_SR, cr, Size::Byte).unwrap();
+    while let Some(cycles) = bus.i2c.cycles_until_event() {
+        bus.advance(cycles);
+    }
+    let sr = bus.read(I2C_BASE + CR_SR, Size::Byte).unwrap();
+    bus.write(I2C_BASE + CR_SR, CR_IACK, Size::Byte).unwrap();
+    sr
+}
+
+fn i2c_read(bus: &mut Bus, cr: u32) -> u8 {
+    i2c(bus, cr, 0);
+    bus.read(I2C_BASE + TXR_RXR, Size::Byte).unwrap() as u8
+}
+
+#[test]
+fn i2c_eeprom_and_sensor() {
+    let mut bus = machine();
+    let eeprom = Eeprom::default();
+    bus.i2c.attach(EEPROM_ADDRESS, Box::new(eeprom.clone()));
+    let sensor = Lm75::default();
+    bus.i2c.attach(LM75_ADDRESS, Box::new(sensor.clone()));
+    // a bit every 10 cycles, 100 kHz
+    bus.write(I2C_BASE + PRER_LO, 1, Size::Byte).unwrap();
+    bus.write(I2C_BASE + PRER_HI, 0, Size::Byte).unwrap();
+    bus.write(I2C_BASE + CTR, CTR_EN | CTR_IEN, Size::Byte).unwrap();
+
+    // a start and an address byte take ten bit times, then interrupt
+    bus.write(I2C_BASE + TXR_RXR, (EEPROM_ADDRESS as u32) << 1, Size::Byte).unwrap();
+    bus.write(I2C_BASE + CR_SR, CR_STA | CR_WR, Size::Byte).unwrap();
+    assert_eq!(bus.read(I2C_BASE + CR_SR, Size::Byte).unwrap(), SR_TIP);
+    bus.advance(99);
+    assert!(*bus.get_raw_cause().lock().unwrap() & 1 << (I2C_IRQ + 8) == 0);
+    bus.advance(1);
+    assert!(*bus.get_raw_cause().lock().unwrap() & 1 << (I2C_IRQ + 8) != 0);
+    assert_eq!(bus.read(I2C_BASE + CR_SR, Size::Byte).unwrap(), SR_BUSY | SR_IF);
+    bus.write(I2C_BASE + CR_SR, CR_IACK, Size::Byte).unwrap();
+
+    // write two bytes at 0x10, then read them back after a repeated start
+    i2c(&mut bus, CR_WR, 0x10);
+    i2c(&mut bus, CR_WR, 0x42);
+    assert_eq!(i2c(&mut bus, CR_WR | CR_STO, 0x43), SR_IF);
+    assert_eq!(eeprom.contents()[0x10..0x12], [0x42, 0x43]);
+    i2c(&mut bus, CR_STA | CR_WR, EEPROM_ADDRESS << 1);
+    i2c(&mut bus, CR_WR, 0x10);
+    assert_eq!(i2c(&mut bus, CR_STA | CR_WR, EEPROM_ADDRESS << 1 | 1), SR_BUSY | SR_IF);
+    assert_eq!(i2c_read(&mut bus, CR_RD), 0x42);
+    assert_eq!(i2c_read(&mut bus, CR_RD | CR_ACK | CR_STO), 0x43);
+
+    // nobody answers at 0x30
+    assert_eq!(i2c(&mut bus, CR_STA | CR_WR, 0x30 << 1), SR_RXACK | SR_BUSY | SR_IF);
+    i2c(&mut bus, CR_STO, 0);
+
+    // the sensor reports what the host sets, in half degrees
+    sensor.set_temperature(-5500);
+    i2c(&mut bus, CR_STA | CR_WR, LM75_ADDRESS << 1);
+    i2c(&mut bus, CR_WR, 0);
+    i2c(&mut bus, CR_STA | CR_WR, LM75_ADDRESS << 1 | 1);
+    assert_eq!(i2c_read(&mut bus, CR_RD), 0xfa);
+    assert_eq!(i2c_read(&mut bus, CR_RD | CR_ACK | CR_STO), 0x80);
+}
+
+/// Exchanges a byte over SPI.
+fn spi(bus: &mut Bus, byte: u8) -> u8 {
+    bus.write(SPI_BASE + DATA, byte as u32, Size::Word).unwrap();
+    bus.advance(16);
+    assert_eq!(bus.read(SPI_BASE + STATUS, Size::Word).unwrap(), STATUS_DONE);
+    bus.write(SPI_BASE + STATUS, STATUS_DONE, Size::Word).unwrap();
+    bus.read(SPI_BASE + DATA, Size::Word).unwrap() as u8
+}
+
+/// Sends `bytes` with the chip select active and returns what came back.
+fn spi_command(bus: &mut Bus, cs: u32, bytes: &[u8]) -> Vec<u8> {
+    bus.write(SPI_BASE + CS, cs, Size::Word).unwrap();
+    let received = bytes.iter().map(|byte| spi(bus, *byte)).collect();
+    bus.write(SPI_BASE + CS, CHIP_SELECTS, Size::Word).unwrap();
+    received
+}
+
+/// A scripted slave answering every byte with the byte plus one, and logging the bytes.
+struct Scripted(Arc<Mutex<Vec<u8>>>);
+
+impl SpiSlave for Scripted {
+    fn select(&mut self) {}
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.0.lock().unwrap().push(byte);
+        byte.wrapping_add(1)
+    }
+    fn deselect(&mut self) {}
+}
+
+#[test]
+fn spi_flash_and_scripted_slave() {
+    let mut bus = machine();
+    let flash = SpiFlash::default();
+    bus.spi.attach(FLASH_CS, Box::new(flash.clone()));
+    bus.write(SPI_BASE + CONTROL, CONTROL_ENABLE, Size::Word).unwrap();
+
+    assert_eq!(spi_command(&mut bus, FLASH_CS, &[0x9f, 0, 0, 0])[1..], FLASH_ID);
+    // programming needs write enable, and clears it
+    spi_command(&mut bus, FLASH_CS, &[0x02, 0x00, 0x01, 0x00, 1, 2, 3]);
+    assert_eq!(flash.contents()[0x100], 0xff);
+    spi_command(&mut bus, FLASH_CS, &[0x06]);
+    assert_eq!(spi_command(&mut bus, FLASH_CS, &[0x05, 0])[1], 0x02);
+    spi_command(&mut bus, FLASH_CS, &[0x02, 0x00, 0x01, 0x00, 1, 2, 3]);
+    assert_eq!(spi_command(&mut bus, FLASH_CS, &[0x05, 0])[1], 0);
+    assert_eq!(spi_command(&mut bus, FLASH_CS, &[0x03, 0x00, 0x01, 0x00, 0, 0, 0, 0])[4..], [1, 2, 3, 0xff]);
+    spi_command(&mut bus, FLASH_CS, &[0x06]);
+    spi_command(&mut bus, FLASH_CS, &[0x20, 0x00, 0x00, 0x42]);
+    assert_eq!(flash.contents()[0x100..0x103], [0xff; 3]);
+
+    // a slave from test code on another chip select, nothing answers on a free one
+    let log = Arc::new(Mutex::new(Vec::new()));
+    bus.spi.attach(1, Box::new(Scripted(log.clone())));
+    assert_eq!(spi_command(&mut bus, 1, &[7, 9]), [8, 10]);
+    assert_eq!(*log.lock().unwrap(), [7, 9]);
+    assert_eq!(spi_command(&mut bus, 2, &[7]), [0xff]);
+}
+
+#[test]
+pub fn test_all() {
+    i2c_eeprom_and_sensor();
+    spi_flash_and_scripted_slave();
+}
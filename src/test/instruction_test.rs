@@ -1,10 +1,247 @@
-use crate::cpu::{Cpu, Instruction};
+use crate::bus::COPROCESSOR_BASE;
+use crate::coprocessor::{CAUSE, CAUSE_PCI, CONFIG0, EBASE, EPC, ERROREPC, PERF_INSTRUCTIONS, PRID, SR, SRSCTL, WATCHHI, WATCH_W};
+use crate::cpu::{Instruction, Size, RA, REBOOT_VECTOR, S0, S1, S2, S3, S4, S5, SP, T0, T1, T2, T3, T4, T5, T6, T7, T8, ZERO};
+use crate::devices::device::Device;
+use crate::devices::syscon::{COMMAND_POWEROFF, COMMAND_REBOOT};
+use crate::memory::walkpgdir;
+use super::{cpu_with_program, PROGRAM_BASE};
 
 #[test]
 fn gauss_sum() {
 }
 
+#[test]
+fn wait_skips_to_timer() {
+    let mut cpu = cpu_with_program(&[Instruction::wait()]);
+    cpu.debug(3);
+    assert!(cpu.stats.idle_cycles > 0);
+    assert_eq!(cpu.stats.waits, 1);
+    assert_eq!(cpu.pc, 0x80000000);
+    assert_eq!(cpu.load_coprocessor0(EPC).unwrap(), 0x80000000 + PROGRAM_BASE + 4);
+}
+
+#[test]
+fn signed_loads_and_branches() {
+    let mut cpu = cpu_with_program(&[
+        Instruction::lui(T3, 0x8010),
+        Instruction::lb(T0, T3, 0x100),
+        Instruction::lh(T1, T3, 0x100),
+        Instruction::lh(T2, T3, 0x102),
+        Instruction::bltz(T0, 1),
+        Instruction::ori(T4, ZERO, 1),
+        Instruction::bgez(T0, 1),
+        Instruction::ori(T5, ZERO, 1),
+        Instruction::blez(ZERO, 1),
+        Instruction::ori(T4, ZERO, 2),
+        Instruction::bgtz(T2, 1),
+        Instruction::ori(T4, ZERO, 3),
+        Instruction::bgezal(T0, 0x10), // not taken, but links
+        Instruction::bltzal(T0, 1),
+        Instruction::ori(T4, ZERO, 4),
+    ]);
+    cpu.bus.dram.write(PROGRAM_BASE + 0x100, 0x7fff8080, Size::Word).unwrap();
+    cpu.debug(11);
+    assert_eq!(cpu.registers[T0 as usize], 0xffffff80);
+    assert_eq!(cpu.registers[T1 as usize], 0xffff8080);
+    assert_eq!(cpu.registers[T2 as usize], 0x7fff);
+    assert_eq!(cpu.registers[T4 as usize], 0);
+    assert_eq!(cpu.registers[T5 as usize], 1);
+    assert_eq!(cpu.registers[RA as usize], 0x80000000 + PROGRAM_BASE + 14 * 4);
+    assert_eq!(cpu.pc, 0x80000000 + PROGRAM_BASE + 15 * 4);
+}
+
+#[test]
+fn variable_shifts_and_unaligned_words() {
+    let mut cpu = cpu_with_program(&[
+        Instruction::lui(T3, 0x8010),
+        Instruction::ori(T0, ZERO, 4),
+        Instruction::addiu(T1, ZERO, 0xfff0),
+        Instruction::sllv(T2, T1, T0),
+        Instruction::srlv(T4, T1, T0),
+        Instruction::srav(T5, T1, T0),
+        Instruction::mul(T6, T1, T0),
+        Instruction::clz(T7, T0),
+        Instruction::movz(S0, T0, ZERO),
+        Instruction::movn(S1, T0, ZERO),
+        Instruction::lwr(S2, T3, 0x102),
+        Instruction::lwl(S2, T3, 0x105),
+        Instruction::swr(T1, T3, 0x109),
+        Instruction::swl(T1, T3, 0x10c),
+        Instruction::div(T0, ZERO), // leaves hi and lo alone
+        Instruction::sltiu(T8, T1, 0xffff),
+        Instruction::ori(S4, T3, 19 * 4),
+        Instruction::jalr(S3, S4),
+        Instruction::ori(S5, ZERO, 1),
+    ]);
+    cpu.bus.dram.write(PROGRAM_BASE + 0x100, 0x44332211, Size::Word).unwrap();
+    cpu.bus.dram.write(PROGRAM_BASE + 0x104, 0x88776655, Size::Word).unwrap();
+    cpu.debug(18);
+    assert_eq!(cpu.registers[T2 as usize], 0xffffff00);
+    assert_eq!(cpu.registers[T4 as usize], 0x0fffffff);
+    assert_eq!(cpu.registers[T5 as usize], 0xffffffff);
+    assert_eq!(cpu.registers[T6 as usize], -64i32 as u32);
+    assert_eq!(cpu.registers[T7 as usize], 29);
+    assert_eq!(cpu.registers[S0 as usize], 4);
+    assert_eq!(cpu.registers[S1 as usize], 0);
+    assert_eq!(cpu.registers[S2 as usize], 0x66554433);
+    assert_eq!(cpu.bus.dram.read(PROGRAM_BASE + 0x108, Size::Word).unwrap(), 0xfffff000);
+    assert_eq!(cpu.bus.dram.read(PROGRAM_BASE + 0x10c, Size::Word).unwrap(), 0xff);
+    assert_eq!((cpu.hi, cpu.lo), (0, 0));
+    assert_eq!(cpu.registers[T8 as usize], 1);
+    assert_eq!(cpu.registers[S3 as usize], 0x80000000 + PROGRAM_BASE + 18 * 4);
+    assert_eq!(cpu.registers[S5 as usize], 0);
+    assert_eq!(cpu.pc, 0x80000000 + PROGRAM_BASE + 19 * 4);
+}
+
+#[test]
+fn calls_with_and_without_delay_slots() {
+    let base = 0x80000000 + PROGRAM_BASE;
+    let program = [
+        Instruction::ori(T0, ZERO, 1),
+        Instruction::jal((base + 5 * 4) >> 2 & 0x3ffffff),
+        Instruction::addiu(T0, T0, 2),
+        Instruction::addiu(T0, T0, 4),
+        Instruction::sll(ZERO, ZERO, 0),
+        Instruction::jr(RA),
+        Instruction::sll(T0, T0, 1),
+    ];
+    // with delay slots both instructions after the jumps run before their targets
+    for (branch_delay, steps, ra, t0) in [(true, 6, base + 3 * 4, (1 + 2) * 2 + 4), (false, 5, base + 2 * 4, 1 + 2 + 4)] {
+        let mut cpu = cpu_with_program(&program);
+        cpu.branch_delay = branch_delay;
+        cpu.debug(steps);
+        assert_eq!(cpu.registers[RA as usize], ra);
+        assert_eq!(cpu.registers[T0 as usize], t0);
+        assert_eq!(cpu.pc, base + 4 * 4);
+    }
+}
+
+#[test]
+fn huge_page_address() {
+    let mut cpu = cpu_with_program(&[]);
+    // the meta page table maps 0x80000000 up with 4 MiB pages, their flag bits are no address bits
+    let paddr = walkpgdir(&mut cpu, 0x80000000 + PROGRAM_BASE + 0x7f).unwrap();
+    assert_eq!(paddr.paddr, PROGRAM_BASE + 0x7f);
+    assert!(paddr.read && paddr.write);
+    assert_eq!(walkpgdir(&mut cpu, 0x80c00004).unwrap().paddr, 0x00c00004);
+}
+
+#[test]
+fn cp0_select_and_write_mask() {
+    let mut cpu = cpu_with_program(&[
+        Instruction::lui(T0, 0xffff),
+        Instruction::mtc0(T0, 15, 0), // PRId is read-only, the write is ignored
+        Instruction::mfc0(T1, 15, 0),
+        Instruction::mfc0(T3, 15, 1),
+        Instruction::mtc0(T0, 15, 1), // EBase only takes [29:12]
+        Instruction::mfc0(T2, 15, 1),
+    ]);
+    cpu.debug(6);
+    assert_eq!(cpu.registers[T1 as usize], 0x00019300);
+    assert_eq!(cpu.registers[T3 as usize], 0x80000000);
+    assert_eq!(cpu.registers[T2 as usize], 0xbfff0000);
+}
+
+#[test]
+fn cp0_legacy_offsets() {
+    let mut cpu = cpu_with_program(&[Instruction::lui(T0, 0x8765), Instruction::mtc0(T0, 15, 0)]);
+    // the MMIO window still has register n at word n and EBase at word 15
+    assert_eq!(cpu.bus.read(COPROCESSOR_BASE + 4 * 12, Size::Word).unwrap(), cpu.load_coprocessor0(SR).unwrap());
+    cpu.bus.write(COPROCESSOR_BASE + 4 * 15, 0x80123000, Size::Word).unwrap();
+    assert_eq!(cpu.load_coprocessor0(EBASE).unwrap(), 0x80123000);
+    assert_eq!(cpu.bus.read(COPROCESSOR_BASE + 4 * 15, Size::Word).unwrap(), 0x80123000);
+    assert_eq!(cpu.bus.read(COPROCESSOR_BASE + 0x400 + 4 * EBASE as u32, Size::Word).unwrap(), 0x80123000);
+    // but `mtc0 $15` without a select is PRId, which ignores writes
+    cpu.debug(2);
+    assert_eq!(cpu.load_coprocessor0(EBASE).unwrap(), 0x80123000);
+    assert_eq!(cpu.load_coprocessor0(PRID).unwrap(), 0x00019300);
+    // Config0 MT: translation goes through a TLB as far as the guest can tell
+    assert_eq!(cpu.load_coprocessor0(CONFIG0).unwrap() >> 7 & 0x7, 1);
+}
+
+#[test]
+fn store_watchpoint() {
+    let mut cpu = cpu_with_program(&[
+        Instruction::lui(T0, 0x8020),
+        Instruction::ori(T1, T0, WATCH_W as u16),
+        Instruction::mtc0(T1, 18, 0),
+        Instruction::sw(ZERO, T0, 0),
+    ]);
+    cpu.debug(4);
+    assert_eq!(cpu.pc, 0x80000000);
+    assert_eq!(cpu.load_coprocessor0(EPC).unwrap(), 0x80000000 + PROGRAM_BASE + 12);
+    assert_eq!(cpu.load_coprocessor0(CAUSE).unwrap() >> 2 & 0x1f, 23);
+    assert_ne!(cpu.load_coprocessor0(WATCHHI).unwrap() & WATCH_W, 0);
+}
+
+#[test]
+fn perf_counter_overflow_interrupts() {
+    let mut cpu = cpu_with_program(&[
+        Instruction::lui(T0, 0x7fff),
+        Instruction::ori(T0, T0, 0xfffe),
+        Instruction::mtc0(T0, 25, 1),
+        Instruction::ori(T1, ZERO, (PERF_INSTRUCTIONS << 5 | 0x12) as u16), // IE, K
+        Instruction::mtc0(T1, 25, 0),
+        Instruction::sll(ZERO, ZERO, 0),
+    ]);
+    cpu.debug(7);
+    assert_eq!(cpu.pc, 0x80000000);
+    assert_eq!(cpu.load_coprocessor0(EPC).unwrap(), 0x80000000 + PROGRAM_BASE + 24);
+    assert_ne!(cpu.load_coprocessor0(CAUSE).unwrap() & CAUSE_PCI, 0);
+}
+
+#[test]
+fn exception_switches_shadow_set() {
+    let mut cpu = cpu_with_program(&[
+        Instruction::ori(T0, ZERO, 1 << 12), // ESS = 1
+        Instruction::mtc0(T0, 12, 2),
+        Instruction::ori(SP, ZERO, 0x1234),
+        Instruction::syscall(),
+    ]);
+    cpu.debug(4);
+    assert_eq!(cpu.pc, 0x80000000);
+    assert_eq!(cpu.load_coprocessor0(SRSCTL).unwrap() & 0x3cf, 1);
+    assert_eq!(cpu.registers[SP as usize], 0);
+    assert_eq!(cpu.shadow[0][SP as usize], 0x1234);
+}
+
+#[test]
+fn poweroff_and_reboot() {
+    // the system device page is mapped at 0x3000
+    let mut cpu = cpu_with_program(&[
+        Instruction::ori(T0, ZERO, 0x3100),
+        Instruction::ori(T1, ZERO, 42),
+        Instruction::sw(T1, T0, 0), // exit status
+        Instruction::ori(T1, ZERO, COMMAND_REBOOT as u16),
+        Instruction::sw(T1, T0, 4),
+    ]);
+    assert_eq!(cpu.debug(5), None);
+    assert_eq!(cpu.pc, REBOOT_VECTOR);
+    assert_eq!(cpu.load_coprocessor0(ERROREPC).unwrap(), 0x80000000 + PROGRAM_BASE + 20);
+
+    let mut cpu = cpu_with_program(&[
+        Instruction::ori(T0, ZERO, 0x3100),
+        Instruction::ori(T1, ZERO, 42),
+        Instruction::sw(T1, T0, 0),
+        Instruction::ori(T1, ZERO, COMMAND_POWEROFF as u16),
+        Instruction::sw(T1, T0, 4),
+    ]);
+    assert_eq!(cpu.run(), 42);
+    assert_eq!(cpu.stats.instructions, 5);
+}
+
 #[test]
 pub fn test_all() {
-    gauss_sum()
-}
\ No newline at end of file
+    gauss_sum();
+    wait_skips_to_timer();
+    signed_loads_and_branches();
+    variable_shifts_and_unaligned_words();
+    calls_with_and_without_delay_slots();
+    huge_page_address();
+    cp0_select_and_write_mask();
+    cp0_legacy_offsets();
+    store_watchpoint();
+    perf_counter_overflow_interrupts();
+    exception_switches_shadow_set();
+    poweroff_and_reboot();
+}
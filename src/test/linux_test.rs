@@ -0,0 +1,208 @@
+use std::{env, fs, process};
+
+use crate::config::{Config, TimeMode, DEFAULT_RTC_EPOCH};
+use crate::coprocessor::{SR, SR_IE, SR_UM};
+use crate::cpu::{Cpu, Instruction, A0, A1, A2, A3, RA, SP, T0, V0, ZERO};
+use crate::hostio::EBADF;
+use crate::linux::{
+    AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHNUM, AT_RANDOM, AT_FDCWD, BRK, CLOCK_GETTIME, CLOCK_GETTIME64, CLOCK_MONOTONIC,
+    CLOCK_REALTIME, CLOSE, ENOSYS, ENOTTY, EXIT_GROUP, FSTAT64, GETRANDOM, IOCTL, LLSEEK, MAP_ANONYMOUS, MAP_FIXED, MMAP2, MMAP_BASE,
+    OPEN, OPENAT, O_CREAT, O_TRUNC, O_WRONLY, READ, SET_THREAD_AREA, UNAME, UNLINK, WRITEV,
+};
+use crate::memory::{read_bytes, read_string, write_bytes, DATA, TEXT};
+use super::elf_file;
+
+/// `elf_file` puts the first instruction after the ELF and program headers.
+const ENTRY: u32 = TEXT + 52 + 2 * 32;
+/// Scratch memory in the page of .data.
+const BUF: u32 = DATA + 0x800;
+const ENOENT: u32 = 2;
+const MAP_PRIVATE: u32 = 0x2;
+
+/// Loads a Linux executable with `args` after the program name and `env` as its environment.
+fn linux(name: &str, text: &[Instruction], data: &[u8], args: &[&str], env: &[&str]) -> Cpu {
+    let path = elf_file(name, text, data);
+    let config = Config {
+        time_mode: TimeMode::Deterministic,
+        kernel: path.display().to_string(),
+        linux: true,
+        uarts: Vec::new(),
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        env: env.iter().map(|var| var.to_string()).collect(),
+        ..Config::default()
+    };
+    let cpu = Cpu::new(&config);
+    fs::remove_file(path).unwrap();
+    cpu
+}
+
+fn word(cpu: &mut Cpu, vaddr: u32) -> u32 {
+    u32::from_le_bytes(read_bytes(cpu, vaddr, 4).unwrap().try_into().unwrap())
+}
+
+/// Makes system call `number` with the `syscall` at `ENTRY`, arguments after the fourth on the
+/// stack, and returns $v0 and $a3.
+fn call(cpu: &mut Cpu, number: u32, args: &[u32]) -> (u32, u32) {
+    cpu.registers[V0 as usize] = number;
+    for (reg, arg) in [A0, A1, A2, A3].iter().zip(args) {
+        cpu.registers[*reg as usize] = *arg;
+    }
+    let sp = cpu.registers[SP as usize];
+    for (i, arg) in args.iter().enumerate().skip(4) {
+        write_bytes(cpu, sp + 4 * i as u32, &arg.to_le_bytes()).unwrap();
+    }
+    cpu.pc = ENTRY;
+    cpu.debug(1);
+    assert_eq!(cpu.pc, ENTRY + 4);
+    (cpu.registers[V0 as usize], cpu.registers[A3 as usize])
+}
+
+#[test]
+fn linux_stack_and_files() {
+    let path = env::temp_dir().join(format!("mips-emu-linux-{}.txt", process::id()));
+    let mut data = format!("{}\0", path.display()).into_bytes();
+    let hello = DATA + data.len() as u32;
+    data.extend(b"hello world");
+    let program = [
+        Instruction::syscall(),
+        Instruction::ori(A0, ZERO, 7),
+        Instruction::ori(V0, ZERO, EXIT_GROUP as u16),
+        Instruction::syscall(),
+    ];
+    let mut cpu = linux("stack", &program, &data, &["-v", "input"], &["HOME=/"]);
+    assert_eq!(cpu.pc, ENTRY);
+    // user mode without interrupts
+    assert_eq!(cpu.load_coprocessor0(SR).unwrap() & (SR_UM | SR_IE), SR_UM);
+
+    // argc, argv, envp and auxv from $sp up
+    let sp = cpu.registers[SP as usize];
+    assert_eq!(sp & 0xf, 0);
+    assert_eq!(word(&mut cpu, sp), 3);
+    let argv0 = word(&mut cpu, sp + 4);
+    assert!(read_string(&mut cpu, argv0).unwrap().ends_with(".elf"));
+    let argv2 = word(&mut cpu, sp + 12);
+    assert_eq!(read_string(&mut cpu, argv2).unwrap(), "input");
+    assert_eq!(word(&mut cpu, sp + 16), 0);
+    let envp0 = word(&mut cpu, sp + 20);
+    assert_eq!(read_string(&mut cpu, envp0).unwrap(), "HOME=/");
+    assert_eq!(word(&mut cpu, sp + 24), 0);
+    let mut auxv = Vec::new();
+    let mut entry = sp + 28;
+    while word(&mut cpu, entry) != AT_NULL {
+        auxv.push((word(&mut cpu, entry), word(&mut cpu, entry + 4)));
+        entry += 8;
+    }
+    let aux = |key| auxv.iter().find(|(k, _)| *k == key).unwrap().1;
+    assert_eq!(aux(AT_PAGESZ), 0x1000);
+    assert_eq!(aux(AT_ENTRY), ENTRY);
+    assert_eq!(aux(AT_PHNUM), 2);
+    // the first program header loads the text
+    assert_eq!(read_bytes(&mut cpu, aux(AT_PHDR), 12).unwrap(), [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x40, 0]);
+    assert_eq!(read_bytes(&mut cpu, aux(AT_RANDOM), 16).unwrap().len(), 16);
+
+    // files, with the whence of _llseek on the stack
+    assert_eq!(call(&mut cpu, OPENAT, &[AT_FDCWD, DATA, O_WRONLY | O_CREAT | O_TRUNC, 0o644]), (3, 0));
+    write_bytes(&mut cpu, BUF, &[hello, 6, hello + 6, 5].map(u32::to_le_bytes).concat()).unwrap();
+    assert_eq!(call(&mut cpu, WRITEV, &[3, BUF, 2]), (11, 0));
+    assert_eq!(call(&mut cpu, CLOSE, &[3]), (0, 0));
+    assert_eq!(fs::read(&path).unwrap(), b"hello world");
+    assert_eq!(call(&mut cpu, OPEN, &[DATA, 0, 0]), (3, 0));
+    assert_eq!(call(&mut cpu, FSTAT64, &[3, BUF]), (0, 0));
+    assert_eq!(word(&mut cpu, BUF + 56), 11);
+    assert_eq!(call(&mut cpu, LLSEEK, &[3, 0, 6, BUF, 0]), (0, 0));
+    assert_eq!(word(&mut cpu, BUF), 6);
+    assert_eq!(call(&mut cpu, READ, &[3, BUF, 16]), (5, 0));
+    assert_eq!(read_bytes(&mut cpu, BUF, 5).unwrap(), b"world");
+    assert_eq!(call(&mut cpu, CLOSE, &[3]), (0, 0));
+    assert_eq!(call(&mut cpu, CLOSE, &[3]), (EBADF, 1));
+    // the standard streams are character devices but not terminals
+    assert_eq!(call(&mut cpu, FSTAT64, &[1, BUF]), (0, 0));
+    assert_eq!(word(&mut cpu, BUF + 24) & 0o170000, 0o20000);
+    assert_eq!(call(&mut cpu, IOCTL, &[1, 0x540d, BUF]), (ENOTTY, 1));
+    assert_eq!(call(&mut cpu, UNLINK, &[DATA]), (0, 0));
+    assert_eq!(call(&mut cpu, OPEN, &[DATA, 0, 0]), (ENOENT, 1));
+
+    cpu.pc = ENTRY + 4;
+    assert_eq!(cpu.run(), 7);
+}
+
+#[test]
+fn linux_memory_time_and_signals() {
+    let program = [
+        Instruction::syscall(),
+        Instruction::rdhwr(T0, 29),
+        Instruction::lw(T0, ZERO, 0),
+    ];
+    let mut cpu = linux("memory", &program, b"data", &[], &[]);
+
+    // the break starts at the page after .data
+    assert_eq!(call(&mut cpu, BRK, &[0]), (DATA + 0x1000, 0));
+    assert_eq!(call(&mut cpu, BRK, &[DATA + 0x3000]), (DATA + 0x3000, 0));
+    write_bytes(&mut cpu, DATA + 0x2ffc, b"heap").unwrap();
+    assert_eq!(call(&mut cpu, BRK, &[TEXT]), (DATA + 0x3000, 0));
+
+    // anonymous mappings are zeroed, also when MAP_FIXED replaces pages in use
+    let anonymous = MAP_PRIVATE | MAP_ANONYMOUS;
+    assert_eq!(call(&mut cpu, MMAP2, &[0, 0x2000, 3, anonymous, u32::MAX, 0]), (MMAP_BASE, 0));
+    assert_eq!(call(&mut cpu, MMAP2, &[0, 1, 3, anonymous, u32::MAX, 0]), (MMAP_BASE + 0x2000, 0));
+    write_bytes(&mut cpu, MMAP_BASE + 0x1000, b"used").unwrap();
+    assert_eq!(call(&mut cpu, MMAP2, &[MMAP_BASE + 0x1000, 4, 3, anonymous | MAP_FIXED, u32::MAX, 0]), (MMAP_BASE + 0x1000, 0));
+    assert_eq!(read_bytes(&mut cpu, MMAP_BASE + 0x1000, 4).unwrap(), [0; 4]);
+    // file mappings are private copies
+    let path = env::temp_dir().join(format!("mips-emu-linux-mmap-{}", process::id()));
+    fs::write(&path, b"mapped!").unwrap();
+    write_bytes(&mut cpu, BUF, format!("{}\0", path.display()).as_bytes()).unwrap();
+    assert_eq!(call(&mut cpu, OPEN, &[BUF, 0, 0]), (3, 0));
+    assert_eq!(call(&mut cpu, MMAP2, &[0, 7, 1, MAP_PRIVATE, 3, 0]), (MMAP_BASE + 0x3000, 0));
+    assert_eq!(read_bytes(&mut cpu, MMAP_BASE + 0x3000, 7).unwrap(), b"mapped!");
+    fs::remove_file(path).unwrap();
+
+    // the RTC's clock and virtual time since power-on
+    assert_eq!(call(&mut cpu, CLOCK_GETTIME, &[CLOCK_REALTIME, BUF]), (0, 0));
+    assert_eq!(word(&mut cpu, BUF) as u64, DEFAULT_RTC_EPOCH);
+    assert_eq!(call(&mut cpu, CLOCK_GETTIME64, &[CLOCK_MONOTONIC, BUF]), (0, 0));
+    assert_eq!(word(&mut cpu, BUF), 0);
+    assert_eq!(word(&mut cpu, BUF + 8) as u64, cpu.stats.cycles * 1000);
+    assert_eq!(call(&mut cpu, UNAME, &[BUF]), (0, 0));
+    assert_eq!(read_string(&mut cpu, BUF).unwrap(), "Linux");
+    assert_eq!(read_string(&mut cpu, BUF + 4 * 65).unwrap(), "mips");
+    assert_eq!(call(&mut cpu, GETRANDOM, &[BUF, 8, 0]), (8, 0));
+    assert_eq!(call(&mut cpu, 4999, &[]), (ENOSYS, 1));
+
+    // rdhwr reads the thread pointer
+    assert_eq!(call(&mut cpu, SET_THREAD_AREA, &[0x7000]), (0, 0));
+    cpu.debug(1);
+    assert_eq!(cpu.registers[T0 as usize], 0x7000);
+    // address 0 is not the program's, SIGSEGV
+    assert_eq!(cpu.run(), 128 + 11);
+}
+
+#[test]
+fn linux_branch_delay_slots() {
+    let program = [
+        Instruction::ori(A0, ZERO, 1),
+        Instruction::jal((ENTRY + 6 * 4) >> 2 & 0x3ffffff),
+        Instruction::addiu(A0, A0, 2), // runs before the call
+        Instruction::ori(V0, ZERO, EXIT_GROUP as u16),
+        Instruction::syscall(),
+        Instruction::sll(ZERO, ZERO, 0),
+        Instruction::bne(A0, ZERO, 2),
+        Instruction::addiu(A0, A0, 10),
+        Instruction::addiu(A0, A0, 100),
+        Instruction::jr(RA),
+        Instruction::sll(A0, A0, 1),
+    ];
+    let mut cpu = linux("delay", &program, &[], &[], &[]);
+    assert_eq!(cpu.run(), 26);
+
+    // a zero divisor traps like the check compilers put after `div`
+    let mut cpu = linux("trap", &[Instruction::div(A0, ZERO), Instruction::teq(ZERO, ZERO)], &[], &[], &[]);
+    assert_eq!(cpu.run(), 128 + 8);
+}
+
+#[test]
+pub fn test_all() {
+    linux_stack_and_files();
+    linux_memory_time_and_signals();
+    linux_branch_delay_slots();
+}
@@ -1,8 +1,118 @@
-
 #[cfg(test)]
 mod instruction_test;
+#[cfg(test)]
+mod virtio_test;
+#[cfg(test)]
+mod disk_test;
+#[cfg(test)]
+mod uart_test;
+#[cfg(test)]
+mod rtc_test;
+#[cfg(test)]
+mod watchdog_test;
+#[cfg(test)]
+mod framebuffer_test;
+#[cfg(test)]
+mod dma_test;
+#[cfg(test)]
+mod sd_test;
+#[cfg(test)]
+mod gpio_test;
+#[cfg(test)]
+mod i2c_spi_test;
+#[cfg(test)]
+mod semihosting_test;
+#[cfg(test)]
+mod spim_test;
+#[cfg(test)]
+mod linux_test;
+
+use std::path::PathBuf;
+use std::{env, fs, process};
+
+use crate::config::{Config, TimeMode};
+use crate::cpu::{Cpu, Instruction, Size};
+use crate::devices::device::Device;
+use crate::memory::{DATA, TEXT};
+
+/// Physical address test programs are loaded at, mapped at `0x80000000 + PROGRAM_BASE`.
+pub const PROGRAM_BASE: u32 = 0x100000;
+
+/// Creates a deterministic CPU running `program` in kernel mode.
+pub fn cpu_with_program(program: &[Instruction]) -> Cpu {
+    cpu_with_config(&Config { time_mode: TimeMode::Deterministic, ..Config::default() }, program)
+}
+
+/// Creates a CPU of the machine `config` describes running `program` in kernel mode.
+pub fn cpu_with_config(config: &Config, program: &[Instruction]) -> Cpu {
+    let mut cpu = Cpu::bare(config);
+    for (i, inst) in program.iter().enumerate() {
+        cpu.bus.dram.write(PROGRAM_BASE + 4 * i as u32, inst.dump(), Size::Word).unwrap();
+    }
+    cpu.pc = 0x80000000 + PROGRAM_BASE;
+    cpu
+}
+
+/// Writes a little-endian MIPS ELF executable with `text` and `data` sections to a temporary file
+/// named after `name`, and returns its path. One segment loads the headers and .text from `TEXT`
+/// on, another .data at `DATA`.
+pub fn elf_file(name: &str, text: &[Instruction], data: &[u8]) -> PathBuf {
+    let text: Vec<u8> = text.iter().flat_map(|inst| inst.dump().to_le_bytes()).collect();
+    let names = b"\0.text\0.data\0.shstrtab\0";
+    let text_offset = 52 + 2 * 32;
+    let data_offset = text_offset + text.len();
+    let names_offset = data_offset + data.len();
+    let headers_offset = (names_offset + names.len()).next_multiple_of(4);
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    // type, machine, version, entry, phoff, shoff, flags, sizes and counts
+    for half in [2u16, 8] {
+        elf.extend(half.to_le_bytes());
+    }
+    for word in [1, TEXT + text_offset as u32, 52, headers_offset as u32, 0] {
+        elf.extend(word.to_le_bytes());
+    }
+    for half in [52u16, 32, 2, 40, 4, 3] {
+        elf.extend(half.to_le_bytes());
+    }
+    // type, offset, vaddr, paddr, filesz, memsz, flags, align
+    let text_end = data_offset as u32;
+    let data_len = data.len() as u32;
+    for word in [[1, 0, TEXT, TEXT, text_end, text_end, 5, 0x1000], [1, text_end, DATA, DATA, data_len, data_len, 6, 0x1000]].iter().flatten() {
+        elf.extend(word.to_le_bytes());
+    }
+    elf.extend(&text);
+    elf.extend(data);
+    elf.extend(names);
+    elf.resize(headers_offset, 0);
+    // name, type, flags, addr, offset, size, link, info, addralign, entsize
+    let sections = [
+        [0; 10],
+        [1, 1, 6, TEXT + text_offset as u32, text_offset as u32, text.len() as u32, 0, 0, 4, 0],
+        [7, 1, 3, DATA, data_offset as u32, data.len() as u32, 0, 0, 4, 0],
+        [13, 3, 0, 0, names_offset as u32, names.len() as u32, 0, 0, 1, 0],
+    ];
+    for word in sections.iter().flatten() {
+        elf.extend(word.to_le_bytes());
+    }
+    let path = env::temp_dir().join(format!("mips-emu-{}-{}.elf", name, process::id()));
+    fs::write(&path, elf).unwrap();
+    path
+}
 
 #[test]
 pub fn test_all() {
     instruction_test::test_all();
-}
\ No newline at end of file
+    virtio_test::test_all();
+    disk_test::test_all();
+    uart_test::test_all();
+    rtc_test::test_all();
+    watchdog_test::test_all();
+    framebuffer_test::test_all();
+    dma_test::test_all();
+    sd_test::test_all();
+    gpio_test::test_all();
+    i2c_spi_test::test_all();
+    semihosting_test::test_all();
+    spim_test::test_all();
+    linux_test::test_all();
+}
@@ -0,0 +1,61 @@
+use crate::bus::{Bus, RTC_BASE};
+use crate::config::{Config, TimeMode};
+use crate::coprocessor::CYCLES_PER_SECOND;
+use crate::cpu::Size;
+use crate::devices::device::Device;
+use crate::devices::rtc::RTC_IRQ;
+
+const TIME_LOW: u32 = 0x00;
+const TIME_HIGH: u32 = 0x04;
+const ALARM_LOW: u32 = 0x08;
+const ALARM_HIGH: u32 = 0x0c;
+const IRQ_ENABLED: u32 = 0x10;
+const ALARM_STATUS: u32 = 0x18;
+const CLEAR_INTERRUPT: u32 = 0x1c;
+
+fn time(bus: &mut Bus) -> u64 {
+    let low = bus.read(RTC_BASE + TIME_LOW, Size::Word).unwrap() as u64;
+    let high = bus.read(RTC_BASE + TIME_HIGH, Size::Word).unwrap() as u64;
+    high << 32 | low
+}
+
+fn interrupting(bus: &Bus) -> bool {
+    *bus.get_raw_cause().lock().unwrap() & 1 << (RTC_IRQ + 8) != 0
+}
+
+#[test]
+fn rtc_epoch_and_alarm() {
+    let config = Config { time_mode: TimeMode::Deterministic, rtc_epoch: Some(1_700_000_000), ..Config::default() };
+    let mut bus = Bus::new(&config);
+    assert_eq!(time(&mut bus), 1_700_000_000_000_000_000);
+    bus.advance(CYCLES_PER_SECOND / 2);
+    assert_eq!(time(&mut bus), 1_700_000_000_500_000_000);
+    assert!(bus.read(RTC_BASE + TIME_LOW, Size::Byte).is_err());
+
+    // setting the time takes the high word first
+    let set = 1_234_567_890_000_000_000u64;
+    bus.write(RTC_BASE + TIME_HIGH, (set >> 32) as u32, Size::Word).unwrap();
+    bus.write(RTC_BASE + TIME_LOW, set as u32, Size::Word).unwrap();
+    assert_eq!(time(&mut bus), set);
+
+    // an alarm 2 ms ahead is the next device event
+    let alarm = set + 2_000_000;
+    bus.write(RTC_BASE + IRQ_ENABLED, 1, Size::Word).unwrap();
+    bus.write(RTC_BASE + ALARM_HIGH, (alarm >> 32) as u32, Size::Word).unwrap();
+    bus.write(RTC_BASE + ALARM_LOW, alarm as u32, Size::Word).unwrap();
+    assert_eq!(bus.read(RTC_BASE + ALARM_STATUS, Size::Word).unwrap(), 1);
+    let cycles = bus.cycles_until_event().unwrap();
+    assert_eq!(cycles, CYCLES_PER_SECOND / 500);
+    bus.advance(cycles - 1);
+    assert!(!interrupting(&bus));
+    bus.advance(1);
+    assert!(interrupting(&bus));
+    assert_eq!(bus.read(RTC_BASE + ALARM_STATUS, Size::Word).unwrap(), 0);
+    bus.write(RTC_BASE + CLEAR_INTERRUPT, 1, Size::Word).unwrap();
+    assert!(!interrupting(&bus));
+}
+
+#[test]
+pub fn test_all() {
+    rtc_epoch_and_alarm();
+}
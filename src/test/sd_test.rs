@@ -0,0 +1,146 @@
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{Bus, SD_BASE};
+use crate::config::Config;
+use crate::cpu::Size;
+use crate::devices::device::Device;
+use crate::devices::disk::Disk;
+use crate::devices::irq::Irq;
+use crate::devices::sd::{
+    SdHost, BLOCK_CYCLES, CONTROL_DMA, OCR_CCS, OCR_READY, PRESENT_CARD, PRESENT_DATA_BUSY, R1_OUT_OF_RANGE, RCA, SD_IRQ,
+    STATUS_BUF_READ_READY, STATUS_BUF_WRITE_READY, STATUS_CMD_DONE, STATUS_CMD_ERROR, STATUS_DATA_DONE,
+};
+use crate::dram::Dram;
+
+const ARG: u32 = 0x00;
+const CMD: u32 = 0x04;
+const RESP0: u32 = 0x08;
+const STATUS: u32 = 0x18;
+const IRQ_ENABLE: u32 = 0x1c;
+const DATA: u32 = 0x20;
+const DMA_ADDR: u32 = 0x24;
+const BLOCK_COUNT: u32 = 0x28;
+const CONTROL: u32 = 0x2c;
+const PRESENT: u32 = 0x30;
+
+fn set(sd: &mut SdHost, reg: u32, value: u32) {
+    sd.write(reg, value, Size::Word).unwrap();
+}
+
+fn get(sd: &mut SdHost, reg: u32) -> u32 {
+    sd.read(reg, Size::Word).unwrap()
+}
+
+/// Sends a command and returns the status bits it set and the first response word.
+fn command(sd: &mut SdHost, index: u32, arg: u32) -> (u32, u32) {
+    set(sd, ARG, arg);
+    set(sd, CMD, index);
+    let status = get(sd, STATUS);
+    set(sd, STATUS, STATUS_CMD_DONE | STATUS_CMD_ERROR);
+    (status & (STATUS_CMD_DONE | STATUS_CMD_ERROR), get(sd, RESP0))
+}
+
+/// The 128-bit register of a long response.
+fn long_response(sd: &mut SdHost) -> u128 {
+    (0..4).fold(0, |register, i| register | (get(sd, RESP0 + 4 * i) as u128) << (32 * i))
+}
+
+fn block(n: u8) -> Vec<u8> {
+    (0..512).map(|i| (i as u8).wrapping_mul(3) ^ n).collect()
+}
+
+#[test]
+fn sd_card_protocol() {
+    let cause = Arc::new(Mutex::new(0));
+    let mut image = vec![0; 2 << 20];
+    image[512..1024].copy_from_slice(&block(1));
+    let mut sd = SdHost::new(Some(Disk::memory(image)), Irq::new(cause.clone(), SD_IRQ));
+    let mut dram = Dram::new();
+    assert_eq!(get(&mut sd, PRESENT), PRESENT_CARD);
+
+    // identification: CMD0, CMD8, ACMD41, CMD2, CMD3, CMD9, CMD7
+    assert_eq!(command(&mut sd, 0, 0).0, STATUS_CMD_DONE);
+    assert_eq!(command(&mut sd, 8, 0x1aa), (STATUS_CMD_DONE, 0x1aa));
+    assert_eq!(command(&mut sd, 2, 0).0, STATUS_CMD_ERROR);
+    command(&mut sd, 55, 0);
+    let (status, ocr) = command(&mut sd, 41, 0x40ff8000);
+    assert_eq!(status, STATUS_CMD_DONE);
+    assert_eq!(ocr & (OCR_READY | OCR_CCS), OCR_READY | OCR_CCS);
+    assert_eq!(command(&mut sd, 2, 0).0, STATUS_CMD_DONE);
+    assert_eq!(long_response(&mut sd) >> 120, 0x5a);
+    assert_eq!(command(&mut sd, 3, 0).1 >> 16, RCA);
+    command(&mut sd, 9, RCA << 16);
+    let csd = long_response(&mut sd);
+    assert_eq!(csd >> 126, 1);
+    // 2 MiB are four 512 KiB units
+    assert_eq!(csd >> 48 & 0x3fffff, 3);
+    assert_eq!(command(&mut sd, 17, 1).0, STATUS_CMD_ERROR);
+    assert_eq!(command(&mut sd, 7, RCA << 16).0, STATUS_CMD_DONE);
+    assert_eq!(command(&mut sd, 13, RCA << 16).1 >> 9 & 0xf, 4);
+
+    // PIO read of block 1, the buffer fills after the card's read time
+    set(&mut sd, IRQ_ENABLE, STATUS_BUF_READ_READY | STATUS_DATA_DONE);
+    assert_eq!(command(&mut sd, 17, 1).0, STATUS_CMD_DONE);
+    assert_eq!(sd.cycles_until_event(), Some(BLOCK_CYCLES));
+    sd.advance(BLOCK_CYCLES - 1, &mut dram);
+    assert_eq!(get(&mut sd, STATUS), 0);
+    sd.advance(1, &mut dram);
+    assert_eq!(get(&mut sd, STATUS), STATUS_BUF_READ_READY);
+    assert_ne!(*cause.lock().unwrap() & 1 << (SD_IRQ + 8), 0);
+    let data: Vec<u8> = (0..128).flat_map(|_| get(&mut sd, DATA).to_le_bytes()).collect();
+    assert_eq!(data, block(1));
+    assert_eq!(get(&mut sd, STATUS), STATUS_DATA_DONE);
+    set(&mut sd, STATUS, STATUS_DATA_DONE);
+    assert_eq!(*cause.lock().unwrap() & 1 << (SD_IRQ + 8), 0);
+
+    // PIO write of block 2, the card programs it once the buffer is full
+    assert_eq!(command(&mut sd, 24, 2).0, STATUS_CMD_DONE);
+    assert_eq!(get(&mut sd, STATUS), STATUS_BUF_WRITE_READY);
+    for word in block(2).chunks(4) {
+        set(&mut sd, DATA, u32::from_le_bytes(word.try_into().unwrap()));
+    }
+    assert_eq!(get(&mut sd, PRESENT), PRESENT_CARD | PRESENT_DATA_BUSY);
+    sd.advance(BLOCK_CYCLES, &mut dram);
+    assert_eq!(get(&mut sd, STATUS), STATUS_DATA_DONE);
+    set(&mut sd, STATUS, STATUS_DATA_DONE);
+
+    // DMA read of blocks 1 and 2
+    set(&mut sd, CONTROL, CONTROL_DMA);
+    set(&mut sd, DMA_ADDR, 0x10000);
+    set(&mut sd, BLOCK_COUNT, 2);
+    assert_eq!(command(&mut sd, 18, 1).0, STATUS_CMD_DONE);
+    sd.advance(2 * BLOCK_CYCLES, &mut dram);
+    assert_eq!(get(&mut sd, STATUS), STATUS_DATA_DONE);
+    assert_eq!(get(&mut sd, DMA_ADDR), 0x10400);
+    let data: Vec<u8> = (0..1024).map(|i| dram.read(0x10000 + i, Size::Byte).unwrap() as u8).collect();
+    assert_eq!(data, [block(1), block(2)].concat());
+
+    // DMA write of block 3, read back by DMA
+    set(&mut sd, DMA_ADDR, 0x10200);
+    command(&mut sd, 24, 3);
+    sd.advance(BLOCK_CYCLES, &mut dram);
+    set(&mut sd, DMA_ADDR, 0x20000);
+    command(&mut sd, 17, 3);
+    sd.advance(BLOCK_CYCLES, &mut dram);
+    assert_eq!(dram.read(0x20000, Size::Word).unwrap(), dram.read(0x10200, Size::Word).unwrap());
+
+    // past the end of the card
+    let (status, response) = command(&mut sd, 17, 4096);
+    assert_eq!(status, STATUS_CMD_DONE);
+    assert_ne!(response & R1_OUT_OF_RANGE, 0);
+    assert_eq!(sd.cycles_until_event(), None);
+}
+
+#[test]
+fn sd_empty_slot() {
+    let mut bus = Bus::new(&Config::default());
+    assert_eq!(bus.read(SD_BASE + PRESENT, Size::Word).unwrap(), 0);
+    bus.write(SD_BASE + CMD, 0, Size::Word).unwrap();
+    assert_eq!(bus.read(SD_BASE + STATUS, Size::Word).unwrap(), STATUS_CMD_ERROR);
+}
+
+#[test]
+pub fn test_all() {
+    sd_card_protocol();
+    sd_empty_slot();
+}
@@ -0,0 +1,107 @@
+use std::{env, fs, process};
+
+use crate::config::{Config, TimeMode, DEFAULT_RTC_EPOCH};
+use crate::coprocessor::CAUSE;
+use crate::cpu::{Cpu, Instruction, Size, A0, A1, A2, T9, V0, V1, ZERO};
+use crate::devices::device::Device;
+use crate::exception::Exception;
+use crate::hostio::EBADF;
+use crate::semihosting::{ARGC, ARGN, ARGNLEN, CLOSE, EXIT, LSEEK, OPEN, O_CREAT, O_TRUNC, O_WRONLY, READ, TIME, UHI_CODE, UNLINK, WRITE};
+use super::{cpu_with_config, PROGRAM_BASE};
+
+/// Guest buffers, physical and virtual.
+const PATH: u32 = PROGRAM_BASE + 0x1000;
+const BUF: u32 = PROGRAM_BASE + 0x2000;
+const ENOENT: u32 = 2;
+
+fn config(args: &[&str]) -> Config {
+    Config {
+        time_mode: TimeMode::Deterministic,
+        kernel: String::from("hello.elf"),
+        semihosting: true,
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        ..Config::default()
+    }
+}
+
+fn put(cpu: &mut Cpu, paddr: u32, bytes: &[u8]) {
+    for (i, byte) in bytes.iter().enumerate() {
+        cpu.bus.dram.write(paddr + i as u32, *byte as u32, Size::Byte).unwrap();
+    }
+}
+
+fn get(cpu: &mut Cpu, paddr: u32, len: u32) -> Vec<u8> {
+    (0..len).map(|i| cpu.bus.dram.read(paddr + i, Size::Byte).unwrap() as u8).collect()
+}
+
+/// Makes UHI call `op` with `args` from $a0 on and returns $v0 and $v1.
+fn call(cpu: &mut Cpu, op: u32, args: &[u32]) -> (u32, u32) {
+    cpu.registers[T9 as usize] = op;
+    for (reg, arg) in [A0, A1, A2].iter().zip(args) {
+        cpu.registers[*reg as usize] = *arg;
+    }
+    cpu.pc = 0x80000000 + PROGRAM_BASE;
+    cpu.debug(1);
+    assert_eq!(cpu.pc, 0x80000000 + PROGRAM_BASE + 4);
+    (cpu.registers[V0 as usize], cpu.registers[V1 as usize])
+}
+
+#[test]
+fn semihosting_files() {
+    let mut cpu = cpu_with_config(&config(&[]), &[Instruction::sdbbp(UHI_CODE)]);
+    let path = env::temp_dir().join(format!("mips-emu-semihosting-{}", process::id()));
+    put(&mut cpu, PATH, format!("{}\0", path.display()).as_bytes());
+    let (path_va, buf_va) = (0x80000000 + PATH, 0x80000000 + BUF);
+
+    // descriptors after the standard streams, closing twice fails
+    assert_eq!(call(&mut cpu, OPEN, &[path_va, O_WRONLY | O_CREAT | O_TRUNC, 0o644]).0, 3);
+    put(&mut cpu, BUF, b"hello world");
+    assert_eq!(call(&mut cpu, WRITE, &[3, buf_va, 11]).0, 11);
+    assert_eq!(call(&mut cpu, CLOSE, &[3]), (0, 0));
+    assert_eq!(call(&mut cpu, CLOSE, &[3]), (u32::MAX, EBADF));
+    assert_eq!(fs::read(&path).unwrap(), b"hello world");
+
+    assert_eq!(call(&mut cpu, OPEN, &[path_va, 0, 0]).0, 3);
+    assert_eq!(call(&mut cpu, LSEEK, &[3, 6, 0]).0, 6);
+    assert_eq!(call(&mut cpu, READ, &[3, buf_va, 16]).0, 5);
+    assert_eq!(get(&mut cpu, BUF, 6), b"world ");
+    assert_eq!(call(&mut cpu, LSEEK, &[3, -5i32 as u32, 2]).0, 6);
+    assert_eq!(call(&mut cpu, READ, &[3, buf_va, 16]).0, 5);
+    assert_eq!(call(&mut cpu, WRITE, &[3, buf_va, 1]), (u32::MAX, EBADF));
+
+    assert_eq!(call(&mut cpu, UNLINK, &[path_va]).0, 0);
+    assert!(!path.exists());
+    assert_eq!(call(&mut cpu, OPEN, &[path_va, 0, 0]), (u32::MAX, ENOENT));
+}
+
+#[test]
+fn semihosting_cmdline_time_exit() {
+    let mut cpu = cpu_with_config(&config(&["-v", "input"]), &[Instruction::sdbbp(UHI_CODE)]);
+    assert_eq!(call(&mut cpu, ARGC, &[]).0, 3);
+    assert_eq!(call(&mut cpu, ARGNLEN, &[0]).0, 9);
+    assert_eq!(call(&mut cpu, ARGN, &[2, 0x80000000 + BUF]).0, 0);
+    assert_eq!(get(&mut cpu, BUF, 6), b"input\0");
+    assert_eq!(call(&mut cpu, ARGNLEN, &[3]).0, u32::MAX);
+    // the RTC's clock, on virtual time
+    let (seconds, nanos) = call(&mut cpu, TIME, &[]);
+    assert_eq!(seconds as u64, DEFAULT_RTC_EPOCH);
+    assert!(nanos > 0 && nanos < 1_000_000);
+
+    let mut cpu = cpu_with_config(&config(&[]), &[
+        Instruction::ori(A0, ZERO, 7),
+        Instruction::ori(T9, ZERO, EXIT as u16),
+        Instruction::sdbbp(UHI_CODE),
+    ]);
+    assert_eq!(cpu.run(), 7);
+
+    // without semihosting sdbbp is a reserved instruction
+    let mut cpu = cpu_with_config(&Config { semihosting: false, ..config(&[]) }, &[Instruction::sdbbp(UHI_CODE)]);
+    cpu.debug(1);
+    assert_eq!(cpu.load_coprocessor0(CAUSE).unwrap() >> 2 & 0x1f, Exception::InstructionBusError as u32);
+}
+
+#[test]
+pub fn test_all() {
+    semihosting_files();
+    semihosting_cmdline_time_exit();
+}
@@ -0,0 +1,167 @@
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, process};
+
+use crate::config::{Config, TimeMode};
+use crate::coprocessor::{CAUSE, SR, SR_ERL};
+use crate::cpu::{Cpu, Instruction, A0, A1, A2, RA, T0, T1, T2, V0, ZERO};
+use crate::memory::{read_bytes, TEXT};
+use crate::exception::Exception;
+use crate::spim::{CLOSE, EXIT2, OPEN, OPEN_WRITE, PRINT_CHAR, PRINT_HEX, PRINT_INT, PRINT_STRING, READ_INT, READ_STRING, SBRK, WRITE};
+use super::elf_file;
+
+/// Console output the test reads back.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Loads a SPIM program with `input` on its console.
+fn spim(name: &str, text: &[Instruction], data: &[u8], input: &str) -> (Cpu, Output) {
+    let path = elf_file(name, text, data);
+    let config = Config { time_mode: TimeMode::Deterministic, kernel: path.display().to_string(), spim: true, uarts: Vec::new(), ..Config::default() };
+    let mut cpu = Cpu::new(&config);
+    fs::remove_file(path).unwrap();
+    let output = Output::default();
+    cpu.spim.as_mut().unwrap().set_console(Box::new(Cursor::new(input.as_bytes().to_vec())), Box::new(output.clone()));
+    (cpu, output)
+}
+
+#[test]
+fn spim_console_and_heap() {
+    // sums 1..n for n read from the console, and keeps the sum on the heap
+    let program = [
+        Instruction::ori(V0, ZERO, READ_INT as u16),
+        Instruction::syscall(),
+        Instruction::addu(T0, V0, ZERO),
+        Instruction::addu(T1, ZERO, ZERO),
+        Instruction::addu(T1, T1, T0),
+        Instruction::addiu(T0, T0, -1i16 as u16),
+        Instruction::bne(ZERO, T0, -3i16 as u16),
+        Instruction::lui(A0, 0x1000),
+        Instruction::ori(V0, ZERO, PRINT_STRING as u16),
+        Instruction::syscall(),
+        Instruction::addu(A0, T1, ZERO),
+        Instruction::ori(V0, ZERO, PRINT_INT as u16),
+        Instruction::syscall(),
+        Instruction::ori(A0, ZERO, b'\n' as u16),
+        Instruction::ori(V0, ZERO, PRINT_CHAR as u16),
+        Instruction::syscall(),
+        Instruction::ori(A0, ZERO, 6),
+        Instruction::ori(V0, ZERO, SBRK as u16),
+        Instruction::syscall(),
+        Instruction::addu(T2, V0, ZERO),
+        Instruction::sw(T1, T2, 4),
+        Instruction::lw(A0, T2, 4),
+        Instruction::ori(V0, ZERO, PRINT_HEX as u16),
+        Instruction::syscall(),
+        // at most 4 bytes of a line and a NUL
+        Instruction::addiu(A0, T2, 8),
+        Instruction::ori(A1, ZERO, 5),
+        Instruction::ori(V0, ZERO, READ_STRING as u16),
+        Instruction::syscall(),
+        Instruction::ori(A0, ZERO, 3),
+        Instruction::ori(V0, ZERO, EXIT2 as u16),
+        Instruction::syscall(),
+    ];
+    let (mut cpu, output) = spim("sum", &program, b"sum: \0", "10\nhello\n");
+    assert_eq!(cpu.run(), 3);
+    assert_eq!(*output.0.lock().unwrap(), b"sum: 55\n0x00000037");
+    // the heap starts at the page after .data, sbrk rounds up to words
+    assert_eq!(cpu.registers[T2 as usize], 0x10008000);
+    assert_eq!(read_bytes(&mut cpu, 0x10008008, 5).unwrap(), b"hell\0");
+    // sbrk(0) returns the break
+    let (mut cpu, _) = spim("sbrk", &[
+        Instruction::ori(A0, ZERO, 6),
+        Instruction::ori(V0, ZERO, SBRK as u16),
+        Instruction::syscall(),
+        Instruction::ori(A0, ZERO, 0),
+        Instruction::ori(V0, ZERO, SBRK as u16),
+        Instruction::syscall(),
+    ], b"", "");
+    cpu.debug(6);
+    assert_eq!(cpu.registers[V0 as usize], 0x10008008);
+    // runs past the first CP0 timer match without taking the interrupt
+    let (mut cpu, _) = spim("timer", &[
+        Instruction::lui(T0, 2),
+        Instruction::addiu(T0, T0, -1i16 as u16),
+        Instruction::bne(ZERO, T0, -2i16 as u16),
+        Instruction::ori(A0, ZERO, 5),
+        Instruction::ori(V0, ZERO, EXIT2 as u16),
+        Instruction::syscall(),
+    ], b"", "");
+    assert_eq!(cpu.run(), 5);
+    // the interrupt would have ended in a double error and a reset
+    assert_eq!(cpu.load_coprocessor0(SR).unwrap() & SR_ERL, 0);
+}
+
+#[test]
+fn spim_files_and_unknown_services() {
+    let path = env::temp_dir().join(format!("mips-emu-spim-{}.txt", process::id()));
+    let mut data = format!("{}\0", path.display()).into_bytes();
+    let text = data.len() as u16;
+    data.extend(b"written\0");
+    let program = [
+        Instruction::lui(A0, 0x1000),
+        Instruction::ori(A1, ZERO, OPEN_WRITE as u16),
+        Instruction::ori(V0, ZERO, OPEN as u16),
+        Instruction::syscall(),
+        Instruction::addu(A0, V0, ZERO),
+        Instruction::lui(A1, 0x1000),
+        Instruction::ori(A1, A1, text),
+        Instruction::ori(A2, ZERO, 7),
+        Instruction::ori(V0, ZERO, WRITE as u16),
+        Instruction::syscall(),
+        Instruction::addu(T0, V0, ZERO),
+        Instruction::ori(V0, ZERO, CLOSE as u16),
+        Instruction::syscall(),
+        // print_float is not serviced and traps
+        Instruction::ori(V0, ZERO, 2),
+        Instruction::syscall(),
+    ];
+    let (mut cpu, _) = spim("files", &program, &data, "");
+    cpu.debug(13);
+    assert_eq!(cpu.registers[T0 as usize], 7);
+    assert_eq!(fs::read(&path).unwrap(), b"written");
+    fs::remove_file(path).unwrap();
+    cpu.debug(2);
+    assert_eq!(cpu.load_coprocessor0(CAUSE).unwrap() >> 2 & 0x1f, Exception::Syscall as u32);
+}
+
+#[test]
+fn spim_calls_return() {
+    // without delay slots the calls link the next instruction and `jr $ra` returns to it
+    let function = TEXT + 10 * 4;
+    let program = [
+        Instruction::ori(A0, ZERO, 5),
+        Instruction::jal(function >> 2 & 0x3ffffff),
+        Instruction::addiu(A0, A0, 1),
+        Instruction::lui(T0, (function >> 16) as u16),
+        Instruction::ori(T0, T0, function as u16),
+        Instruction::jalr(RA, T0),
+        Instruction::bgezal(ZERO, 3),
+        Instruction::ori(V0, ZERO, EXIT2 as u16),
+        Instruction::syscall(),
+        Instruction::sll(ZERO, ZERO, 0),
+        Instruction::sll(A0, A0, 1),
+        Instruction::jr(RA),
+    ];
+    let (mut cpu, _) = spim("calls", &program, b"", "");
+    assert_eq!(cpu.run(), ((5 * 2 + 1) * 2) * 2);
+    assert_eq!(cpu.registers[RA as usize], TEXT + 7 * 4);
+}
+
+#[test]
+pub fn test_all() {
+    spim_console_and_heap();
+    spim_files_and_unknown_services();
+    spim_calls_return();
+}
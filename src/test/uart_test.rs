@@ -0,0 +1,193 @@
+use std::{env, fs, process, thread};
+use std::time::Duration;
+
+use crate::bus::{Bus, UART_BASE, UART_DIRECTORY};
+use crate::config::{Config, TimeMode, UartConfig};
+use crate::cpu::Size;
+use crate::devices::chardev::Backend;
+use crate::devices::device::Device;
+use crate::devices::serial::{Memory, SerialLink};
+use crate::devices::uart::UART_IRQ;
+
+const RBR: u32 = 0;
+const IER: u32 = 1;
+const IIR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+/// One 8N1 character at 115200 baud on the 1 MHz virtual clock.
+const CHAR_CYCLES: u64 = 86;
+
+fn reg(bus: &mut Bus, index: u32) -> u32 {
+    bus.read(UART_BASE + index, Size::Byte).unwrap()
+}
+
+fn set(bus: &mut Bus, index: u32, value: u32) {
+    bus.write(UART_BASE + index, value, Size::Byte).unwrap();
+}
+
+fn interrupting(bus: &Bus) -> bool {
+    *bus.get_raw_cause().lock().unwrap() & 1 << (UART_IRQ + 8) != 0
+}
+
+/// A deterministic machine with one UART connected to `link`.
+fn machine(link: SerialLink) -> Bus {
+    let uarts = vec![UartConfig { base: UART_BASE, irq: UART_IRQ, link }];
+    Bus::new(&Config { time_mode: TimeMode::Deterministic, uarts, ..Config::default() })
+}
+
+#[test]
+fn uart_16550a() {
+    let mut bus = machine(SerialLink::Memory(Memory::default()));
+    assert_eq!(reg(&mut bus, LSR), 0x60);
+    assert_eq!(reg(&mut bus, IIR), 0x01);
+    assert!(bus.read(UART_BASE, Size::Word).is_err());
+
+    // divisor latch shadows RBR/THR and IER
+    set(&mut bus, IER, 0x05);
+    set(&mut bus, LCR, 0x83);
+    set(&mut bus, RBR, 12);
+    set(&mut bus, IER, 0);
+    assert_eq!(reg(&mut bus, RBR), 12);
+    set(&mut bus, RBR, 1);
+    set(&mut bus, LCR, 0x03);
+    assert_eq!(reg(&mut bus, IER), 0x05);
+    set(&mut bus, SCR, 0x5a);
+    assert_eq!(reg(&mut bus, SCR), 0x5a);
+
+    // FIFOs on with a trigger level of 4, loopback with OUT2 and RTS
+    set(&mut bus, IIR, 0x47);
+    set(&mut bus, MCR, 0x1a);
+    assert_eq!(reg(&mut bus, MSR) & 0xf0, 0x90);
+    set(&mut bus, IER, 0x01);
+    for byte in b"ping" {
+        set(&mut bus, RBR, *byte as u32);
+    }
+    assert_eq!(reg(&mut bus, LSR) & 0x60, 0);
+    assert_eq!(bus.cycles_until_event(), Some(1));
+    bus.advance(3 * CHAR_CYCLES);
+    assert!(!interrupting(&bus));
+    assert_eq!(reg(&mut bus, IIR), 0xc1);
+    bus.advance(CHAR_CYCLES);
+    assert!(interrupting(&bus));
+    assert_eq!(reg(&mut bus, IIR), 0xc4);
+    let mut received = Vec::new();
+    while reg(&mut bus, LSR) & 1 != 0 {
+        received.push(reg(&mut bus, RBR) as u8);
+    }
+    assert_eq!(received, b"ping");
+    assert!(!interrupting(&bus));
+
+    // below the trigger level the character timeout fires after four character times
+    set(&mut bus, RBR, b'!' as u32);
+    bus.advance(CHAR_CYCLES);
+    assert_eq!(reg(&mut bus, IIR), 0xc1);
+    bus.advance(5 * CHAR_CYCLES);
+    assert_eq!(reg(&mut bus, IIR), 0xcc);
+    assert_eq!(reg(&mut bus, RBR), b'!' as u32);
+    assert_eq!(reg(&mut bus, LSR), 0x60);
+
+    // THR empty raises an interrupt on enabling, reading IIR acknowledges it
+    set(&mut bus, IER, 0x03);
+    assert!(interrupting(&bus));
+    assert_eq!(reg(&mut bus, IIR), 0xc2);
+    assert_eq!(reg(&mut bus, IIR), 0xc1);
+    assert!(!interrupting(&bus));
+
+    // overrunning the RX FIFO is a line status error, cleared by reading LSR
+    set(&mut bus, IER, 0x04);
+    for _ in 0..2 {
+        for byte in 0..16 {
+            set(&mut bus, RBR, byte);
+        }
+        bus.advance(16 * CHAR_CYCLES);
+    }
+    assert_eq!(reg(&mut bus, IIR), 0xc6);
+    assert_eq!(reg(&mut bus, LSR) & 0x03, 0x03);
+    assert_eq!(reg(&mut bus, IIR), 0xc1);
+    set(&mut bus, IIR, 0x03);
+    assert_eq!(reg(&mut bus, LSR) & 0x01, 0);
+}
+
+#[test]
+fn uart_backends() {
+    let memory = Memory::default();
+    let mut bus = machine(SerialLink::Memory(memory.clone()));
+    set(&mut bus, IIR, 0x01);
+    for byte in b"hi" {
+        set(&mut bus, RBR, *byte as u32);
+    }
+    memory.feed(b"ok");
+    bus.advance(CHAR_CYCLES);
+    assert_eq!(memory.take_output(), b"h");
+    assert_eq!(reg(&mut bus, RBR), b'o' as u32);
+    assert_eq!(reg(&mut bus, LSR) & 1, 0);
+    bus.advance(CHAR_CYCLES);
+    assert_eq!(memory.take_output(), b"i");
+    assert_eq!(reg(&mut bus, RBR), b'k' as u32);
+
+    // input and output files
+    let dir = env::temp_dir().join(format!("mips-emu-uart-{}-{:?}", process::id(), thread::current().id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("in").to_str().unwrap().to_string();
+    let output = dir.join("out").to_str().unwrap().to_string();
+    fs::write(&input, b"go").unwrap();
+    let backend = Backend::File { input: Some(input), output: output.clone() };
+    let mut bus = machine(SerialLink::Char(backend));
+    set(&mut bus, IIR, 0x01);
+    let mut received = Vec::new();
+    for _ in 0..1000 {
+        bus.advance(CHAR_CYCLES);
+        while reg(&mut bus, LSR) & 1 != 0 {
+            received.push(reg(&mut bus, RBR) as u8);
+        }
+        if received.len() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(received, b"go");
+    set(&mut bus, RBR, b'!' as u32);
+    bus.advance(CHAR_CYCLES);
+    for _ in 0..1000 {
+        if fs::read(&output).unwrap() == b"!" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(fs::read(&output).unwrap(), b"!");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn uart_directory() {
+    let (debug, user) = (Memory::default(), Memory::default());
+    let uarts = vec![
+        UartConfig { base: UART_BASE + 0x300, irq: 6, link: SerialLink::Memory(debug.clone()) },
+        UartConfig { base: UART_BASE, irq: UART_IRQ, link: SerialLink::Memory(user.clone()) },
+    ];
+    let mut bus = Bus::new(&Config { time_mode: TimeMode::Deterministic, uarts, ..Config::default() });
+    let directory: Vec<u32> = (0..6).map(|i| bus.read(UART_DIRECTORY + 4 * i, Size::Word).unwrap()).collect();
+    assert_eq!(directory, [2, UART_BASE + 0x300, 6, UART_BASE, UART_IRQ as u32, 0]);
+    assert!(bus.write(UART_DIRECTORY, 0, Size::Word).is_err());
+    assert!(bus.read(UART_BASE + 0x100, Size::Byte).is_err());
+
+    set(&mut bus, 0x300 + IER, 0x02);
+    let cause = *bus.get_raw_cause().lock().unwrap();
+    assert_eq!(cause & 1 << (6 + 8), 1 << (6 + 8));
+    assert!(!interrupting(&bus));
+    set(&mut bus, 0x300 + RBR, b'd' as u32);
+    set(&mut bus, RBR, b'u' as u32);
+    bus.advance(CHAR_CYCLES);
+    assert_eq!(debug.take_output(), b"d");
+    assert_eq!(user.take_output(), b"u");
+}
+
+#[test]
+pub fn test_all() {
+    uart_16550a();
+    uart_backends();
+    uart_directory();
+}
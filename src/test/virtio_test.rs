@@ -0,0 +1,560 @@
+use std::{env, fs, process};
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::{Bus, VIRTIO_BASE, VIRTIO_SLOT_SIZE};
+use crate::config::{Config, NetConfig, ShareConfig, TimeMode};
+use crate::coprocessor::CAUSE;
+use crate::cpu::Size;
+use crate::devices::device::Device;
+use crate::devices::chardev::{Backend, CharDev};
+use crate::devices::disk::Disk;
+use crate::devices::netdev::{checksum, Link, USER_IP};
+use crate::devices::irq::Irq;
+use crate::devices::virtio::{blk::Blk, console::{PortConfig, MAX_PORTS}, queue::MAX_DESC_LEN, Virtio, VIRTIO_IRQ};
+
+const QUEUE: u32 = 0x200000;
+const REQUEST: u32 = 0x300000;
+
+/// Puts a block device holding `disk` behind a transport of the given `version` in slot 0.
+fn attach_blk(bus: &mut Bus, disk: Vec<u8>, version: u32) {
+    let irq = Irq::new(bus.get_raw_cause(), VIRTIO_IRQ);
+    bus.virtio[0] = Virtio::new(Box::new(Blk::new(Disk::memory(disk))), version, irq);
+}
+
+/// Queues a three-descriptor block request (header, data, status) at avail index `idx`.
+fn queue_request(bus: &mut Bus, idx: u32, req_type: u32, sector: u64, write: bool) {
+    // header
+    bus.write(REQUEST, req_type, Size::Word).unwrap();
+    bus.write(REQUEST + 8, sector as u32, Size::Word).unwrap();
+    bus.write(REQUEST + 12, (sector >> 32) as u32, Size::Word).unwrap();
+    // descriptors: addr, len, flags (NEXT = 1, WRITE = 2), next
+    let descs = [
+        (REQUEST, 16, 1, 1),
+        (REQUEST + 0x100, 512, if write { 1 } else { 3 }, 2),
+        (REQUEST + 0x400, 1, 2, 0),
+    ];
+    for (i, (addr, len, flags, next)) in descs.into_iter().enumerate() {
+        let desc = QUEUE + 16 * i as u32;
+        bus.write(desc, addr, Size::Word).unwrap();
+        bus.write(desc + 8, len, Size::Word).unwrap();
+        bus.write(desc + 12, flags, Size::Halfword).unwrap();
+        bus.write(desc + 14, next, Size::Halfword).unwrap();
+    }
+    // avail ring right after 8 descriptors
+    bus.write(QUEUE + 128 + 4 + 2 * (idx % 8), 0, Size::Halfword).unwrap();
+    bus.write(QUEUE + 128 + 2, idx + 1, Size::Halfword).unwrap();
+    bus.write(VIRTIO_BASE + 0x50, 0, Size::Word).unwrap(); // QueueNotify
+    bus.advance(1);
+}
+
+#[test]
+fn blk_read_write() {
+    let config = Config { time_mode: TimeMode::Deterministic, ..Config::default() };
+    let mut bus = Bus::new(&config);
+    let mut disk = vec![0; 1024];
+    disk[512..].fill(0xab);
+    attach_blk(&mut bus, disk, 1);
+
+    bus.write(VIRTIO_BASE + 0x28, 0x1000, Size::Word).unwrap(); // GuestPageSize
+    bus.write(VIRTIO_BASE + 0x38, 8, Size::Word).unwrap(); // QueueNum
+    bus.write(VIRTIO_BASE + 0x40, QUEUE >> 12, Size::Word).unwrap(); // QueuePFN
+    bus.write(VIRTIO_BASE + 0x70, 0xf, Size::Word).unwrap(); // Status: DRIVER_OK
+    assert_eq!(bus.read(VIRTIO_BASE + 0x100, Size::Word).unwrap(), 2); // capacity
+
+    // read sector 1
+    queue_request(&mut bus, 0, 0, 1, false);
+    assert_eq!(bus.read(REQUEST + 0x100, Size::Byte).unwrap(), 0xab);
+    assert_eq!(bus.read(REQUEST + 0x400, Size::Byte).unwrap(), 0);
+    assert_eq!(bus.read(QUEUE + 0x1000 + 2, Size::Halfword).unwrap(), 1); // used idx
+    assert_eq!(bus.read(VIRTIO_BASE + 0x60, Size::Word).unwrap(), 1); // InterruptStatus
+    assert_ne!(bus.coprocessor.get(CAUSE) & 1 << (8 + 3), 0);
+
+    // write it back to sector 0, then reading past the capacity fails
+    queue_request(&mut bus, 1, 1, 0, true);
+    assert_eq!(bus.read(REQUEST + 0x400, Size::Byte).unwrap(), 0);
+    queue_request(&mut bus, 2, 0, 0, false);
+    assert_eq!(bus.read(REQUEST + 0x100, Size::Byte).unwrap(), 0xab);
+    queue_request(&mut bus, 3, 0, 2, false);
+    assert_eq!(bus.read(REQUEST + 0x400, Size::Byte).unwrap(), 1);
+    // a sector whose byte offset does not fit in 64 bits fails instead of wrapping around to 0
+    queue_request(&mut bus, 4, 0, u64::MAX / 256, false);
+    assert_eq!(bus.read(REQUEST + 0x400, Size::Byte).unwrap(), 1);
+    queue_request(&mut bus, 5, 1, u64::MAX >> 9, true);
+    assert_eq!(bus.read(REQUEST + 0x400, Size::Byte).unwrap(), 1);
+    assert_eq!(bus.read(QUEUE + 0x1000 + 2, Size::Halfword).unwrap(), 6);
+
+    bus.write(VIRTIO_BASE + 0x64, 1, Size::Word).unwrap(); // InterruptACK
+    assert_eq!(bus.coprocessor.get(CAUSE) & 1 << (8 + 3), 0);
+    assert_eq!(bus.read(VIRTIO_BASE + 0x108, Size::Word).unwrap(), MAX_DESC_LEN); // size_max
+
+    // a descriptor longer than MAX_DESC_LEN is not allocated, the device needs a reset
+    bus.write(QUEUE + 16 + 8, MAX_DESC_LEN + 1, Size::Word).unwrap();
+    bus.write(QUEUE + 128 + 4 + 2 * 6, 0, Size::Halfword).unwrap();
+    bus.write(QUEUE + 128 + 2, 7, Size::Halfword).unwrap();
+    bus.write(VIRTIO_BASE + 0x50, 0, Size::Word).unwrap(); // QueueNotify
+    bus.advance(1);
+    assert_eq!(bus.read(QUEUE + 0x1000 + 2, Size::Halfword).unwrap(), 6);
+    assert_eq!(bus.read(VIRTIO_BASE + 0x70, Size::Word).unwrap() & 64, 64); // DEVICE_NEEDS_RESET
+    assert_eq!(bus.read(VIRTIO_BASE + 0x60, Size::Word).unwrap(), 2); // configuration change
+    // the driver cannot clear it by writing the status, only by resetting
+    bus.write(VIRTIO_BASE + 0x70, 0xf, Size::Word).unwrap();
+    assert_eq!(bus.read(VIRTIO_BASE + 0x70, Size::Word).unwrap(), 0x4f);
+    bus.write(VIRTIO_BASE + 0x70, 0, Size::Word).unwrap();
+    assert_eq!(bus.read(VIRTIO_BASE + 0x70, Size::Word).unwrap(), 0);
+}
+
+#[test]
+fn modern_transport() {
+    let config = Config { time_mode: TimeMode::Deterministic, ..Config::default() };
+    let mut bus = Bus::new(&config);
+    let mut disk = vec![0; 1024];
+    disk[512..].fill(0xab);
+    attach_blk(&mut bus, disk, 2);
+    assert_eq!(bus.read(VIRTIO_BASE + 0x4, Size::Word).unwrap(), 2); // Version
+
+    // the device offers VIRTIO_F_VERSION_1 in the second feature word
+    bus.write(VIRTIO_BASE + 0x14, 1, Size::Word).unwrap(); // DeviceFeaturesSel
+    let features = bus.read(VIRTIO_BASE + 0x10, Size::Word).unwrap();
+    assert_ne!(features & 1, 0);
+
+    // FEATURES_OK is refused without VERSION_1, then accepted with it
+    bus.write(VIRTIO_BASE + 0x70, 0xb, Size::Word).unwrap();
+    assert_eq!(bus.read(VIRTIO_BASE + 0x70, Size::Word).unwrap(), 0x3);
+    // neither queues nor the device go live before that
+    bus.write(VIRTIO_BASE + 0x44, 1, Size::Word).unwrap(); // QueueReady
+    assert_eq!(bus.read(VIRTIO_BASE + 0x44, Size::Word).unwrap(), 0);
+    bus.write(VIRTIO_BASE + 0x70, 0x7, Size::Word).unwrap(); // DRIVER_OK
+    assert_eq!(bus.read(VIRTIO_BASE + 0x70, Size::Word).unwrap(), 0x3);
+    bus.write(VIRTIO_BASE + 0x24, 1, Size::Word).unwrap(); // DriverFeaturesSel
+    bus.write(VIRTIO_BASE + 0x20, features, Size::Word).unwrap(); // DriverFeatures
+    bus.write(VIRTIO_BASE + 0x70, 0xb, Size::Word).unwrap();
+    assert_eq!(bus.read(VIRTIO_BASE + 0x70, Size::Word).unwrap(), 0xb);
+
+    // a block device has a single queue
+    bus.write(VIRTIO_BASE + 0x30, 1, Size::Word).unwrap(); // QueueSel
+    assert_eq!(bus.read(VIRTIO_BASE + 0x34, Size::Word).unwrap(), 0); // QueueNumMax
+    bus.write(VIRTIO_BASE + 0x30, 0, Size::Word).unwrap();
+    assert_eq!(bus.read(VIRTIO_BASE + 0x34, Size::Word).unwrap(), 8);
+
+    // same rings as the legacy layout, given explicitly
+    bus.write(VIRTIO_BASE + 0x38, 8, Size::Word).unwrap(); // QueueNum
+    bus.write(VIRTIO_BASE + 0x80, QUEUE, Size::Word).unwrap(); // QueueDescLow
+    bus.write(VIRTIO_BASE + 0x90, QUEUE + 128, Size::Word).unwrap(); // QueueDriverLow
+    bus.write(VIRTIO_BASE + 0xa0, QUEUE + 0x1000, Size::Word).unwrap(); // QueueDeviceLow
+    bus.write(VIRTIO_BASE + 0x44, 1, Size::Word).unwrap(); // QueueReady
+    assert_eq!(bus.read(VIRTIO_BASE + 0x44, Size::Word).unwrap(), 1);
+    bus.write(VIRTIO_BASE + 0x70, 0xf, Size::Word).unwrap(); // DRIVER_OK
+    assert_eq!(bus.read(VIRTIO_BASE + 0xfc, Size::Word).unwrap(), 0); // ConfigGeneration
+
+    queue_request(&mut bus, 0, 0, 1, false);
+    assert_eq!(bus.read(REQUEST + 0x100, Size::Byte).unwrap(), 0xab);
+    assert_eq!(bus.read(QUEUE + 0x1000 + 2, Size::Halfword).unwrap(), 1); // used idx
+    assert_ne!(bus.coprocessor.get(CAUSE) & 1 << (8 + 3), 0);
+
+    // reset clears QueueReady and the interrupt
+    bus.write(VIRTIO_BASE + 0x70, 0, Size::Word).unwrap();
+    assert_eq!(bus.read(VIRTIO_BASE + 0x44, Size::Word).unwrap(), 0);
+    assert_eq!(bus.coprocessor.get(CAUSE) & 1 << (8 + 3), 0);
+}
+
+#[test]
+fn legacy_queue_layout() {
+    let config = Config { time_mode: TimeMode::Deterministic, ..Config::default() };
+    let mut bus = Bus::new(&config);
+    let mut disk = vec![0; 1024];
+    disk[512..].fill(0xab);
+    attach_blk(&mut bus, disk, 1);
+    bus.write(VIRTIO_BASE + 0x28, 0x1000, Size::Word).unwrap(); // GuestPageSize
+
+    // QueueAlign must be a power of two, and a QueueNum past QueueNumMax lays out the rings of
+    // the largest queue, so the used ring stays at the next page
+    bus.write(VIRTIO_BASE + 0x3c, 0, Size::Word).unwrap();
+    bus.write(VIRTIO_BASE + 0x3c, 0x1800, Size::Word).unwrap();
+    bus.write(VIRTIO_BASE + 0x38, u32::MAX, Size::Word).unwrap(); // QueueNum
+    bus.write(VIRTIO_BASE + 0x40, QUEUE >> 12, Size::Word).unwrap(); // QueuePFN
+    bus.write(VIRTIO_BASE + 0x70, 0xf, Size::Word).unwrap(); // Status: DRIVER_OK
+    queue_request(&mut bus, 0, 0, 1, false);
+    assert_eq!(bus.read(REQUEST + 0x100, Size::Byte).unwrap(), 0xab);
+    assert_eq!(bus.read(QUEUE + 0x1000 + 2, Size::Halfword).unwrap(), 1); // used idx
+
+    // rings past 4 GiB are a device error
+    bus.write(VIRTIO_BASE + 0x40, 0xfffff, Size::Word).unwrap();
+    assert_eq!(bus.read(VIRTIO_BASE + 0x70, Size::Word).unwrap() & 64, 64); // DEVICE_NEEDS_RESET
+    assert_eq!(bus.read(VIRTIO_BASE + 0x60, Size::Word).unwrap() & 2, 2); // configuration change
+}
+
+/// Rings of queue `q` in tests driving a version 2 transport: descriptors, then the available
+/// ring at +0x100 and the used ring at +0x200.
+fn ring_base(q: u32) -> u32 {
+    QUEUE + 0x400 * q
+}
+
+/// Negotiates all offered features and sets up `queues` queues of the transport at `base`.
+fn start_modern(bus: &mut Bus, base: u32, queues: u32) {
+    bus.write(base + 0x70, 0x3, Size::Word).unwrap(); // ACKNOWLEDGE | DRIVER
+    for sel in 0..2 {
+        bus.write(base + 0x14, sel, Size::Word).unwrap();
+        let features = bus.read(base + 0x10, Size::Word).unwrap();
+        bus.write(base + 0x24, sel, Size::Word).unwrap();
+        bus.write(base + 0x20, features, Size::Word).unwrap();
+    }
+    bus.write(base + 0x70, 0xb, Size::Word).unwrap(); // FEATURES_OK
+    for q in 0..queues {
+        bus.write(base + 0x30, q, Size::Word).unwrap();
+        bus.write(base + 0x38, 8, Size::Word).unwrap();
+        bus.write(base + 0x80, ring_base(q), Size::Word).unwrap();
+        bus.write(base + 0x90, ring_base(q) + 0x100, Size::Word).unwrap();
+        bus.write(base + 0xa0, ring_base(q) + 0x200, Size::Word).unwrap();
+        bus.write(base + 0x44, 1, Size::Word).unwrap();
+    }
+    bus.write(base + 0x70, 0xf, Size::Word).unwrap(); // DRIVER_OK
+}
+
+/// Offers a single buffer in queue `q` as its `idx`th request and notifies the device.
+fn offer(bus: &mut Bus, base: u32, q: u32, idx: u32, addr: u32, data: &[u8], writable: bool) {
+    let desc = ring_base(q) + 16 * (idx % 8);
+    for (i, byte) in data.iter().enumerate() {
+        bus.write(addr + i as u32, *byte as u32, Size::Byte).unwrap();
+    }
+    bus.write(desc, addr, Size::Word).unwrap();
+    bus.write(desc + 8, data.len() as u32, Size::Word).unwrap();
+    bus.write(desc + 12, if writable { 2 } else { 0 }, Size::Halfword).unwrap();
+    bus.write(ring_base(q) + 0x100 + 4 + 2 * (idx % 8), idx % 8, Size::Halfword).unwrap();
+    bus.write(ring_base(q) + 0x100 + 2, idx + 1, Size::Halfword).unwrap();
+    bus.write(base + 0x50, q, Size::Word).unwrap();
+    bus.advance(1);
+}
+
+/// Returns the used index of queue `q`.
+fn used_idx(bus: &mut Bus, q: u32) -> u32 {
+    bus.read(ring_base(q) + 0x200 + 2, Size::Halfword).unwrap()
+}
+
+/// Returns the used element `idx` of queue `q` as the bytes written to `addr`.
+fn used_bytes(bus: &mut Bus, q: u32, idx: u32, addr: u32) -> Vec<u8> {
+    let len = bus.read(ring_base(q) + 0x200 + 4 + 8 * (idx % 8) + 4, Size::Word).unwrap();
+    (0..len).map(|i| bus.read(addr + i, Size::Byte).unwrap() as u8).collect()
+}
+
+#[test]
+fn console_ports() {
+    let dir = env::temp_dir().join(format!("mips-emu-console-{}-{:?}", process::id(), thread::current().id()));
+    fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("log").to_str().unwrap().to_string();
+    let socket = dir.join("sock").to_str().unwrap().to_string();
+    let config = Config {
+        time_mode: TimeMode::Deterministic,
+        console_ports: vec![
+            PortConfig { name: String::from("log"), backend: Backend::File { input: None, output: log.clone() } },
+            PortConfig { name: String::from("test"), backend: Backend::Unix(socket.clone()) },
+        ],
+        ..Config::default()
+    };
+    let mut bus = Bus::new(&config);
+    let base = VIRTIO_BASE + VIRTIO_SLOT_SIZE;
+    assert_eq!(bus.read(base + 0x8, Size::Word).unwrap(), 3); // DeviceID
+    assert_eq!(bus.read(base + 0x104, Size::Word).unwrap(), 2); // max_nr_ports
+    start_modern(&mut bus, base, 6);
+
+    // DEVICE_READY is answered with DEVICE_ADD for both ports
+    for i in 0..2 {
+        offer(&mut bus, base, 2, i, REQUEST + 0x40 * i, &[0; 0x40], true);
+    }
+    offer(&mut bus, base, 3, 0, REQUEST + 0x800, &[0, 0, 0, 0, 0, 0, 1, 0], false);
+    assert_eq!(used_idx(&mut bus, 2), 2);
+    assert_eq!(used_bytes(&mut bus, 2, 1, REQUEST + 0x40), [1, 0, 0, 0, 1, 0, 0, 0]);
+
+    // PORT_READY for port 1 brings its name and PORT_OPEN, then the guest opens it
+    offer(&mut bus, base, 3, 1, REQUEST + 0x800, &[1, 0, 0, 0, 3, 0, 1, 0], false);
+    for i in 2..4 {
+        offer(&mut bus, base, 2, i, REQUEST + 0x40 * i, &[0; 0x40], true);
+    }
+    assert_eq!(used_bytes(&mut bus, 2, 2, REQUEST + 0x80), b"\x01\0\0\0\x07\0\0\0test");
+    offer(&mut bus, base, 3, 2, REQUEST + 0x800, &[1, 0, 0, 0, 6, 0, 1, 0], false);
+
+    // port 0 output goes to the file
+    offer(&mut bus, base, 1, 0, REQUEST + 0x900, b"hello", false);
+    // port 1 input comes from the socket into receiveq(port1), queue 4
+    let mut client = UnixStream::connect(&socket).unwrap();
+    client.write_all(b"ping").unwrap();
+    offer(&mut bus, base, 4, 0, REQUEST + 0xa00, &[0; 16], true);
+    for _ in 0..1000 {
+        if used_idx(&mut bus, 4) == 1 && fs::read(&log).unwrap() == b"hello" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+        bus.advance(1);
+    }
+    assert_eq!(used_bytes(&mut bus, 4, 0, REQUEST + 0xa00), b"ping");
+    assert_eq!(fs::read(&log).unwrap(), b"hello");
+    // only a stale socket is replaced, any other file at the path is left alone
+    assert!(CharDev::open(&Backend::Unix(log.clone())).is_err());
+    assert_eq!(fs::read(&log).unwrap(), b"hello");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn console_many_ports() {
+    let dir = env::temp_dir().join(format!("mips-emu-ports-{}-{:?}", process::id(), thread::current().id()));
+    fs::create_dir_all(&dir).unwrap();
+    let log = |i: usize| dir.join(format!("port{}", i)).to_str().unwrap().to_string();
+    let config = Config {
+        time_mode: TimeMode::Deterministic,
+        console_ports: (0..MAX_PORTS)
+            .map(|i| PortConfig { name: format!("port{}", i), backend: Backend::File { input: None, output: log(i) } })
+            .collect(),
+        ..Config::default()
+    };
+    let mut bus = Bus::new(&config);
+    let base = VIRTIO_BASE + VIRTIO_SLOT_SIZE;
+    let queues = 2 * (MAX_PORTS as u32 + 1);
+    start_modern(&mut bus, base, queues);
+
+    // the transmitq of the last port is far beyond queue 31
+    offer(&mut bus, base, queues - 1, 0, REQUEST, b"last", false);
+    assert_eq!(used_idx(&mut bus, queues - 1), 1);
+    for _ in 0..1000 {
+        if fs::read(log(MAX_PORTS - 1)).unwrap() == b"last" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(fs::read(log(MAX_PORTS - 1)).unwrap(), b"last");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn net_user_stack() {
+    let dir = env::temp_dir().join(format!("mips-emu-net-{}-{:?}", process::id(), thread::current().id()));
+    fs::create_dir_all(&dir).unwrap();
+    let pcap = dir.join("net.pcap").to_str().unwrap().to_string();
+    let mac = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    let config = Config {
+        time_mode: TimeMode::Deterministic,
+        net: Some(NetConfig { link: Link::User, pcap: Some(pcap.clone()), mac }),
+        ..Config::default()
+    };
+    let mut bus = Bus::new(&config);
+    let base = VIRTIO_BASE + VIRTIO_SLOT_SIZE;
+    assert_eq!(bus.read(base + 0x8, Size::Word).unwrap(), 1); // DeviceID
+    assert_eq!(bus.read(base + 0x100, Size::Word).unwrap(), 0x12005452); // mac
+    start_modern(&mut bus, base, 2);
+    for i in 0..2 {
+        offer(&mut bus, base, 0, i, REQUEST + 0x100 * i, &[0; 0x100], true);
+    }
+
+    // ARP who-has 10.0.2.2 from 10.0.2.15, after the 12-byte virtio_net_hdr
+    let mut arp = vec![0; 12];
+    arp.extend_from_slice(&[0xff; 6]);
+    arp.extend_from_slice(&mac);
+    arp.extend_from_slice(&[0x08, 0x06, 0, 1, 8, 0, 6, 4, 0, 1]);
+    arp.extend_from_slice(&mac);
+    arp.extend_from_slice(&[10, 0, 2, 15, 0, 0, 0, 0, 0, 0]);
+    arp.extend_from_slice(&USER_IP);
+    offer(&mut bus, base, 1, 0, REQUEST + 0x800, &arp, false);
+    let reply = used_bytes(&mut bus, 0, 0, REQUEST);
+    assert_eq!(reply[12..18], mac);
+    assert_eq!(reply[12 + 20..12 + 22], [0, 2]); // oper: reply
+    assert_eq!(reply[12 + 28..12 + 32], USER_IP);
+
+    // ICMP echo request
+    let mut icmp = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1, b'h', b'i'];
+    let sum = checksum(&icmp);
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+    let mut ip = vec![0x45, 0, 0, 20 + icmp.len() as u8, 0, 0, 0, 0, 64, 1, 0, 0, 10, 0, 2, 15];
+    ip.extend_from_slice(&USER_IP);
+    let sum = checksum(&ip);
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+    let mut ping = vec![0; 12];
+    ping.extend_from_slice(&reply[18..24]);
+    ping.extend_from_slice(&mac);
+    ping.extend_from_slice(&[0x08, 0x00]);
+    ping.extend_from_slice(&ip);
+    ping.extend_from_slice(&icmp);
+    offer(&mut bus, base, 1, 1, REQUEST + 0x800, &ping, false);
+    let reply = used_bytes(&mut bus, 0, 1, REQUEST + 0x100);
+    let ip = &reply[12 + 14..];
+    assert_eq!(checksum(&ip[..20]), 0);
+    assert_eq!(ip[16..20], [10, 0, 2, 15]);
+    assert_eq!(ip[20], 0); // echo reply
+    assert_eq!(checksum(&ip[20..]), 0);
+    assert_eq!(&ip[28..], b"hi");
+
+    // the pcap file has its header and both directions of both exchanges
+    drop(bus);
+    let pcap = fs::read(&pcap).unwrap();
+    assert_eq!(pcap[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+    assert_eq!(pcap.len(), 24 + 4 * 16 + (arp.len() - 12) * 2 + (ping.len() - 12) * 2);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Fills an entropy request with the device in slot 1 of a fresh deterministic bus.
+fn rng_bytes(seed: Option<u64>) -> Vec<u8> {
+    let config = Config { time_mode: TimeMode::Deterministic, rng: true, rng_seed: seed, ..Config::default() };
+    let mut bus = Bus::new(&config);
+    let base = VIRTIO_BASE + VIRTIO_SLOT_SIZE;
+    assert_eq!(bus.read(base + 0x8, Size::Word).unwrap(), 4); // DeviceID
+    start_modern(&mut bus, base, 1);
+    offer(&mut bus, base, 0, 0, REQUEST, &[0; 20], true);
+    used_bytes(&mut bus, 0, 0, REQUEST)
+}
+
+#[test]
+fn rng_is_reproducible() {
+    let bytes = rng_bytes(None);
+    assert_eq!(bytes.len(), 20);
+    assert_ne!(bytes, [0; 20]);
+    assert_eq!(bytes, rng_bytes(None));
+    assert_ne!(bytes, rng_bytes(Some(1)));
+}
+
+/// Sends a 9P T-message through queue 0 of the transport at `base` and returns the R-message.
+fn transact(bus: &mut Bus, base: u32, idx: u32, kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = (7 + body.len() as u32).to_le_bytes().to_vec();
+    message.push(kind);
+    message.extend_from_slice(&(idx as u16).to_le_bytes());
+    message.extend_from_slice(body);
+    for (i, byte) in message.iter().enumerate() {
+        bus.write(REQUEST + i as u32, *byte as u32, Size::Byte).unwrap();
+    }
+    // descriptors: the request, then a writable buffer for the response
+    let descs = [(REQUEST, message.len() as u32, 1, 1), (REQUEST + 0x1000, 0x1000, 2, 0)];
+    for (i, (addr, len, flags, next)) in descs.into_iter().enumerate() {
+        let desc = ring_base(0) + 16 * i as u32;
+        bus.write(desc, addr, Size::Word).unwrap();
+        bus.write(desc + 8, len, Size::Word).unwrap();
+        bus.write(desc + 12, flags, Size::Halfword).unwrap();
+        bus.write(desc + 14, next, Size::Halfword).unwrap();
+    }
+    bus.write(ring_base(0) + 0x100 + 4 + 2 * (idx % 8), 0, Size::Halfword).unwrap();
+    bus.write(ring_base(0) + 0x100 + 2, idx + 1, Size::Halfword).unwrap();
+    bus.write(base + 0x50, 0, Size::Word).unwrap();
+    bus.advance(1);
+    let response = used_bytes(bus, 0, idx, REQUEST + 0x1000);
+    assert_eq!(response[5..7], (idx as u16).to_le_bytes()); // tag
+    response
+}
+
+/// Concatenates 9P fields: numbers are passed as little-endian bytes, strings get their length.
+fn p9_string(s: &str) -> Vec<u8> {
+    let mut bytes = (s.len() as u16).to_le_bytes().to_vec();
+    bytes.extend_from_slice(s.as_bytes());
+    bytes
+}
+
+/// Starts a 9P session sharing `dir` and attaches fid 0 to its root.
+fn p9_session(dir: &str, read_only: bool) -> (Bus, u32) {
+    let config = Config {
+        time_mode: TimeMode::Deterministic,
+        share: Some(ShareConfig { path: dir.to_string(), tag: String::from("build"), read_only }),
+        ..Config::default()
+    };
+    let mut bus = Bus::new(&config);
+    let base = VIRTIO_BASE + VIRTIO_SLOT_SIZE;
+    assert_eq!(bus.read(base + 0x8, Size::Word).unwrap(), 9); // DeviceID
+    assert_eq!(bus.read(base + 0x100, Size::Halfword).unwrap(), 5); // tag_len
+    assert_eq!(bus.read(base + 0x102, Size::Byte).unwrap(), b'b' as u32);
+    start_modern(&mut bus, base, 1);
+
+    let version = [&0x2000u32.to_le_bytes()[..], &p9_string("9P2000.L")].concat();
+    let response = transact(&mut bus, base, 0, 100, &version);
+    assert_eq!(response[4], 101);
+    assert_eq!(response[11..], p9_string("9P2000.L"));
+    let attach = [&0u32.to_le_bytes()[..], &u32::MAX.to_le_bytes(), &p9_string("root"), &p9_string(""), &0u32.to_le_bytes()].concat();
+    assert_eq!(transact(&mut bus, base, 1, 104, &attach)[4], 105);
+    (bus, base)
+}
+
+#[test]
+fn p9_share() {
+    let dir = env::temp_dir().join(format!("mips-emu-9p-{}-{:?}", process::id(), thread::current().id()));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("hello.txt"), "hi there").unwrap();
+    let outside = env::temp_dir().join(format!("mips-emu-9p-outside-{}-{:?}", process::id(), thread::current().id()));
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("secret"), "host only").unwrap();
+    std::os::unix::fs::symlink(&outside, dir.join("out")).unwrap();
+    std::os::unix::fs::symlink(outside.join("secret"), dir.join("secret")).unwrap();
+    let path = dir.to_str().unwrap();
+
+    let (mut bus, base) = p9_session(path, false);
+    // walking above the root stays at the root
+    let walk = [&0u32.to_le_bytes()[..], &9u32.to_le_bytes(), &1u16.to_le_bytes(), &p9_string("..")].concat();
+    let up = transact(&mut bus, base, 2, 110, &walk);
+    let walk = [&0u32.to_le_bytes()[..], &10u32.to_le_bytes(), &0u16.to_le_bytes()].concat();
+    transact(&mut bus, base, 3, 110, &walk);
+    let getattr = [&10u32.to_le_bytes()[..], &0x7ffu64.to_le_bytes()].concat();
+    let root = transact(&mut bus, base, 4, 24, &getattr);
+    assert_eq!(up[9..22], root[15..28]); // qid
+
+    // read a file
+    let walk = [&0u32.to_le_bytes()[..], &1u32.to_le_bytes(), &1u16.to_le_bytes(), &p9_string("hello.txt")].concat();
+    assert_eq!(transact(&mut bus, base, 5, 110, &walk)[7..9], [1, 0]);
+    transact(&mut bus, base, 6, 12, &[1, 0, 0, 0, 0, 0, 0, 0]);
+    let read = [&1u32.to_le_bytes()[..], &3u64.to_le_bytes(), &100u32.to_le_bytes()].concat();
+    let response = transact(&mut bus, base, 7, 116, &read);
+    assert_eq!(&response[11..], b"there");
+
+    // list the root: ., .., hello.txt, sub
+    transact(&mut bus, base, 8, 12, &[10, 0, 0, 0, 0, 0, 0, 0]);
+    let readdir = [&10u32.to_le_bytes()[..], &0u64.to_le_bytes(), &0x800u32.to_le_bytes()].concat();
+    let response = transact(&mut bus, base, 9, 40, &readdir);
+    let mut names = Vec::new();
+    let mut pos = 11;
+    while pos < response.len() {
+        let len = u16::from_le_bytes([response[pos + 22], response[pos + 23]]) as usize;
+        names.push(String::from_utf8(response[pos + 24..pos + 24 + len].to_vec()).unwrap());
+        pos += 24 + len;
+    }
+    assert_eq!(names, [".", "..", "hello.txt", "out", "secret", "sub"]);
+
+    // create and write a file
+    let walk = [&0u32.to_le_bytes()[..], &2u32.to_le_bytes(), &0u16.to_le_bytes()].concat();
+    transact(&mut bus, base, 10, 110, &walk);
+    let create = [&2u32.to_le_bytes()[..], &p9_string("new.txt"), &2u32.to_le_bytes(), &0o644u32.to_le_bytes(), &0u32.to_le_bytes()].concat();
+    assert_eq!(transact(&mut bus, base, 11, 14, &create)[4], 15);
+    let write = [&2u32.to_le_bytes()[..], &0u64.to_le_bytes(), &3u32.to_le_bytes(), b"abc"].concat();
+    assert_eq!(transact(&mut bus, base, 12, 118, &write)[7..11], 3u32.to_le_bytes());
+    assert_eq!(fs::read(dir.join("new.txt")).unwrap(), b"abc");
+
+    // links out of the share are seen as links, but the host neither walks nor opens through them
+    let walk = [&0u32.to_le_bytes()[..], &3u32.to_le_bytes(), &2u16.to_le_bytes(), &p9_string("out"), &p9_string("secret")].concat();
+    assert_eq!(transact(&mut bus, base, 13, 110, &walk)[7..9], [1, 0]);
+    let walk = [&0u32.to_le_bytes()[..], &3u32.to_le_bytes(), &1u16.to_le_bytes(), &p9_string("secret")].concat();
+    assert_eq!(transact(&mut bus, base, 14, 110, &walk)[7..9], [1, 0]);
+    let response = transact(&mut bus, base, 15, 12, &[3, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(response[4], 7); // Rlerror
+    assert_eq!(response[7..11], 40u32.to_le_bytes()); // ELOOP
+    let response = transact(&mut bus, base, 16, 22, &3u32.to_le_bytes());
+    assert_eq!(response[7..], p9_string(outside.join("secret").to_str().unwrap()));
+    let create = [&0u32.to_le_bytes()[..], &p9_string("secret"), &2u32.to_le_bytes(), &0o644u32.to_le_bytes(), &0u32.to_le_bytes()].concat();
+    assert_eq!(transact(&mut bus, base, 17, 14, &create)[4], 7);
+    assert_eq!(fs::read(outside.join("secret")).unwrap(), b"host only");
+
+    // a read-only share refuses to create files
+    let (mut bus, base) = p9_session(path, true);
+    let walk = [&0u32.to_le_bytes()[..], &2u32.to_le_bytes(), &0u16.to_le_bytes()].concat();
+    transact(&mut bus, base, 2, 110, &walk);
+    let create = [&2u32.to_le_bytes()[..], &p9_string("other.txt"), &2u32.to_le_bytes(), &0o644u32.to_le_bytes(), &0u32.to_le_bytes()].concat();
+    let response = transact(&mut bus, base, 3, 14, &create);
+    assert_eq!(response[4], 7); // Rlerror
+    assert_eq!(response[7..11], 30u32.to_le_bytes()); // EROFS
+    assert!(!dir.join("other.txt").exists());
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&outside);
+}
+
+#[test]
+pub fn test_all() {
+    blk_read_write();
+    modern_transport();
+    legacy_queue_layout();
+    console_ports();
+    console_many_ports();
+    net_user_stack();
+    rng_is_reproducible();
+    p9_share();
+}
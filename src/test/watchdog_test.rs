@@ -0,0 +1,64 @@
+use crate::bus::WATCHDOG_BASE;
+use crate::config::{Config, TimeMode};
+use crate::coprocessor::{SR, SR_ERL, SR_NMI};
+use crate::cpu::{Instruction, Size, REBOOT_VECTOR, T0, T1, ZERO};
+use crate::devices::device::Device;
+use crate::devices::watchdog::{Action, WATCHDOG_EXIT_STATUS};
+use super::cpu_with_config;
+
+const CONTROL: u32 = 0x00;
+const TIMEOUT: u32 = 0x04;
+const KICK: u32 = 0x08;
+const REMAINING: u32 = 0x0c;
+const STATUS: u32 = 0x10;
+
+fn config(action: Action, armed: Option<u32>) -> Config {
+    Config { time_mode: TimeMode::Deterministic, watchdog_action: action, watchdog_timeout: armed, ..Config::default() }
+}
+
+#[test]
+fn watchdog_halts_idle_guest() {
+    // armed from power-on, a guest waiting for an interrupt that never comes is stopped
+    let mut cpu = cpu_with_config(&config(Action::Halt, Some(5)), &[Instruction::wait()]);
+    assert_eq!(cpu.run(), WATCHDOG_EXIT_STATUS);
+    assert!(cpu.stats.cycles >= 5000);
+    assert!(cpu.bus.watchdog.report().contains("no kick for 5 ms"));
+}
+
+#[test]
+fn watchdog_nmi() {
+    let mut cpu = cpu_with_config(&config(Action::Nmi, None), &[
+        Instruction::ori(T0, ZERO, 0x3200),
+        Instruction::ori(T1, ZERO, 2),
+        Instruction::sw(T1, T0, TIMEOUT as u16),
+        Instruction::ori(T1, ZERO, 1),
+        Instruction::sw(T1, T0, CONTROL as u16),
+        Instruction::wait(),
+    ]);
+    assert_eq!(cpu.debug(6), None);
+    let bus = &mut cpu.bus;
+    assert_eq!(bus.read(WATCHDOG_BASE + REMAINING, Size::Word).unwrap(), 2);
+    bus.advance(1500);
+    assert_eq!(bus.read(WATCHDOG_BASE + REMAINING, Size::Word).unwrap(), 1);
+    bus.write(WATCHDOG_BASE + KICK, 0, Size::Word).unwrap();
+    assert_eq!(bus.read(WATCHDOG_BASE + REMAINING, Size::Word).unwrap(), 2);
+
+    for _ in 0..10 {
+        if cpu.pc == REBOOT_VECTOR {
+            break;
+        }
+        cpu.debug(1);
+    }
+    assert_eq!(cpu.pc, REBOOT_VECTOR);
+    assert_eq!(cpu.load_coprocessor0(SR).unwrap() & (SR_NMI | SR_ERL), SR_NMI | SR_ERL);
+    assert_eq!(cpu.bus.read(WATCHDOG_BASE + STATUS, Size::Word).unwrap(), 1);
+    assert_eq!(cpu.bus.read(WATCHDOG_BASE + CONTROL, Size::Word).unwrap(), 0);
+    cpu.bus.write(WATCHDOG_BASE + STATUS, 1, Size::Word).unwrap();
+    assert_eq!(cpu.bus.read(WATCHDOG_BASE + STATUS, Size::Word).unwrap(), 0);
+}
+
+#[test]
+pub fn test_all() {
+    watchdog_halts_idle_guest();
+    watchdog_nmi();
+}
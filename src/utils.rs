@@ -73,9 +73,27 @@ impl Instruction {
     pub fn sra(rd: u8, rt: u8, shamt: u8) -> Self {
         Self::R { opcode: 0x0, rs: 0, rt, rd, shamt, funct: 0x03 }
     }
+    pub fn sllv(rd: u8, rt: u8, rs: u8) -> Self {
+        Self::R { opcode: 0x0, rs, rt, rd, shamt: 0, funct: 0x04 }
+    }
+    pub fn srlv(rd: u8, rt: u8, rs: u8) -> Self {
+        Self::R { opcode: 0x0, rs, rt, rd, shamt: 0, funct: 0x06 }
+    }
+    pub fn srav(rd: u8, rt: u8, rs: u8) -> Self {
+        Self::R { opcode: 0x0, rs, rt, rd, shamt: 0, funct: 0x07 }
+    }
     pub fn jr(rs: u8) -> Self {
         Self::R {opcode: 0x0, rs, rt: 0, rd: 0, shamt: 0, funct: 0x08 }
     }
+    pub fn jalr(rd: u8, rs: u8) -> Self {
+        Self::R { opcode: 0x0, rs, rt: 0, rd, shamt: 0, funct: 0x09 }
+    }
+    pub fn movz(rd: u8, rs: u8, rt: u8) -> Self {
+        Self::R { opcode: 0x0, rs, rt, rd, shamt: 0, funct: 0x0a }
+    }
+    pub fn movn(rd: u8, rs: u8, rt: u8) -> Self {
+        Self::R { opcode: 0x0, rs, rt, rd, shamt: 0, funct: 0x0b }
+    }
     pub fn syscall() -> Self {
         Self::R { opcode: 0x0, rs: 0, rt: 0, rd: 0, shamt: 0, funct: 0xc }
     }
@@ -134,24 +152,69 @@ impl Instruction {
     pub fn sltu(rd: u8, rs: u8, rt: u8) -> Self {
         Self::R { opcode: 0x0, rs, rt, rd, shamt: 0, funct: 0x2b }
     }
+    pub fn teq(rs: u8, rt: u8) -> Self {
+        Self::R { opcode: 0x0, rs, rt, rd: 0, shamt: 0, funct: 0x34 }
+    }
 
-    pub fn mfc0(rt: u8, rd: u8) -> Self {
-        Self::R { opcode: 0x10, rs: 0, rt, rd, shamt: 0, funct: 0 }
+    pub fn mfc0(rt: u8, rd: u8, sel: u8) -> Self {
+        Self::R { opcode: 0x10, rs: 0, rt, rd, shamt: 0, funct: sel }
     }
-    pub fn mtc0(rt: u8, rd: u8) -> Self {
-        Self::R { opcode: 0x10, rs: 0x4, rt, rd, shamt: 0, funct: 0 }
+    pub fn mtc0(rt: u8, rd: u8, sel: u8) -> Self {
+        Self::R { opcode: 0x10, rs: 0x4, rt, rd, shamt: 0, funct: sel }
+    }
+    pub fn rdpgpr(rd: u8, rt: u8) -> Self {
+        Self::R { opcode: 0x10, rs: 0xa, rt, rd, shamt: 0, funct: 0 }
+    }
+    pub fn wrpgpr(rd: u8, rt: u8) -> Self {
+        Self::R { opcode: 0x10, rs: 0xe, rt, rd, shamt: 0, funct: 0 }
     }
     pub fn eret() -> Self {
         Self::R { opcode: 0x10, rs: 0x10, rt: 0, rd: 0, shamt: 0, funct: 0x18 }
     }
+    pub fn wait() -> Self {
+        Self::R { opcode: 0x10, rs: 0x10, rt: 0, rd: 0, shamt: 0, funct: 0x20 }
+    }
+    pub fn mul(rd: u8, rs: u8, rt: u8) -> Self {
+        Self::R { opcode: 0x1c, rs, rt, rd, shamt: 0, funct: 0x02 }
+    }
+    pub fn clz(rd: u8, rs: u8) -> Self {
+        Self::R { opcode: 0x1c, rs, rt: rd, rd, shamt: 0, funct: 0x20 }
+    }
+    pub fn clo(rd: u8, rs: u8) -> Self {
+        Self::R { opcode: 0x1c, rs, rt: rd, rd, shamt: 0, funct: 0x21 }
+    }
+    pub fn sdbbp(code: u32) -> Self {
+        Self::R { opcode: 0x1c, rs: (code >> 15 & 0x1f) as u8, rt: (code >> 10 & 0x1f) as u8, rd: (code >> 5 & 0x1f) as u8, shamt: (code & 0x1f) as u8, funct: 0x3f }
+    }
+    pub fn rdhwr(rt: u8, rd: u8) -> Self {
+        Self::R { opcode: 0x1f, rs: 0, rt, rd, shamt: 0, funct: 0x3b }
+    }
 
     // I types
+    pub fn bltz(rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x1, rs, rt: 0x00, imm }
+    }
+    pub fn bgez(rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x1, rs, rt: 0x01, imm }
+    }
+    pub fn bltzal(rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x1, rs, rt: 0x10, imm }
+    }
+    pub fn bgezal(rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x1, rs, rt: 0x11, imm }
+    }
     pub fn beq(rt: u8, rs: u8, imm: u16) -> Self {
         Self::I { opcode: 0x4, rs, rt, imm }
     }
     pub fn bne(rt: u8, rs: u8, imm: u16) -> Self {
         Self::I { opcode: 0x5, rs, rt, imm }
     }
+    pub fn blez(rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x6, rs, rt: 0, imm }
+    }
+    pub fn bgtz(rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x7, rs, rt: 0, imm }
+    }
     pub fn addi(rt: u8, rs: u8, imm: u16) -> Self {
         Self::I { opcode: 0x8, rs, rt, imm }
     }
@@ -176,6 +239,15 @@ impl Instruction {
     pub fn lui(rt: u8, imm: u16) -> Self {
         Self::I { opcode: 0xf, rs: 0, rt, imm }
     }
+    pub fn lb(rt: u8, rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x20, rs, rt, imm }
+    }
+    pub fn lh(rt: u8, rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x21, rs, rt, imm }
+    }
+    pub fn lwl(rt: u8, rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x22, rs, rt, imm }
+    }
     pub fn lw(rt: u8, rs: u8, imm: u16) -> Self {
         Self::I { opcode: 0x23, rs, rt, imm }
     }
@@ -185,15 +257,24 @@ impl Instruction {
     pub fn lhu(rt: u8, rs: u8, imm: u16) -> Self {
         Self::I { opcode: 0x25, rs, rt, imm }
     }
+    pub fn lwr(rt: u8, rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x26, rs, rt, imm }
+    }
     pub fn sb(rt: u8, rs: u8, imm: u16) -> Self {
         Self::I { opcode: 0x28, rs, rt, imm }
     }
     pub fn sh(rt: u8, rs: u8, imm: u16) -> Self {
         Self::I { opcode: 0x29, rs, rt, imm }
     }
+    pub fn swl(rt: u8, rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x2a, rs, rt, imm }
+    }
     pub fn sw(rt: u8, rs: u8, imm: u16) -> Self {
         Self::I { opcode: 0x2b, rs, rt, imm }
     }
+    pub fn swr(rt: u8, rs: u8, imm: u16) -> Self {
+        Self::I { opcode: 0x2e, rs, rt, imm }
+    }
     pub fn ll(rt: u8, rs: u8, imm: u16) -> Self {
         Self::I { opcode: 0x30, rs, rt, imm }
     }